@@ -0,0 +1,47 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// protoc isn't needed, protox parses the schema in rust
+fn compile_protos() {
+    let descriptors = protox::compile(["proto/absher.proto"], ["proto"])
+        .unwrap_or_else(|err| panic!("proto/absher.proto doesn't compile: {err}"));
+    tonic_prost_build::configure()
+        .build_client(false)
+        // so answers can be handed over as buffers that wipe themselves
+        .bytes(".absher.v1.Resolved.message")
+        .compile_fds(descriptors)
+        .unwrap_or_else(|err| panic!("unable to generate the grpc service: {err}"));
+    println!("cargo:rerun-if-changed=proto");
+}
+
+fn main() {
+    compile_protos();
+
+    let sha = match git(&["rev-parse", "HEAD"]) {
+        Some(sha) if git(&["status", "--porcelain"]).is_some_and(|changes| !changes.is_empty()) => format!("{sha}-dirty"),
+        Some(sha) => sha,
+        // built from a source tarball
+        None => "unknown".to_owned(),
+    };
+
+    // reproducible builds pin the time through SOURCE_DATE_EPOCH
+    let built_at = std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |dur| dur.as_secs()));
+
+    println!("cargo:rustc-env=ABSHER_GIT_SHA={sha}");
+    println!("cargo:rustc-env=ABSHER_BUILT_AT={built_at}");
+
+    // only a new commit or a change to the work tree moves the sha
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    if let Some(head) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        println!("cargo:rerun-if-changed=.git/{head}");
+    }
+}
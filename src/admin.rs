@@ -0,0 +1,628 @@
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::middleware::Next;
+use dashmap::{DashMap, Entry};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use crate::config::setting;
+use crate::error::AppError;
+use crate::geo::GeoCheck;
+use crate::sms::SmsPolicy;
+use crate::ttl::TtlPolicy;
+use crate::watermark::Watermark;
+use crate::webhooks::RetryPolicy;
+use crate::req_code::RequestCode;
+use crate::model::RequestedAutofillFields;
+use crate::store::{self, expire_request, pending_requests, withdraw_request};
+use crate::blocklist::{self, Cidr, Target};
+use crate::{challenge, client_errors, config, denylist, events, history, maintenance, metering, oauth, stats, to_json_str};
+
+/// the admin api is disabled unless this is set
+static ADMIN_TOKEN: LazyLock<Option<String>> = LazyLock::new(|| {
+    setting("ABSHER_ADMIN_TOKEN").filter(|token| !token.is_empty())
+});
+
+static TENANTS: LazyLock<DashMap<String, Tenant>> = LazyLock::new(DashMap::new);
+static KEYS: LazyLock<DashMap<String, ApiKey>> = LazyLock::new(DashMap::new);
+
+const KEY_PREFIX: &str = "azt";
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |dur| dur.as_secs())
+}
+
+#[derive(Clone, Serialize)]
+pub struct Tenant {
+    id: String,
+    name: String,
+    ttl: TtlPolicy,
+    /// how document images shared with this tenant get marked
+    watermark: Watermark,
+    /// how many codes its listeners may have texted and what the texts say
+    sms: SmsPolicy,
+    /// whether its codes have to be answered from the country they were shown in
+    geo: GeoCheck,
+    /// how its listeners' webhooks are retried
+    webhook_retries: RetryPolicy,
+    created_at: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub tenant: String,
+    /// the fields this key is allowed to request
+    pub scopes: RequestedAutofillFields,
+    /// where the openid connect flow may send users back to, when the key is also a client
+    pub redirect_uris: Vec<String>,
+    created_at: u64,
+    rotated_at: Option<u64>,
+    #[serde(skip)]
+    secret: String,
+}
+
+impl ApiKey {
+    fn full_secret(&self) -> String {
+        format!("{KEY_PREFIX}_{}_{}", self.id, self.secret)
+    }
+}
+
+/// only ever sent back once, on creation and rotation
+#[derive(Serialize)]
+struct IssuedKey<'a> {
+    #[serde(flatten)]
+    key: &'a ApiKey,
+    secret: String,
+}
+
+impl<'a> From<&'a ApiKey> for IssuedKey<'a> {
+    fn from(key: &'a ApiKey) -> Self {
+        Self { secret: key.full_secret(), key }
+    }
+}
+
+fn new_secret() -> String {
+    Alphanumeric.sample_string(&mut rand::rng(), 32)
+}
+
+/// the ttl policy a tenant's requests are created with
+pub fn tenant_ttl(tenant: &str) -> TtlPolicy {
+    TENANTS.get(tenant).map(|tenant| tenant.ttl).unwrap_or_default()
+}
+
+/// how to mark images shared with a tenant and the name to mark them with, if at all
+pub fn tenant_watermark(tenant: &str) -> Option<(Watermark, String)> {
+    let tenant = TENANTS.get(tenant)?;
+    (tenant.watermark != Watermark::Off).then(|| (tenant.watermark, tenant.name.clone()))
+}
+
+/// how a tenant's webhooks are retried
+pub fn tenant_webhook_retries(tenant: &str) -> RetryPolicy {
+    TENANTS.get(tenant).map(|tenant| tenant.webhook_retries.clone()).unwrap_or_default()
+}
+
+/// how a tenant's codes are texted, and the name the texts call it by
+pub fn tenant_sms(tenant: &str) -> (SmsPolicy, String) {
+    match TENANTS.get(tenant) {
+        Some(tenant) => (tenant.sms.clone(), tenant.name.clone()),
+        None => (SmsPolicy::default(), tenant.to_owned()),
+    }
+}
+
+/// what to do when a tenant's codes are answered from abroad
+pub fn tenant_geo(tenant: &str) -> GeoCheck {
+    TENANTS.get(tenant).map(|tenant| tenant.geo).unwrap_or_default()
+}
+
+/// a key by its id, which openid connect calls the client id
+pub fn key(id: &str) -> Option<ApiKey> {
+    KEYS.get(id).map(|key| key.clone())
+}
+
+/// what a listener authenticates with, an api key or access token in `x-api-key` or an access
+/// token as a bearer
+pub fn presented(headers: &HeaderMap) -> Option<&str> {
+    let value = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    value("x-api-key").or_else(|| value(header::AUTHORIZATION.as_str())?.strip_prefix("Bearer "))
+}
+
+/// the id of a presented key, or of the key an access token was issued to, whether or not either
+/// is valid
+pub fn key_id(presented: &str) -> Option<String> {
+    let Some(rest) = presented.strip_prefix(KEY_PREFIX) else {
+        return oauth::client_id(presented)
+    };
+    let rest = rest.strip_prefix('_')?;
+    rest.split_once('_').map(|(id, _secret)| id.to_owned())
+}
+
+/// looks up the key a listener presented, or the one behind its access token
+pub fn authenticate(presented: &str) -> Option<ApiKey> {
+    match presented.starts_with(KEY_PREFIX) {
+        true => authenticate_key(presented),
+        false => oauth::authenticate(presented),
+    }
+}
+
+/// looks up a key, access tokens don't count
+pub fn authenticate_key(presented: &str) -> Option<ApiKey> {
+    let rest = presented.strip_prefix(KEY_PREFIX)?.strip_prefix('_')?;
+    let (id, secret) = rest.split_once('_')?;
+    let key = KEYS.get(id)?;
+
+    bool::from(key.secret.as_bytes().ct_eq(secret.as_bytes())).then(|| key.clone())
+}
+
+pub async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(expected) = ADMIN_TOKEN.as_deref() else {
+        return Err(AppError::AdminDisabled.into())
+    };
+
+    let presented = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if !bool::from(presented.as_bytes().ct_eq(expected.as_bytes())) {
+        return Err(AppError::InvalidAdminToken.into())
+    }
+
+    next.call(req).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .service(list_tenants)
+            .service(create_tenant)
+            .service(get_tenant)
+            .service(update_tenant)
+            .service(delete_tenant)
+            .service(tenant_client_errors)
+            .service(list_keys)
+            .service(create_key)
+            .service(get_key)
+            .service(update_scopes)
+            .service(update_redirect_uris)
+            .service(rotate_key)
+            .service(revoke_key)
+            .service(stats_snapshot)
+            .service(usage_report)
+            .service(request_history)
+            .service(events::feed)
+            .service(list_requests)
+            .service(expire)
+            .service(get_challenge)
+            .service(set_challenge)
+            .service(get_maintenance)
+            .service(set_maintenance)
+            .service(reload_config)
+            .service(list_denials)
+            .service(add_denial)
+            .service(lift_denial)
+            .service(list_blocks)
+            .service(add_block)
+            .service(lift_block)
+            .wrap(actix_web::middleware::from_fn(require_admin))
+    );
+}
+
+fn json_ok(ser: &impl Serialize) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(to_json_str(ser)?))
+}
+
+
+#[derive(Deserialize)]
+struct NewTenant {
+    id: String,
+    name: String,
+    #[serde(default)]
+    ttl: TtlPolicy,
+    #[serde(default)]
+    watermark: Watermark,
+    #[serde(default)]
+    sms: SmsPolicy,
+    #[serde(default)]
+    geo: GeoCheck,
+    #[serde(default)]
+    webhook_retries: RetryPolicy,
+}
+
+#[derive(Deserialize)]
+struct TenantUpdate {
+    name: Option<String>,
+    ttl: Option<TtlPolicy>,
+    watermark: Option<Watermark>,
+    sms: Option<SmsPolicy>,
+    geo: Option<GeoCheck>,
+    webhook_retries: Option<RetryPolicy>,
+}
+
+#[get("/tenants")]
+async fn list_tenants() -> Result<HttpResponse, AppError> {
+    let tenants = TENANTS.iter()
+        .map(|entry| entry.value().clone())
+        .collect::<Vec<_>>();
+
+    json_ok(&tenants)
+}
+
+#[post("/tenants")]
+async fn create_tenant(body: web::Json<NewTenant>) -> Result<HttpResponse, AppError> {
+    let NewTenant { id, name, ttl, watermark, sms, geo, webhook_retries } = body.into_inner();
+    ttl.validate()?;
+    sms.validate()?;
+    webhook_retries.validate()?;
+
+    let valid_id = !id.is_empty() && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    if !valid_id {
+        return Err(AppError::InvalidTenantId)
+    }
+
+    match TENANTS.entry(id.clone()) {
+        Entry::Occupied(_) => Err(AppError::TenantExists),
+        Entry::Vacant(vacant) => {
+            let tenant = vacant.insert(Tenant { id, name, ttl, watermark, sms, geo, webhook_retries, created_at: unix_now() });
+            json_ok(tenant.value())
+        }
+    }
+}
+
+#[get("/tenants/{id}")]
+async fn get_tenant(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let tenant = TENANTS.get(id.as_str()).ok_or(AppError::TenantNotFound)?;
+    json_ok(tenant.value())
+}
+
+/// how often this tenant's listeners reported each client error
+#[get("/tenants/{id}/client-errors")]
+async fn tenant_client_errors(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    if !TENANTS.contains_key(id.as_str()) {
+        return Err(AppError::TenantNotFound)
+    }
+
+    json_ok(&client_errors::tenant_counts(&id))
+}
+
+#[put("/tenants/{id}")]
+async fn update_tenant(id: web::Path<String>, body: web::Json<TenantUpdate>) -> Result<HttpResponse, AppError> {
+    let TenantUpdate { name, ttl, watermark, sms, geo, webhook_retries } = body.into_inner();
+    if let Some(ttl) = ttl {
+        ttl.validate()?;
+    }
+    if let Some(sms) = &sms {
+        sms.validate()?;
+    }
+    if let Some(webhook_retries) = &webhook_retries {
+        webhook_retries.validate()?;
+    }
+
+    let mut tenant = TENANTS.get_mut(id.as_str()).ok_or(AppError::TenantNotFound)?;
+    if let Some(name) = name {
+        tenant.name = name;
+    }
+    if let Some(ttl) = ttl {
+        tenant.ttl = ttl;
+    }
+    if let Some(watermark) = watermark {
+        tenant.watermark = watermark;
+    }
+    if let Some(sms) = sms {
+        tenant.sms = sms;
+    }
+    if let Some(geo) = geo {
+        tenant.geo = geo;
+    }
+    if let Some(webhook_retries) = webhook_retries {
+        tenant.webhook_retries = webhook_retries;
+    }
+
+    json_ok(tenant.value())
+}
+
+#[delete("/tenants/{id}")]
+async fn delete_tenant(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    TENANTS.remove(id.as_str()).ok_or(AppError::TenantNotFound)?;
+
+    // a tenant's keys die with it
+    KEYS.retain(|_id, key| key.tenant != *id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+
+#[derive(Deserialize)]
+struct KeyFilter {
+    tenant: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NewKey {
+    tenant: String,
+    scopes: RequestedAutofillFields,
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+}
+
+#[get("/keys")]
+async fn list_keys(filter: web::Query<KeyFilter>) -> Result<HttpResponse, AppError> {
+    let keys = KEYS.iter()
+        .filter(|key| filter.tenant.as_ref().is_none_or(|tenant| key.tenant == *tenant))
+        .map(|key| key.value().clone())
+        .collect::<Vec<_>>();
+
+    json_ok(&keys)
+}
+
+#[post("/keys")]
+async fn create_key(body: web::Json<NewKey>) -> Result<HttpResponse, AppError> {
+    let NewKey { tenant, scopes, redirect_uris } = body.into_inner();
+    if !TENANTS.contains_key(&tenant) {
+        return Err(AppError::TenantNotFound)
+    }
+    check_redirect_uris(&redirect_uris)?;
+
+    loop {
+        let id = Alphanumeric.sample_string(&mut rand::rng(), 12);
+        let Entry::Vacant(vacant) = KEYS.entry(id.clone()) else {
+            continue
+        };
+
+        let key = vacant.insert(ApiKey {
+            id,
+            tenant,
+            scopes,
+            redirect_uris,
+            created_at: unix_now(),
+            rotated_at: None,
+            secret: new_secret(),
+        });
+
+        break json_ok(&IssuedKey::from(key.value()))
+    }
+}
+
+/// a key as `POST /admin/keys` would hand it out, with its secret, for tests of what keys open
+#[cfg(test)]
+pub(crate) fn issue_test_key(tenant: &str, scopes: RequestedAutofillFields, redirect_uris: Vec<String>) -> (ApiKey, String) {
+    let id = Alphanumeric.sample_string(&mut rand::rng(), 12);
+    let key = ApiKey {
+        id: id.clone(),
+        tenant: tenant.to_owned(),
+        scopes,
+        redirect_uris,
+        created_at: unix_now(),
+        rotated_at: None,
+        secret: new_secret(),
+    };
+    let secret = key.full_secret();
+    KEYS.insert(id, key.clone());
+    (key, secret)
+}
+
+#[cfg(test)]
+pub(crate) fn revoke_test_key(id: &str) {
+    KEYS.remove(id);
+}
+
+#[get("/keys/{id}")]
+async fn get_key(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let key = KEYS.get(id.as_str()).ok_or(AppError::KeyNotFound)?;
+    json_ok(key.value())
+}
+
+#[put("/keys/{id}/scopes")]
+async fn update_scopes(id: web::Path<String>, scopes: web::Json<RequestedAutofillFields>) -> Result<HttpResponse, AppError> {
+    let mut key = KEYS.get_mut(id.as_str()).ok_or(AppError::KeyNotFound)?;
+
+    key.scopes = scopes.into_inner();
+    json_ok(key.value())
+}
+
+/// https only, but for local development on loopback, and never with a fragment
+fn check_redirect_uris(uris: &[String]) -> Result<(), AppError> {
+    let acceptable = |uri: &str| !uri.contains('#') && (uri.starts_with("https://")
+        || uri.starts_with("http://localhost:")
+        || uri.starts_with("http://localhost/")
+        || uri.starts_with("http://127.0.0.1:"));
+
+    match uris.iter().all(|uri| acceptable(uri)) {
+        true => Ok(()),
+        false => Err(AppError::InvalidRedirectUri),
+    }
+}
+
+#[put("/keys/{id}/redirect-uris")]
+async fn update_redirect_uris(id: web::Path<String>, uris: web::Json<Vec<String>>) -> Result<HttpResponse, AppError> {
+    let uris = uris.into_inner();
+    check_redirect_uris(&uris)?;
+    let mut key = KEYS.get_mut(id.as_str()).ok_or(AppError::KeyNotFound)?;
+
+    key.redirect_uris = uris;
+    json_ok(key.value())
+}
+
+#[post("/keys/{id}/rotate")]
+async fn rotate_key(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let mut key = KEYS.get_mut(id.as_str()).ok_or(AppError::KeyNotFound)?;
+
+    key.secret = new_secret();
+    key.rotated_at = Some(unix_now());
+    json_ok(&IssuedKey::from(key.value()))
+}
+
+#[delete("/keys/{id}")]
+async fn revoke_key(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    KEYS.remove(id.as_str()).ok_or(AppError::KeyNotFound)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+
+/// live counts with a breakdown by tenant, prometheus has the history
+#[get("/stats")]
+async fn stats_snapshot() -> Result<HttpResponse, AppError> {
+    json_ok(&stats::snapshot(store::pending_by_tenant()))
+}
+
+#[derive(Deserialize)]
+struct UsageFilter {
+    tenant: Option<String>,
+    /// `YYYY-MM-DD`, utc, both ends included
+    from: Option<String>,
+    to: Option<String>,
+}
+
+/// daily usage by tenant, for billing
+#[get("/usage")]
+async fn usage_report(filter: web::Query<UsageFilter>) -> Result<HttpResponse, AppError> {
+    json_ok(&metering::report(filter.tenant.as_deref(), filter.from.as_deref(), filter.to.as_deref())?)
+}
+
+#[derive(Deserialize)]
+struct HistoryFilter {
+    tenant: Option<String>,
+    /// unix seconds the requests were created in, both ends included
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+/// requests that were opened, newest first, for looking into ones that went missing
+#[get("/history")]
+async fn request_history(filter: web::Query<HistoryFilter>) -> Result<HttpResponse, AppError> {
+    json_ok(&history::search(filter.tenant.as_deref(), filter.from, filter.to).await?)
+}
+
+#[get("/requests")]
+async fn list_requests() -> Result<HttpResponse, AppError> {
+    json_ok(&pending_requests())
+}
+
+#[delete("/requests/{code}")]
+async fn expire(code: web::Path<RequestCode>) -> Result<HttpResponse, AppError> {
+    match expire_request(*code) {
+        true => Ok(HttpResponse::NoContent().finish()),
+        false => Err(AppError::RequestNotFound(Some(code.into_inner())))
+    }
+}
+
+
+#[derive(Deserialize, Serialize)]
+struct ChallengeMode {
+    /// whether listeners without an api key must solve a proof of work
+    required: bool,
+}
+
+#[get("/challenge")]
+async fn get_challenge() -> Result<HttpResponse, AppError> {
+    json_ok(&ChallengeMode { required: challenge::required() })
+}
+
+#[put("/challenge")]
+async fn set_challenge(mode: web::Json<ChallengeMode>) -> Result<HttpResponse, AppError> {
+    challenge::set_required(mode.required);
+    json_ok(&mode.into_inner())
+}
+
+
+#[derive(Deserialize, Serialize)]
+struct MaintenanceMode {
+    /// whether new listeners are turned away
+    enabled: bool,
+}
+
+#[get("/maintenance")]
+async fn get_maintenance() -> Result<HttpResponse, AppError> {
+    json_ok(&MaintenanceMode { enabled: maintenance::enabled() })
+}
+
+#[put("/maintenance")]
+async fn set_maintenance(mode: web::Json<MaintenanceMode>) -> Result<HttpResponse, AppError> {
+    maintenance::set_enabled(mode.enabled);
+    json_ok(&mode.into_inner())
+}
+
+
+/// same as a SIGHUP, for setups where signals are hard to deliver
+#[post("/config/reload")]
+async fn reload_config() -> Result<HttpResponse, AppError> {
+    config::reload().map_err(AppError::InvalidConfig)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+struct NewDenial {
+    code: RequestCode,
+    reason: String,
+    /// falls back to the deny list's default ttl
+    ttl_secs: Option<u64>,
+}
+
+#[get("/denylist")]
+async fn list_denials() -> Result<HttpResponse, AppError> {
+    json_ok(&denylist::list())
+}
+
+#[post("/denylist")]
+async fn add_denial(body: web::Json<NewDenial>) -> Result<HttpResponse, AppError> {
+    let NewDenial { code, reason, ttl_secs } = body.into_inner();
+    let denial = denylist::deny(code, reason, ttl_secs.map(Duration::from_secs));
+    withdraw_request(&code);
+    json_ok(&denial)
+}
+
+#[delete("/denylist/{code}")]
+async fn lift_denial(code: web::Path<RequestCode>) -> Result<HttpResponse, AppError> {
+    match denylist::lift(&code) {
+        true => Ok(HttpResponse::NoContent().finish()),
+        false => Err(AppError::RequestNotFound(Some(code.into_inner())))
+    }
+}
+
+
+#[derive(Deserialize)]
+struct NewBlock {
+    cidr: Option<Cidr>,
+    origin: Option<String>,
+    key_id: Option<String>,
+    reason: String,
+    /// blocked for good without one
+    ttl_secs: Option<u64>,
+}
+
+#[get("/blocklist")]
+async fn list_blocks() -> Result<HttpResponse, AppError> {
+    json_ok(&blocklist::list())
+}
+
+#[post("/blocklist")]
+async fn add_block(body: web::Json<NewBlock>) -> Result<HttpResponse, AppError> {
+    let NewBlock { cidr, origin, key_id, reason, ttl_secs } = body.into_inner();
+    let target = match (cidr, origin, key_id) {
+        (Some(cidr), None, None) => Target::Cidr(cidr),
+        (None, Some(origin), None) if !origin.is_empty() => Target::Origin(origin),
+        (None, None, Some(key_id)) if !key_id.is_empty() => Target::KeyId(key_id),
+        _ => return Err(AppError::InvalidBlock),
+    };
+
+    json_ok(&blocklist::block(target, reason, ttl_secs.map(Duration::from_secs)))
+}
+
+#[delete("/blocklist/{id}")]
+async fn lift_block(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    match blocklist::lift(&id) {
+        true => Ok(HttpResponse::NoContent().finish()),
+        false => Err(AppError::BlockNotFound),
+    }
+}
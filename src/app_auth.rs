@@ -0,0 +1,77 @@
+use std::sync::LazyLock;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::Deserialize;
+use crate::config::setting;
+use crate::error::AppError;
+use crate::{signing, unix_now};
+
+/// longest user id a token may carry
+const MAX_SUBJECT_LEN: usize = 128;
+
+/// the public half of the key the app's backend signs its users' tokens with, users are only
+/// known to this server when it is set
+static KEY: LazyLock<Option<VerifyingKey>> = LazyLock::new(|| {
+    let key = setting("ABSHER_APP_AUTH_KEY")?;
+    let parsed = parse_key(&key);
+    if parsed.is_none() {
+        log::error!("ABSHER_APP_AUTH_KEY isn't an ed25519 public key, app users can't sign in");
+    }
+    parsed
+});
+
+/// a public key as `ABSHER_APP_AUTH_KEY` holds it, 32 bytes in standard base64
+pub fn parse_key(key: &str) -> Option<VerifyingKey> {
+    VerifyingKey::from_bytes(&signing::parse_signing_key(key)?).ok()
+}
+
+pub fn enabled() -> bool {
+    KEY.is_some()
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    /// unix seconds
+    exp: u64,
+}
+
+/// the user whose token is in the `Authorization` header, an EdDSA signed JWT from the app's
+/// backend with `sub` and `exp`; `None` when app users aren't set up
+pub(crate) fn user(req: &HttpRequest) -> Result<Option<String>, AppError> {
+    let Some(key) = KEY.as_ref() else {
+        return Ok(None)
+    };
+
+    let token = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(AppError::InvalidAppToken)?;
+
+    verify(key, token).map(Some).ok_or(AppError::InvalidAppToken)
+}
+
+fn verify(key: &VerifyingKey, token: &str) -> Option<String> {
+    let (signing_input, signature) = token.rsplit_once('.')?;
+    let (header, claims) = signing_input.split_once('.')?;
+
+    let header: Header = serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    if header.alg != "EdDSA" {
+        return None
+    }
+
+    let signature = Signature::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?).ok()?;
+    key.verify_strict(signing_input.as_bytes(), &signature).ok()?;
+
+    let claims: Claims = serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+    let subject_ok = !claims.sub.is_empty() && claims.sub.len() <= MAX_SUBJECT_LEN;
+    (subject_ok && unix_now() < claims.exp).then_some(claims.sub)
+}
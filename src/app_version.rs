@@ -0,0 +1,135 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::Next;
+use actix_web::HttpRequest;
+use serde::{Serialize, Serializer};
+use crate::config::setting;
+use crate::error::AppError;
+
+/// what the resolving app reports itself as
+pub const HEADER: &str = "x-app-version";
+
+/// a dotted `major.minor.patch` version, missing parts count as 0
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AppVersion([u32; 3]);
+
+impl FromStr for AppVersion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = [0; 3];
+        let mut split = s.trim().split('.');
+        for part in &mut parts {
+            match split.next() {
+                Some(num) => *part = num.parse().map_err(|_| ())?,
+                None => break,
+            }
+        }
+
+        match split.next() {
+            Some(_) => Err(()),
+            None => Ok(Self(parts)),
+        }
+    }
+}
+
+impl fmt::Display for AppVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [major, minor, patch] = self.0;
+        write!(f, "{major}.{minor}.{patch}")
+    }
+}
+
+impl Serialize for AppVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// parts of the resolve flow old apps can be cut off from separately
+#[derive(Copy, Clone, Debug)]
+pub enum Feature {
+    /// any call on a request code
+    Resolve,
+    /// fetching a public key and submitting sealed data
+    Encryption,
+}
+
+impl Feature {
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Resolve => "resolve",
+            Feature::Encryption => "encryption",
+        }
+    }
+
+    fn env_var(self) -> &'static str {
+        match self {
+            Feature::Resolve => "ABSHER_MIN_APP_VERSION",
+            Feature::Encryption => "ABSHER_MIN_APP_VERSION_ENCRYPTION",
+        }
+    }
+}
+
+struct Minimums {
+    resolve: Option<AppVersion>,
+    encryption: Option<AppVersion>,
+}
+
+/// nothing is gated unless a minimum is configured for it
+static MINIMUMS: LazyLock<Minimums> = LazyLock::new(|| {
+    let read = |feature: Feature| {
+        let value = setting(feature.env_var())?;
+        let version = value.parse().ok();
+        if version.is_none() {
+            log::error!("ignoring malformed {}={value}", feature.env_var());
+        }
+        version
+    };
+
+    Minimums { resolve: read(Feature::Resolve), encryption: read(Feature::Encryption) }
+});
+
+fn minimum(feature: Feature) -> Option<AppVersion> {
+    match feature {
+        Feature::Resolve => MINIMUMS.resolve,
+        Feature::Encryption => MINIMUMS.encryption,
+    }
+}
+
+fn check(headers: &HeaderMap, feature: Feature) -> Result<(), AppError> {
+    allows(headers.get(HEADER).and_then(|version| version.to_str().ok()), feature)
+}
+
+/// the same check for a version that didn't come in a header
+pub(crate) fn allows(version: Option<&str>, feature: Feature) -> Result<(), AppError> {
+    let Some(minimum) = minimum(feature) else {
+        return Ok(())
+    };
+
+    // apps from before the header existed are older than any minimum
+    let version = version.and_then(|version| version.parse::<AppVersion>().ok());
+
+    match version {
+        Some(version) if version >= minimum => Ok(()),
+        _ => Err(AppError::UpdateRequired { feature, minimum }),
+    }
+}
+
+/// fails with an update required error if the calling app is too old for `feature`
+pub fn require(req: &HttpRequest, feature: Feature) -> Result<(), AppError> {
+    check(req.headers(), feature)
+}
+
+/// turns away apps below the minimum version for any resolve
+pub async fn gate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    check(req.headers(), Feature::Resolve)?;
+    next.call(req).await
+}
@@ -0,0 +1,143 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{LazyLock, OnceLock};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::config::setting;
+use crate::error::AppError;
+use crate::req_code::RequestCode;
+use crate::{env_or, http_client};
+
+/// `<kind> <token>`, like `play_integrity eyJhbGciOi…`
+pub const HEADER: &str = "x-absher-attestation";
+
+/// a service that checks tokens for us, answers aren't attested without one
+static URL: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_ATTESTATION_URL"));
+static TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(env_or("ABSHER_ATTESTATION_TIMEOUT_MS", 5000))
+});
+
+/// what vouches for the app that answers
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// an assertion from a passkey the official app registered
+    #[serde(rename = "webauthn")]
+    WebAuthn,
+    /// a Google Play Integrity verdict
+    PlayIntegrity,
+}
+
+impl Kind {
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "webauthn" => Some(Kind::WebAuthn),
+            "play_integrity" => Some(Kind::PlayIntegrity),
+            _ => None,
+        }
+    }
+}
+
+pub type Verifying<'a> = Pin<Box<dyn Future<Output = Result<bool, String>> + 'a>>;
+
+/// tells genuine installs of the official app from everything else; runs on the main thread
+pub trait Verifier: Send + Sync {
+    /// whether `token` vouches for a genuine app, with `challenge` as its nonce; an `Err` means
+    /// it couldn't tell, and the app may try again
+    fn verify<'a>(&'a self, kind: Kind, token: &'a str, challenge: &'a str) -> Verifying<'a>;
+}
+
+#[derive(Serialize)]
+struct VerifyRequest<'a> {
+    kind: Kind,
+    token: &'a str,
+    challenge: &'a str,
+}
+
+#[derive(Deserialize)]
+struct VerifyResponse {
+    genuine: bool,
+}
+
+/// posts `{"kind", "token", "challenge"}` to a service that answers with `{"genuine": ...}`, which
+/// is where the relying party ids and the Play Console credentials live
+pub struct HttpVerifier {
+    pub url: String,
+    pub timeout: Duration,
+}
+
+impl Verifier for HttpVerifier {
+    fn verify<'a>(&'a self, kind: Kind, token: &'a str, challenge: &'a str) -> Verifying<'a> {
+        Box::pin(async move {
+            let mut response = http_client().post(&self.url)
+                .timeout(self.timeout)
+                .send_json(&VerifyRequest { kind, token, challenge })
+                .await
+                .map_err(|err| err.to_string())?;
+
+            if !response.status().is_success() {
+                return Err(format!("attestation service answered {}", response.status()))
+            }
+
+            let response = response.json::<VerifyResponse>().await.map_err(|err| err.to_string())?;
+            Ok(response.genuine)
+        })
+    }
+}
+
+static INSTALLED: OnceLock<Option<Box<dyn Verifier>>> = OnceLock::new();
+
+/// replaces the verifier the settings pick, only before the first resolve; hands `verifier` back
+/// if that is too late
+pub fn install<V: Verifier + 'static>(verifier: V) -> Result<(), V> {
+    let mut verifier = Some(verifier);
+    INSTALLED.get_or_init(|| verifier.take().map(|verifier| Box::new(verifier) as Box<dyn Verifier>));
+    match verifier {
+        None => Ok(()),
+        Some(verifier) => Err(verifier),
+    }
+}
+
+fn configured() -> Option<Box<dyn Verifier>> {
+    let url = URL.clone()?;
+    Some(Box::new(HttpVerifier { url, timeout: *TIMEOUT }))
+}
+
+fn verifier() -> Option<&'static dyn Verifier> {
+    INSTALLED.get_or_init(configured).as_deref()
+}
+
+/// whether every answer has to come with an attestation
+pub fn required() -> bool {
+    verifier().is_some()
+}
+
+/// checks the attestation an answer to `code` came with, the code's correlation id is the nonce
+/// the app had to put in it, it is handed out with the request
+pub(crate) async fn verify(presented: Option<&str>, code: RequestCode) -> Result<(), AppError> {
+    let Some(verifier) = verifier() else {
+        return Ok(())
+    };
+
+    let (kind, token) = presented
+        .and_then(|presented| presented.split_once(' '))
+        .and_then(|(kind, token)| Some((Kind::parse(kind)?, token.trim())))
+        .filter(|(_kind, token)| !token.is_empty())
+        .ok_or(AppError::AttestationRequired(code))?;
+
+    let correlation = code.correlation_id();
+    match verifier.verify(kind, token, &correlation).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            log::warn!(
+                code_hash = correlation.as_str();
+                "[{correlation}] turned away an answer without a genuine {kind:?} attestation"
+            );
+            Err(AppError::AttestationFailed(code))
+        }
+        Err(err) => {
+            log::error!(code_hash = correlation.as_str(); "[{correlation}] unable to check an attestation: {err}");
+            Err(AppError::AttestationUnavailable)
+        }
+    }
+}
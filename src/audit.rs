@@ -0,0 +1,213 @@
+use std::future::Future;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{LazyLock, OnceLock};
+use std::time::Duration;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use crate::config::setting;
+use crate::req_code::RequestCode;
+use crate::{broker, env_or, to_json_str, unix_now};
+
+static SINK: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_AUDIT_SINK"));
+static FILE: LazyLock<Option<PathBuf>> = LazyLock::new(|| setting("ABSHER_AUDIT_FILE").map(PathBuf::from));
+/// a remote syslog over udp, the local one at /dev/log when unset
+static SYSLOG_ADDR: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_AUDIT_SYSLOG_ADDR"));
+/// a kafka rest proxy, records are posted to it rather than spoken to the brokers directly
+static KAFKA_URL: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_AUDIT_KAFKA_URL"));
+static KAFKA_TOPIC: LazyLock<String> = LazyLock::new(|| {
+    setting("ABSHER_AUDIT_KAFKA_TOPIC").unwrap_or_else(|| "absher-audit".to_owned())
+});
+static TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(env_or("ABSHER_AUDIT_TIMEOUT_MS", 5000))
+});
+
+/// how long a failing sink is left alone before the record is tried again
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// who did something to a request, as far as the server can tell
+#[derive(Clone, Default, Serialize)]
+pub struct Actor {
+    /// the listener's api key id, the resolver's device, or `admin`; empty when the server did it
+    /// on its own, like running a request out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl Actor {
+    /// an operator acting through the admin api
+    pub fn admin() -> Self {
+        Self { by: Some("admin".to_owned()), ..Self::default() }
+    }
+}
+
+/// one line of the audit log, it names the fields involved but never holds what was in them
+#[derive(Serialize)]
+struct Record<'a> {
+    /// `created`, `fetched`, `resolved`, `rejected`, `expired` or `client_error`
+    action: &'static str,
+    /// unix seconds
+    at: u64,
+    /// the correlation id the logs use, not the code
+    code_hash: String,
+    tenant: Option<&'a str>,
+    fields: Vec<&'static str>,
+    #[serde(flatten)]
+    actor: &'a Actor,
+}
+
+pub type Writing<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + 'a>>;
+
+/// somewhere audit records are appended to, one json object per call and in the order they
+/// happened; runs on the main thread
+pub trait Sink: Send + Sync {
+    fn write<'a>(&'a self, line: &'a str) -> Writing<'a>;
+}
+
+/// appends to a file, opened again for every record so a rotated one is picked up
+pub struct File {
+    pub path: PathBuf,
+}
+
+impl Sink for File {
+    fn write<'a>(&'a self, line: &'a str) -> Writing<'a> {
+        let written = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(format!("{line}\n").as_bytes()))
+            .map_err(|err| format!("unable to append to {}: {err}", self.path.display()));
+        Box::pin(async move { written })
+    }
+}
+
+/// sends to syslog as authpriv, where the host keeps what shouldn't be in the general log
+pub struct Syslog {
+    /// a remote syslog over udp, the local socket when `None`
+    pub addr: Option<SocketAddr>,
+}
+
+/// facility authpriv, severity info
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+impl Sink for Syslog {
+    fn write<'a>(&'a self, line: &'a str) -> Writing<'a> {
+        let message = format!("<{SYSLOG_PRIORITY}>{}: {line}", env!("CARGO_PKG_NAME"));
+        let sent = match self.addr {
+            Some(addr) => {
+                let local = match addr {
+                    SocketAddr::V4(_) => "0.0.0.0:0",
+                    SocketAddr::V6(_) => "[::]:0",
+                };
+                UdpSocket::bind(local).and_then(|socket| socket.send_to(message.as_bytes(), addr))
+            }
+            #[cfg(unix)]
+            None => std::os::unix::net::UnixDatagram::unbound()
+                .and_then(|socket| socket.send_to(message.as_bytes(), "/dev/log")),
+            #[cfg(not(unix))]
+            None => Err(std::io::Error::other("there is no local syslog, set an address")),
+        };
+        Box::pin(async move { sent.map(|_| ()).map_err(|err| format!("unable to send to syslog: {err}")) })
+    }
+}
+
+/// produces to a topic through a kafka rest proxy
+pub struct Kafka {
+    pub url: String,
+    pub topic: String,
+    pub timeout: Duration,
+}
+
+impl Sink for Kafka {
+    fn write<'a>(&'a self, line: &'a str) -> Writing<'a> {
+        Box::pin(broker::produce(&self.url, &self.topic, self.timeout, line))
+    }
+}
+
+static INSTALLED: OnceLock<Option<Box<dyn Sink>>> = OnceLock::new();
+static QUEUE: OnceLock<mpsc::UnboundedSender<String>> = OnceLock::new();
+
+/// replaces the sink the settings pick, only before `start`; hands `sink` back if that is too late
+pub fn install<S: Sink + 'static>(sink: S) -> Result<(), S> {
+    let mut sink = Some(sink);
+    INSTALLED.get_or_init(|| sink.take().map(|sink| Box::new(sink) as Box<dyn Sink>));
+    match sink {
+        None => Ok(()),
+        Some(sink) => Err(sink),
+    }
+}
+
+/// `file`, `syslog` or `kafka`, nothing is audited without one
+fn configured() -> Option<Box<dyn Sink>> {
+    match SINK.as_deref()? {
+        "file" => match FILE.clone() {
+            Some(path) => Some(Box::new(File { path })),
+            None => {
+                log::error!("the audit sink is a file, but ABSHER_AUDIT_FILE isn't set");
+                None
+            }
+        },
+        "syslog" => {
+            let addr = SYSLOG_ADDR.as_deref().map(str::parse::<SocketAddr>).transpose();
+            match addr {
+                Ok(addr) => Some(Box::new(Syslog { addr })),
+                Err(err) => {
+                    log::error!("ABSHER_AUDIT_SYSLOG_ADDR isn't an address: {err}");
+                    None
+                }
+            }
+        }
+        "kafka" => match KAFKA_URL.clone() {
+            Some(url) => Some(Box::new(Kafka { url, topic: KAFKA_TOPIC.clone(), timeout: *TIMEOUT })),
+            None => {
+                log::error!("the audit sink is kafka, but ABSHER_AUDIT_KAFKA_URL isn't set");
+                None
+            }
+        },
+        other => {
+            log::error!("unknown audit sink {other:?}, expected file, syslog or kafka");
+            None
+        }
+    }
+}
+
+/// starts writing records to the sink, if there is one; records from before this are lost
+pub fn start() {
+    let Some(sink) = INSTALLED.get_or_init(configured).as_deref() else {
+        return
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    if QUEUE.set(tx).is_err() {
+        return
+    }
+
+    // awc clients are bound to the current thread, so is this
+    actix_web::rt::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            // the log is append only and in order, so a record waits for its sink to come back
+            while let Err(err) = sink.write(&line).await {
+                log::error!("unable to write an audit record, trying again in {}s: {err}", RETRY_AFTER.as_secs());
+                tokio::time::sleep(RETRY_AFTER).await;
+            }
+        }
+    });
+}
+
+/// appends what `actor` did to `code` to the audit log, costs nothing without one
+pub fn record(action: &'static str, code: &RequestCode, tenant: Option<&str>, fields: Vec<&'static str>, actor: &Actor) {
+    let Some(queue) = QUEUE.get() else {
+        return
+    };
+
+    let record = Record { action, at: unix_now(), code_hash: code.correlation_id(), tenant, fields, actor };
+    if let Ok(line) = to_json_str(&record) {
+        let _ = queue.send(line);
+    }
+}
@@ -0,0 +1,187 @@
+//! talks to a running instance's admin api, so incidents don't need hand written curl commands
+
+use std::process::ExitCode;
+use std::time::Duration;
+use actix_web::http::{header, Method};
+use awc::ws::{Frame, Message};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+
+const USAGE: &str = "\
+usage: absherctl <command>
+
+commands:
+  requests                   list open requests
+  expire <code>              end a request early, its listener sees it expire
+  deny <code> [reason]       put a code on the deny list
+  lift <code>                take a code off the deny list
+  denylist                   list denied codes
+  maintenance [on|off]       show or flip maintenance mode
+  reload                     re-read the config file
+  challenge [on|off]         show or flip proof of work for keyless listeners
+  tenants                    list tenants
+  keys [tenant]              list api keys, optionally of one tenant
+  client-errors <tenant>     what a tenant's listeners reported
+  tail                       follow request events as they happen, until interrupted
+
+environment:
+  ABSHER_URL                 where the instance is, defaults to http://127.0.0.1
+  ABSHER_ADMIN_TOKEN         the instance's admin token";
+
+enum Command {
+    Call(Call),
+    Tail,
+}
+
+struct Call {
+    method: Method,
+    path: String,
+    body: Option<Value>,
+}
+
+impl Call {
+    fn get(path: impl Into<String>) -> Self {
+        Self { method: Method::GET, path: path.into(), body: None }
+    }
+
+    fn with(method: Method, path: impl Into<String>, body: Option<Value>) -> Self {
+        Self { method, path: path.into(), body }
+    }
+}
+
+fn toggle(path: &str, field: &str, state: Option<&str>) -> Option<Call> {
+    let enabled = match state {
+        None => return Some(Call::get(path)),
+        Some("on") => true,
+        Some("off") => false,
+        Some(_) => return None,
+    };
+
+    Some(Call::with(Method::PUT, path, Some(json!({ field: enabled }))))
+}
+
+fn parse(args: &[String]) -> Option<Command> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    if args == ["tail"] {
+        return Some(Command::Tail)
+    }
+
+    let call = match args.as_slice() {
+        ["requests"] => Call::get("/admin/requests"),
+        ["expire", code] => Call::with(Method::DELETE, format!("/admin/requests/{code}"), None),
+        ["deny", code, reason @ ..] => {
+            let reason = match reason {
+                [] => "denied by an operator".to_owned(),
+                words => words.join(" "),
+            };
+            Call::with(Method::POST, "/admin/denylist", Some(json!({ "code": code, "reason": reason })))
+        }
+        ["lift", code] => Call::with(Method::DELETE, format!("/admin/denylist/{code}"), None),
+        ["denylist"] => Call::get("/admin/denylist"),
+        ["maintenance", state @ ..] if state.len() <= 1 => toggle("/admin/maintenance", "enabled", state.first().copied())?,
+        ["challenge", state @ ..] if state.len() <= 1 => toggle("/admin/challenge", "required", state.first().copied())?,
+        ["reload"] => Call::with(Method::POST, "/admin/config/reload", None),
+        ["tenants"] => Call::get("/admin/tenants"),
+        ["keys"] => Call::get("/admin/keys"),
+        ["keys", tenant] => Call::get(format!("/admin/keys?tenant={tenant}")),
+        ["client-errors", tenant] => Call::get(format!("/admin/tenants/{tenant}/client-errors")),
+        _ => return None,
+    };
+
+    Some(Command::Call(call))
+}
+
+/// the message of one of our error bodies, or whatever else came back
+fn error_message(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(body) => match (&body["error"]["code"], &body["error"]["message"]) {
+            (Value::String(code), Value::String(message)) => format!("{code}: {message}"),
+            _ => body.to_string(),
+        },
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+/// where the instance is and the token it takes
+fn instance() -> Result<(String, String), String> {
+    let base = std::env::var("ABSHER_URL").unwrap_or_else(|_| "http://127.0.0.1".to_owned());
+    let token = std::env::var("ABSHER_ADMIN_TOKEN").map_err(|_| "ABSHER_ADMIN_TOKEN is not set".to_owned())?;
+    Ok((base, token))
+}
+
+async fn run(call: Call) -> Result<(), String> {
+    let (base, token) = instance()?;
+
+    let client = awc::Client::builder().timeout(Duration::from_secs(10)).finish();
+    let request = client.request(call.method, format!("{}{}", base.trim_end_matches('/'), call.path))
+        .insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+
+    let sent = match call.body {
+        Some(body) => request.send_json(&body).await,
+        None => request.send().await,
+    };
+    let mut response = sent.map_err(|err| format!("unable to reach {base}: {err}"))?;
+
+    let body = response.body().limit(16 * 1024 * 1024).await
+        .map_err(|err| format!("unable to read the response: {err}"))?;
+
+    if !response.status().is_success() {
+        return Err(error_message(&body))
+    }
+
+    if let Ok(body) = serde_json::from_slice::<Value>(&body) {
+        println!("{}", serde_json::to_string_pretty(&body).unwrap_or_default());
+    }
+
+    Ok(())
+}
+
+/// prints each event from the admin feed on its own line, as the server sent it
+async fn tail() -> Result<(), String> {
+    let (base, token) = instance()?;
+
+    let client = awc::Client::builder().timeout(Duration::from_secs(10)).finish();
+    let (_, mut feed) = client.ws(format!("{}/admin/events", base.trim_end_matches('/')))
+        .bearer_auth(token)
+        .connect().await
+        .map_err(|err| format!("unable to follow {base}: {err}"))?;
+
+    while let Some(frame) = feed.next().await {
+        match frame.map_err(|err| format!("the feed broke off: {err}"))? {
+            Frame::Text(event) => println!("{}", String::from_utf8_lossy(&event)),
+            Frame::Ping(ping) => {
+                let _ = feed.send(Message::Pong(ping)).await;
+            }
+            Frame::Close(reason) => {
+                return match reason.and_then(|reason| reason.description) {
+                    Some(description) => Err(format!("the feed was closed: {description}")),
+                    None => Ok(()),
+                }
+            }
+            Frame::Binary(_) | Frame::Pong(_) | Frame::Continuation(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[actix_web::main]
+async fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let Some(command) = parse(&args) else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2)
+    };
+
+    let done = match command {
+        Command::Call(call) => run(call).await,
+        Command::Tail => tail().await,
+    };
+    match done {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("absherctl: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
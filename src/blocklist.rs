@@ -0,0 +1,269 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::Duration;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use dashmap::DashMap;
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use crate::config::setting;
+use crate::error::AppError;
+use crate::{admin, peer, unix_now};
+
+/// where blocks survive restarts, nothing is persisted when unset
+static FILE: LazyLock<Option<PathBuf>> = LazyLock::new(|| setting("ABSHER_BLOCKLIST_FILE").map(PathBuf::from));
+
+static BLOCKS: LazyLock<DashMap<String, Block>> = LazyLock::new(DashMap::new);
+
+/// serializes writers so the file always matches some recent state of the map
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+/// an address range like `203.0.113.0/24`, a bare address is a range of one
+#[derive(Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn single(ip: IpAddr) -> Self {
+        let prefix = if ip.is_ipv4() { 32 } else { 128 };
+        Self { network: ip, prefix }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            // v4 clients of a dual stack socket show up as mapped v6 addresses
+            (IpAddr::V4(_), IpAddr::V6(ip)) => ip.to_ipv4_mapped().is_some_and(|ip| self.contains(IpAddr::V4(ip))),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = match value.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (value, None),
+        };
+
+        let network = network.parse::<IpAddr>().map_err(|_| format!("{value} is not an address or range"))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("{value} has a prefix longer than {max} bits"))?,
+            None => max,
+        };
+
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+/// what a block turns away
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Cidr(Cidr),
+    /// pages from this origin, exactly as browsers send it
+    Origin(String),
+    /// every caller presenting this api key
+    KeyId(String),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Block {
+    pub id: String,
+    #[serde(flatten)]
+    pub target: Target,
+    pub reason: String,
+    /// unix seconds, blocked for good without one
+    pub until: Option<u64>,
+    /// put here by the lockout rather than by an operator
+    #[serde(default)]
+    pub automatic: bool,
+    pub created_at: u64,
+}
+
+impl Block {
+    fn live(&self, now: u64) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+}
+
+/// blocks `target` for `ttl`, or for good
+pub fn block(target: Target, reason: String, ttl: Option<Duration>) -> Block {
+    let block = Block {
+        id: Alphanumeric.sample_string(&mut rand::rng(), 12),
+        target,
+        reason,
+        until: ttl.map(|ttl| unix_now().saturating_add(ttl.as_secs())),
+        automatic: false,
+        created_at: unix_now(),
+    };
+
+    BLOCKS.insert(block.id.clone(), block.clone());
+    save();
+    block
+}
+
+/// blocks `ip` everywhere for as long as the lockout bans it, a repeat ban replaces the last one
+pub(crate) fn block_banned(ip: IpAddr, ban: Duration) {
+    let id = format!("lockout-{ip}");
+    let now = unix_now();
+    BLOCKS.insert(id.clone(), Block {
+        id,
+        target: Target::Cidr(Cidr::single(ip)),
+        reason: "repeated code misses".to_owned(),
+        until: Some(now.saturating_add(ban.as_secs().max(1))),
+        automatic: true,
+        created_at: now,
+    });
+    save();
+}
+
+pub fn lift(id: &str) -> bool {
+    let lifted = BLOCKS.remove(id).is_some();
+    if lifted {
+        save();
+    }
+    lifted
+}
+
+pub fn list() -> Vec<Block> {
+    BLOCKS.iter().map(|block| block.value().clone()).collect()
+}
+
+pub fn count() -> usize {
+    BLOCKS.len()
+}
+
+/// turns the caller away if its address, origin or api key is blocked
+pub(crate) fn check(ip: Option<IpAddr>, origin: Option<&str>, api_key: Option<&str>) -> Result<(), AppError> {
+    let key_id = api_key.and_then(admin::key_id);
+    check_targets(ip, origin, key_id.as_deref())
+}
+
+/// turns a caller away by the id of the key it names, for clients that don't present the key
+/// itself like openid connect relying parties
+pub(crate) fn check_key_id(key_id: &str) -> Result<(), AppError> {
+    check_targets(None, None, Some(key_id))
+}
+
+fn check_targets(ip: Option<IpAddr>, origin: Option<&str>, key_id: Option<&str>) -> Result<(), AppError> {
+    let now = unix_now();
+    let blocked = BLOCKS.iter().any(|block| block.live(now) && match &block.target {
+        Target::Cidr(cidr) => ip.is_some_and(|ip| cidr.contains(ip)),
+        Target::Origin(blocked) => origin == Some(blocked.as_str()),
+        Target::KeyId(blocked) => key_id == Some(blocked.as_str()),
+    });
+
+    match blocked {
+        true => Err(AppError::Blocked),
+        false => Ok(()),
+    }
+}
+
+/// runs in front of every route but the admin api, where an operator can always lift a block
+pub async fn guard(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if !req.path().starts_with("/admin") {
+        let origin = req.headers().get(header::ORIGIN).and_then(|value| value.to_str().ok());
+        check(peer::ip(req.request()), origin, admin::presented(req.headers()))?;
+    }
+
+    next.call(req).await
+}
+
+pub fn prune() {
+    let now = unix_now();
+    let before = BLOCKS.len();
+    BLOCKS.retain(|_id, block| block.live(now));
+
+    if BLOCKS.len() != before {
+        save();
+    }
+}
+
+/// reads back the blocks from the last run
+pub fn load() {
+    let Some(path) = FILE.as_deref() else {
+        return
+    };
+
+    let blocks = match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<Vec<Block>>(&bytes),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return,
+        Err(err) => {
+            log::error!("unable to read block list {}: {err}", path.display());
+            return
+        }
+    };
+
+    match blocks {
+        Ok(blocks) => {
+            let now = unix_now();
+            for block in blocks.into_iter().filter(|block| block.live(now)) {
+                BLOCKS.insert(block.id.clone(), block);
+            }
+        }
+        Err(err) => log::error!("block list {} is corrupt: {err}", path.display()),
+    }
+}
+
+fn save() {
+    let Some(path) = FILE.as_deref() else {
+        return
+    };
+
+    let _guard = SAVE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let blocks = list();
+
+    // write then rename so a crash mid write never leaves half a file behind
+    let tmp = path.with_extension("tmp");
+    let written = serde_json::to_vec(&blocks)
+        .map_err(std::io::Error::other)
+        .and_then(|bytes| std::fs::write(&tmp, bytes))
+        .and_then(|()| std::fs::rename(&tmp, path));
+
+    if let Err(err) = written {
+        log::error!("unable to persist block list to {}: {err}", path.display());
+    }
+}
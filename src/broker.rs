@@ -0,0 +1,327 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{LazyLock, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use crate::config::setting;
+use crate::req_code::RequestCode;
+use crate::{env_or, http_client, to_json_str, unix_now};
+
+/// `kafka` or `nats`, nothing is published without one
+static KIND: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_BROKER_KIND"));
+/// the kafka rest proxy, or the nats server as `host:port`
+static URL: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_BROKER_URL"));
+/// the kafka topic or the nats subject
+static TOPIC: LazyLock<String> = LazyLock::new(|| {
+    setting("ABSHER_BROKER_TOPIC").unwrap_or_else(|| "absher.requests".to_owned())
+});
+/// where unpublished events survive restarts, they only live in memory when unset
+static OUTBOX_FILE: LazyLock<Option<PathBuf>> = LazyLock::new(|| setting("ABSHER_BROKER_OUTBOX").map(PathBuf::from));
+static TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(env_or("ABSHER_BROKER_TIMEOUT_MS", 5000))
+});
+
+/// bumped whenever a field changes meaning or goes away, new fields don't bump it
+pub const SCHEMA_VERSION: u32 = 1;
+/// a broker that is down this long makes the oldest events go
+const MAX_OUTBOX: usize = 100_000;
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// what is published, consumers may see a message more than once and can tell by its id
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Message {
+    pub schema_version: u32,
+    pub id: String,
+    /// how a request ended: `resolved`, `expired`, `rejected`, `superseded`, `locked` or
+    /// `withdrawn`, which come with the `code_hash`, the correlation id the logs use; or `usage`,
+    /// a tenant's metering record
+    pub event: String,
+    pub tenant: Option<String>,
+    /// unix seconds
+    pub at: u64,
+    /// whatever else the event comes with
+    #[serde(flatten)]
+    pub details: Map<String, Value>,
+}
+
+pub type Publishing<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + 'a>>;
+
+/// hands a message to a broker, only done once the broker has it; runs on the main thread
+pub trait Broker: Send + Sync {
+    fn publish<'a>(&'a self, payload: &'a str) -> Publishing<'a>;
+}
+
+/// posts `value` to `topic` through a kafka rest proxy
+pub(crate) async fn produce(url: &str, topic: &str, timeout: Duration, value: &str) -> Result<(), String> {
+    let url = format!("{}/topics/{topic}", url.trim_end_matches('/'));
+    let response = http_client().post(url)
+        .timeout(timeout)
+        .content_type("application/vnd.kafka.json.v2+json")
+        .send_body(format!(r#"{{"records":[{{"value":{value}}}]}}"#))
+        .await
+        .map_err(|err| err.to_string())?;
+
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(format!("answered {}", response.status())),
+    }
+}
+
+/// produces through a kafka rest proxy, which answers once the brokers acknowledged
+pub struct Kafka {
+    pub url: String,
+    pub topic: String,
+    pub timeout: Duration,
+}
+
+impl Broker for Kafka {
+    fn publish<'a>(&'a self, payload: &'a str) -> Publishing<'a> {
+        Box::pin(produce(&self.url, &self.topic, self.timeout, payload))
+    }
+}
+
+/// publishes to a nats subject, a PING after every PUB tells when the server has it
+pub struct Nats {
+    pub addr: String,
+    pub subject: String,
+    pub timeout: Duration,
+    connection: tokio::sync::Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl Nats {
+    pub fn new(addr: String, subject: String, timeout: Duration) -> Self {
+        Self { addr, subject, timeout, connection: Default::default() }
+    }
+
+    async fn connect(&self) -> Result<BufReader<TcpStream>, String> {
+        let mut stream = BufReader::new(TcpStream::connect(&self.addr).await.map_err(|err| err.to_string())?);
+
+        // the server introduces itself before anything else
+        let mut info = String::new();
+        stream.read_line(&mut info).await.map_err(|err| err.to_string())?;
+        if !info.starts_with("INFO") {
+            return Err(format!("unexpected greeting {:?}", info.trim_end()))
+        }
+
+        stream.get_mut()
+            .write_all(br#"CONNECT {"verbose":false,"pedantic":false,"name":"absher-zt-backend"}"#)
+            .await
+            .map_err(|err| err.to_string())?;
+        stream.get_mut().write_all(b"\r\n").await.map_err(|err| err.to_string())?;
+        Ok(stream)
+    }
+
+    async fn send(&self, stream: &mut BufReader<TcpStream>, payload: &str) -> Result<(), String> {
+        let frame = format!("PUB {} {}\r\n{payload}\r\nPING\r\n", self.subject, payload.len());
+        stream.get_mut().write_all(frame.as_bytes()).await.map_err(|err| err.to_string())?;
+
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.map_err(|err| err.to_string())? == 0 {
+                return Err("the server closed the connection".to_owned())
+            }
+
+            match line.trim_end() {
+                "PONG" => return Ok(()),
+                // the server checks on us too
+                "PING" => stream.get_mut().write_all(b"PONG\r\n").await.map_err(|err| err.to_string())?,
+                line if line.starts_with("-ERR") => return Err(line.to_owned()),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Broker for Nats {
+    fn publish<'a>(&'a self, payload: &'a str) -> Publishing<'a> {
+        Box::pin(async move {
+            let mut connection = self.connection.lock().await;
+            let published = tokio::time::timeout(self.timeout, async {
+                if connection.is_none() {
+                    *connection = Some(self.connect().await?);
+                }
+                let stream = connection.as_mut().expect("a connection that was just made");
+                self.send(stream, payload).await
+            }).await.unwrap_or_else(|_| Err("timed out".to_owned()));
+
+            // whatever state the connection was left in, the next try starts over
+            if published.is_err() {
+                *connection = None;
+            }
+            published
+        })
+    }
+}
+
+static INSTALLED: OnceLock<Option<Box<dyn Broker>>> = OnceLock::new();
+/// events the broker doesn't have yet, oldest first
+static OUTBOX: LazyLock<Mutex<VecDeque<Message>>> = LazyLock::new(Default::default);
+static QUEUED: Notify = Notify::const_new();
+static STARTED: OnceLock<()> = OnceLock::new();
+
+/// replaces the broker the settings pick, only before `start`; hands `broker` back if that is
+/// too late
+pub fn install<B: Broker + 'static>(broker: B) -> Result<(), B> {
+    let mut broker = Some(broker);
+    INSTALLED.get_or_init(|| broker.take().map(|broker| Box::new(broker) as Box<dyn Broker>));
+    match broker {
+        None => Ok(()),
+        Some(broker) => Err(broker),
+    }
+}
+
+fn configured() -> Option<Box<dyn Broker>> {
+    let kind = KIND.as_deref()?;
+    let Some(url) = URL.clone() else {
+        log::error!("the broker is {kind}, but ABSHER_BROKER_URL isn't set");
+        return None
+    };
+
+    match kind {
+        "kafka" => Some(Box::new(Kafka { url, topic: TOPIC.clone(), timeout: *TIMEOUT })),
+        "nats" => Some(Box::new(Nats::new(url, TOPIC.clone(), *TIMEOUT))),
+        other => {
+            log::error!("unknown broker {other:?}, expected kafka or nats");
+            None
+        }
+    }
+}
+
+/// whether anything is published at all
+pub fn enabled() -> bool {
+    STARTED.get().is_some()
+}
+
+/// queues the end of `code` for the broker, costs nothing without one
+pub fn completed(event: &'static str, code: &RequestCode, tenant: Option<&str>) {
+    if !enabled() {
+        return
+    }
+
+    let details = Map::from_iter([("code_hash".to_owned(), Value::String(code.correlation_id()))]);
+    publish(event, tenant, details);
+}
+
+/// queues an event for the broker, `details` go next to the fields every message has
+pub fn publish(event: &'static str, tenant: Option<&str>, details: Map<String, Value>) {
+    if !enabled() {
+        return
+    }
+
+    let message = Message {
+        schema_version: SCHEMA_VERSION,
+        id: Alphanumeric.sample_string(&mut rand::rng(), 20),
+        event: event.to_owned(),
+        tenant: tenant.map(str::to_owned),
+        at: unix_now(),
+        details,
+    };
+
+    let mut outbox = OUTBOX.lock().unwrap_or_else(PoisonError::into_inner);
+    if outbox.len() >= MAX_OUTBOX {
+        log::error!("the broker outbox is full, dropping the oldest event");
+        outbox.pop_front();
+    }
+    outbox.push_back(message);
+    save(&outbox);
+    drop(outbox);
+    QUEUED.notify_one();
+}
+
+/// reads back what the last run didn't get to publish, and starts publishing
+pub fn start() {
+    let Some(broker) = INSTALLED.get_or_init(configured).as_deref() else {
+        return
+    };
+    if STARTED.set(()).is_err() {
+        return
+    }
+    load();
+
+    // awc clients are bound to the current thread, so is this
+    actix_web::rt::spawn(async move {
+        loop {
+            let next = OUTBOX.lock().unwrap_or_else(PoisonError::into_inner).front().cloned();
+            let Some(message) = next else {
+                QUEUED.notified().await;
+                continue
+            };
+
+            let Ok(payload) = to_json_str(&message) else {
+                forget(&message.id);
+                continue
+            };
+            match broker.publish(&payload).await {
+                Ok(()) => forget(&message.id),
+                Err(err) => {
+                    log::warn!("unable to publish to the broker, trying again in {}s: {err}", RETRY_AFTER.as_secs());
+                    tokio::time::sleep(RETRY_AFTER).await;
+                }
+            }
+        }
+    });
+}
+
+/// drops a published event, unless it was pushed out of a full outbox meanwhile
+fn forget(id: &str) {
+    let mut outbox = OUTBOX.lock().unwrap_or_else(PoisonError::into_inner);
+    if outbox.front().is_some_and(|message| message.id == id) {
+        outbox.pop_front();
+        save(&outbox);
+    }
+}
+
+/// how many events the broker doesn't have yet
+pub fn pending() -> usize {
+    OUTBOX.lock().unwrap_or_else(PoisonError::into_inner).len()
+}
+
+fn load() {
+    let Some(path) = OUTBOX_FILE.as_deref() else {
+        return
+    };
+
+    let messages = match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<VecDeque<Message>>(&bytes),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return,
+        Err(err) => {
+            log::error!("unable to read the broker outbox {}: {err}", path.display());
+            return
+        }
+    };
+
+    match messages {
+        Ok(messages) => {
+            let mut outbox = OUTBOX.lock().unwrap_or_else(PoisonError::into_inner);
+            // anything queued before this ended later
+            let newer = std::mem::replace(&mut *outbox, messages);
+            outbox.extend(newer);
+        }
+        Err(err) => log::error!("the broker outbox {} is corrupt: {err}", path.display()),
+    }
+}
+
+/// called with the outbox locked, so the file always matches some recent state of it
+fn save(outbox: &VecDeque<Message>) {
+    let Some(path) = OUTBOX_FILE.as_deref() else {
+        return
+    };
+
+    // write then rename so a crash mid write never leaves half a file behind
+    let tmp = path.with_extension("tmp");
+    let written = serde_json::to_vec(outbox)
+        .map_err(std::io::Error::other)
+        .and_then(|bytes| std::fs::write(&tmp, bytes))
+        .and_then(|()| std::fs::rename(&tmp, path));
+
+    if let Err(err) = written {
+        log::error!("unable to persist the broker outbox to {}: {err}", path.display());
+    }
+}
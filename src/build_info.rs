@@ -0,0 +1,29 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::tls::TlsSettings;
+
+/// what is actually deployed, for checking during incidents
+#[derive(Serialize, ToSchema)]
+pub struct BuildInfo {
+    version: &'static str,
+    /// with `-dirty` if it was built from uncommitted changes, `unknown` outside of git
+    git_sha: &'static str,
+    /// unix seconds
+    built_at: u64,
+    profile: &'static str,
+    store: &'static str,
+    tls: bool,
+    otlp: bool,
+}
+
+pub fn get() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("ABSHER_GIT_SHA"),
+        built_at: env!("ABSHER_BUILT_AT").parse().unwrap_or_default(),
+        profile: if cfg!(debug_assertions) { "debug" } else { "release" },
+        store: "memory",
+        tls: TlsSettings::from_env().is_some(),
+        otlp: std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some(),
+    }
+}
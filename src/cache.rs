@@ -0,0 +1,53 @@
+use std::sync::LazyLock;
+use actix_web::http::header::{CacheControl, CacheDirective, ETag, EntityTag, IfNoneMatch};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use crate::env_or;
+use crate::error::AppError;
+
+/// how long browsers and proxies may reuse a cached response without asking again
+static MAX_AGE: LazyLock<u32> = LazyLock::new(|| env_or("ABSHER_CACHE_MAX_AGE_SECS", 300));
+
+/// a response body that only changes when the server does
+struct Cached {
+    body: web::Bytes,
+    etag: EntityTag,
+}
+
+static ENTRIES: LazyLock<DashMap<&'static str, Cached>> = LazyLock::new(DashMap::new);
+
+/// answers with the cached json under `key`, building it once, and with 304 if the client has it already
+pub fn serve(
+    req: &HttpRequest,
+    key: &'static str,
+    build: impl FnOnce() -> Result<String, AppError>,
+) -> Result<HttpResponse, AppError> {
+    let entry = ENTRIES.entry(key).or_try_insert_with(|| {
+        let body = build()?;
+        let digest = Sha256::digest(body.as_bytes());
+        let etag = EntityTag::new_strong(BASE64_URL_SAFE_NO_PAD.encode(&digest[..12]));
+        Ok::<_, AppError>(Cached { body: body.into(), etag })
+    })?;
+
+    let fresh = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&entry.etag)),
+        None => false,
+    };
+
+    let mut response = match fresh {
+        true => HttpResponse::NotModified(),
+        false => HttpResponse::Ok(),
+    };
+
+    response
+        .insert_header(ETag(entry.etag.clone()))
+        .insert_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(*MAX_AGE)]));
+
+    Ok(match fresh {
+        true => response.finish(),
+        false => response.content_type("application/json").body(entry.body.clone()),
+    })
+}
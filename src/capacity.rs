@@ -0,0 +1,85 @@
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use dashmap::DashMap;
+use crate::env_or;
+use crate::error::AppError;
+use crate::metrics::METRICS;
+
+/// open requests across everyone, keyless listeners included
+static MAX_PENDING: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_MAX_PENDING", 100_000));
+/// so one tenant's burst can't take what the others need
+static MAX_PENDING_PER_TENANT: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_MAX_PENDING_PER_TENANT", 10_000));
+/// requests come and go by the second, a full store rarely stays full for long
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static BY_TENANT: LazyLock<DashMap<String, usize>> = LazyLock::new(DashMap::new);
+
+/// a place in the pending map, taken before the handshake and given back when the request is gone
+pub struct Slot {
+    tenant: Option<String>,
+}
+
+fn full(scope: &str) -> AppError {
+    METRICS.capacity_rejections.with_label_values(&[scope]).inc();
+    AppError::AtCapacity { retry_after: RETRY_AFTER }
+}
+
+pub fn reserve(tenant: Option<&str>) -> Result<Slot, AppError> {
+    PENDING.fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| (pending < *MAX_PENDING).then_some(pending + 1))
+        .map_err(|_| full("global"))?;
+
+    if let Some(tenant) = tenant {
+        let mut pending = BY_TENANT.entry(tenant.to_owned()).or_default();
+        if *pending >= *MAX_PENDING_PER_TENANT {
+            drop(pending);
+            PENDING.fetch_sub(1, Ordering::AcqRel);
+            return Err(full("tenant"))
+        }
+        *pending += 1;
+    }
+
+    Ok(Slot { tenant: tenant.map(str::to_owned) })
+}
+
+impl Slot {
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        PENDING.fetch_sub(1, Ordering::AcqRel);
+        if let Some(tenant) = &self.tenant {
+            BY_TENANT.remove_if_mut(tenant, |_, pending| {
+                *pending -= 1;
+                *pending == 0
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenants_are_held_to_their_share() {
+        let tenant = "capacity-test";
+        let mut slots = (0..10_000).map(|_| reserve(Some(tenant)).unwrap()).collect::<Vec<_>>();
+        assert!(matches!(reserve(Some(tenant)), Err(AppError::AtCapacity { .. })));
+        // others don't pay for it
+        drop(reserve(Some("capacity-test-other")).unwrap());
+        drop(reserve(None).unwrap());
+
+        slots.pop();
+        let slot = reserve(Some(tenant)).unwrap();
+        assert_eq!(slot.tenant(), Some(tenant));
+        drop(slot);
+
+        slots.clear();
+        assert!(!BY_TENANT.contains_key(tenant));
+    }
+}
@@ -0,0 +1,147 @@
+use actix_web::http::header;
+use actix_web::{dev, web, FromRequest, HttpRequest};
+use futures_util::TryStreamExt;
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::error::AppError;
+use crate::{redact, to_json_str, MAX_BODY_BYTES};
+
+pub const CONTENT_TYPE: &str = "application/cbor";
+
+fn is_cbor(value: &str) -> bool {
+    value.split(';').next().is_some_and(|kind| kind.trim().eq_ignore_ascii_case(CONTENT_TYPE))
+}
+
+/// whether the client asked for cbor back, anything else gets json
+fn accepted(req: &HttpRequest) -> bool {
+    req.headers().get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.split(',').any(is_cbor))
+}
+
+pub fn to_vec(value: &impl Serialize) -> Result<Vec<u8>, AppError> {
+    let mut out = Vec::new();
+    ciborium::into_writer(value, &mut out).map_err(|err| {
+        let type_name = core::any::type_name_of_val(value);
+        log::error!("unable to turn {type_name} to cbor: {err}");
+        AppError::Serialization
+    })?;
+    Ok(out)
+}
+
+/// the same message in cbor, for frames that are built as json
+pub fn from_json(json: &str) -> Result<Vec<u8>, AppError> {
+    let value = serde_json::from_str::<serde_json::Value>(json).map_err(|err| {
+        log::error!("unable to read back our own json: {err}");
+        AppError::Serialization
+    })?;
+    to_vec(&value)
+}
+
+/// a cbor frame from a listener as json, so it goes through the same parsing as text frames
+pub fn to_json(cbor: &[u8]) -> Option<String> {
+    ciborium::from_reader::<serde_json::Value, _>(cbor).ok().map(|value| value.to_string())
+}
+
+/// `value` as whichever of cbor or json the client accepts, with its content type
+pub fn encode(req: &HttpRequest, value: &impl Serialize) -> Result<(&'static str, Vec<u8>), AppError> {
+    match accepted(req) {
+        true => Ok((CONTENT_TYPE, to_vec(value)?)),
+        false => Ok(("application/json", to_json_str(value)?.into_bytes())),
+    }
+}
+
+/// a json body, or a cbor one when the content type says so, which spares phones base64 for
+/// their images
+pub struct Body<T>(pub T);
+
+impl<T: DeserializeOwned + 'static> FromRequest for Body<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        let cbor = req.headers().get(header::CONTENT_TYPE)
+            .and_then(|kind| kind.to_str().ok())
+            .is_some_and(is_cbor);
+
+        if !cbor {
+            let json = web::Json::<T>::from_request(req, payload);
+            return Box::pin(async move { Ok(Body(json.await?.into_inner())) })
+        }
+
+        let mut payload = payload.take();
+        Box::pin(async move {
+            let mut body = web::BytesMut::new();
+            while let Some(chunk) = payload.try_next().await? {
+                if body.len() + chunk.len() > *MAX_BODY_BYTES {
+                    return Err(AppError::BodyTooLarge { max_bytes: *MAX_BODY_BYTES }.into())
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            ciborium::from_reader(&body[..]).map(Body).map_err(|err| {
+                let message = match err {
+                    ciborium::de::Error::Io(_) => "the cbor ends early".to_owned(),
+                    ciborium::de::Error::Syntax(offset) => format!("malformed cbor at byte {offset}"),
+                    ciborium::de::Error::Semantic(_, message) => redact::parse_error(&message),
+                    ciborium::de::Error::RecursionLimitExceeded => "the cbor is nested too deeply".to_owned(),
+                };
+                AppError::InvalidBody(message).into()
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use serde_json::{json, Value};
+    use super::*;
+
+    async fn body(content_type: &str, bytes: Vec<u8>) -> Result<Value, String> {
+        let (req, mut payload) = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(bytes)
+            .to_http_parts();
+        match Body::<Value>::from_request(&req, &mut payload).await {
+            Ok(Body(value)) => Ok(value),
+            Err(err) => match err.as_error::<AppError>() {
+                Some(AppError::InvalidBody(message)) => Err(message.clone()),
+                _ => Err(err.to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn frames_read_the_same_either_way() {
+        let frame = json!({"type": "request", "fields": {"email": true}, "ttl": 120});
+        let cbor = from_json(&frame.to_string()).unwrap();
+        let back = serde_json::from_str::<Value>(&to_json(&cbor).unwrap()).unwrap();
+        assert_eq!(back, frame);
+        assert_eq!(to_json(&cbor[..cbor.len() - 1]), None);
+    }
+
+    #[test]
+    fn answers_follow_the_accept_header() {
+        let value = json!({"receipt": "r"});
+        let req = TestRequest::default().insert_header((header::ACCEPT, "text/html, Application/CBOR; q=0.9")).to_http_request();
+        let (content_type, bytes) = encode(&req, &value).unwrap();
+        assert_eq!(content_type, CONTENT_TYPE);
+        assert_eq!(ciborium::from_reader::<Value, _>(&bytes[..]).unwrap(), value);
+
+        let req = TestRequest::default().insert_header((header::ACCEPT, "*/*")).to_http_request();
+        assert_eq!(encode(&req, &value).unwrap(), ("application/json", br#"{"receipt":"r"}"#.to_vec()));
+    }
+
+    #[actix_web::test]
+    async fn bodies_are_framed_by_their_content_type() {
+        let value = json!({"email": "user@example.com", "photo": [1, 2, 3]});
+        assert_eq!(body("application/cbor; charset=binary", to_vec(&value).unwrap()).await, Ok(value.clone()));
+        assert_eq!(body("application/json", value.to_string().into_bytes()).await, Ok(value.clone()));
+
+        let cbor = to_vec(&value).unwrap();
+        assert_eq!(body(CONTENT_TYPE, cbor[..cbor.len() - 2].to_vec()).await, Err("the cbor ends early".to_owned()));
+        assert_eq!(body(CONTENT_TYPE, vec![0xa1, 0x1c]).await, Err("malformed cbor at byte 1".to_owned()));
+    }
+}
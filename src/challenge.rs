@@ -0,0 +1,71 @@
+use std::fmt::Write;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use crate::env_or;
+
+/// set by operators (or anything watching for abuse) while the service is under attack
+static REQUIRED: AtomicBool = AtomicBool::new(false);
+
+/// leading zero bits a solution's hash needs, every bit doubles the client's work
+static DIFFICULTY: LazyLock<u32> = LazyLock::new(|| env_or("ABSHER_POW_DIFFICULTY", 18u32).min(64));
+
+pub fn required() -> bool {
+    REQUIRED.load(Ordering::Relaxed)
+}
+
+pub fn set_required(required: bool) {
+    if REQUIRED.swap(required, Ordering::Relaxed) != required {
+        log::warn!("proof of work for keyless listeners turned {}", if required { "on" } else { "off" });
+    }
+}
+
+/// a proof of work puzzle, solved by finding a string so that
+/// `sha256(nonce ++ solution)` starts with `difficulty` zero bits
+pub struct Challenge {
+    pub nonce: String,
+    pub difficulty: u32,
+}
+
+impl Challenge {
+    pub fn new() -> Self {
+        let mut bytes = [0; 16];
+        rand::rng().fill_bytes(&mut bytes);
+
+        let nonce = bytes.iter().fold(String::with_capacity(32), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+
+        Self { nonce, difficulty: *DIFFICULTY }
+    }
+
+    pub fn verify(&self, solution: &str) -> bool {
+        let digest = Sha256::new()
+            .chain_update(self.nonce.as_bytes())
+            .chain_update(solution.as_bytes())
+            .finalize();
+
+        let mut leading_zeros = 0;
+        for byte in digest {
+            leading_zeros += byte.leading_zeros();
+            if byte != 0 {
+                break
+            }
+        }
+
+        leading_zeros >= self.difficulty
+    }
+
+    /// the listener's side, counting up until a number fits
+    pub fn solve(&self) -> String {
+        (0u64..).map(|counter| counter.to_string()).find(|solution| self.verify(solution)).unwrap_or_default()
+    }
+}
+
+impl Default for Challenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::time::Duration;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::config::setting;
+use crate::{env_or, http_client};
+use crate::error::AppError;
+
+/// what an image field is supposed to show
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentKind {
+    Portrait,
+    License,
+    IdCard,
+    Passport,
+    /// anything a classifier recognizes that isn't one of the above
+    #[serde(other)]
+    Other,
+}
+
+impl DocumentKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            DocumentKind::Portrait => "portrait",
+            DocumentKind::License => "license",
+            DocumentKind::IdCard => "id_card",
+            DocumentKind::Passport => "passport",
+            DocumentKind::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for DocumentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// a classifier's best guess at what an image shows
+#[derive(Deserialize)]
+pub struct Verdict {
+    pub kind: DocumentKind,
+    /// 0 to 1
+    pub confidence: f64,
+}
+
+pub type ClassifyFuture<'a> = Pin<Box<dyn Future<Output = Result<Verdict, String>> + 'a>>;
+
+/// anything that can tell documents apart, a local model or a remote service
+pub trait Classifier: Send + Sync {
+    fn classify<'a>(&'a self, image: &'a [u8]) -> ClassifyFuture<'a>;
+}
+
+/// posts the raw image to a service that answers with a json [`Verdict`]
+pub struct HttpClassifier {
+    url: String,
+    timeout: Duration,
+}
+
+impl Classifier for HttpClassifier {
+    fn classify<'a>(&'a self, image: &'a [u8]) -> ClassifyFuture<'a> {
+        Box::pin(async move {
+            let mut response = http_client().post(&self.url)
+                .timeout(self.timeout)
+                .content_type("application/octet-stream")
+                .send_body(image.to_vec())
+                .await
+                .map_err(|err| err.to_string())?;
+
+            if !response.status().is_success() {
+                return Err(format!("classifier answered {}", response.status()))
+            }
+
+            response.json::<Verdict>().await.map_err(|err| err.to_string())
+        })
+    }
+}
+
+/// classification is off unless a classifier is configured
+static CLASSIFIER: LazyLock<Option<Box<dyn Classifier>>> = LazyLock::new(|| {
+    let url = setting("ABSHER_CLASSIFIER_URL")?;
+    let timeout = Duration::from_millis(env_or("ABSHER_CLASSIFIER_TIMEOUT_MS", 3000));
+    Some(Box::new(HttpClassifier { url, timeout }))
+});
+
+pub fn enabled() -> bool {
+    CLASSIFIER.is_some()
+}
+
+/// below this a verdict is too unsure to turn an image away
+static MIN_CONFIDENCE: LazyLock<f64> = LazyLock::new(|| env_or("ABSHER_CLASSIFIER_MIN_CONFIDENCE", 0.8));
+
+/// rejects `image` if the classifier is confident it isn't a `expected`, the resolver can retake it
+pub async fn check(field: &'static str, expected: DocumentKind, image: &str) -> Result<(), AppError> {
+    let Some(classifier) = CLASSIFIER.as_deref() else {
+        return Ok(())
+    };
+
+    // images that aren't valid base64 are the relaying side's problem, not a document mismatch
+    let Ok(image) = BASE64_STANDARD.decode(image) else {
+        return Ok(())
+    };
+
+    // an unreachable classifier must not take resolving down with it
+    let verdict = match classifier.classify(&image).await {
+        Ok(verdict) => verdict,
+        Err(err) => {
+            log::warn!("unable to classify {field}, letting it through: {err}");
+            return Ok(())
+        }
+    };
+
+    if verdict.kind != expected && verdict.confidence >= *MIN_CONFIDENCE {
+        return Err(AppError::DocumentMismatch { field, expected, found: verdict.kind })
+    }
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
+use crate::config;
+
+/// an address to listen on, with its own port or the shared `--port`
+#[derive(Clone, Copy)]
+pub struct Bind {
+    ip: IpAddr,
+    port: Option<u16>,
+}
+
+impl FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(sock) = s.parse::<SocketAddr>() {
+            return Ok(Self { ip: sock.ip(), port: Some(sock.port()) })
+        }
+
+        // ipv6 addresses may come bracketed without a port too
+        let ip = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+        ip.parse().map(|ip| Self { ip, port: None })
+            .map_err(|_| format!("{s} is neither an ip address nor an ip address with a port"))
+    }
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("{s} isn't a file mode in octal, like 660"))
+}
+
+/// the server's flags are global, so they work before `serve` as well as after it
+#[derive(Parser)]
+#[command(version, about = "relays identity data from the absher app to the pages that asked for it")]
+pub struct Args {
+    /// a toml file with settings, anything set in the environment or here overrides it
+    #[arg(long, env = "ABSHER_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// addresses to listen on, like `0.0.0.0`, `::` or `127.0.0.1:8080`
+    #[arg(long, env = "ABSHER_BIND", value_delimiter = ',', default_value = "0.0.0.0", global = true)]
+    bind: Vec<Bind>,
+
+    /// port for addresses that don't name their own, 80 or 443 with tls
+    #[arg(long, env = "ABSHER_PORT", global = true)]
+    port: Option<u16>,
+
+    /// also listens on this unix socket, for a proxy on the same host; tcp addresses are then only
+    /// listened on when `--bind` or `--port` are given, like next to sockets systemd passes in
+    #[arg(long, env = "ABSHER_UNIX_SOCKET", global = true)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// permissions of the unix socket in octal, like 660 so a proxy in the same group can connect
+    #[arg(long, env = "ABSHER_UNIX_SOCKET_MODE", value_parser = parse_mode, global = true)]
+    pub unix_socket_mode: Option<u32>,
+
+    /// also serves the grpc api, on this port of every address, with the https certificate when tls
+    /// is configured
+    #[arg(long, env = "ABSHER_GRPC_PORT", global = true)]
+    pub grpc_port: Option<u16>,
+
+    /// worker threads, one per core by default
+    #[arg(long, env = "ABSHER_WORKERS", global = true)]
+    pub workers: Option<usize>,
+
+    /// release builds leave out anything below warn
+    #[arg(long, env = "ABSHER_LOG_LEVEL", default_value = "info", global = true)]
+    pub log_level: LevelFilter,
+
+    /// serves a page at /demo that plays both the listener and the resolver, for trying flows by
+    /// hand; anyone reaching it can answer requests, so never where real users are
+    #[arg(long, env = "ABSHER_DEV", global = true)]
+    pub dev: bool,
+
+    /// makes request codes follow from this seed, for end to end tests that need to know them in
+    /// advance; anyone knowing the seed can predict codes, so it needs `--dev` as well
+    #[arg(long, value_name = "SEED", global = true)]
+    pub code_seed: Option<u64>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// whether the level came from the command line, which outranks a reloaded config
+    #[arg(skip)]
+    pub log_level_pinned: bool,
+
+    /// whether addresses or a port were set rather than left as they default
+    #[arg(skip)]
+    pub tcp_given: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// runs the server, the same as giving no subcommand
+    Serve,
+    /// prints request codes in this deployment's format, without reserving them anywhere
+    GenCode(GenCodeArgs),
+    /// checks the settings, the config file and the tls files, failing if anything is off
+    CheckConfig,
+    /// prints the openapi description, or writes it to a file
+    ExportOpenapi {
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// writes typescript, kotlin and json schema for the api's types into a directory
+    ExportTypes {
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+    },
+    /// measures code generation, proof of work, receipt signing and whole flows in this process
+    Bench(BenchArgs),
+    /// plays the user on the phone against a running server, for exercising pages without one
+    Simulate(SimulateArgs),
+}
+
+#[derive(clap::Args)]
+pub struct GenCodeArgs {
+    /// numeric codes instead of letters
+    #[arg(long)]
+    pub numeric: bool,
+
+    #[arg(long, default_value_t = 1)]
+    pub count: usize,
+}
+
+#[derive(clap::Args)]
+pub struct BenchArgs {
+    /// listen and resolve round trips to time against a server started in this process
+    #[arg(long, default_value_t = 200)]
+    pub flows: usize,
+}
+
+#[derive(clap::Args)]
+pub struct SimulateArgs {
+    /// the server whose requests get answered
+    #[arg(long, default_value = "http://127.0.0.1")]
+    pub server: String,
+
+    /// answers just this request, without it every new request does, which takes an admin token
+    #[arg(long)]
+    pub code: Option<String>,
+
+    #[arg(long, env = "ABSHER_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// what the simulated user does with each request
+    #[arg(long, value_enum, default_value_t = Outcome::Resolve)]
+    pub outcome: Outcome,
+
+    /// how long the simulated user takes to decide
+    #[arg(long, default_value_t = 2)]
+    pub delay_secs: u64,
+
+    /// the pin to type for requests that have one
+    #[arg(long)]
+    pub pin: Option<String>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Outcome {
+    /// shares made up data for every field asked for
+    Resolve,
+    /// declines the request
+    Reject,
+    /// never answers, so the request times out
+    Ignore,
+}
+
+impl Args {
+    /// reads the command line and the environment, and the config file underneath both
+    pub fn load() -> Self {
+        let matches = Args::command().get_matches();
+        let Some(path) = matches.get_one::<PathBuf>("config") else {
+            return Self::from_matches(matches)
+        };
+
+        if let Err(err) = config::load(path) {
+            Args::command().error(clap::error::ErrorKind::Io, err).exit()
+        }
+
+        // clap doesn't know about the file, so its settings become the defaults
+        let mut command = Args::command();
+        let names = command.get_arguments()
+            .filter_map(|arg| Some((arg.get_id().clone(), arg.get_env()?.to_str()?.to_owned())))
+            .collect::<Vec<_>>();
+        for (id, env) in names {
+            if let Some(value) = config::setting(&env) {
+                let values = value.split(',').map(|value| &*value.to_owned().leak()).collect::<Vec<_>>();
+                command = command.mut_arg(id, |arg| arg.default_values(values));
+            }
+        }
+
+        Self::from_matches(command.get_matches())
+    }
+
+    fn from_matches(matches: ArgMatches) -> Self {
+        let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        args.log_level_pinned = matches.value_source("log_level") == Some(ValueSource::CommandLine);
+        // settings from the config file only ever show up as defaults
+        args.tcp_given = ["bind", "port"].iter().any(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+            || ["ABSHER_BIND", "ABSHER_PORT"].iter().any(|name| config::setting(name).is_some());
+        // checked here rather than with `requires`, `--dev` may come from the config file as a default
+        if args.code_seed.is_some() && !args.dev {
+            let message = "--code-seed makes every code predictable, it is only accepted together with --dev";
+            Args::command().error(clap::error::ErrorKind::MissingRequiredArgument, message).exit()
+        }
+        args
+    }
+
+    /// where to listen, `default_port` filling in for the addresses without one
+    pub fn sockets(&self, default_port: u16) -> Vec<SocketAddr> {
+        self.bind.iter()
+            .map(|bind| SocketAddr::new(bind.ip, bind.port.or(self.port).unwrap_or(default_port)))
+            .collect()
+    }
+
+    /// whether to listen on tcp at all, which is left to a unix socket or passed sockets unless
+    /// asked for
+    pub fn wants_tcp(&self, passed_sockets: bool) -> bool {
+        self.tcp_given || (self.unix_socket.is_none() && !passed_sockets)
+    }
+
+    /// the addresses alone, for servers on a port of their own
+    pub fn ips(&self) -> Vec<IpAddr> {
+        let mut ips = self.bind.iter().map(|bind| bind.ip).collect::<Vec<_>>();
+        ips.sort();
+        ips.dedup();
+        ips
+    }
+}
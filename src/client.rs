@@ -0,0 +1,546 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::time::Duration;
+use actix_web::web::Bytes;
+use awc::error::{WsClientError, WsProtocolError};
+use awc::http::{Method, StatusCode};
+use awc::ws::{CloseCode, CloseReason, Frame, Message};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use crate::challenge::Challenge;
+use crate::error::EXPIRED_CLOSE_CODE;
+use crate::devices::Platform;
+use crate::handlers::FetchResponse;
+use crate::model::AutofillData;
+use crate::protocol::{ClientMessage, ListenSpec, OutputFormat};
+use crate::req_code::RequestCode;
+use crate::store::IDEMPOTENCY_HEADER;
+use crate::{app_version, devices, pin};
+
+/// larger than any frame the server sends whole, bigger data frames come in chunks
+const MAX_FRAME_BYTES: usize = 4 << 20;
+/// the most a streamed data frame may add up to
+const MAX_DATA_BYTES: usize = 64 << 20;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// resolves with images can take a while to be checked
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+/// why a call to the server didn't go through
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unable to reach the server: {0}")]
+    Connect(String),
+    /// a refusal without an error body, from a proxy in front of the server most likely
+    #[error("the server answered with status {0}")]
+    Status(u16),
+    #[error("{0}")]
+    Rejected(Box<ServerError>),
+    #[error("the request expired before anyone answered it")]
+    Expired,
+    #[error("a newer listener for the same session took over the request")]
+    Superseded,
+    #[error("the connection closed before the data came")]
+    Closed,
+    #[error("the data didn't match its integrity check")]
+    Integrity,
+    #[error("the server sent something unexpected: {0}")]
+    Protocol(String),
+    /// sealed data and credentials are the listener's to decode, only plain json is done here
+    #[error("only plain json deliveries can be decoded here")]
+    Unsupported,
+}
+
+/// a code handed out for a request, for the page to show
+#[derive(Debug, Clone)]
+pub struct Issued {
+    pub code: String,
+    /// the code grouped the way resolvers type it
+    pub display: String,
+    pub expires_in: Duration,
+    pub pin: Option<String>,
+}
+
+/// the server's own error, as it puts it in its error bodies
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerError {
+    /// stable name to match on, like `PIN_INCORRECT`
+    pub code: String,
+    pub message: String,
+    /// the id the server logged the failed request under, to quote when reporting it
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// wrong pins left before the request is cancelled
+    #[serde(default)]
+    pub attempts_left: Option<u32>,
+    /// the feature an outdated app was turned away from, and the version it needs
+    #[serde(default)]
+    pub feature: Option<String>,
+    #[serde(default)]
+    pub min_version: Option<String>,
+    /// the payload field a rejected document, image or custom answer was submitted in
+    #[serde(default)]
+    pub field: Option<String>,
+    /// what is wrong with each rejected field, by field
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl From<ServerError> for Error {
+    fn from(error: ServerError) -> Self {
+        Error::Rejected(Box::new(error))
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ServerError,
+}
+
+/// the server's messages, only as far as this client cares about them
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Incoming {
+    Challenge { nonce: String, difficulty: u32 },
+    Code { code: String, display: String, expires_in: u64, #[serde(default)] pin: Option<String> },
+    Data { data: Value },
+    Stream { bytes: usize, chunks: usize },
+    Error { error: ServerError },
+    Superseded,
+    Expired,
+    Integrity { sha256: String, bytes: usize },
+    /// timing, receipts and whatever newer servers send
+    #[serde(other)]
+    Other,
+}
+
+/// an open websocket, whichever transport awc put under it
+trait Connection: Stream<Item = Result<Frame, WsProtocolError>> + Sink<Message, Error = WsProtocolError> + Unpin {}
+
+impl<C: Stream<Item = Result<Frame, WsProtocolError>> + Sink<Message, Error = WsProtocolError> + Unpin> Connection for C {}
+
+enum Inbound {
+    Text(Bytes),
+    Binary(Bytes),
+}
+
+fn unexpected(what: &str) -> Error {
+    Error::Protocol(what.to_owned())
+}
+
+fn parse(frame: &[u8]) -> Result<Incoming, Error> {
+    serde_json::from_slice(frame).map_err(|err| Error::Protocol(err.to_string()))
+}
+
+/// a listener for rust services, it speaks the websocket protocol along with its challenges,
+/// streamed frames and integrity checks
+///
+/// awc connections are bound to the thread they were made on, so are the futures this hands out
+#[derive(Clone)]
+pub struct AbsherZtClient {
+    base_url: String,
+    api_key: Option<String>,
+    retries: u32,
+}
+
+impl AbsherZtClient {
+    /// `base_url` is where the server is, like `wss://absher-zt.example.com`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self { base_url: base_url.into(), api_key: None, retries: 3 }
+    }
+
+    /// the tenant's key, without one the server may have the client solve a challenge first
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// how often connecting is retried when the server can't be reached or is busy, 3 by default
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// opens a request, returning its code right away and a future for the data; a connection
+    /// lost after the code was issued fails the future, the code only ever reaches that socket
+    pub async fn request_fields(
+        &self,
+        spec: ListenSpec,
+    ) -> Result<(Issued, impl Future<Output = Result<AutofillData, Error>> + use<>), Error> {
+        if spec.public_key.is_some() || spec.format != OutputFormat::Json {
+            return Err(Error::Unsupported)
+        }
+
+        let request = serde_json::to_string(&ClientMessage::Request(spec))
+            .map_err(|err| Error::Protocol(err.to_string()))?;
+        let mut conn = self.connect().await?;
+        send(&mut conn, request).await?;
+
+        let issued = loop {
+            let Inbound::Text(frame) = receive(&mut conn).await? else {
+                return Err(unexpected("a binary frame before the code"))
+            };
+
+            match parse(&frame)? {
+                Incoming::Challenge { nonce, difficulty } => {
+                    let challenge = Challenge { nonce, difficulty };
+                    let solution = actix_web::web::block(move || challenge.solve()).await
+                        .map_err(|err| Error::Protocol(err.to_string()))?;
+                    let answer = serde_json::to_string(&ClientMessage::Solution { solution })
+                        .map_err(|err| Error::Protocol(err.to_string()))?;
+                    send(&mut conn, answer).await?;
+                }
+                Incoming::Code { code, display, expires_in, pin } => {
+                    break Issued { code, display, expires_in: Duration::from_secs(expires_in), pin }
+                }
+                Incoming::Error { error } => return Err(error.into()),
+                _ => return Err(unexpected("a message other than a code or a challenge")),
+            }
+        };
+
+        Ok((issued, delivery(conn)))
+    }
+
+    async fn connect(&self) -> Result<impl Connection + use<>, Error> {
+        let url = format!("{}/listen", self.base_url.trim_end_matches('/'));
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            let client = awc::Client::builder().timeout(CONNECT_TIMEOUT).finish();
+            let mut request = client.ws(&url).max_frame_size(MAX_FRAME_BYTES);
+            if let Some(api_key) = &self.api_key {
+                request = request.header("x-api-key", api_key.as_str());
+            }
+
+            let err = match request.connect().await {
+                Ok((_, conn)) => return Ok(conn),
+                // the server's answer won't change by asking again
+                Err(WsClientError::InvalidResponseStatus(status))
+                    if !status.is_server_error() && status != StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    return Err(Error::Status(status.as_u16()))
+                }
+                Err(WsClientError::InvalidResponseStatus(status)) if attempt == self.retries => {
+                    return Err(Error::Status(status.as_u16()))
+                }
+                Err(err) => err,
+            };
+
+            if attempt == self.retries {
+                return Err(Error::Connect(err.to_string()))
+            }
+
+            log::debug!("connecting to {url} failed, retrying in {backoff:?}: {err}");
+            actix_web::rt::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// how a resolve went
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    /// signed proof of what was shared
+    pub receipt: String,
+    /// false when the server took the data but its listener had already gone, a 202 rather
+    /// than a 200; the request is answered either way and can't be resolved again
+    pub delivered: bool,
+}
+
+#[derive(Deserialize)]
+struct ResolveBody {
+    receipt: String,
+}
+
+/// the resolver side, for the app's backend: looks requests up and answers or declines them
+///
+/// calls are retried while the server can't be reached or is busy, resolves carry an
+/// idempotency key so a retry of one that went through gets its answer instead of a 409
+#[derive(Clone)]
+pub struct ResolverClient {
+    base_url: String,
+    app_version: Option<String>,
+    retries: u32,
+    device: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RegisteredBody {
+    device: String,
+}
+
+fn request_path(code: RequestCode, rest: &str) -> String {
+    format!("/requests/{}{rest}", code.as_str())
+}
+
+impl ResolverClient {
+    /// `base_url` is where the server is, like `https://absher-zt.example.com`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self { base_url: base_url.into(), app_version: None, retries: 3, device: None }
+    }
+
+    /// the version of the app the calls are made for, requests it is too old for are refused
+    pub fn app_version(mut self, app_version: impl Into<String>) -> Self {
+        self.app_version = Some(app_version.into());
+        self
+    }
+
+    /// how often a call is retried when the server can't be reached or is busy, 3 by default
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// the credential `register_device` gave, resolves link the device to the listener's tenant
+    pub fn device(mut self, device: impl Into<String>) -> Self {
+        self.device = Some(device.into());
+        self
+    }
+
+    /// registers the phone's fcm or apns token, the credential it returns goes into `device`;
+    /// `user_token` is the signed in user's token from the app's backend, where the server wants one
+    pub async fn register_device(&self, platform: Platform, push_token: &str, user_token: Option<&str>) -> Result<String, Error> {
+        let body = serde_json::to_vec(&json!({ "platform": platform, "push_token": push_token }))
+            .map_err(|err| Error::Protocol(err.to_string()))?;
+        let authorization = user_token.map(|token| format!("Bearer {token}"));
+        let headers = authorization.iter().map(|value| ("authorization", value.as_str())).collect::<Vec<_>>();
+        let (_, body) = self.call(Method::POST, "/devices".to_owned(), Some(body), &headers).await?;
+        let RegisteredBody { device } = serde_json::from_slice(&body).map_err(|err| Error::Protocol(err.to_string()))?;
+        Ok(device)
+    }
+
+    /// what the listener asks for, to show the user before they agree
+    pub async fn fetch_request(&self, code: RequestCode) -> Result<FetchResponse, Error> {
+        let (_, body) = self.call(Method::GET, request_path(code, ""), None, &[]).await?;
+        serde_json::from_slice(&body).map_err(|err| Error::Protocol(err.to_string()))
+    }
+
+    /// answers a request, `pin` is what the user typed for requests that have one
+    pub async fn resolve(&self, code: RequestCode, data: &AutofillData, pin: Option<&str>) -> Result<Resolved, Error> {
+        let body = serde_json::to_vec(data).map_err(|err| Error::Protocol(err.to_string()))?;
+        self.answer(code, body, pin).await
+    }
+
+    /// answers a request that has a public key, `ciphertext` being the data sealed to it
+    pub async fn resolve_sealed(&self, code: RequestCode, ciphertext: &str, pin: Option<&str>) -> Result<Resolved, Error> {
+        let body = serde_json::to_vec(&json!({ "ciphertext": ciphertext })).map_err(|err| Error::Protocol(err.to_string()))?;
+        self.answer(code, body, pin).await
+    }
+
+    /// declines a request for the user, its listener is told right away
+    pub async fn reject(&self, code: RequestCode, reason: Option<&str>) -> Result<(), Error> {
+        let body = serde_json::to_vec(&json!({ "reason": reason })).map_err(|err| Error::Protocol(err.to_string()))?;
+        match self.call(Method::POST, request_path(code, "/reject"), Some(body), &[]).await {
+            Ok(_) => Ok(()),
+            // declined already, by a retry whose first attempt went through after all
+            Err(Error::Rejected(error)) if error.code == "REQUEST_REJECTED" => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn answer(&self, code: RequestCode, body: Vec<u8>, pin: Option<&str>) -> Result<Resolved, Error> {
+        // the same key for every attempt, it's what makes them one resolve
+        let key = Alphanumeric.sample_string(&mut rand::rng(), 32);
+        let mut headers = vec![(IDEMPOTENCY_HEADER, key.as_str())];
+        if let Some(pin) = pin {
+            headers.push((pin::HEADER, pin));
+        }
+        if let Some(device) = &self.device {
+            headers.push((devices::HEADER, device));
+        }
+
+        let (status, body) = self.call(Method::POST, request_path(code, ""), Some(body), &headers).await?;
+        let ResolveBody { receipt } = serde_json::from_slice(&body).map_err(|err| Error::Protocol(err.to_string()))?;
+        Ok(Resolved { receipt, delivered: status == StatusCode::OK })
+    }
+
+    /// one call to `path`, retried with backoff on connection errors, 5xx and 429
+    async fn call(
+        &self,
+        method: Method,
+        path: String,
+        body: Option<Vec<u8>>,
+        headers: &[(&str, &str)],
+    ) -> Result<(StatusCode, Bytes), Error> {
+        let url = format!("{}{path}", self.base_url.trim_end_matches('/'));
+        let body = body.map(Bytes::from);
+        let client = awc::Client::builder().timeout(REQUEST_TIMEOUT).finish();
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            let mut request = client.request(method.clone(), &url).insert_header(("accept", "application/json"));
+            if let Some(app_version) = &self.app_version {
+                request = request.insert_header((app_version::HEADER, app_version.as_str()));
+            }
+            for &header in headers {
+                request = request.insert_header(header);
+            }
+
+            let sent = match &body {
+                Some(body) => request.content_type("application/json").send_body(body.clone()).await,
+                None => request.send().await,
+            };
+
+            let failure = match sent {
+                Ok(mut response) => {
+                    let status = response.status();
+                    let body = response.body().limit(MAX_FRAME_BYTES).await
+                        .map_err(|err| Error::Protocol(err.to_string()))?;
+                    if status.is_success() {
+                        return Ok((status, body))
+                    }
+
+                    // the server's answer won't change by asking again
+                    let busy = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+                    if !busy || attempt == self.retries {
+                        return Err(match serde_json::from_slice::<ErrorBody>(&body) {
+                            Ok(body) => body.error.into(),
+                            Err(_) => Error::Status(status.as_u16()),
+                        })
+                    }
+                    format!("status {status}")
+                }
+                Err(err) if attempt == self.retries => return Err(Error::Connect(err.to_string())),
+                Err(err) => err.to_string(),
+            };
+
+            log::debug!("{method} {url} failed, retrying in {backoff:?}: {failure}");
+            actix_web::rt::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+async fn send(conn: &mut impl Connection, json: String) -> Result<(), Error> {
+    conn.send(Message::Text(json.into())).await.map_err(|_| Error::Closed)
+}
+
+/// the next text or binary frame, answering pings on the way
+async fn receive(conn: &mut impl Connection) -> Result<Inbound, Error> {
+    loop {
+        match conn.next().await {
+            Some(Ok(Frame::Text(frame))) => return Ok(Inbound::Text(frame)),
+            Some(Ok(Frame::Binary(frame))) => return Ok(Inbound::Binary(frame)),
+            Some(Ok(Frame::Ping(ping))) => {
+                let _ = conn.send(Message::Pong(ping)).await;
+            }
+            Some(Ok(Frame::Pong(_))) => {}
+            Some(Ok(Frame::Close(reason))) => return Err(closed(reason)),
+            Some(Ok(Frame::Continuation(_))) => return Err(unexpected("a fragmented frame")),
+            Some(Err(err)) => return Err(Error::Protocol(err.to_string())),
+            None => return Err(Error::Closed),
+        }
+    }
+}
+
+fn closed(reason: Option<CloseReason>) -> Error {
+    match reason {
+        Some(reason) if reason.code == CloseCode::Other(EXPIRED_CLOSE_CODE) => Error::Expired,
+        _ => Error::Closed,
+    }
+}
+
+/// waits for the data and the trailers after it, the connection closes once they are through
+async fn delivery(mut conn: impl Connection) -> Result<AutofillData, Error> {
+    let mut data_frame: Option<Vec<u8>> = None;
+
+    loop {
+        let inbound = match receive(&mut conn).await {
+            Err(Error::Closed) if data_frame.is_some() => break,
+            inbound => inbound?,
+        };
+        let Inbound::Text(frame) = inbound else {
+            return Err(unexpected("a binary frame without a stream announcement"))
+        };
+
+        match parse(&frame)? {
+            Incoming::Data { .. } => data_frame = Some(frame.to_vec()),
+            Incoming::Stream { bytes, chunks } => data_frame = Some(reassemble(&mut conn, bytes, chunks).await?),
+            Incoming::Integrity { sha256, bytes } => {
+                let Some(data_frame) = &data_frame else {
+                    return Err(unexpected("an integrity check before the data"))
+                };
+                if data_frame.len() != bytes || hex_sha256(data_frame) != sha256 {
+                    return Err(Error::Integrity)
+                }
+                break
+            }
+            Incoming::Error { error } => return Err(error.into()),
+            Incoming::Expired => return Err(Error::Expired),
+            Incoming::Superseded => return Err(Error::Superseded),
+            Incoming::Other => {}
+            Incoming::Challenge { .. } | Incoming::Code { .. } => return Err(unexpected("a second code")),
+        }
+    }
+
+    let _ = conn.close().await;
+    decode(&data_frame.ok_or(Error::Closed)?)
+}
+
+/// puts a streamed data frame back together, each chunk is its length as a big endian u32
+/// followed by that many bytes
+async fn reassemble(conn: &mut impl Connection, bytes: usize, chunks: usize) -> Result<Vec<u8>, Error> {
+    if bytes > MAX_DATA_BYTES {
+        return Err(unexpected("a stream larger than the data can be"))
+    }
+
+    let mut frame = Vec::with_capacity(bytes);
+    for _ in 0..chunks {
+        let Inbound::Binary(chunk) = receive(conn).await? else {
+            return Err(unexpected("a text frame inside a stream"))
+        };
+        let Some((length, rest)) = chunk.split_first_chunk::<4>() else {
+            return Err(unexpected("a chunk without its length"))
+        };
+        if u32::from_be_bytes(*length) as usize != rest.len() || frame.len() + rest.len() > bytes {
+            return Err(unexpected("a chunk of the wrong length"))
+        }
+        frame.extend_from_slice(rest);
+    }
+
+    match frame.len() == bytes {
+        true => Ok(frame),
+        false => Err(unexpected("a stream shorter than announced")),
+    }
+}
+
+fn hex_sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn decode(data_frame: &[u8]) -> Result<AutofillData, Error> {
+    let Incoming::Data { mut data } = parse(data_frame)? else {
+        return Err(unexpected("a stream that isn't data"))
+    };
+
+    // apps can't send these, so deserializing drops them and they are put back by hand
+    let age_over = data.get_mut("age_over").map(Value::take).unwrap_or_default();
+    let hash_matches = data.get_mut("hash_matches").map(Value::take).unwrap_or_default();
+    let invalid = |err: serde_json::Error| Error::Protocol(err.to_string());
+
+    let mut decoded: AutofillData = serde_json::from_value(data).map_err(invalid)?;
+    decoded.age_over = serde_json::from_value(age_over).map_err(invalid)?;
+    decoded.hash_matches = match hash_matches {
+        Value::Null => BTreeMap::new(),
+        hash_matches => serde_json::from_value(hash_matches).map_err(invalid)?,
+    };
+
+    Ok(decoded)
+}
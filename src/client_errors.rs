@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::error::AppError;
+use crate::req_code::RequestCode;
+
+/// error codes are identifiers, not messages
+const MAX_ERROR_LEN: usize = 64;
+/// details are cut off past this before they reach the logs
+const MAX_DETAIL_LEN: usize = 256;
+
+/// a listener telling us it couldn't parse or show something we sent it
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct ClientError {
+    /// the frontend's own code for what went wrong, like `data_parse_failed`
+    pub error: String,
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+impl ClientError {
+    /// only short snake case codes are kept, so a broken client can't flood the counters
+    pub fn validate(&self) -> Result<(), AppError> {
+        let valid = !self.error.is_empty()
+            && self.error.len() <= MAX_ERROR_LEN
+            && self.error.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'_');
+
+        if !valid {
+            return Err(AppError::InvalidBody(format!("error must be at most {MAX_ERROR_LEN} of a-z, 0-9 and _")))
+        }
+
+        Ok(())
+    }
+}
+
+/// reports per tenant and error code, keyless listeners count under ""
+static REPORTS: LazyLock<DashMap<String, HashMap<String, u64>>> = LazyLock::new(DashMap::new);
+
+pub fn record(code: RequestCode, tenant: Option<&str>, report: &ClientError) {
+    let detail = report.detail.as_deref().unwrap_or_default();
+    let detail = match detail.char_indices().nth(MAX_DETAIL_LEN) {
+        Some((end, _)) => &detail[..end],
+        None => detail,
+    };
+
+    let correlation = code.correlation_id();
+    log::warn!(
+        code_hash = correlation.as_str(),
+        tenant = tenant.unwrap_or_default();
+        "[{correlation}] listener for tenant {} reported {}: {detail}", tenant.unwrap_or("-"), report.error
+    );
+
+    *REPORTS.entry(tenant.unwrap_or_default().to_owned())
+        .or_default()
+        .entry(report.error.clone())
+        .or_default() += 1;
+}
+
+/// how often each error was reported by a tenant's listeners
+pub fn tenant_counts(tenant: &str) -> HashMap<String, u64> {
+    REPORTS.get(tenant).map(|counts| counts.clone()).unwrap_or_default()
+}
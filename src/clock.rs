@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+use crate::expiry;
+
+/// where request lifetimes are measured from: expiry, the grace period, tombstones and the sweep
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// tokio's clock, the real time unless a test paused it with `tokio::time::pause`
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+/// a clock that only moves when told to, for tests whose server runs on other threads than
+/// the test, where pausing tokio's clock doesn't reach
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), elapsed: Mutex::new(Duration::ZERO) }
+    }
+
+    /// moves the clock on, requests that ran out by then expire right away
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner) += by;
+        expiry::wake();
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+static CLOCK: OnceLock<Box<dyn Clock>> = OnceLock::new();
+
+/// replaces tokio's clock for the whole process, only before anything asked for the time;
+/// hands `clock` back if that is too late
+pub fn install<C: Clock + 'static>(clock: C) -> Result<(), C> {
+    let mut clock = Some(clock);
+    CLOCK.get_or_init(|| Box::new(clock.take().expect("the clock to be installed once")));
+    match clock {
+        None => Ok(()),
+        Some(clock) => Err(clock),
+    }
+}
+
+pub fn now() -> Instant {
+    CLOCK.get_or_init(|| Box::new(TokioClock)).now()
+}
@@ -0,0 +1,94 @@
+use std::collections::VecDeque;
+use std::sync::{LazyLock, Mutex, MutexGuard};
+use tokio::sync::Notify;
+use crate::{env_or, health};
+use crate::metrics::METRICS;
+use crate::req_code::{CodeKind, RequestCode};
+
+/// codes kept ready per kind, 0 turns pooling off
+static DEPTH: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_CODE_POOL_SIZE", 256));
+
+/// codes drawn between yields while refilling
+const BATCH: usize = 64;
+/// batches a refill gives up after, so a crowded code space can't keep it spinning
+const MAX_BATCHES: usize = 16;
+
+const KINDS: [CodeKind; 2] = [CodeKind::Letters, CodeKind::Numeric];
+
+static POOLS: LazyLock<[Mutex<VecDeque<RequestCode>>; 2]> = LazyLock::new(Default::default);
+
+/// woken whenever a pool falls below half its depth
+static LOW: Notify = Notify::const_new();
+
+fn pool(kind: CodeKind) -> MutexGuard<'static, VecDeque<RequestCode>> {
+    let index = KINDS.iter().position(|pooled| *pooled == kind).unwrap_or_default();
+    POOLS[index].lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn report_depth(kind: CodeKind, depth: usize) {
+    METRICS.code_pool_depth.with_label_values(&[kind.name()]).set(depth as i64);
+}
+
+/// a code that was free when it was pooled, whoever takes it still has to claim it
+pub fn take(kind: CodeKind) -> Option<RequestCode> {
+    if *DEPTH == 0 {
+        return None
+    }
+
+    let mut codes = pool(kind);
+    let code = codes.pop_front();
+    report_depth(kind, codes.len());
+
+    if codes.len() < *DEPTH / 2 {
+        LOW.notify_one();
+    }
+
+    if code.is_none() {
+        METRICS.code_pool_misses.with_label_values(&[kind.name()]).inc();
+    }
+
+    code
+}
+
+/// keeps every pool topped up, `is_free` tells whether a code could be handed out right now
+pub async fn run(is_free: impl Fn(&RequestCode) -> bool) {
+    if *DEPTH == 0 {
+        return
+    }
+
+    let _watch = health::watch("code_pool");
+    loop {
+        for kind in KINDS {
+            refill(kind, &is_free).await;
+        }
+
+        LOW.notified().await;
+    }
+}
+
+async fn refill(kind: CodeKind, is_free: &impl Fn(&RequestCode) -> bool) {
+    for _ in 0..MAX_BATCHES {
+        let missing = DEPTH.saturating_sub(pool(kind).len());
+        if missing == 0 {
+            return
+        }
+
+        // drawn without holding the lock, so taking a code never waits on a refill
+        let fresh: Vec<_> = (0..missing.min(BATCH))
+            .map(|_| RequestCode::new_rand(kind))
+            .filter(is_free)
+            .collect();
+
+        {
+            let mut codes = pool(kind);
+            for code in fresh {
+                if codes.len() < *DEPTH && !codes.contains(&code) {
+                    codes.push_back(code);
+                }
+            }
+            report_depth(kind, codes.len());
+        }
+
+        tokio::task::yield_now().await;
+    }
+}
@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, OnceLock, PoisonError, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::{cors, logging, rate_limit, ttl};
+
+/// a config file, every setting in it is named after the environment variable that overrides it:
+/// `tombstone_secs` is `ABSHER_TOMBSTONE_SECS` and `timeout_ms` under `[webhook]` is
+/// `ABSHER_WEBHOOK_TIMEOUT_MS`
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    bind: Option<Vec<String>>,
+    port: Option<u16>,
+    unix_socket: Option<String>,
+    /// in octal, like `"660"`
+    unix_socket_mode: Option<String>,
+    /// takes client addresses from `Forwarded` or `X-Forwarded-For`, only ever from a unix socket
+    /// or loopback peer
+    trusted_proxy: Option<bool>,
+    grpc_port: Option<u16>,
+    workers: Option<usize>,
+    log_level: Option<String>,
+    /// `json` or `text`
+    log_format: Option<String>,
+    admin_token: Option<String>,
+    /// base64 ed25519 public key of the app's backend, which signs its users' tokens
+    app_auth_key: Option<String>,
+    signing_key: Option<String>,
+    credential_issuer: Option<String>,
+    /// serves a swagger ui at /docs, for local development
+    swagger_ui: Option<bool>,
+    /// serves the demo page at /demo, never where real users are
+    dev: Option<bool>,
+
+    tombstone_secs: Option<u64>,
+    cleanup_interval_secs: Option<u64>,
+    min_ttl_secs: Option<u64>,
+    max_ttl_secs: Option<u64>,
+    default_ttl_secs: Option<u64>,
+    min_app_version: Option<String>,
+    min_app_version_encryption: Option<String>,
+    numeric_code_length: Option<usize>,
+    max_pending: Option<usize>,
+    max_pending_per_tenant: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_message_bytes: Option<usize>,
+    spec_timeout_secs: Option<u64>,
+    max_body_bytes: Option<usize>,
+    max_profile_picture_bytes: Option<usize>,
+    max_license_bytes: Option<usize>,
+    max_id_image_bytes: Option<usize>,
+    image_max_dimension: Option<u32>,
+    stream_threshold_bytes: Option<usize>,
+    stream_chunk_bytes: Option<usize>,
+    max_devices: Option<usize>,
+    stats_window_secs: Option<u64>,
+
+    code: CodeConfig,
+    cors: CorsConfig,
+    tls: TlsConfig,
+    store: StoreConfig,
+    denylist: DenylistConfig,
+    blocklist: BlocklistConfig,
+    geo: GeoConfig,
+    devices: DevicesConfig,
+    ip_rate: RateConfig,
+    key_rate: RateConfig,
+    lockout: LockoutConfig,
+    pow: PowConfig,
+    pin: PinConfig,
+    cache: CacheConfig,
+    upload: UploadConfig,
+    webhook: WebhookConfig,
+    audit: AuditConfig,
+    broker: BrokerConfig,
+    metering: MeteringConfig,
+    history: HistoryConfig,
+    push: PushConfig,
+    sms: SmsConfig,
+    classifier: ClassifierConfig,
+    ocr: OcrConfig,
+    attestation: AttestationConfig,
+    oidc: OidcConfig,
+    oauth: OauthConfig,
+    signing: SigningConfig,
+    shutdown: ShutdownConfig,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct CodeConfig {
+    length: Option<usize>,
+    /// `full` or `unambiguous`
+    alphabet: Option<String>,
+    checksum: Option<bool>,
+    pool_size: Option<usize>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct CorsConfig {
+    /// origins pages may call us from, any origin if left out
+    allowed_origins: Option<Vec<String>>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsConfig {
+    cert: Option<String>,
+    key: Option<String>,
+    redirect_port: Option<u16>,
+    http3_port: Option<u16>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum StoreBackend {
+    /// the only one so far, nothing survives a restart but the deny list and devices; specs and
+    /// shared data never leave the process, a shared backend would have to seal them first
+    Memory,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct StoreConfig {
+    backend: Option<StoreBackend>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct DenylistConfig {
+    file: Option<String>,
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct BlocklistConfig {
+    file: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct GeoConfig {
+    /// a MaxMind country or city database, tenants can't check countries without one
+    database: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct DevicesConfig {
+    file: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateConfig {
+    burst: Option<f64>,
+    per_sec: Option<f64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct LockoutConfig {
+    misses: Option<u32>,
+    window_secs: Option<u64>,
+    ban_secs: Option<u64>,
+    max_ban_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct PowConfig {
+    difficulty: Option<u32>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct PinConfig {
+    attempts: Option<u32>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheConfig {
+    max_age_secs: Option<u32>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct UploadConfig {
+    max_bytes: Option<u64>,
+    /// what all uploads in flight may hold together
+    budget_bytes: Option<u64>,
+    chunk_bytes: Option<usize>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct WebhookConfig {
+    timeout_ms: Option<u64>,
+    allow_http: Option<bool>,
+    /// loopback and private addresses, for a receiver on the same machine or network
+    allow_private: Option<bool>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct AuditConfig {
+    /// `file`, `syslog` or `kafka`, nothing is audited without one
+    sink: Option<String>,
+    file: Option<String>,
+    /// like `10.0.0.5:514`, the local syslog when left out
+    syslog_addr: Option<String>,
+    /// a kafka rest proxy
+    kafka_url: Option<String>,
+    kafka_topic: Option<String>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct BrokerConfig {
+    /// `kafka` or `nats`, where the ends of requests are published
+    kind: Option<String>,
+    /// the kafka rest proxy, or the nats server as `host:port`
+    url: Option<String>,
+    /// the kafka topic or the nats subject
+    topic: Option<String>,
+    /// keeps what wasn't published yet across restarts
+    outbox: Option<String>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct MeteringConfig {
+    /// how often usage records go to the broker
+    interval_secs: Option<u64>,
+    retention_days: Option<u64>,
+    file: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct HistoryConfig {
+    /// `sqlite://history.db?mode=rwc` or `postgres://…`, no history is kept without one
+    url: Option<String>,
+    retention_days: Option<u64>,
+    purge_interval_secs: Option<u64>,
+    /// changes waiting to be written, past this they are dropped
+    queue: Option<usize>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct PushConfig {
+    /// where notifications for fcm and apns devices are posted, codes aren't pushed without one
+    gateway_url: Option<String>,
+    gateway_token: Option<String>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct SmsConfig {
+    /// twilio is used when both of these are set, the gateway otherwise
+    twilio_sid: Option<String>,
+    twilio_token: Option<String>,
+    gateway_url: Option<String>,
+    gateway_token: Option<String>,
+    from: Option<String>,
+    timeout_ms: Option<u64>,
+    /// for tenants whose policy doesn't say
+    daily_quota: Option<u32>,
+    /// percent of the quota at which a warning is published
+    quota_warning_percent: Option<u32>,
+    template: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClassifierConfig {
+    url: Option<String>,
+    timeout_ms: Option<u64>,
+    min_confidence: Option<f64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct OcrConfig {
+    url: Option<String>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct AttestationConfig {
+    /// the service that checks webauthn assertions and play integrity verdicts, every answer
+    /// needs one once this is set
+    url: Option<String>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct OidcConfig {
+    /// the https url relying parties know this server by, openid connect is off without one
+    issuer: Option<String>,
+    id_token_ttl_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct OauthConfig {
+    token_ttl_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct SigningConfig {
+    /// a fresh key derived from `signing_key` takes over this often, never when unset
+    rotation_days: Option<u64>,
+    /// how many retired keys stay published
+    retained: Option<u64>,
+    /// seeds that signed before `signing_key` changed, still published
+    previous_keys: Option<Vec<String>>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct ShutdownConfig {
+    grace_secs: Option<u64>,
+}
+
+/// the file's settings by the names of the variables they stand in for
+static FILE: LazyLock<RwLock<HashMap<String, String>>> = LazyLock::new(Default::default);
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+fn flatten(prefix: &str, value: Value, settings: &mut HashMap<String, String>) {
+    let setting = match value {
+        Value::Null => return,
+        Value::Object(table) => {
+            for (key, value) in table {
+                flatten(&format!("{prefix}_{}", key.to_ascii_uppercase()), value, settings);
+            }
+            return
+        }
+        Value::Array(values) => values.iter()
+            .map(|value| value.as_str().map_or_else(|| value.to_string(), str::to_owned))
+            .collect::<Vec<_>>()
+            .join(","),
+        Value::String(value) => value,
+        value => value.to_string(),
+    };
+
+    settings.insert(prefix.to_owned(), setting);
+}
+
+fn read(path: &Path) -> Result<HashMap<String, String>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("unable to read {}: {err}", path.display()))?;
+    let config = toml::from_str::<Config>(&text).map_err(|err| format!("{} is invalid: {err}", path.display()))?;
+
+    let mut settings = HashMap::new();
+    let value = serde_json::to_value(&config).map_err(|err| err.to_string())?;
+    flatten("ABSHER", value, &mut settings);
+    Ok(settings)
+}
+
+/// reads the config file, has to happen before anything looks at a setting
+pub fn load(path: &Path) -> Result<(), String> {
+    let settings = read(path)?;
+    PATH.set(path.to_owned()).map_err(|_| "the config file was already loaded".to_owned())?;
+    *FILE.write().unwrap_or_else(PoisonError::into_inner) = settings;
+    Ok(())
+}
+
+/// reads the config file again and applies what can change at runtime: rate limits, the cors
+/// allowlist, ttl bounds and the log level, everything else still needs a restart
+pub fn reload() -> Result<(), String> {
+    let path = PATH.get().ok_or("no config file was given at startup")?;
+
+    // a broken file leaves the running settings alone
+    let settings = read(path)?;
+    *FILE.write().unwrap_or_else(PoisonError::into_inner) = settings;
+
+    rate_limit::reload();
+    cors::reload();
+    ttl::reload();
+    logging::reload();
+    log::warn!("reloaded the config from {}", path.display());
+    Ok(())
+}
+
+/// reloads on every SIGHUP, for as long as the server runs
+pub async fn reload_on_hangup() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => return log::error!("unable to listen for SIGHUP, reload through the admin api: {err}"),
+        };
+
+        while hangups.recv().await.is_some() {
+            if let Err(err) = reload() {
+                log::error!("unable to reload the config: {err}");
+            }
+        }
+    }
+}
+
+/// variables that are read before the config file or instead of it
+const NOT_IN_FILES: [&str; 1] = ["ABSHER_CONFIG"];
+
+/// every setting a config file can hold, by its variable's name, with where it sits in the file
+fn collect_known(prefix: &str, value: Value, path: &mut Vec<String>, known: &mut Vec<(String, Vec<String>)>) {
+    match value {
+        Value::Object(table) => {
+            for (key, value) in table {
+                path.push(key.clone());
+                collect_known(&format!("{prefix}_{}", key.to_ascii_uppercase()), value, path, known);
+                path.pop();
+            }
+        }
+        _ => known.push((prefix.to_owned(), path.clone())),
+    }
+}
+
+/// whether `value` can be read for the setting at `path`, the way it would in a file: as a
+/// number or a bool, as a string, or as a comma separated list
+fn fits(path: &[String], value: &str) -> bool {
+    let candidates = [
+        serde_json::from_str(value).ok(),
+        Some(Value::String(value.to_owned())),
+        Some(Value::Array(value.split(',').map(|item| Value::String(item.trim().to_owned())).collect())),
+    ];
+
+    candidates.into_iter().flatten().any(|candidate| {
+        let document = path.iter().rev().fold(candidate, |value, key| {
+            Value::Object([(key.clone(), value)].into_iter().collect())
+        });
+        serde_json::from_value::<Config>(document).is_ok()
+    })
+}
+
+/// what is wrong with the settings in the environment and the config file: names that aren't
+/// settings, most likely typos, and values of the wrong type, which would quietly fall back to
+/// their defaults
+pub fn problems() -> Vec<String> {
+    let mut settings = Vec::new();
+    collect_known("ABSHER", serde_json::to_value(Config::default()).unwrap_or_default(), &mut Vec::new(), &mut settings);
+
+    let mut problems = std::env::vars()
+        .map(|(name, _)| name)
+        .filter(|name| name.starts_with("ABSHER_") && !NOT_IN_FILES.contains(&name.as_str()))
+        .filter(|name| !settings.iter().any(|(known, _)| known == name))
+        .map(|name| format!("{name} is not a setting"))
+        .collect::<Vec<_>>();
+
+    for (name, path) in &settings {
+        if let Some(value) = setting(name)
+            && !fits(path, &value)
+        {
+            problems.push(format!("{name} has a value of the wrong type, it would be ignored"));
+        }
+    }
+
+    problems.sort();
+    problems
+}
+
+/// a setting from the environment, or else from the config file
+pub fn setting(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+        .or_else(|| FILE.read().unwrap_or_else(PoisonError::into_inner).get(name).cloned())
+}
@@ -0,0 +1,53 @@
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+use dashmap::DashMap;
+use crate::env_or;
+use crate::error::AppError;
+
+/// open listener websockets a single address may hold at once
+pub static MAX_PER_IP: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_MAX_CONNECTIONS_PER_IP", 20));
+/// the largest frame a listener may send, specifications and client errors are far smaller
+pub static MAX_MESSAGE_BYTES: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_MAX_MESSAGE_BYTES", 16 * 1024));
+/// how long a fresh socket gets to send its specification
+pub static SPEC_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_SPEC_TIMEOUT_SECS", 10))
+});
+
+static BY_IP: LazyLock<DashMap<IpAddr, usize>> = LazyLock::new(DashMap::new);
+
+/// one of an address's connections, given back when the socket is done
+pub struct Connection {
+    ip: Option<IpAddr>,
+}
+
+/// connections without a peer address, like over a unix socket with no trusted proxy, are not limited
+pub fn open(ip: Option<IpAddr>) -> Result<Connection, AppError> {
+    let Some(ip) = ip else {
+        return Ok(Connection { ip })
+    };
+
+    let mut open = BY_IP.entry(ip).or_default();
+    if *open >= *MAX_PER_IP {
+        return Err(AppError::TooManyConnections { max: *MAX_PER_IP })
+    }
+    *open += 1;
+
+    Ok(Connection { ip: Some(ip) })
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            BY_IP.remove_if_mut(&ip, |_, open| {
+                *open -= 1;
+                *open == 0
+            });
+        }
+    }
+}
+
+/// addresses with a connection open right now
+pub fn count() -> usize {
+    BY_IP.len()
+}
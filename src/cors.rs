@@ -0,0 +1,36 @@
+use std::sync::{LazyLock, PoisonError, RwLock};
+use actix_cors::Cors;
+use crate::config::setting;
+
+/// origins pages may call us from, any origin unless the operator names the ones that may
+static ORIGINS: LazyLock<RwLock<Option<Vec<String>>>> = LazyLock::new(|| RwLock::new(from_env()));
+
+fn from_env() -> Option<Vec<String>> {
+    setting("ABSHER_CORS_ALLOWED_ORIGINS")
+        .map(|origins| origins.split(',').map(|origin| origin.trim().to_owned()).collect())
+}
+
+pub fn reload() {
+    *ORIGINS.write().unwrap_or_else(PoisonError::into_inner) = from_env();
+}
+
+/// whether pages from `origin` may call us, browsers don't apply cors to websockets so `listen`
+/// asks this itself
+pub fn allows(origin: &[u8]) -> bool {
+    match &*ORIGINS.read().unwrap_or_else(PoisonError::into_inner) {
+        Some(origins) => origins.iter().any(|allowed| allowed.as_bytes() == origin),
+        None => true,
+    }
+}
+
+/// permissive apart from the origin, which is looked up on every request so a reload applies right away
+pub fn middleware() -> Cors {
+    Cors::default()
+        .allowed_origin_fn(|origin, _req| allows(origin.as_bytes()))
+        .allow_any_method()
+        .allow_any_header()
+        .expose_any_header()
+        .supports_credentials()
+        .max_age(3600)
+        .block_on_origin_mismatch(false)
+}
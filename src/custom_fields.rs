@@ -0,0 +1,175 @@
+use std::collections::{BTreeMap, HashSet};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use zeroize::Zeroize;
+use crate::error::AppError;
+
+/// fields a single listener may declare, partners rarely need more than a handful
+pub const MAX_CUSTOM_FIELDS: usize = 20;
+const MAX_KEY_LEN: usize = 64;
+const MAX_LABEL_LEN: usize = 200;
+/// longest string answer, these are meant for membership numbers and the like, not documents
+const MAX_STRING_LEN: usize = 1024;
+
+/// a one-off field a listener asks for on top of the built in ones, the app renders it from the label
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+pub struct CustomField {
+    pub key: String,
+    pub label: String,
+    #[serde(rename = "type")]
+    pub kind: CustomType,
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CustomType {
+    String,
+    Number,
+    Bool,
+}
+
+impl CustomType {
+    fn name(self) -> &'static str {
+        match self {
+            CustomType::String => "a string",
+            CustomType::Number => "a number",
+            CustomType::Bool => "true or false",
+        }
+    }
+}
+
+/// what a resolver answered for a custom field
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum CustomValue {
+    Bool(bool),
+    #[schema(value_type = f64)]
+    Number(serde_json::Number),
+    String(String),
+}
+
+impl CustomValue {
+    fn kind(&self) -> CustomType {
+        match self {
+            CustomValue::Bool(_) => CustomType::Bool,
+            CustomValue::Number(_) => CustomType::Number,
+            CustomValue::String(_) => CustomType::String,
+        }
+    }
+}
+
+/// only answers in words can say anything about the resolver
+impl Zeroize for CustomValue {
+    fn zeroize(&mut self) {
+        if let CustomValue::String(value) = self {
+            value.zeroize();
+        }
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && key.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+}
+
+/// whether a listener's declarations make sense, checked before it gets a code
+pub fn check_declared(fields: &[CustomField]) -> Result<(), AppError> {
+    let mut keys = HashSet::new();
+    let valid = fields.len() <= MAX_CUSTOM_FIELDS && fields.iter().all(|field| {
+        valid_key(&field.key)
+            && !field.label.trim().is_empty()
+            && field.label.chars().count() <= MAX_LABEL_LEN
+            && keys.insert(field.key.as_str())
+    });
+
+    match valid {
+        true => Ok(()),
+        false => Err(AppError::InvalidCustomFields { max: MAX_CUSTOM_FIELDS }),
+    }
+}
+
+/// makes sure the answers match what the listener declared, nothing more and nothing missing
+pub fn check_answers(fields: &[CustomField], answers: &BTreeMap<String, CustomValue>) -> Result<(), AppError> {
+    let invalid = |key: &str, reason: String| AppError::InvalidCustomField { key: key.to_owned(), reason };
+
+    if let Some(key) = answers.keys().find(|key| !fields.iter().any(|field| &field.key == *key)) {
+        return Err(invalid(key, "was not asked for".to_owned()))
+    }
+
+    for field in fields {
+        match answers.get(&field.key) {
+            None if field.required => return Err(invalid(&field.key, "is required".to_owned())),
+            None => {}
+            Some(answer) if answer.kind() != field.kind => {
+                return Err(invalid(&field.key, format!("has to be {}", field.kind.name())))
+            }
+            Some(CustomValue::String(answer)) if answer.chars().count() > MAX_STRING_LEN => {
+                return Err(invalid(&field.key, format!("can be at most {MAX_STRING_LEN} characters")))
+            }
+            Some(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn field(key: &str, kind: CustomType, required: bool) -> CustomField {
+        CustomField { key: key.to_owned(), label: format!("your {key}"), kind, required }
+    }
+
+    fn answers(value: serde_json::Value) -> BTreeMap<String, CustomValue> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// why `answers` were turned away, or `None` when they weren't
+    fn reason(fields: &[CustomField], value: serde_json::Value) -> Option<(String, String)> {
+        match check_answers(fields, &answers(value)) {
+            Ok(()) => None,
+            Err(AppError::InvalidCustomField { key, reason }) => Some((key, reason)),
+            Err(err) => panic!("unexpected {err:?}"),
+        }
+    }
+
+    #[test]
+    fn declarations_are_checked() {
+        let membership = field("membership_no", CustomType::String, true);
+        assert!(check_declared(&[membership.clone(), field("points", CustomType::Number, false)]).is_ok());
+
+        let invalid = |fields: &[CustomField]| matches!(check_declared(fields), Err(AppError::InvalidCustomFields { max: 20 }));
+        assert!(invalid(&[membership.clone(), membership.clone()]));
+        assert!(invalid(&[field("membership-no", CustomType::String, false)]));
+        assert!(invalid(&[field(&"k".repeat(65), CustomType::String, false)]));
+        assert!(invalid(&[CustomField { label: " ".to_owned(), ..membership.clone() }]));
+        assert!(invalid(&[CustomField { label: "ل".repeat(201), ..membership.clone() }]));
+        assert!(!invalid(&[CustomField { label: "ل".repeat(200), ..membership }]));
+
+        let many = (0..=MAX_CUSTOM_FIELDS).map(|n| field(&format!("f{n}"), CustomType::Bool, false)).collect::<Vec<_>>();
+        assert!(invalid(&many));
+        assert!(!invalid(&many[1..]));
+    }
+
+    #[test]
+    fn answers_match_their_declarations() {
+        let fields = [field("membership_no", CustomType::String, true), field("points", CustomType::Number, false)];
+        assert_eq!(reason(&fields, json!({"membership_no": "A-1", "points": 12.5})), None);
+        assert_eq!(reason(&fields, json!({"membership_no": "A-1"})), None);
+
+        let refused = |key: &str, reason: &str| Some((key.to_owned(), reason.to_owned()));
+        assert_eq!(reason(&fields, json!({"points": 3})), refused("membership_no", "is required"));
+        assert_eq!(reason(&fields, json!({"membership_no": "A-1", "points": "3"})), refused("points", "has to be a number"));
+        assert_eq!(reason(&fields, json!({"membership_no": 7})), refused("membership_no", "has to be a string"));
+        assert_eq!(reason(&fields, json!({"membership_no": "A-1", "extra": true})), refused("extra", "was not asked for"));
+        assert_eq!(
+            reason(&fields, json!({"membership_no": "x".repeat(1025)})),
+            refused("membership_no", "can be at most 1024 characters"),
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use actix_web::{web, HttpResponse};
+
+/// turned on by `--dev`, the page talks to whatever server serves it
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+    log::warn!("dev mode is on, /demo lets anyone answer requests from a browser");
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// both sides of a request on one page, the listener on the left and the resolver on the right
+const DEMO_PAGE: &str = r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>absher-zt demo</title>
+<script src="https://unpkg.com/qrcode-generator@1.4.4/qrcode.js"></script>
+<style>
+body { font-family: system-ui, sans-serif; margin: 2em; display: flex; gap: 3em; flex-wrap: wrap; }
+section { flex: 1; min-width: 22em; }
+label { display: block; margin: .2em 0; }
+textarea { width: 100%; height: 16em; font-family: monospace; }
+pre { background: #f4f4f4; padding: .5em; max-height: 20em; overflow: auto; white-space: pre-wrap; word-break: break-all; }
+#code { font-size: 2em; letter-spacing: .1em; }
+</style>
+</head>
+<body>
+<section>
+<h2>listener</h2>
+<div id="fields"></div>
+<label>ttl seconds <input id="ttl" type="number" value="180" min="1"></label>
+<label><input id="pin" type="checkbox"> with a pin</label>
+<button id="listen">open a request</button>
+<button id="close" disabled>close the socket</button>
+<p id="code"></p>
+<p id="pin-shown"></p>
+<div id="qr"></div>
+<pre id="log"></pre>
+</section>
+<section>
+<h2>resolver</h2>
+<label>code <input id="resolve-code"></label>
+<label>pin <input id="resolve-pin"></label>
+<button id="fetch">fetch</button>
+<button id="resolve">resolve</button>
+<button id="reject">reject</button>
+<p>data</p>
+<textarea id="data"></textarea>
+<pre id="answer"></pre>
+</section>
+<script>
+const SAMPLES = {
+  name: ["name", { arabic: { given: "محمد", family: "العتيبي" }, english: { given: "Mohammed", family: "Alotaibi" } }],
+  email: ["email", "demo@example.com"],
+  phone_number: ["phone_number", "+966500000000"],
+  id: ["id", "1000000008"],
+  date_of_birth: ["date_of_birth", "1990-01-31"],
+  nationality: ["nationality", "SA"],
+};
+const $ = (id) => document.getElementById(id);
+let socket = null;
+
+for (const field of Object.keys(SAMPLES)) {
+  $("fields").insertAdjacentHTML("beforeend", `<label><input type="checkbox" value="${field}" checked> ${field}</label>`);
+}
+
+function log(line) {
+  $("log").textContent += line + "\n";
+}
+
+function requestedFields() {
+  const fields = {};
+  for (const box of $("fields").querySelectorAll("input:checked")) {
+    fields[box.value] = box.value === "name" ? { arabic: true, english: true } : true;
+  }
+  return fields;
+}
+
+function sampleData() {
+  const data = {};
+  for (const field of Object.keys(requestedFields())) {
+    const [key, value] = SAMPLES[field];
+    data[key] = value;
+  }
+  return JSON.stringify(data, null, 2);
+}
+
+async function solve(nonce, difficulty) {
+  const encoder = new TextEncoder();
+  for (let counter = 0; ; counter++) {
+    const digest = new Uint8Array(await crypto.subtle.digest("SHA-256", encoder.encode(nonce + counter)));
+    let zeros = 0;
+    for (const byte of digest) {
+      zeros += byte === 0 ? 8 : Math.clz32(byte) - 24;
+      if (byte !== 0) break;
+    }
+    if (zeros >= difficulty) return String(counter);
+  }
+}
+
+function showCode(message) {
+  $("code").textContent = message.display;
+  $("pin-shown").textContent = message.pin ? `pin ${message.pin}` : "";
+  $("resolve-code").value = message.code;
+  $("resolve-pin").value = message.pin || "";
+  const qr = qrcode(0, "M");
+  qr.addData(message.code);
+  qr.make();
+  $("qr").innerHTML = qr.createSvgTag(4);
+}
+
+$("listen").onclick = () => {
+  if (socket) socket.close();
+  $("log").textContent = "";
+  $("data").value = sampleData();
+  const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+  socket = new WebSocket(`${scheme}//${location.host}/listen`);
+  socket.onopen = () => {
+    const request = { type: "request", fields: requestedFields(), ttl_secs: Number($("ttl").value) };
+    if ($("pin").checked) request.pin = true;
+    socket.send(JSON.stringify(request));
+    $("close").disabled = false;
+  };
+  socket.onmessage = async (event) => {
+    if (typeof event.data !== "string") {
+      log(`binary frame, ${event.data.size} bytes`);
+      return;
+    }
+    log(event.data);
+    const message = JSON.parse(event.data);
+    if (message.type === "challenge") {
+      log(`solving a challenge of difficulty ${message.difficulty}...`);
+      socket.send(JSON.stringify({ type: "solution", solution: await solve(message.nonce, message.difficulty) }));
+    } else if (message.type === "code") {
+      showCode(message);
+    }
+  };
+  socket.onclose = (event) => {
+    log(`closed ${event.code} ${event.reason}`);
+    $("close").disabled = true;
+  };
+};
+
+$("close").onclick = () => socket && socket.close();
+
+async function call(method, path, body) {
+  const headers = { "content-type": "application/json" };
+  if ($("resolve-pin").value) headers["x-request-pin"] = $("resolve-pin").value;
+  const response = await fetch(`/requests/${encodeURIComponent($("resolve-code").value)}${path}`, { method, headers, body });
+  const text = await response.text();
+  $("answer").textContent = `${response.status} ${response.statusText}\n${text}`;
+}
+
+$("fetch").onclick = () => call("GET", "");
+$("resolve").onclick = () => call("POST", "", $("data").value);
+$("reject").onclick = () => call("POST", "/reject", JSON.stringify({ reason: "declined on the demo page" }));
+</script>
+</body>
+</html>
+"##;
+
+async fn demo_page() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(DEMO_PAGE)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    if enabled() {
+        cfg.route("/demo", web::get().to(demo_page));
+    }
+}
@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use crate::config::setting;
+use crate::{env_or, unix_now};
+use crate::req_code::RequestCode;
+
+/// where denials survive restarts, nothing is persisted when unset
+static FILE: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
+    setting("ABSHER_DENYLIST_FILE").map(PathBuf::from)
+});
+
+/// how long a reported code stays denied unless the report says otherwise
+static DEFAULT_TTL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_DENYLIST_TTL_SECS", 24 * 60 * 60))
+});
+
+static DENIED: LazyLock<DashMap<RequestCode, Denial>> = LazyLock::new(DashMap::new);
+
+/// serializes writers so the file always matches some recent state of the map
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Denial {
+    pub code: RequestCode,
+    pub reason: String,
+    /// unix seconds
+    pub denied_until: u64,
+}
+
+pub fn is_denied(code: &RequestCode) -> bool {
+    DENIED.get(code).is_some_and(|denial| unix_now() < denial.denied_until)
+}
+
+pub fn deny(code: RequestCode, reason: String, ttl: Option<Duration>) -> Denial {
+    let ttl = ttl.unwrap_or(*DEFAULT_TTL);
+    let denial = Denial {
+        code,
+        reason,
+        denied_until: unix_now().saturating_add(ttl.as_secs()),
+    };
+
+    DENIED.insert(code, denial.clone());
+    save();
+    denial
+}
+
+pub fn lift(code: &RequestCode) -> bool {
+    let lifted = DENIED.remove(code).is_some();
+    if lifted {
+        save();
+    }
+    lifted
+}
+
+pub fn count() -> usize {
+    DENIED.len()
+}
+
+pub fn list() -> Vec<Denial> {
+    DENIED.iter().map(|denial| denial.value().clone()).collect()
+}
+
+pub fn prune() {
+    let now = unix_now();
+    let before = DENIED.len();
+    DENIED.retain(|_code, denial| now < denial.denied_until);
+
+    if DENIED.len() != before {
+        save();
+    }
+}
+
+/// reads back the denials from the last run
+pub fn load() {
+    let Some(path) = FILE.as_deref() else {
+        return
+    };
+
+    let denials = match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<Vec<Denial>>(&bytes),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return,
+        Err(err) => {
+            log::error!("unable to read deny list {}: {err}", path.display());
+            return
+        }
+    };
+
+    match denials {
+        Ok(denials) => {
+            let now = unix_now();
+            for denial in denials.into_iter().filter(|denial| now < denial.denied_until) {
+                DENIED.insert(denial.code, denial);
+            }
+        }
+        Err(err) => log::error!("deny list {} is corrupt: {err}", path.display()),
+    }
+}
+
+fn save() {
+    let Some(path) = FILE.as_deref() else {
+        return
+    };
+
+    let _guard = SAVE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let denials = list();
+
+    // write then rename so a crash mid write never leaves half a file behind
+    let tmp = path.with_extension("tmp");
+    let written = serde_json::to_vec(&denials)
+        .map_err(std::io::Error::other)
+        .and_then(|bytes| std::fs::write(&tmp, bytes))
+        .and_then(|()| std::fs::rename(&tmp, path));
+
+    if let Err(err) = written {
+        log::error!("unable to persist deny list to {}: {err}", path.display());
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use utoipa::ToSchema;
+use crate::config::setting;
+use crate::error::{AppError, ErrorResponse};
+use crate::{app_auth, env_or, rate_limit, to_json_str, unix_now};
+
+/// how the app names the device it resolves from, with the credential it got on registering
+pub const HEADER: &str = "x-absher-device";
+const CREDENTIAL_PREFIX: &str = "azd";
+
+/// longer than any fcm or apns token
+const MAX_PUSH_TOKEN_LEN: usize = 4096;
+/// phones, tablets and a few left over from before a reinstall
+const MAX_DEVICES_PER_USER: usize = 10;
+
+static MAX_DEVICES: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_MAX_DEVICES", 100_000));
+
+/// where devices and their links survive restarts, without it every app registers again
+static FILE: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
+    setting("ABSHER_DEVICES_FILE").map(PathBuf::from)
+});
+
+/// which service delivers to the device
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Platform {
+    Fcm,
+    Apns,
+}
+
+impl Platform {
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Fcm => "fcm",
+            Platform::Apns => "apns",
+        }
+    }
+}
+
+/// who a hint reaches: every device of a signed in user, or the one device that was linked
+/// when app users aren't set up
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Owner {
+    User(String),
+    Device(String),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Device {
+    id: String,
+    /// sha256 of the credential's secret, base64url, the secret itself is only ever with the app
+    secret_hash: String,
+    platform: Platform,
+    push_token: String,
+    user: Option<String>,
+    /// unix seconds
+    registered_at: u64,
+}
+
+impl Device {
+    fn owner(&self) -> Owner {
+        match &self.user {
+            Some(user) => Owner::User(user.clone()),
+            None => Owner::Device(self.id.clone()),
+        }
+    }
+}
+
+/// a hint and who it belongs to, hints are useless to any other tenant
+#[derive(Clone, Deserialize, Serialize)]
+struct Link {
+    hint: String,
+    tenant: String,
+    owner: Owner,
+}
+
+static DEVICES: LazyLock<DashMap<String, Device>> = LazyLock::new(DashMap::new);
+static USER_DEVICES: LazyLock<DashMap<String, HashSet<String>>> = LazyLock::new(DashMap::new);
+static LINKS: LazyLock<DashMap<String, Link>> = LazyLock::new(DashMap::new);
+/// the hint each tenant got for each owner, the same one every time
+static HINTS: LazyLock<DashMap<(String, Owner), String>> = LazyLock::new(DashMap::new);
+
+/// serializes writers so the file always matches some recent state of the maps
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Saved {
+    devices: Vec<Device>,
+    links: Vec<Link>,
+}
+
+/// where pushes for a linked device go
+pub struct Target {
+    pub device: String,
+    pub platform: Platform,
+    pub push_token: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Registration {
+    platform: Platform,
+    /// the fcm registration token or the apns device token
+    push_token: String,
+}
+
+/// only ever sent back once, the app keeps it like a password
+#[derive(Serialize, ToSchema)]
+struct Registered<'a> {
+    id: &'a str,
+    /// goes in the `x-absher-device` header of resolves that should link the device
+    device: String,
+}
+
+/// a device as its user sees it in the app's settings
+#[derive(Serialize, ToSchema)]
+struct DeviceSummary<'a> {
+    id: &'a str,
+    platform: Platform,
+    /// unix seconds
+    registered_at: u64,
+}
+
+impl Registration {
+    fn check(&self) -> Result<(), AppError> {
+        let token = self.push_token.trim();
+        match token.is_empty() || token.len() > MAX_PUSH_TOKEN_LEN {
+            true => Err(AppError::InvalidBody(format!("push_token has to be 1 to {MAX_PUSH_TOKEN_LEN} characters"))),
+            false => Ok(()),
+        }
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// the id of the device an app presented, an error if it presented one nobody knows
+pub(crate) fn from_request(req: &HttpRequest) -> Result<Option<String>, AppError> {
+    match req.headers().get(HEADER) {
+        None => Ok(None),
+        Some(presented) => from_credential(presented.to_str().unwrap_or_default()).map(Some),
+    }
+}
+
+/// the id of the device `presented` is the credential of
+pub(crate) fn from_credential(presented: &str) -> Result<String, AppError> {
+    let known = presented.strip_prefix(CREDENTIAL_PREFIX)
+        .and_then(|presented| presented.strip_prefix('_'))
+        .and_then(|rest| rest.split_once('_'))
+        .filter(|(id, secret)| DEVICES.get(*id).is_some_and(|device| {
+            bool::from(device.secret_hash.as_bytes().ct_eq(hash_secret(secret).as_bytes()))
+        }))
+        .map(|(id, _secret)| id.to_owned());
+
+    known.ok_or(AppError::UnknownDevice)
+}
+
+/// the hint `tenant` can push to `device`'s owner with
+pub(crate) fn link(device: &str, tenant: &str) -> Option<String> {
+    let owner = DEVICES.get(device)?.owner();
+    let entry = HINTS.entry((tenant.to_owned(), owner.clone()));
+    if let dashmap::Entry::Occupied(hint) = &entry {
+        return Some(hint.get().clone())
+    }
+
+    let hint = Alphanumeric.sample_string(&mut rand::rng(), 32);
+    entry.or_insert(hint.clone());
+    LINKS.insert(hint.clone(), Link { hint: hint.clone(), tenant: tenant.to_owned(), owner });
+    save();
+    Some(hint)
+}
+
+/// whom `hint` stands for, if `tenant` is the one it was given to
+pub(crate) fn owner(tenant: &str, hint: &str) -> Option<Owner> {
+    LINKS.get(hint)
+        .filter(|link| link.tenant == tenant)
+        .map(|link| link.owner.clone())
+}
+
+/// whether `device` may answer for `owner`
+pub(crate) fn belongs_to(device: &str, owner: &Owner) -> bool {
+    DEVICES.get(device).is_some_and(|device| device.owner() == *owner)
+}
+
+/// every device pushes for `owner` go to
+pub(crate) fn targets(owner: &Owner) -> Vec<Target> {
+    let ids = match owner {
+        Owner::User(user) => USER_DEVICES.get(user).map(|ids| ids.iter().cloned().collect()).unwrap_or_default(),
+        Owner::Device(id) => vec![id.clone()],
+    };
+
+    ids.into_iter()
+        .filter_map(|id| DEVICES.get(&id).map(|device| Target {
+            platform: device.platform,
+            push_token: device.push_token.clone(),
+            device: id,
+        }))
+        .collect()
+}
+
+fn insert(device: Device) {
+    if let Some(user) = &device.user {
+        USER_DEVICES.entry(user.clone()).or_default().insert(device.id.clone());
+    }
+    DEVICES.insert(device.id.clone(), device);
+}
+
+/// drops a device, a user's hints stay for their other and future devices
+pub(crate) fn forget(id: &str) {
+    let Some((_id, device)) = DEVICES.remove(id) else {
+        return
+    };
+
+    match &device.user {
+        Some(user) => {
+            USER_DEVICES.remove_if_mut(user, |_user, ids| {
+                ids.remove(id);
+                ids.is_empty()
+            });
+        }
+        None => {
+            let owner = device.owner();
+            LINKS.retain(|_hint, link| link.owner != owner);
+            HINTS.retain(|(_tenant, hinted), _hint| *hinted != owner);
+        }
+    }
+    save();
+}
+
+/// reads back the devices and links from the last run
+pub fn load() {
+    let Some(path) = FILE.as_deref() else {
+        return
+    };
+
+    let saved = match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<Saved>(&bytes),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return,
+        Err(err) => {
+            log::error!("unable to read devices {}: {err}", path.display());
+            return
+        }
+    };
+
+    match saved {
+        Ok(saved) => {
+            for device in saved.devices {
+                insert(device);
+            }
+            for link in saved.links {
+                HINTS.insert((link.tenant.clone(), link.owner.clone()), link.hint.clone());
+                LINKS.insert(link.hint.clone(), link);
+            }
+        }
+        Err(err) => log::error!("devices file {} is corrupt: {err}", path.display()),
+    }
+}
+
+fn save() {
+    let Some(path) = FILE.as_deref() else {
+        return
+    };
+
+    let _guard = SAVE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let saved = Saved {
+        devices: DEVICES.iter().map(|device| device.value().clone()).collect(),
+        links: LINKS.iter().map(|link| link.value().clone()).collect(),
+    };
+
+    // write then rename so a crash mid write never leaves half a file behind
+    let tmp = path.with_extension("tmp");
+    let written = serde_json::to_vec(&saved)
+        .map_err(std::io::Error::other)
+        .and_then(|bytes| write_private(&tmp, &bytes))
+        .and_then(|()| std::fs::rename(&tmp, path));
+
+    if let Err(err) = written {
+        log::error!("unable to persist devices to {}: {err}", path.display());
+    }
+}
+
+/// push tokens are only for this server, so the file is only readable by it
+fn write_private(path: &std::path::Path, bytes: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(bytes)
+}
+
+pub fn count() -> usize {
+    DEVICES.len()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(
+            web::resource("/devices")
+                .route(web::post().to(register))
+                .route(web::get().to(list))
+                .route(web::put().to(update))
+                .route(web::delete().to(unregister))
+                .wrap(from_fn(rate_limit::limit))
+        )
+        .service(
+            web::resource("/devices/{id}")
+                .route(web::delete().to(remove))
+                .wrap(from_fn(rate_limit::limit))
+        );
+}
+
+#[utoipa::path(
+    post,
+    path = "/devices",
+    tag = "devices",
+    description = "Registers the app's push token, for the signed in user when app users are set up. \
+        Resolving with the returned credential links the device to the listener's tenant so later \
+        requests can be pushed to it.",
+    params(("authorization" = Option<String>, Header, description = "`Bearer` and the user's token from the app's backend")),
+    request_body = Registration,
+    responses(
+        (status = 201, body = Registered),
+        (status = 400, body = ErrorResponse),
+        (status = 401, description = "missing or invalid user token", body = ErrorResponse),
+        (status = 409, description = "the user has as many devices as they can have", body = ErrorResponse),
+        (status = 503, description = "too many devices registered", body = ErrorResponse),
+    ),
+)]
+async fn register(req: HttpRequest, body: web::Json<Registration>) -> Result<HttpResponse, AppError> {
+    let registration = body.into_inner();
+    registration.check()?;
+    let user = app_auth::user(&req)?;
+
+    if DEVICES.len() >= *MAX_DEVICES {
+        return Err(AppError::AtCapacity { retry_after: Duration::from_secs(60) })
+    }
+    if let Some(user) = &user
+        && USER_DEVICES.get(user).is_some_and(|ids| ids.len() >= MAX_DEVICES_PER_USER)
+    {
+        return Err(AppError::DeviceLimitReached { max: MAX_DEVICES_PER_USER })
+    }
+
+    let id = Alphanumeric.sample_string(&mut rand::rng(), 16);
+    let secret = Alphanumeric.sample_string(&mut rand::rng(), 32);
+    let credential = format!("{CREDENTIAL_PREFIX}_{id}_{secret}");
+    insert(Device {
+        id: id.clone(),
+        secret_hash: hash_secret(&secret),
+        platform: registration.platform,
+        push_token: registration.push_token.trim().to_owned(),
+        user,
+        registered_at: unix_now(),
+    });
+    save();
+
+    Ok(HttpResponse::Created()
+        .content_type("application/json")
+        .body(to_json_str(&Registered { id: &id, device: credential })?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/devices",
+    tag = "devices",
+    description = "The signed in user's devices, for the app to show and let them remove lost ones.",
+    params(("authorization" = String, Header, description = "`Bearer` and the user's token from the app's backend")),
+    responses(
+        (status = 200, body = [DeviceSummary]),
+        (status = 401, description = "missing or invalid user token", body = ErrorResponse),
+        (status = 404, description = "app users aren't set up", body = ErrorResponse),
+    ),
+)]
+async fn list(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let user = app_auth::user(&req)?.ok_or(AppError::UnknownEndpoint)?;
+    let ids = USER_DEVICES.get(&user).map(|ids| ids.clone()).unwrap_or_default();
+    let devices = ids.iter().filter_map(|id| DEVICES.get(id).map(|device| device.clone())).collect::<Vec<_>>();
+    let summaries = devices.iter()
+        .map(|device| DeviceSummary { id: &device.id, platform: device.platform, registered_at: device.registered_at })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(to_json_str(&summaries)?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/devices",
+    tag = "devices",
+    description = "Replaces the push token of a registered device, its links stay.",
+    params(("x-absher-device" = String, Header)),
+    request_body = Registration,
+    responses(
+        (status = 204, description = "updated"),
+        (status = 401, description = "unknown device", body = ErrorResponse),
+    ),
+)]
+async fn update(req: HttpRequest, body: web::Json<Registration>) -> Result<HttpResponse, AppError> {
+    let registration = body.into_inner();
+    registration.check()?;
+    let id = from_request(&req)?.ok_or(AppError::UnknownDevice)?;
+
+    if let Some(mut device) = DEVICES.get_mut(&id) {
+        device.platform = registration.platform;
+        device.push_token = registration.push_token.trim().to_owned();
+    }
+    save();
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/devices",
+    tag = "devices",
+    description = "Forgets the device making the call, nothing is pushed to it anymore.",
+    params(("x-absher-device" = String, Header)),
+    responses(
+        (status = 204, description = "forgotten"),
+        (status = 401, description = "unknown device", body = ErrorResponse),
+    ),
+)]
+async fn unregister(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let id = from_request(&req)?.ok_or(AppError::UnknownDevice)?;
+    forget(&id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/devices/{id}",
+    tag = "devices",
+    description = "Forgets one of the signed in user's devices, like a lost phone.",
+    params(
+        ("id" = String, Path),
+        ("authorization" = String, Header, description = "`Bearer` and the user's token from the app's backend"),
+    ),
+    responses(
+        (status = 204, description = "forgotten"),
+        (status = 401, description = "missing or invalid user token", body = ErrorResponse),
+        (status = 404, description = "no such device for this user", body = ErrorResponse),
+    ),
+)]
+async fn remove(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let user = app_auth::user(&req)?.ok_or(AppError::UnknownEndpoint)?;
+    if !belongs_to(&id, &Owner::User(user)) {
+        return Err(AppError::DeviceNotFound)
+    }
+
+    forget(&id);
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,501 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use actix_ws::{CloseCode, CloseReason};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::app_version::{AppVersion, Feature};
+use crate::classify::DocumentKind;
+use crate::req_code::RequestCode;
+use crate::{request_id, to_json_str};
+
+/// every way a request to this service can fail
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("no request exists with this code")]
+    RequestNotFound(Option<RequestCode>),
+    #[error("this code has a typo, check it and enter it again")]
+    CodeTypo,
+    #[error("this request timed out before it was answered")]
+    RequestExpired(RequestCode),
+    #[error("this request has already been answered")]
+    RequestAlreadyResolved(RequestCode),
+    #[error("this request was replaced by a newer one from the same page")]
+    RequestSuperseded(RequestCode),
+    #[error("this request was reported as compromised")]
+    RequestDenied(RequestCode),
+    #[error("the user declined to share this data")]
+    RequestRejected(RequestCode),
+    #[error("this request also needs the pin shown next to the code")]
+    PinRequired(RequestCode),
+    #[error("wrong pin, attempts left: {attempts_left}")]
+    PinIncorrect { code: RequestCode, attempts_left: u32 },
+    #[error("too many wrong pins, this request was cancelled")]
+    PinAttemptsExhausted(RequestCode),
+
+    #[error("no such endpoint")]
+    UnknownEndpoint,
+    #[error("expected a websocket upgrade")]
+    ExpectedWebsocket,
+    #[error("this request is end to end encrypted, submit the data sealed to its public key")]
+    EncryptionRequired(RequestCode),
+    #[error("this request has no public key, submit the data unencrypted")]
+    EncryptionUnavailable(RequestCode),
+    #[error("expected a JSON specification as the first message")]
+    ExpectedSpecification,
+    #[error("invalid data specification JSON")]
+    InvalidSpecification,
+    #[error("no specification arrived in time")]
+    SpecificationTimeout,
+    #[error("messages can be at most {max} bytes")]
+    MessageTooLarge { max: usize },
+    #[error("too many open connections from this address, at most {max}")]
+    TooManyConnections { max: usize },
+    #[error("pages from this origin may not listen here")]
+    OriginNotAllowed,
+    #[error("this address, origin or api key is blocked")]
+    Blocked,
+    #[error("requested fields are outside of the api key's scope")]
+    ScopeExceeded,
+    #[error("custom fields need a label and a unique key of letters, digits or '_', at most {max} of them")]
+    InvalidCustomFields { max: usize },
+    #[error("custom field {key} {reason}")]
+    InvalidCustomField { key: String, reason: String },
+    #[error("hash checks need a salt of at least {min_salt_len} characters and can only cover email, phone_number, id, date_of_birth or nationality")]
+    InvalidHashChecks { min_salt_len: usize },
+    #[error("credentials and sd-jwts can't be issued for end to end encrypted requests, the server can't read them")]
+    CredentialUnavailable,
+    #[error("webhooks need an api key and can be at most {max} absolute https urls on public addresses")]
+    InvalidWebhook { max: usize },
+    #[error("followers need an api key, a request can have at most {max}")]
+    InvalidFollowers { max: usize },
+    #[error("solve the proof of work challenge to get a code, this needs the envelope protocol")]
+    ChallengeRequired,
+    #[error("the proof of work solution was missing or wrong")]
+    ChallengeFailed,
+    #[error("invalid request body: {0}")]
+    InvalidBody(String),
+    #[error("request bodies can be at most {max_bytes} bytes")]
+    BodyTooLarge { max_bytes: usize },
+    #[error("{field} can be at most {max_bytes} bytes, send larger images as an upload")]
+    ImageTooLarge { field: &'static str, max_bytes: usize },
+    #[error("some fields are not valid, see `fields` for each of them")]
+    InvalidFields(BTreeMap<&'static str, &'static str>),
+    #[error("{field} has to be a YYYY-MM-DD date")]
+    InvalidDate { field: &'static str },
+    #[error("{field} is not a valid base64 encoded image")]
+    InvalidImage { field: &'static str },
+    #[error("{field} has to be a jpeg, png or webp image")]
+    UnsupportedImage { field: &'static str },
+    #[error("this app is too old for {}, update it to {minimum} or later", .feature.name())]
+    UpdateRequired { feature: Feature, minimum: AppVersion },
+
+    #[error("down for maintenance, no new requests can be opened right now")]
+    UnderMaintenance,
+    #[error("server shutting down, please retry")]
+    ShuttingDown,
+    #[error("too many open requests, retry in {}s", .retry_after.as_secs())]
+    AtCapacity { retry_after: Duration },
+
+    #[error("no upload exists with this id for this request")]
+    UploadNotFound,
+    #[error("uploads can be at most {max_bytes} bytes")]
+    UploadTooLarge { max_bytes: u64 },
+    #[error("this request already has as many uploads as it can take")]
+    UploadLimitReached(RequestCode),
+    #[error("this request already has as many followers as it was opened for")]
+    FollowerLimitReached(RequestCode),
+    #[error("expected a Content-Range of `bytes first-last/size` matching the upload and the chunk")]
+    InvalidContentRange,
+    #[error("chunks must continue the upload, {received} bytes were received so far")]
+    UploadOffsetMismatch { received: u64 },
+    #[error("only {received} of {size} bytes were uploaded")]
+    UploadIncomplete { received: u64, size: u64 },
+    #[error("this upload was already finalized")]
+    UploadFinalized,
+    #[error("{field} was classified as {found} instead of {expected}, retake it and try again")]
+    DocumentMismatch { field: &'static str, expected: DocumentKind, found: DocumentKind },
+
+    #[error("invalid api key")]
+    InvalidApiKey,
+    #[error("this device isn't registered, register it again")]
+    UnknownDevice,
+    #[error("the app's sign in token is missing, expired or wasn't issued for this server")]
+    InvalidAppToken,
+    #[error("a user can have at most {max} devices, remove one first")]
+    DeviceLimitReached { max: usize },
+    #[error("no device exists with this id for this user")]
+    DeviceNotFound,
+    #[error("this request can only be answered from the user's registered devices")]
+    DeviceNotAllowed(RequestCode),
+    #[error("this request has to be answered from the country it was shown in")]
+    CountryMismatch(RequestCode),
+    #[error("answers have to come with an attestation from the official app")]
+    AttestationRequired(RequestCode),
+    #[error("the attestation didn't vouch for a genuine install of the official app")]
+    AttestationFailed(RequestCode),
+    #[error("the attestation can't be checked right now, try again")]
+    AttestationUnavailable,
+    #[error("this user hint isn't linked to a device")]
+    UserNotLinked,
+    #[error("codes can only be texted for listeners with an api key, on servers with an sms provider")]
+    SmsUnavailable,
+    #[error("sms_to has to be a mobile number, add the country code for numbers outside saudi arabia")]
+    InvalidPhoneNumber,
+    #[error("no more texts can be sent to this number or for this tenant right now")]
+    SmsLimitReached,
+    #[error("sms templates have to show {{display}} or {{code}} in at most {max_chars} characters")]
+    InvalidSmsTemplate { max_chars: usize },
+    #[error("too many requests, retry in {}s", .retry_after.as_secs())]
+    RateLimited { retry_after: Duration },
+
+    #[error("the server failed to encode its response")]
+    Serialization,
+
+    #[error("admin api is disabled")]
+    AdminDisabled,
+    #[error("invalid admin token")]
+    InvalidAdminToken,
+    #[error("tenant ids must be alphanumeric or '-'")]
+    InvalidTenantId,
+    #[error("a tenant with this id already exists")]
+    TenantExists,
+    #[error("ttl policies must stay within {min_secs}s and {max_secs}s with the default below the max")]
+    InvalidTtlPolicy { min_secs: u64, max_secs: u64 },
+    #[error("webhooks can be retried at most {max_retries} times, with pauses of at most {max_backoff_secs}s")]
+    InvalidRetryPolicy { max_retries: usize, max_backoff_secs: u64 },
+    #[error("no tenant exists with this id")]
+    TenantNotFound,
+    #[error("no api key exists with this id")]
+    KeyNotFound,
+    #[error("unable to reload the config: {0}")]
+    InvalidConfig(String),
+    #[error("blocks need exactly one of cidr, origin or key_id")]
+    InvalidBlock,
+    #[error("no block exists with this id")]
+    BlockNotFound,
+    #[error("no such client, or the redirect uri isn't one of its own")]
+    UnknownClient,
+    #[error("redirect uris have to be https, or http on localhost, and can't have a fragment")]
+    InvalidRedirectUri,
+    #[error("no request history is kept")]
+    HistoryDisabled,
+    #[error("the request history can't be read right now")]
+    HistoryUnavailable,
+}
+
+/// what listeners see when their request ran out, from the range left to applications
+pub const EXPIRED_CLOSE_CODE: u16 = 4000;
+
+const SERIALIZATION_FAILED_BODY: &str =
+    r#"{"error":{"code":"SERIALIZATION_FAILED","message":"the server failed to encode its response"}}"#;
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    code: &'static str,
+    message: String,
+    /// the code the failure happened to, the one the user was shown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    request_code: Option<RequestCode>,
+    /// the `X-Request-Id` of the http request that failed, to look it up in our logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// which feature an outdated app was turned away from, and the version it needs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feature: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    min_version: Option<AppVersion>,
+    /// the payload field a rejected document, image or custom answer was submitted in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attempts_left: Option<u32>,
+    /// what is wrong with each rejected field, by field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<BTreeMap<&'static str, &'static str>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    error: ErrorBody,
+}
+
+impl AppError {
+    /// stable machine readable name for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::RequestNotFound(_) => "REQUEST_NOT_FOUND",
+            AppError::CodeTypo => "CODE_TYPO",
+            AppError::RequestExpired(_) => "REQUEST_EXPIRED",
+            AppError::RequestAlreadyResolved(_) => "REQUEST_ALREADY_RESOLVED",
+            AppError::RequestSuperseded(_) => "REQUEST_SUPERSEDED",
+            AppError::RequestDenied(_) => "REQUEST_DENIED",
+            AppError::RequestRejected(_) => "REQUEST_REJECTED",
+            AppError::PinRequired(_) => "PIN_REQUIRED",
+            AppError::PinIncorrect { .. } => "PIN_INCORRECT",
+            AppError::PinAttemptsExhausted(_) => "PIN_ATTEMPTS_EXHAUSTED",
+            AppError::UnknownEndpoint => "UNKNOWN_ENDPOINT",
+            AppError::ExpectedWebsocket => "EXPECTED_WEBSOCKET",
+            AppError::EncryptionRequired(_) => "ENCRYPTION_REQUIRED",
+            AppError::EncryptionUnavailable(_) => "ENCRYPTION_UNAVAILABLE",
+            AppError::ExpectedSpecification => "EXPECTED_SPECIFICATION",
+            AppError::SpecificationTimeout => "SPECIFICATION_TIMEOUT",
+            AppError::MessageTooLarge { .. } => "MESSAGE_TOO_LARGE",
+            AppError::TooManyConnections { .. } => "TOO_MANY_CONNECTIONS",
+            AppError::InvalidSpecification => "INVALID_SPECIFICATION",
+            AppError::OriginNotAllowed => "ORIGIN_NOT_ALLOWED",
+            AppError::Blocked => "BLOCKED",
+            AppError::ScopeExceeded => "SCOPE_EXCEEDED",
+            AppError::InvalidCustomFields { .. } => "INVALID_CUSTOM_FIELDS",
+            AppError::InvalidCustomField { .. } => "INVALID_CUSTOM_FIELD",
+            AppError::InvalidHashChecks { .. } => "INVALID_HASH_CHECKS",
+            AppError::CredentialUnavailable => "CREDENTIAL_UNAVAILABLE",
+            AppError::InvalidWebhook { .. } => "INVALID_WEBHOOK",
+            AppError::InvalidFollowers { .. } => "INVALID_FOLLOWERS",
+            AppError::ChallengeRequired => "CHALLENGE_REQUIRED",
+            AppError::ChallengeFailed => "CHALLENGE_FAILED",
+            AppError::InvalidBody(_) => "INVALID_BODY",
+            AppError::BodyTooLarge { .. } => "BODY_TOO_LARGE",
+            AppError::ImageTooLarge { .. } => "IMAGE_TOO_LARGE",
+            AppError::InvalidFields(_) => "INVALID_FIELDS",
+            AppError::InvalidDate { .. } => "INVALID_DATE",
+            AppError::InvalidImage { .. } => "INVALID_IMAGE",
+            AppError::UnsupportedImage { .. } => "UNSUPPORTED_IMAGE",
+            AppError::UpdateRequired { .. } => "UPDATE_REQUIRED",
+            AppError::UnderMaintenance => "UNDER_MAINTENANCE",
+            AppError::ShuttingDown => "SHUTTING_DOWN",
+            AppError::AtCapacity { .. } => "AT_CAPACITY",
+            AppError::UploadNotFound => "UPLOAD_NOT_FOUND",
+            AppError::UploadTooLarge { .. } => "UPLOAD_TOO_LARGE",
+            AppError::UploadLimitReached(_) => "UPLOAD_LIMIT_REACHED",
+            AppError::FollowerLimitReached(_) => "FOLLOWER_LIMIT_REACHED",
+            AppError::InvalidContentRange => "INVALID_CONTENT_RANGE",
+            AppError::UploadOffsetMismatch { .. } => "UPLOAD_OFFSET_MISMATCH",
+            AppError::UploadIncomplete { .. } => "UPLOAD_INCOMPLETE",
+            AppError::UploadFinalized => "UPLOAD_FINALIZED",
+            AppError::DocumentMismatch { .. } => "DOCUMENT_MISMATCH",
+            AppError::InvalidApiKey => "INVALID_API_KEY",
+            AppError::UnknownDevice => "UNKNOWN_DEVICE",
+            AppError::InvalidAppToken => "INVALID_APP_TOKEN",
+            AppError::DeviceLimitReached { .. } => "DEVICE_LIMIT_REACHED",
+            AppError::DeviceNotFound => "DEVICE_NOT_FOUND",
+            AppError::DeviceNotAllowed(_) => "DEVICE_NOT_ALLOWED",
+            AppError::CountryMismatch(_) => "COUNTRY_MISMATCH",
+            AppError::AttestationRequired(_) => "ATTESTATION_REQUIRED",
+            AppError::AttestationFailed(_) => "ATTESTATION_FAILED",
+            AppError::AttestationUnavailable => "ATTESTATION_UNAVAILABLE",
+            AppError::UserNotLinked => "USER_NOT_LINKED",
+            AppError::SmsUnavailable => "SMS_UNAVAILABLE",
+            AppError::InvalidPhoneNumber => "INVALID_PHONE_NUMBER",
+            AppError::SmsLimitReached => "SMS_LIMIT_REACHED",
+            AppError::InvalidSmsTemplate { .. } => "INVALID_SMS_TEMPLATE",
+            AppError::RateLimited { .. } => "RATE_LIMITED",
+            AppError::Serialization => "SERIALIZATION_FAILED",
+            AppError::AdminDisabled => "ADMIN_DISABLED",
+            AppError::InvalidAdminToken => "INVALID_ADMIN_TOKEN",
+            AppError::InvalidTenantId => "INVALID_TENANT_ID",
+            AppError::TenantExists => "TENANT_EXISTS",
+            AppError::InvalidTtlPolicy { .. } => "INVALID_TTL_POLICY",
+            AppError::InvalidRetryPolicy { .. } => "INVALID_RETRY_POLICY",
+            AppError::TenantNotFound => "TENANT_NOT_FOUND",
+            AppError::KeyNotFound => "KEY_NOT_FOUND",
+            AppError::InvalidConfig(_) => "INVALID_CONFIG",
+            AppError::InvalidBlock => "INVALID_BLOCK",
+            AppError::BlockNotFound => "BLOCK_NOT_FOUND",
+            AppError::HistoryDisabled => "HISTORY_DISABLED",
+            AppError::HistoryUnavailable => "HISTORY_UNAVAILABLE",
+            AppError::InvalidRedirectUri => "INVALID_REDIRECT_URI",
+            AppError::UnknownClient => "UNKNOWN_CLIENT",
+        }
+    }
+
+    /// the request code this error is about, if any
+    pub fn request_code(&self) -> Option<RequestCode> {
+        match *self {
+            AppError::RequestNotFound(code) => code,
+            AppError::RequestExpired(code)
+            | AppError::RequestAlreadyResolved(code)
+            | AppError::RequestSuperseded(code)
+            | AppError::RequestDenied(code)
+            | AppError::RequestRejected(code)
+            | AppError::PinRequired(code)
+            | AppError::PinIncorrect { code, .. }
+            | AppError::PinAttemptsExhausted(code)
+            | AppError::EncryptionRequired(code)
+            | AppError::EncryptionUnavailable(code)
+            | AppError::DeviceNotAllowed(code)
+            | AppError::CountryMismatch(code)
+            | AppError::AttestationRequired(code)
+            | AppError::AttestationFailed(code)
+            | AppError::UploadLimitReached(code)
+            | AppError::FollowerLimitReached(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let (feature, min_version) = match *self {
+            AppError::UpdateRequired { feature, minimum } => (Some(feature.name()), Some(minimum)),
+            _ => (None, None),
+        };
+        let field = match self {
+            AppError::DocumentMismatch { field, .. }
+            | AppError::ImageTooLarge { field, .. }
+            | AppError::InvalidImage { field }
+            | AppError::InvalidDate { field }
+            | AppError::UnsupportedImage { field } => Some((*field).to_owned()),
+            AppError::InvalidCustomField { key, .. } => Some(format!("custom_fields.{key}")),
+            _ => None,
+        };
+        let attempts_left = match *self {
+            AppError::PinIncorrect { attempts_left, .. } => Some(attempts_left),
+            _ => None,
+        };
+        let fields = match self {
+            AppError::InvalidFields(fields) => Some(fields.clone()),
+            _ => None,
+        };
+
+        ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            request_code: self.request_code(),
+            request_id: request_id::current(),
+            feature,
+            min_version,
+            field,
+            attempts_left,
+            fields,
+        }
+    }
+
+    /// close frame for a listener, the reason is the same code http clients get
+    pub fn close_reason(&self) -> CloseReason {
+        let code = match self {
+            AppError::InvalidApiKey
+            | AppError::ScopeExceeded
+            | AppError::UserNotLinked
+            | AppError::ChallengeRequired
+            | AppError::ChallengeFailed
+            | AppError::RequestDenied(_)
+            | AppError::PinAttemptsExhausted(_)
+            | AppError::SpecificationTimeout => CloseCode::Policy,
+            AppError::MessageTooLarge { .. } => CloseCode::Size,
+            // nothing went wrong, another tab simply owns the session now, or the user said no
+            AppError::RequestSuperseded(_) | AppError::RequestRejected(_) => CloseCode::Normal,
+            AppError::ShuttingDown => CloseCode::Restart,
+            // a code of its own, so pages can tell running out of time from a failure
+            AppError::RequestExpired(_) => CloseCode::Other(EXPIRED_CLOSE_CODE),
+            _ => CloseCode::Error,
+        };
+
+        CloseReason { code, description: Some(self.code().to_owned()) }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::RequestNotFound(_)
+            | AppError::UnknownEndpoint
+            | AppError::AdminDisabled
+            | AppError::TenantNotFound
+            | AppError::KeyNotFound
+            | AppError::DeviceNotFound
+            | AppError::UploadNotFound
+            | AppError::BlockNotFound
+            | AppError::HistoryDisabled => StatusCode::NOT_FOUND,
+            AppError::RequestExpired(_)
+            | AppError::RequestSuperseded(_)
+            | AppError::RequestRejected(_)
+            | AppError::PinAttemptsExhausted(_) => StatusCode::GONE,
+            AppError::RequestAlreadyResolved(_)
+            | AppError::TenantExists
+            | AppError::DeviceLimitReached { .. }
+            | AppError::UploadLimitReached(_)
+            | AppError::FollowerLimitReached(_)
+            | AppError::UploadOffsetMismatch { .. }
+            | AppError::UploadIncomplete { .. }
+            | AppError::UploadFinalized => StatusCode::CONFLICT,
+            AppError::ExpectedWebsocket
+            | AppError::ExpectedSpecification
+            | AppError::InvalidSpecification
+            | AppError::InvalidWebhook { .. }
+            | AppError::InvalidFollowers { .. }
+            | AppError::InvalidCustomFields { .. }
+            | AppError::InvalidHashChecks { .. }
+            | AppError::CredentialUnavailable
+            | AppError::InvalidBody(_)
+            | AppError::InvalidTenantId
+            | AppError::InvalidTtlPolicy { .. }
+            | AppError::InvalidRetryPolicy { .. }
+            | AppError::InvalidContentRange
+            | AppError::UserNotLinked
+            | AppError::SmsUnavailable
+            | AppError::InvalidPhoneNumber
+            | AppError::InvalidSmsTemplate { .. }
+            | AppError::InvalidBlock
+            | AppError::InvalidRedirectUri
+            | AppError::UnknownClient
+            | AppError::CodeTypo => StatusCode::BAD_REQUEST,
+            AppError::RequestDenied(_)
+            | AppError::PinRequired(_)
+            | AppError::PinIncorrect { .. }
+            | AppError::ScopeExceeded
+            | AppError::DeviceNotAllowed(_)
+            | AppError::CountryMismatch(_)
+            | AppError::AttestationRequired(_)
+            | AppError::AttestationFailed(_)
+            | AppError::OriginNotAllowed
+            | AppError::Blocked
+            | AppError::ChallengeRequired
+            | AppError::ChallengeFailed => StatusCode::FORBIDDEN,
+            AppError::InvalidApiKey
+            | AppError::InvalidAdminToken
+            | AppError::InvalidAppToken
+            | AppError::UnknownDevice => StatusCode::UNAUTHORIZED,
+            AppError::EncryptionRequired(_)
+            | AppError::EncryptionUnavailable(_)
+            | AppError::DocumentMismatch { .. }
+            | AppError::InvalidImage { .. }
+            | AppError::InvalidDate { .. }
+            | AppError::InvalidFields(_)
+            | AppError::InvalidCustomField { .. }
+            | AppError::InvalidConfig(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::RateLimited { .. }
+            | AppError::TooManyConnections { .. }
+            | AppError::SmsLimitReached => StatusCode::TOO_MANY_REQUESTS,
+            AppError::SpecificationTimeout => StatusCode::REQUEST_TIMEOUT,
+            AppError::UpdateRequired { .. } => StatusCode::UPGRADE_REQUIRED,
+            AppError::UnsupportedImage { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::UploadTooLarge { .. }
+            | AppError::MessageTooLarge { .. }
+            | AppError::BodyTooLarge { .. }
+            | AppError::ImageTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Serialization => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::UnderMaintenance
+            | AppError::ShuttingDown
+            | AppError::AtCapacity { .. }
+            | AppError::HistoryUnavailable
+            | AppError::AttestationUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::RateLimited { retry_after } | AppError::AtCapacity { retry_after } = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.as_secs().max(1)));
+        }
+
+        // the error body is plain strings, but never let a failure here fail again
+        let body = to_json_str(&ErrorResponse { error: self.body() })
+            .unwrap_or_else(|_| SERIALIZATION_FAILED_BODY.to_owned());
+
+        response
+            .content_type("application/json")
+            .body(body)
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt::Write;
+use std::sync::LazyLock;
+use std::time::Duration;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::broadcast::{self, error::RecvError};
+use crate::error::AppError;
+use crate::req_code::RequestCode;
+use crate::{shutdown, signing, to_json_str, unix_now};
+
+/// a dashboard that falls this far behind misses events, and is told how many
+const BUFFER: usize = 1024;
+/// keeps proxies from closing a feed that is quiet for a while
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// what tenant ids are keyed with before they reach a dashboard
+static TENANT_KEY: LazyLock<[u8; 32]> = LazyLock::new(|| signing::derived_secret("event tenants"));
+
+/// something that happened to a request, with nothing in it the request carried
+#[derive(Clone, Serialize)]
+pub struct Event {
+    /// `created`, `fetched`, or how it ended: `resolved`, `expired`, `rejected`, `superseded`,
+    /// `locked` or `withdrawn`
+    pub event: &'static str,
+    /// the correlation id the logs use, not the code
+    pub code_hash: String,
+    /// the same for every event of a tenant, keyed like the code hash so it can't be matched
+    /// against a list of tenant ids
+    pub tenant_hash: Option<String>,
+    /// unix seconds
+    pub at: u64,
+}
+
+static FEED: LazyLock<broadcast::Sender<Event>> = LazyLock::new(|| broadcast::Sender::new(BUFFER));
+
+/// tells the dashboards that are watching, costs nothing while none is
+pub fn emit(event: &'static str, code: &RequestCode, tenant: Option<&str>) {
+    if FEED.receiver_count() == 0 {
+        return
+    }
+
+    let _ = FEED.send(Event {
+        event,
+        code_hash: code.correlation_id(),
+        tenant_hash: tenant.map(tenant_hash),
+        at: unix_now(),
+    });
+}
+
+fn tenant_hash(tenant: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&*TENANT_KEY).expect("hmac to take keys of any length");
+    mac.update(tenant.as_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    digest[..8].iter().fold(String::with_capacity(16), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+#[derive(Serialize)]
+struct Lagged {
+    event: &'static str,
+    missed: u64,
+}
+
+/// a websocket of every event as json, one per message, as they happen
+#[get("/events")]
+pub async fn feed(req: HttpRequest, body: web::Payload) -> Result<HttpResponse, AppError> {
+    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, body)
+        .map_err(|_| AppError::ExpectedWebsocket)?;
+    let mut events = FEED.subscribe();
+
+    actix_web::rt::spawn(async move {
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        loop {
+            let text = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => to_json_str(&event),
+                    Err(RecvError::Lagged(missed)) => to_json_str(&Lagged { event: "lagged", missed }),
+                    Err(RecvError::Closed) => break,
+                },
+                msg = msg_stream.recv() => match msg {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return
+                        }
+                        continue
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                },
+                _ = ping.tick() => {
+                    if session.ping(b"").await.is_err() {
+                        return
+                    }
+                    continue
+                }
+                () = shutdown::drained() => {
+                    let _ = session.close(Some(AppError::ShuttingDown.close_reason())).await;
+                    return
+                }
+            };
+
+            let Ok(text) = text else {
+                continue
+            };
+            if session.text(text).await.is_err() {
+                return
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
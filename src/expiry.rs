@@ -0,0 +1,103 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{LazyLock, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+use tokio::sync::Notify;
+use crate::{clock, health};
+use crate::req_code::RequestCode;
+
+/// requests by when they run out, soonest first; answered requests stay in until then and are
+/// skipped, which is cheaper than finding them in the heap
+type Queue = BinaryHeap<Reverse<(Instant, RequestCode)>>;
+
+static QUEUE: LazyLock<Mutex<Queue>> = LazyLock::new(Default::default);
+/// pinged when a request runs out sooner than anything the task is already waiting for
+static SOONER: Notify = Notify::const_new();
+
+fn queue() -> MutexGuard<'static, Queue> {
+    QUEUE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn schedule(code: RequestCode, at: Instant) {
+    let mut queue = queue();
+    let soonest = queue.peek().is_none_or(|Reverse((next, _))| at < *next);
+    queue.push(Reverse((at, code)));
+    drop(queue);
+
+    if soonest {
+        SOONER.notify_one();
+    }
+}
+
+/// has the task look at the queue again, for a clock that jumped ahead
+pub fn wake() {
+    SOONER.notify_one();
+}
+
+/// pops the codes whose time is up by `now`, and tells when the next one runs out
+fn take_due(queue: &mut Queue, now: Instant) -> (Vec<RequestCode>, Option<Instant>) {
+    let mut due = Vec::new();
+    while let Some(&Reverse((at, code))) = queue.peek()
+        && at <= now
+    {
+        queue.pop();
+        due.push(code);
+    }
+    (due, queue.peek().map(|Reverse((at, _))| *at))
+}
+
+/// hands every code to `expire` once its time is up, `expire` has to check the code is still
+/// the request that was scheduled since codes get reused after their tombstone is gone
+pub async fn run(expire: impl Fn(RequestCode, Instant)) {
+    let _watch = health::watch("expiry");
+    loop {
+        let now = clock::now();
+        let (due, next) = take_due(&mut queue(), now);
+
+        for code in due {
+            expire(code, now);
+        }
+
+        match next {
+            Some(at) => tokio::select! {
+                // a duration rather than a deadline, `at` is on the clock and not always tokio's
+                () = tokio::time::sleep(at.saturating_duration_since(now)) => {},
+                () = SOONER.notified() => {},
+            },
+            None => SOONER.notified().await,
+        }
+    }
+}
+
+/// requests still waiting to run out, answered ones included
+pub fn count() -> usize {
+    queue().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn codes_come_due_in_order_of_running_out() {
+        let now = Instant::now();
+        let [first, second, third] = ["AAAAAAAAA", "BBBBBBBBB", "CCCCCCCCC"].map(|code| code.parse::<RequestCode>().unwrap());
+        let mut queue = Queue::new();
+        queue.push(Reverse((now + Duration::from_secs(30), third)));
+        queue.push(Reverse((now + Duration::from_secs(10), second)));
+        queue.push(Reverse((now, first)));
+
+        let (due, next) = take_due(&mut queue, now + Duration::from_secs(10));
+        assert_eq!(due, [first, second]);
+        assert_eq!(next, Some(now + Duration::from_secs(30)));
+
+        let (due, next) = take_due(&mut queue, now + Duration::from_secs(29));
+        assert!(due.is_empty());
+        assert_eq!(next, Some(now + Duration::from_secs(30)));
+
+        let (due, next) = take_due(&mut queue, now + Duration::from_secs(30));
+        assert_eq!(due, [third]);
+        assert_eq!(next, None);
+    }
+}
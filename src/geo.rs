@@ -0,0 +1,97 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{LazyLock, OnceLock};
+use maxminddb::{geoip2, Reader};
+use serde::{Deserialize, Serialize};
+use crate::config::setting;
+
+/// a MaxMind country or city database, like GeoLite2-Country.mmdb; nothing is looked up without one
+static DATABASE: LazyLock<Option<PathBuf>> = LazyLock::new(|| setting("ABSHER_GEO_DATABASE").map(PathBuf::from));
+
+/// what a tenant wants done when a code is answered from another country than it was shown in
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GeoCheck {
+    #[default]
+    Off,
+    /// logged and counted, the answer still goes through
+    Warn,
+    /// turned away, the request stays pending for the user to answer from where they are
+    Block,
+}
+
+/// tells which country an address is in
+pub trait Locator: Send + Sync {
+    /// the ISO 3166 alpha-2 code, `None` for private addresses and ones it doesn't know
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+/// looks addresses up in a MaxMind database, read into memory once
+pub struct MaxMind {
+    reader: Reader<Vec<u8>>,
+}
+
+impl MaxMind {
+    pub fn open(path: &std::path::Path) -> Result<Self, String> {
+        let reader = Reader::open_readfile(path).map_err(|err| format!("unable to open {}: {err}", path.display()))?;
+        Ok(Self { reader })
+    }
+}
+
+impl Locator for MaxMind {
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        // v4 clients of a dual stack socket show up as mapped v6 addresses, the database keys them as v4
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+
+        let found = self.reader.lookup::<geoip2::Country>(ip).ok()??;
+        found.country?.iso_code.map(str::to_owned)
+    }
+}
+
+static INSTALLED: OnceLock<Option<Box<dyn Locator>>> = OnceLock::new();
+
+/// replaces the database the settings pick, only before the first lookup; hands `locator` back if
+/// that is too late
+pub fn install<L: Locator + 'static>(locator: L) -> Result<(), L> {
+    let mut locator = Some(locator);
+    INSTALLED.get_or_init(|| locator.take().map(|locator| Box::new(locator) as Box<dyn Locator>));
+    match locator {
+        None => Ok(()),
+        Some(locator) => Err(locator),
+    }
+}
+
+fn configured() -> Option<Box<dyn Locator>> {
+    let path = DATABASE.as_deref()?;
+    match MaxMind::open(path) {
+        Ok(locator) => Some(Box::new(locator)),
+        Err(err) => {
+            log::error!("countries won't be checked, {err}");
+            None
+        }
+    }
+}
+
+fn locator() -> Option<&'static dyn Locator> {
+    INSTALLED.get_or_init(configured).as_deref()
+}
+
+/// opens the database now rather than on the first request, so a bad path shows up at startup
+pub fn init() {
+    if locator().is_some() {
+        log::info!("checking where resolvers answer from");
+    }
+}
+
+/// whether addresses can be placed at all
+pub fn enabled() -> bool {
+    locator().is_some()
+}
+
+/// where `ip` is, as far as the database knows
+pub fn country(ip: IpAddr) -> Option<String> {
+    locator()?.country(ip)
+}
@@ -0,0 +1,267 @@
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use actix_web::{web, HttpRequest, HttpResponse};
+use async_graphql::{Context, ErrorExtensions, InputObject, Json, Object, Schema, SimpleObject, Subscription};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use serde_json::Value;
+use crate::app_version::{self, Feature};
+use crate::audit::Actor;
+use crate::custom_fields::CustomField;
+use crate::error::AppError;
+use crate::model::RequestedAutofillFields;
+use crate::protocol::ListenSpec;
+use crate::req_code::{ParseCodeError, RequestCode};
+use crate::store::Resolution;
+use crate::{
+    admin, capacity, handlers, lockout, maintenance, parked, peer, protocol, rate_limit, request_id, sensitive, shutdown, ws,
+};
+
+pub type AbsherSchema = Schema<Query, Mutation, Subscription>;
+
+/// built once, every worker serves the same schema; federated gateways read it through `_service`
+static SCHEMA: LazyLock<AbsherSchema> = LazyLock::new(|| {
+    Schema::build(Query, Mutation, Subscription)
+        .enable_federation()
+        .finish()
+});
+
+/// errors carry the api's error code as their `code` extension
+fn error(err: AppError) -> async_graphql::Error {
+    let code = err.code();
+    async_graphql::Error::new(err.to_string()).extend_with(|_, extensions| extensions.set("code", code))
+}
+
+fn parse_code(code: &str) -> async_graphql::Result<RequestCode> {
+    code.parse().map_err(|err| error(match err {
+        ParseCodeError::Checksum => AppError::CodeTypo,
+        _ => AppError::RequestNotFound(None),
+    }))
+}
+
+/// what the http request a query came in told about its caller
+struct Caller {
+    api_key: Option<String>,
+    app_version: Option<String>,
+    ip: Option<IpAddr>,
+    request_id: String,
+}
+
+impl Caller {
+    /// who the caller is in the audit log, `by` the key or device it acted with
+    fn actor(&self, by: Option<String>) -> Actor {
+        Actor { by, ip: self.ip, request_id: Some(self.request_id.clone()) }
+    }
+
+    fn of(req: &HttpRequest) -> Self {
+        let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_owned);
+        Self {
+            api_key: admin::presented(req.headers()).map(str::to_owned),
+            app_version: header(app_version::HEADER),
+            ip: peer::ip(req),
+            request_id: request_id::of(req),
+        }
+    }
+}
+
+/// what a request asks of the resolver, as `GET /requests/{code}` tells it
+#[derive(SimpleObject)]
+struct AutofillRequest {
+    display: String,
+    fields: Json<RequestedAutofillFields>,
+    public_key: Option<String>,
+    pin_required: bool,
+    custom_fields: Json<Vec<CustomField>>,
+    hash_checks: Vec<String>,
+    /// the nonce an attestation of the answer has to carry
+    correlation_id: String,
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// what the app shows the user before they agree
+    async fn request(&self, ctx: &Context<'_>, code: String) -> async_graphql::Result<AutofillRequest> {
+        let caller = ctx.data_unchecked::<Caller>();
+        if let Some(ip) = caller.ip {
+            lockout::check(ip).map_err(error)?;
+        }
+        rate_limit::check(None, caller.ip).map_err(error)?;
+        app_version::allows(caller.app_version.as_deref(), Feature::Resolve).map_err(error)?;
+
+        let found = handlers::lookup(parse_code(&code)?, &caller.actor(None));
+        if let (Some(ip), Err(AppError::RequestNotFound(_))) = (caller.ip, &found) {
+            lockout::missed(ip);
+        }
+        let found = found.map_err(error)?;
+        if found.public_key.is_some() {
+            app_version::allows(caller.app_version.as_deref(), Feature::Encryption).map_err(error)?;
+        }
+
+        Ok(AutofillRequest {
+            display: found.display,
+            fields: Json(found.fields),
+            public_key: found.public_key,
+            pin_required: found.pin_required,
+            custom_fields: Json(found.custom_fields),
+            hash_checks: found.hash_checks,
+            correlation_id: found.correlation_id,
+        })
+    }
+}
+
+/// the options of a websocket specification, serialized into one
+#[derive(InputObject, Serialize)]
+struct CreateAutofillRequestInput {
+    /// the `fields` object of a specification
+    fields: Json<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl_secs: Option<u64>,
+    /// base64 x25519 key the answer has to be sealed to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    /// `letters` or `numeric`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pin: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhooks: Option<Vec<String>>,
+    /// websockets that may follow the request at `/requests/{code}/follow`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    followers: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_hint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    linked_devices_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sms_to: Option<String>,
+}
+
+#[derive(SimpleObject)]
+struct CreatedRequest {
+    code: String,
+    display: String,
+    expires_in: u64,
+    pin: Option<String>,
+    correlation_id: String,
+    /// what `requestResolved` takes to hand over the answer, only for whoever opened the request
+    watch_token: String,
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// opens a request, its answer is collected with `requestResolved`; takes an api key
+    async fn create_autofill_request(
+        &self,
+        ctx: &Context<'_>,
+        input: CreateAutofillRequestInput,
+    ) -> async_graphql::Result<CreatedRequest> {
+        let caller = ctx.data_unchecked::<Caller>();
+        rate_limit::check(caller.api_key.as_deref(), caller.ip).map_err(error)?;
+        if shutdown::draining() {
+            return Err(error(AppError::ShuttingDown))
+        }
+        if maintenance::enabled() {
+            return Err(error(AppError::UnderMaintenance))
+        }
+
+        // keyless listeners can be asked for proof of work, which only the websocket hands out
+        let key = caller.api_key.as_deref()
+            .and_then(admin::authenticate)
+            .ok_or_else(|| error(AppError::InvalidApiKey))?;
+        let spec = serde_json::to_value(&input)
+            .and_then(serde_json::from_value::<ListenSpec>)
+            .map_err(|_| error(AppError::InvalidSpecification))?;
+        ws::check_spec(&spec, Some(&key)).map_err(error)?;
+
+        let slot = capacity::reserve(Some(&key.tenant)).map_err(error)?;
+        let mut opened = ws::open(spec, Some(&key.tenant), slot, &caller.actor(Some(key.id.clone()))).map_err(error)?;
+        let (code, ttl) = (opened.code, opened.ttl);
+        let correlation = code.correlation_id();
+        let request_id = caller.request_id.as_str();
+        log::info!(
+            code_hash = correlation.as_str(),
+            request_id = request_id,
+            tenant = key.tenant.as_str();
+            "[{correlation}] issued to graphql listener {request_id} for {}s", ttl.as_secs()
+        );
+
+        opened.announce();
+        let pin = opened.pin.take();
+        let watch_token = parked::park(code, ttl, opened.resolution);
+        Ok(CreatedRequest {
+            code: code.as_str().to_owned(),
+            display: code.display_grouped(),
+            expires_in: ttl.as_secs(),
+            pin,
+            correlation_id: correlation,
+            watch_token,
+        })
+    }
+}
+
+/// the answer, the same a websocket listener gets
+#[derive(SimpleObject)]
+struct RequestResolved {
+    /// the `data`, `sealed` or `credential` message
+    message: Json<Value>,
+    receipt: String,
+    user_hint: Option<String>,
+}
+
+/// the message's strings are wiped once graphql has written it out
+impl Drop for RequestResolved {
+    fn drop(&mut self) {
+        sensitive::wipe(&mut self.message.0);
+    }
+}
+
+fn resolved(resolution: Resolution) -> Result<RequestResolved, AppError> {
+    let message = protocol::answer_message(&resolution.data, resolution.verification.as_ref());
+    let message = serde_json::to_value(message).map_err(|_| AppError::Serialization)?;
+    Ok(RequestResolved { message: Json(message), receipt: resolution.receipt, user_hint: resolution.user_hint })
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// the answer once the user gave it, then the subscription ends; fails with why the request
+    /// ended if it did without one
+    async fn request_resolved(
+        &self,
+        code: String,
+        watch_token: String,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<RequestResolved>>> {
+        let code = parse_code(&code)?;
+        Ok(stream::once(async move {
+            match parked::collect(code, &watch_token, None).await {
+                Ok(Some(resolution)) => resolved(resolution).map_err(error),
+                Ok(None) => Err(error(AppError::RequestExpired(code))),
+                Err(err) => Err(error(err)),
+            }
+        }))
+    }
+}
+
+async fn execute(req: HttpRequest, query: GraphQLRequest) -> GraphQLResponse {
+    SCHEMA.execute(query.into_inner().data(Caller::of(&req))).await.into()
+}
+
+/// subscriptions speak graphql-ws, or the older subscriptions-transport-ws
+async fn subscribe(req: HttpRequest, payload: web::Payload) -> actix_web::Result<HttpResponse> {
+    GraphQLSubscription::new(SCHEMA.clone()).start(&req, payload)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/graphql")
+            .route(web::post().to(execute))
+            .route(web::get().to(subscribe)),
+    );
+}
@@ -0,0 +1,434 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use actix_web::http::StatusCode;
+use actix_rt::{Arbiter, ArbiterHandle};
+use actix_web::ResponseError;
+use rustls::ServerConfig;
+use serde_json::{Map, Value};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::server::{Connected, TcpConnectInfo, TcpIncoming};
+use tonic::{Code, Request, Response, Status};
+use zeroize::Zeroizing;
+use crate::audit::Actor;
+use crate::error::AppError;
+use crate::handlers::{self, Answer};
+use crate::model::Payload;
+use crate::protocol::ListenSpec;
+use crate::req_code::{ParseCodeError, RequestCode};
+use crate::ws::{self, Opened};
+use crate::{
+    admin, app_version, blocklist, capacity, connections, lockout, maintenance, protocol, rate_limit, redact, request_id, sensitive,
+    shutdown, store, to_json_str, MAX_BODY_BYTES,
+};
+use proto::create_request_event::Event;
+use proto::requests_server::{Requests, RequestsServer};
+use proto::{
+    CreateRequestEvent, CreateRequestRequest, FetchRequestRequest, FetchRequestResponse, Issued, ResolveRequestRequest,
+    ResolveRequestResponse, Resolved,
+};
+
+/// generated from `proto/absher.proto`
+pub mod proto {
+    tonic::include_proto!("absher.v1");
+}
+
+/// the api's error code, next to the grpc status that stands for it
+pub const ERROR_METADATA: &str = "x-absher-error";
+
+/// longest a client gets to finish the tls handshake before it's dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// finished handshakes waiting for tonic to pick them up
+const HANDSHAKE_BACKLOG: usize = 64;
+
+/// where calls run once tonic took them, awc and the webhooks only work on an actix thread
+static ARBITER: OnceLock<ArbiterHandle> = OnceLock::new();
+
+impl From<AppError> for Status {
+    fn from(err: AppError) -> Self {
+        let code = match err.status_code() {
+            StatusCode::NOT_FOUND => Code::NotFound,
+            StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::CONFLICT => Code::AlreadyExists,
+            StatusCode::GONE | StatusCode::UPGRADE_REQUIRED => Code::FailedPrecondition,
+            StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+            StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+            status if status.is_client_error() => Code::InvalidArgument,
+            _ => Code::Internal,
+        };
+
+        let mut status = Status::new(code, err.to_string());
+        status.metadata_mut().insert(ERROR_METADATA, MetadataValue::from_static(err.code()));
+        status
+    }
+}
+
+/// runs `work` on the arbiter, where actix's thread locals are
+async fn on_arbiter<T, F>(work: impl FnOnce() -> F + Send + 'static) -> Result<T, Status>
+where
+    T: Send + 'static,
+    F: Future<Output = T> + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let arbiter = ARBITER.get().expect("the grpc server to be started");
+    arbiter.spawn_fn(move || {
+        actix_web::rt::spawn(async move {
+            let _ = tx.send(work().await);
+        });
+    });
+    rx.await.map_err(|_| AppError::ShuttingDown.into())
+}
+
+fn text(metadata: &MetadataMap, key: &str) -> Option<String> {
+    metadata.get(key).map(|value| value.to_str().unwrap_or_default().to_owned())
+}
+
+fn parse_code(code: &str) -> Result<RequestCode, AppError> {
+    code.parse().map_err(|err| match err {
+        ParseCodeError::Checksum => AppError::CodeTypo,
+        _ => AppError::RequestNotFound(None),
+    })
+}
+
+/// the specification a websocket listener would have sent for `message`
+fn spec(message: CreateRequestRequest) -> Result<ListenSpec, AppError> {
+    let fields = serde_json::from_slice::<Value>(&message.fields).map_err(|_| AppError::InvalidSpecification)?;
+
+    let mut spec = Map::new();
+    spec.insert("fields".to_owned(), fields);
+    spec.insert("pin".to_owned(), message.pin.into());
+    spec.insert("webhooks".to_owned(), message.webhooks.into());
+    spec.insert("followers".to_owned(), message.followers.into());
+    spec.insert("linked_devices_only".to_owned(), message.linked_devices_only.into());
+    let optional = [
+        ("ttl_secs", message.ttl_secs.map(Value::from)),
+        ("public_key", message.public_key.map(Value::from)),
+        ("code_format", message.code_format.map(Value::from)),
+        ("user_hint", message.user_hint.map(Value::from)),
+        ("sms_to", message.sms_to.map(Value::from)),
+    ];
+    for (name, value) in optional {
+        if let Some(value) = value {
+            spec.insert(name.to_owned(), value);
+        }
+    }
+
+    serde_json::from_value(Value::Object(spec)).map_err(|_| AppError::InvalidSpecification)
+}
+
+type Events = mpsc::Sender<Result<CreateRequestEvent, Status>>;
+
+/// sends the code of `opened`, then waits for its answer the way a websocket listener would
+async fn follow(mut opened: Opened, events: Events, request_id: String) {
+    let code = opened.code;
+    let correlation = code.correlation_id();
+    let issued = Issued {
+        code: code.as_str().to_owned(),
+        display: code.display_grouped(),
+        expires_in: opened.ttl.as_secs(),
+        pin: opened.pin.take(),
+        correlation_id: correlation.clone(),
+    };
+    if events.send(Ok(CreateRequestEvent { event: Some(Event::Issued(issued)) })).await.is_err() {
+        store::withdraw_request(&code);
+        return;
+    }
+    opened.announce();
+
+    let outcome = tokio::select! {
+        outcome = &mut opened.resolution => outcome,
+        () = events.closed() => {
+            // nobody is left to hand the answer to
+            store::withdraw_request(&code);
+            log::info!(code_hash = correlation.as_str(); "[{correlation}] withdrawn, grpc listener {request_id} went away");
+            return;
+        }
+        () = shutdown::drained() => {
+            store::withdraw_request(&code);
+            log::info!(code_hash = correlation.as_str(); "[{correlation}] sent away by a shutdown");
+            let _ = events.send(Err(AppError::ShuttingDown.into())).await;
+            return;
+        }
+    };
+
+    let event = match outcome {
+        Ok(resolution) => protocol::answer_json(&resolution.data, resolution.verification.as_ref())
+            .map(|message| CreateRequestEvent {
+                event: Some(Event::Resolved(Resolved {
+                    message: sensitive::bytes(message.into_bytes()),
+                    receipt: resolution.receipt,
+                    user_hint: resolution.user_hint,
+                })),
+            })
+            .map_err(Status::from),
+        Err(_) => Err(ws::unanswered(code).into()),
+    };
+
+    let delivered = event.is_ok();
+    let _ = events.send(event).await;
+    match delivered {
+        true => log::info!(code_hash = correlation.as_str(); "[{correlation}] delivered to grpc listener {request_id}"),
+        false => log::info!(code_hash = correlation.as_str(); "[{correlation}] ended without an answer"),
+    }
+}
+
+struct Service;
+
+#[tonic::async_trait]
+impl Requests for Service {
+    type CreateRequestStream = ReceiverStream<Result<CreateRequestEvent, Status>>;
+
+    async fn create_request(
+        &self,
+        request: Request<CreateRequestRequest>,
+    ) -> Result<Response<Self::CreateRequestStream>, Status> {
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        // an access token may come as a bearer, like over http
+        let presented = text(request.metadata(), "x-api-key").or_else(|| {
+            text(request.metadata(), "authorization")?.strip_prefix("Bearer ").map(str::to_owned)
+        });
+        let request_id = request_id::adopt(text(request.metadata(), "x-request-id").as_deref());
+        blocklist::check(ip, None, presented.as_deref())?;
+        rate_limit::check(presented.as_deref(), ip)?;
+
+        if shutdown::draining() {
+            return Err(AppError::ShuttingDown.into())
+        }
+        if maintenance::enabled() {
+            return Err(AppError::UnderMaintenance.into())
+        }
+
+        // keyless listeners can be asked for proof of work, which grpc has no way to hand out
+        let key = presented.as_deref().and_then(admin::authenticate).ok_or(AppError::InvalidApiKey)?;
+        let spec = spec(request.into_inner())?;
+        ws::check_spec(&spec, Some(&key))?;
+
+        let connection = connections::open(ip)?;
+        let slot = capacity::reserve(Some(&key.tenant))?;
+        let (events, stream) = mpsc::channel(2);
+        let tenant = key.tenant;
+        let actor = Actor { by: Some(key.id), ip, request_id: Some(request_id.clone()) };
+        let opened = on_arbiter(move || async move {
+            let opened = ws::open(spec, Some(&tenant), slot, &actor)?;
+            let (code, ttl) = (opened.code, opened.ttl);
+            let correlation = code.correlation_id();
+            log::info!(
+                code_hash = correlation.as_str(),
+                request_id = request_id.as_str(),
+                tenant = tenant.as_str();
+                "[{correlation}] issued to grpc listener {request_id} for {}s", ttl.as_secs()
+            );
+
+            actix_web::rt::spawn(async move {
+                let _connection = connection;
+                follow(opened, events, request_id).await;
+            });
+            Ok::<_, AppError>(())
+        });
+        opened.await??;
+
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+
+    async fn fetch_request(&self, request: Request<FetchRequestRequest>) -> Result<Response<FetchRequestResponse>, Status> {
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        if let Some(ip) = ip {
+            lockout::check(ip)?;
+        }
+        blocklist::check(ip, None, None)?;
+        rate_limit::check(None, ip)?;
+
+        let message = request.into_inner();
+        app_version::allows(message.app_version.as_deref(), app_version::Feature::Resolve)?;
+        let actor = Actor { ip, ..Actor::default() };
+        let found = parse_code(&message.code).and_then(|code| handlers::lookup(code, &actor));
+        if let (Some(ip), Err(AppError::RequestNotFound(_))) = (ip, &found) {
+            lockout::missed(ip);
+        }
+
+        let found = found?;
+        if found.public_key.is_some() {
+            app_version::allows(message.app_version.as_deref(), app_version::Feature::Encryption)?;
+        }
+
+        Ok(Response::new(FetchRequestResponse { request: to_json_str(&found)?.into_bytes() }))
+    }
+
+    async fn resolve_request(
+        &self,
+        request: Request<ResolveRequestRequest>,
+    ) -> Result<Response<ResolveRequestResponse>, Status> {
+        let arrived = Instant::now();
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        if let Some(ip) = ip {
+            lockout::check(ip)?;
+        }
+        blocklist::check(ip, None, None)?;
+        rate_limit::check(None, ip)?;
+
+        let request_id = request_id::adopt(text(request.metadata(), "x-request-id").as_deref());
+        let mut message = request.into_inner();
+        app_version::allows(message.app_version.as_deref(), app_version::Feature::Resolve)?;
+        let body = Zeroizing::new(std::mem::take(&mut message.payload));
+        let payload = serde_json::from_slice::<Payload>(&body)
+            .map_err(|err| AppError::InvalidBody(redact::parse_error(&err)))?;
+
+        let answered = on_arbiter(move || async move {
+            let code = parse_code(&message.code)?;
+            let answer = Answer {
+                pin: message.pin.as_deref(),
+                app_version: message.app_version.as_deref(),
+                idempotency_key: message.idempotency_key.as_deref().filter(|key| store::usable_idempotency_key(key)),
+                device: message.device.as_deref(),
+                attestation: message.attestation.as_deref(),
+                ip,
+                request_id,
+                arrived,
+                started: Instant::now(),
+            };
+            handlers::answer_request(code, payload, answer).await
+        }).await?;
+
+        if let (Some(ip), Err(AppError::RequestNotFound(_))) = (ip, &answered) {
+            lockout::missed(ip);
+        }
+        let answered = answered?;
+        Ok(Response::new(ResolveRequestResponse { receipt: answered.receipt, delivered: answered.delivered }))
+    }
+}
+
+/// serves the grpc api on `port` of each of `ips` on a thread of its own, over tls with the
+/// https certificate when there is one and as plain http/2 only without
+pub fn start(ips: &[IpAddr], port: u16, tls: Option<ServerConfig>) -> io::Result<()> {
+    let listeners = ips.iter()
+        .map(|&ip| {
+            let listener = std::net::TcpListener::bind(SocketAddr::new(ip, port))?;
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let tls = tls.map(|mut config| {
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Arc::new(config)
+    });
+
+    let arbiter = Arbiter::new();
+    let handle = ARBITER.get_or_init(|| arbiter.handle());
+    for listener in listeners {
+        let addr = listener.local_addr()?;
+        let tls = tls.clone();
+        log::info!("serving grpc on {addr}{}", if tls.is_some() { " with tls" } else { "" });
+        handle.spawn_fn(move || {
+            actix_web::rt::spawn(async move {
+                let listener = match TcpListener::from_std(listener) {
+                    Ok(listener) => listener,
+                    Err(err) => return log::error!("unable to serve grpc on {addr}: {err}"),
+                };
+
+                let service = RequestsServer::new(Service).max_decoding_message_size(*MAX_BODY_BYTES);
+                let router = tonic::transport::Server::builder().add_service(service);
+                let served = match tls {
+                    Some(config) => router.serve_with_incoming_shutdown(handshakes(listener, config), shutdown::drained()).await,
+                    None => router.serve_with_incoming_shutdown(TcpIncoming::from(listener), shutdown::drained()).await,
+                };
+                if let Err(err) = served {
+                    log::error!("grpc on {addr} stopped: {err}");
+                }
+            });
+        });
+    }
+
+    Ok(())
+}
+
+/// connections on `listener` once their tls handshake is done, each one shaken hands with on its
+/// own so a slow client doesn't hold up the rest
+fn handshakes(listener: TcpListener, config: Arc<ServerConfig>) -> ReceiverStream<io::Result<TlsConnection>> {
+    let acceptor = TlsAcceptor::from(config);
+    let (tx, rx) = mpsc::channel(HANDSHAKE_BACKLOG);
+
+    actix_web::rt::spawn(async move {
+        while !tx.is_closed() {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // out of file descriptors most likely, which takes a moment to get better
+                    log::warn!("unable to accept a grpc connection: {err}");
+                    actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+                    continue
+                }
+            };
+
+            let (acceptor, tx) = (acceptor.clone(), tx.clone());
+            actix_web::rt::spawn(async move {
+                match actix_web::rt::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok(TlsConnection(stream))).await;
+                    }
+                    Ok(Err(err)) => log::debug!("tls handshake with grpc client {peer} failed: {err}"),
+                    Err(_) => log::debug!("tls handshake with grpc client {peer} timed out"),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+/// a tls connection tonic can serve, it only knows rustls streams of its own making
+struct TlsConnection(TlsStream<TcpStream>);
+
+impl Connected for TlsConnection {
+    type ConnectInfo = TcpConnectInfo;
+
+    /// the tcp peer, so `remote_addr` and everything keyed by it keep working
+    fn connect_info(&self) -> TcpConnectInfo {
+        self.0.get_ref().0.connect_info()
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
@@ -0,0 +1,682 @@
+use std::net::IpAddr;
+use std::time::Instant;
+use actix_web::error::JsonPayloadError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::middleware::{from_fn, Compress, Condition, DefaultHeaders, Logger};
+use actix_web::{get, post, web, App, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::app_version::Feature;
+use crate::audit::Actor;
+use crate::build_info::BuildInfo;
+use crate::client_errors::ClientError;
+use crate::error::{AppError, ErrorResponse};
+use crate::hash_checks::HashChecks;
+use crate::metrics::METRICS;
+use crate::model::{normalize_payload, watermark_payload, FieldInfo, Payload, RequestedAutofillFields, FIELD_CATALOG};
+use crate::custom_fields::CustomField;
+use crate::protocol::OutputFormat;
+use crate::signing::{Jwk, Jwks};
+use crate::req_code::{ParseCodeError, RequestCode};
+use crate::store::{bury, burial, buried_error, check_country, check_device, check_pin, ensure_pending, withdraw_request, Burial, PendingRequest, Resolution, MAP, TOMBSTONES};
+use crate::timing::{Arrival, Timing};
+use crate::webhooks::SinkStatus;
+use crate::{
+    admin, app_version, attestation, audit, blocklist, broker, build_info, cache, cbor, classify, clock, client_errors, connections, cors, custom_fields,
+    demo, denylist, devices, events, expiry, graphql, health, lockout, logging, metering, metrics, oauth, ocr, oidc, openapi, peer, pin, poll, protocol, quic, rate_limit, receipt, redact,
+    request_id, sessions, signing, stats, store, telemetry, timing, unix_now, uploads, webhooks, ws, to_json_str, MAX_BODY_BYTES,
+};
+
+/// the key a listener presented, a missing one is fine but a wrong one is not
+pub(crate) fn presented_key(req: &HttpRequest) -> Result<Option<admin::ApiKey>, AppError> {
+    match admin::presented(req.headers()) {
+        None => Ok(None),
+        Some(key) => admin::authenticate(key).map(Some).ok_or(AppError::InvalidApiKey),
+    }
+}
+
+/// what a request asks of the resolver
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct FetchResponse {
+    /// the code the way the app should show it back
+    pub display: String,
+    #[serde(flatten)]
+    pub fields: RequestedAutofillFields,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// the app has to ask for the pin before resolving
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pin_required: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub custom_fields: Vec<CustomField>,
+    /// fields the app has to send so they can be compared, they don't reach the listener
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hash_checks: Vec<String>,
+    /// the nonce an attestation of the answer has to carry
+    #[serde(default)]
+    pub correlation_id: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ResolveResponse<'a> {
+    receipt: &'a str,
+}
+
+#[utoipa::path(
+    post,
+    path = "/requests/{code}",
+    tag = "resolver",
+    description = "Answers a request, in json or with `Content-Type: application/cbor`. Sealed payloads are `{ciphertext}`.",
+    params(
+        ("code" = String, Path, description = "the request code, with or without its dashes"),
+        ("x-request-pin" = Option<String>, Header, description = "the pin shown next to the code, when the request has one"),
+        ("x-app-version" = Option<String>, Header, description = "the app's version, older ones are turned away from newer features"),
+        ("idempotency-key" = Option<String>, Header, description = "a random key kept across retries, a retry of a resolve that went through gets the same answer"),
+        ("x-absher-device" = Option<String>, Header, description = "the credential from `POST /devices`, links the device to the listener's tenant"),
+        ("x-absher-attestation" = Option<String>, Header, description = "`webauthn <assertion>` or `play_integrity <token>` with the code's correlation id as the nonce, required when the server checks attestations"),
+    ),
+    request_body(content((Payload = "application/json"), (Payload = "application/cbor"))),
+    responses(
+        (status = 200, description = "relayed to the listener", body = ResolveResponse),
+        (status = 202, description = "accepted, but nobody is listening anymore", body = ResolveResponse),
+        (status = 401, description = "the device isn't registered", body = ErrorResponse),
+        (status = 403, description = "denied, answered from a device or country the request isn't for, without a genuine app's attestation, or the pin is missing or wrong", body = ErrorResponse),
+        (status = 404, description = "no such request", body = ErrorResponse),
+        (status = 409, description = "already answered", body = ErrorResponse),
+        (status = 410, description = "expired, superseded or locked", body = ErrorResponse),
+        (status = 413, description = "the body or an image is too large", body = ErrorResponse),
+        (status = 415, description = "an image isn't jpeg, png or webp", body = ErrorResponse),
+        (status = 422, description = "invalid fields, images, documents or custom answers, the request stays open", body = ErrorResponse),
+        (status = 503, description = "the attestation couldn't be checked, the request stays open", body = ErrorResponse),
+    ),
+)]
+#[post(
+    "/requests/{code}",
+    wrap = "from_fn(app_version::gate)",
+    wrap = "from_fn(lockout::guard)",
+    wrap = "from_fn(rate_limit::limit)",
+    wrap = "from_fn(timing::stamp)",
+    wrap = "from_fn(telemetry::trace)"
+)]
+pub async fn resolve(
+    req: HttpRequest,
+    code: web::Path<RequestCode>,
+    data: cbor::Body<Payload>,
+) -> Result<HttpResponse, AppError> {
+    let started = Instant::now();
+    let answer = Answer {
+        pin: req.headers().get(pin::HEADER).and_then(|pin| pin.to_str().ok()),
+        app_version: req.headers().get(app_version::HEADER).and_then(|value| value.to_str().ok()),
+        idempotency_key: store::idempotency_key(&req),
+        device: req.headers().get(devices::HEADER).map(|device| device.to_str().unwrap_or_default()),
+        attestation: req.headers().get(attestation::HEADER).and_then(|value| value.to_str().ok()),
+        ip: peer::ip(&req),
+        request_id: request_id::of(&req),
+        arrived: req.extensions().get::<Arrival>().map_or(started, |arrival| arrival.0),
+        started,
+    };
+
+    let answered = answer_request(code.into_inner(), data.0, answer).await?;
+    let (content_type, body) = cbor::encode(&req, &ResolveResponse { receipt: &answered.receipt })?;
+    let mut response = match answered.delivered {
+        true => HttpResponse::Ok(),
+        // it was aproved, but nobody is listening
+        false => HttpResponse::Accepted(),
+    };
+    if let Some(timing) = answered.timing {
+        response.insert_header(("Server-Timing", timing.header_value()));
+    }
+
+    Ok(response.content_type(content_type).body(body))
+}
+
+/// what a resolve came with besides its payload, whichever way it arrived
+pub(crate) struct Answer<'a> {
+    pub(crate) pin: Option<&'a str>,
+    pub(crate) app_version: Option<&'a str>,
+    pub(crate) idempotency_key: Option<&'a str>,
+    /// the credential from `POST /devices`
+    pub(crate) device: Option<&'a str>,
+    /// vouches for the app that answers, when the server wants to know
+    pub(crate) attestation: Option<&'a str>,
+    /// where the answer came from, for the audit log and the tenant's geo check
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) request_id: String,
+    pub(crate) arrived: Instant,
+    pub(crate) started: Instant,
+}
+
+pub(crate) struct Answered {
+    pub(crate) receipt: String,
+    /// false when nobody was listening anymore
+    pub(crate) delivered: bool,
+    /// missing for the replay of an earlier resolve
+    pub(crate) timing: Option<Timing>,
+}
+
+/// checks an answer to `code` and hands it to the listener
+pub(crate) async fn answer_request(code: RequestCode, mut payload: Payload, answer: Answer<'_>) -> Result<Answered, AppError> {
+    let now = clock::now();
+    let Answer { pin, app_version, idempotency_key, device, attestation, ip, request_id, arrived, started } = answer;
+
+    if denylist::is_denied(&code) {
+        return Err(AppError::RequestDenied(code))
+    }
+
+    // a retry whose first attempt went through would otherwise see its own 409
+    if let Some(key) = idempotency_key
+        && let Some((delivered, receipt)) = store::replayed_resolve(&code, key, now)
+    {
+        return Ok(Answered { receipt, delivered, timing: None })
+    }
+
+    // a stale registration fails before the code is used up, the app registers again and retries
+    let device = device.map(devices::from_credential).transpose()?;
+    // before the pin, so a stranger's phone can't burn the user's attempts
+    check_device(code, device.as_deref())?;
+    check_country(code, ip)?;
+    check_pin(code, pin, now)?;
+    // a round trip to the verifier, so only for codes that can still be answered
+    if attestation::required() {
+        ensure_pending(code)?;
+        attestation::verify(attestation, code).await?;
+    }
+
+    if payload.is_sealed() {
+        app_version::allows(app_version, Feature::Encryption)?;
+    }
+
+    // sealed answers are only readable by the listener, which has to check them itself
+    if let Payload::Plain(data) = &payload
+        && let Some(pending) = MAP.get(&code)
+    {
+        custom_fields::check_answers(&pending.custom_fields, &data.custom_fields)?;
+        data.check_age(&pending.data_requested)?;
+    }
+
+    payload.validate()?;
+    payload.check_images()?;
+    payload.attach_uploads(code)?;
+    if payload.has_images() {
+        payload = normalize_payload(payload).await?;
+    }
+
+    // looking at documents is slow, so only codes that can still be answered get that far
+    let mut verification = None;
+    if (classify::enabled() || ocr::enabled()) && payload.has_images() {
+        ensure_pending(code)?;
+        // a mismatch leaves the request pending so the phone can retake the picture
+        payload.classify_documents().await?;
+
+        // only flags what it finds, whether that matters is for the integrator to judge
+        if let Payload::Plain(data) = &payload {
+            verification = data.cross_check().await;
+        }
+    }
+
+    // a payload in the wrong form must not burn the code, the resolver can still fix it
+    let acceptable = |pending: &PendingRequest| {
+        now > pending.expires_at || payload.is_sealed() == pending.public_key.is_some()
+    };
+
+    // both lookups always happen so hits and misses cost about the same
+    let pending = MAP.remove_if(&code, |_code, pending| acceptable(pending)).map(|(_, data)| data);
+    let buried = burial(&code, now);
+
+    let mut request = match (pending, buried) {
+        (Some(request), _) if now <= request.expires_at => request,
+        (Some(request), _) => {
+            bury(code, Burial::Expired, request.tenant.as_deref(), now);
+            audit::record("expired", &code, request.tenant.as_deref(), request.data_requested.names(), &Actor::default());
+            return Err(AppError::RequestExpired(code))
+        }
+        (None, Some(cause)) => return Err(buried_error(code, cause)),
+        (None, None) if MAP.contains_key(&code) => return Err(match payload.is_sealed() {
+            true => AppError::EncryptionUnavailable(code),
+            false => AppError::EncryptionRequired(code),
+        }),
+        (None, None) => return Err(AppError::RequestNotFound(Some(code)))
+    };
+
+    bury(code, Burial::Resolved, request.tenant.as_deref(), now);
+    uploads::discard(&code);
+    let validated = Instant::now();
+
+    let resolved_at = unix_now();
+    if let Payload::Plain(data) = &mut payload {
+        data.attest_age(&request.data_requested, code, resolved_at)?;
+        if let Some(checks) = &request.hash_checks {
+            data.check_hashes(checks, &request.data_requested);
+        }
+    }
+    let mut payload = watermark_payload(payload, request.tenant.as_deref(), code, resolved_at).await?;
+    // sealed answers can't be looked into, what was asked for is all there is to tell
+    let shared = match &payload {
+        Payload::Plain(data) => data.shared_fields(),
+        Payload::Sealed { .. } | Payload::Credential { .. } => request.data_requested.names(),
+    };
+    let actor = Actor { by: device.clone(), ip, request_id: Some(request_id.clone()) };
+    audit::record("resolved", &code, request.tenant.as_deref(), shared, &actor);
+    if let Payload::Plain(data) = &payload {
+        match request.format {
+            OutputFormat::Json => {}
+            OutputFormat::JwtVc => payload = Payload::Credential { jwt: receipt::issue_credential(code, data, resolved_at)? },
+            OutputFormat::SdJwt => payload = Payload::Credential { jwt: receipt::issue_sd_jwt(code, data, resolved_at)? },
+        }
+    }
+    let receipt = receipt::sign(code, &request.data_requested, &payload, request.created_at, resolved_at)?;
+    let followers = std::mem::take(&mut request.followers);
+    let webhook_body = match request.webhooks.is_empty() && followers.is_empty() {
+        true => None,
+        false => Some(protocol::webhook_body(&payload, verification.as_ref(), &receipt, code.correlation_id())?),
+    };
+
+    let replay_receipt = idempotency_key.map(|_| receipt.clone());
+    // hints are per tenant, keyless listeners have nothing to push with
+    let user_hint = device.zip(request.tenant.as_deref())
+        .and_then(|(device, tenant)| devices::link(&device, tenant));
+
+    let sent = request.notify.send(Resolution {
+        data: payload,
+        timing: Timing::new(arrived, started, validated, validated),
+        handed_off: validated,
+        verification,
+        receipt: receipt.clone(),
+        user_hint,
+    });
+    METRICS.resolve_seconds.observe(arrived.elapsed().as_secs_f64());
+    stats::resolved_in(request.tenant.as_deref(), arrived.elapsed());
+    metering::resolved(request.tenant.as_deref(), &request.data_requested);
+    if let (Some(key), Some(receipt)) = (idempotency_key, replay_receipt) {
+        store::remember_resolve(code, key, sent.is_ok(), receipt, now);
+    }
+    let correlation = code.correlation_id();
+    log::info!(
+        code_hash = correlation.as_str(),
+        request_id = request_id.as_str(),
+        tenant = request.tenant.as_deref().unwrap_or_default();
+        "[{correlation}] resolved by {request_id}, {}", if sent.is_ok() { "relaying" } else { "nobody was listening" }
+    );
+
+    if let (Some(tenant), Some(webhook_body)) = (request.tenant, webhook_body) {
+        webhooks::fan_out(code, tenant, sent.is_ok(), followers, request.webhooks, webhook_body);
+    }
+
+    let timing = Timing::new(arrived, started, validated, Instant::now());
+    Ok(Answered { receipt, delivered: sent.is_ok(), timing: Some(timing) })
+}
+
+/// held only briefly, the keys change as they rotate
+fn signing_keys(keys: &impl Serialize) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(signing::MAX_AGE_SECS)]))
+        .body(to_json_str(keys)?))
+}
+
+/// the key receipts are signed with right now, as a JWK
+#[utoipa::path(
+    get,
+    path = "/receipt-key",
+    tag = "verification",
+    description = "The ed25519 key receipts, age attestations and credentials are signed with right now. Verifiers \
+        should prefer /.well-known/jwks.json, which also has the next and the retired keys.",
+    responses((status = 200, body = Jwk)),
+)]
+#[get("/receipt-key")]
+pub async fn receipt_key() -> Result<HttpResponse, AppError> {
+    signing_keys(&signing::current_jwk())
+}
+
+/// every key this server signs with, as a JWK set
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    tag = "verification",
+    description = "Every ed25519 key receipts, age attestations, credentials, id tokens, access tokens and webhook \
+        signatures may be signed with, told apart by `kid`. With rotation on, the next key is here a rotation period \
+        before it signs anything and retired keys stay for a few more.",
+    responses((status = 200, body = Jwks)),
+)]
+#[get("/.well-known/jwks.json")]
+pub async fn jwks() -> Result<HttpResponse, AppError> {
+    signing_keys(&signing::jwks())
+}
+
+/// which build is running, left uncached so a deploy shows up right away
+#[utoipa::path(get, path = "/version", tag = "meta", responses((status = 200, body = BuildInfo)))]
+#[get("/version")]
+pub async fn version() -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().content_type("application/json").body(to_json_str(&build_info::get())?))
+}
+
+/// every field a listener can request
+#[utoipa::path(get, path = "/fields", tag = "meta", responses((status = 200, body = [FieldInfo])))]
+#[get("/fields")]
+pub async fn field_catalog(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    cache::serve(&req, "fields", || to_json_str(&FIELD_CATALOG))
+}
+
+#[utoipa::path(
+    get,
+    path = "/requests/{code}",
+    tag = "resolver",
+    description = "What the listener asks for, so the app can show it before the user agrees. Answers in cbor for `Accept: application/cbor`.",
+    params(("code" = String, Path, description = "the request code, with or without its dashes")),
+    responses(
+        (status = 200, body = FetchResponse),
+        (status = 404, description = "no such request", body = ErrorResponse),
+        (status = 410, description = "expired, superseded or locked", body = ErrorResponse),
+        (status = 426, description = "the app is too old for this request", body = ErrorResponse),
+    ),
+)]
+#[get(
+    "/requests/{code}",
+    wrap = "from_fn(app_version::gate)",
+    wrap = "from_fn(lockout::guard)",
+    wrap = "from_fn(rate_limit::limit)",
+    wrap = "from_fn(telemetry::trace)"
+)]
+pub async fn fetch(req: HttpRequest, code: web::Path<RequestCode>) -> Result<HttpResponse, AppError> {
+    let actor = Actor {
+        by: None,
+        ip: peer::ip(&req),
+        request_id: Some(request_id::of(&req)),
+    };
+    let request = lookup(code.into_inner(), &actor)?;
+
+    // an app that can't seal the data would only find out once it tries to submit
+    if request.public_key.is_some() {
+        app_version::require(&req, Feature::Encryption)?;
+    }
+
+    let (content_type, body) = cbor::encode(&req, &request)?;
+    Ok(HttpResponse::Ok().content_type(content_type).body(body))
+}
+
+/// what a pending request asks for, or why it can't be answered; `actor` is who looked
+pub(crate) fn lookup(code: RequestCode, actor: &Actor) -> Result<FetchResponse, AppError> {
+    let now = clock::now();
+
+    if denylist::is_denied(&code) {
+        return Err(AppError::RequestDenied(code))
+    }
+
+    let pending = MAP.get(&code).map(|entry| {
+        let response = FetchResponse {
+            display: code.display_grouped(),
+            fields: entry.data_requested,
+            public_key: entry.public_key.clone(),
+            pin_required: entry.pin.is_some(),
+            custom_fields: entry.custom_fields.clone(),
+            hash_checks: entry.hash_checks.iter().flat_map(HashChecks::fields).map(str::to_owned).collect(),
+            correlation_id: code.correlation_id(),
+        };
+        (response, entry.expires_at, entry.tenant.clone())
+    });
+    let buried = burial(&code, now);
+
+    match (pending, buried) {
+        (Some((response, expires_at, tenant)), _) if now <= expires_at => {
+            events::emit("fetched", &code, tenant.as_deref());
+            audit::record("fetched", &code, tenant.as_deref(), response.fields.names(), actor);
+            Ok(response)
+        }
+        (Some(_), _) => Err(AppError::RequestExpired(code)),
+        (None, Some(cause)) => Err(buried_error(code, cause)),
+        (None, None) => {
+            METRICS.fetch_misses.inc();
+            Err(AppError::RequestNotFound(Some(code)))
+        }
+    }
+}
+
+
+#[derive(Deserialize, ToSchema)]
+struct Report {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// lets someone holding a code flag it as leaked, so nobody can use it anymore
+#[utoipa::path(
+    post,
+    path = "/requests/{code}/report",
+    tag = "resolver",
+    description = "Flags a code as leaked, nobody can use it anymore.",
+    params(("code" = String, Path, description = "the request code, with or without its dashes")),
+    request_body(content = Option<Report>),
+    responses((status = 204, description = "denied"), (status = 404, description = "no such request", body = ErrorResponse)),
+)]
+#[post("/requests/{code}/report", wrap = "from_fn(lockout::guard)", wrap = "from_fn(rate_limit::limit)")]
+pub async fn report(code: web::Path<RequestCode>, body: Option<web::Json<Report>>) -> Result<HttpResponse, AppError> {
+    let code = code.into_inner();
+    let known = MAP.contains_key(&code) || burial(&code, clock::now()).is_some();
+    if !known && !denylist::is_denied(&code) {
+        return Err(AppError::RequestNotFound(Some(code)))
+    }
+
+    let reason = body.and_then(|body| body.into_inner().reason)
+        .unwrap_or_else(|| "reported by a code holder".to_owned());
+
+    denylist::deny(code, reason, None);
+    withdraw_request(&code);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// longest decline reason that makes it into the logs
+const MAX_REJECT_REASON_CHARS: usize = 200;
+
+/// lets the user decline a request in the app, its listener is told right away
+#[utoipa::path(
+    post,
+    path = "/requests/{code}/reject",
+    tag = "resolver",
+    description = "Declines a request, its listener is told the user said no.",
+    params(("code" = String, Path, description = "the request code, with or without its dashes")),
+    request_body(content = Option<Report>),
+    responses(
+        (status = 204, description = "declined"),
+        (status = 401, description = "the device isn't registered", body = ErrorResponse),
+        (status = 403, description = "the request is only for the linked user's devices", body = ErrorResponse),
+        (status = 404, description = "no such request", body = ErrorResponse),
+        (status = 409, description = "already answered", body = ErrorResponse),
+        (status = 410, description = "expired, superseded, locked or already declined", body = ErrorResponse),
+    ),
+)]
+#[post(
+    "/requests/{code}/reject",
+    wrap = "from_fn(lockout::guard)",
+    wrap = "from_fn(rate_limit::limit)",
+    wrap = "from_fn(telemetry::trace)"
+)]
+pub async fn reject(
+    req: HttpRequest,
+    code: web::Path<RequestCode>,
+    body: Option<web::Json<Report>>,
+) -> Result<HttpResponse, AppError> {
+    let code = code.into_inner();
+    if denylist::is_denied(&code) {
+        return Err(AppError::RequestDenied(code))
+    }
+
+    let device = devices::from_request(&req)?;
+    check_device(code, device.as_deref())?;
+    let request_id = request_id::of(&req);
+    let actor = Actor { by: device, ip: peer::ip(&req), request_id: Some(request_id.clone()) };
+    store::reject(code, clock::now(), &actor)?;
+    uploads::discard(&code);
+
+    let reason: String = body.and_then(|body| body.into_inner().reason)
+        .map(|reason| reason.chars().take(MAX_REJECT_REASON_CHARS).collect())
+        .unwrap_or_default();
+    let correlation = code.correlation_id();
+    log::info!(
+        code_hash = correlation.as_str(),
+        request_id = request_id.as_str();
+        "[{correlation}] declined by {request_id} {reason:?}"
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// how the data of a resolved request reached each of its sinks, for the tenant that opened it
+#[utoipa::path(
+    get,
+    path = "/requests/{code}/deliveries",
+    tag = "listener",
+    description = "How the data of a resolved request reached each webhook, for the tenant that opened it.",
+    params(("code" = String, Path, description = "the request code, with or without its dashes"), ("x-api-key" = String, Header)),
+    responses(
+        (status = 200, description = "one status for the websocket, each follower and each webhook", body = [SinkStatus]),
+        (status = 401, description = "missing or wrong api key", body = ErrorResponse),
+        (status = 404, description = "no such request for this tenant", body = ErrorResponse),
+    ),
+)]
+#[get("/requests/{code}/deliveries", wrap = "from_fn(rate_limit::limit)")]
+pub async fn deliveries(req: HttpRequest, code: web::Path<RequestCode>) -> Result<HttpResponse, AppError> {
+    let code = code.into_inner();
+    let Some(key) = presented_key(&req)? else {
+        return Err(AppError::InvalidApiKey)
+    };
+
+    let statuses = webhooks::statuses(&code, &key.tenant).ok_or(AppError::RequestNotFound(Some(code)))?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(to_json_str(&statuses)?))
+}
+
+/// where listeners that aren't on a websocket report what they failed to parse or show
+#[utoipa::path(
+    post,
+    path = "/requests/{code}/client-errors",
+    tag = "listener",
+    params(("code" = String, Path, description = "the request code, with or without its dashes"), ("x-api-key" = Option<String>, Header)),
+    request_body = ClientError,
+    responses((status = 204, description = "recorded"), (status = 400, body = ErrorResponse), (status = 404, body = ErrorResponse)),
+)]
+#[post("/requests/{code}/client-errors", wrap = "from_fn(lockout::guard)", wrap = "from_fn(rate_limit::limit)")]
+pub async fn report_client_error(
+    req: HttpRequest,
+    code: web::Path<RequestCode>,
+    body: web::Json<ClientError>,
+) -> Result<HttpResponse, AppError> {
+    let code = code.into_inner();
+    let key = presented_key(&req)?;
+
+    // failures usually show up once the data arrived, so resolved codes still count; a miss counts
+    // towards the lockout like any other lookup, this would be a free way to guess codes otherwise.
+    // the report goes to the tenant of the request, whatever key came with it
+    let Some(tenant) = store::tenant_of(&code, clock::now()) else {
+        return Err(AppError::RequestNotFound(Some(code)))
+    };
+
+    let client_error = body.into_inner();
+    client_error.validate()?;
+    client_errors::record(code, tenant.as_deref(), &client_error);
+    let actor = Actor { by: key.map(|key| key.id), ip: peer::ip(&req), request_id: Some(request_id::of(&req)) };
+    audit::record("client_error", &code, tenant.as_deref(), Vec::new(), &actor);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// counters and gauges for prometheus to scrape
+#[get("/metrics")]
+pub async fn metrics_page() -> Result<HttpResponse, AppError> {
+    let body = metrics::render(MAP.len(), &[
+        ("tombstones", TOMBSTONES.len()),
+        ("uploads", uploads::count()),
+        ("sessions", sessions::count()),
+        ("denials", denylist::count()),
+        ("devices", devices::count()),
+        ("expiry_queue", expiry::count()),
+        ("connections", connections::count()),
+        ("broker_outbox", broker::pending()),
+        ("blocks", blocklist::count()),
+    ])?;
+
+    Ok(HttpResponse::Ok().content_type(prometheus::TEXT_FORMAT).body(body))
+}
+
+
+#[get("/")]
+pub async fn index_page() -> impl Responder {
+    "why are you on the index page of an API????"
+}
+
+/// every route, with the extractor settings they share
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        // malformed codes can't exist, so they get the same answer as unknown ones, unless
+        // only the check character is off and it was most likely mistyped
+        .app_data(web::PathConfig::default().error_handler(|_err, req| {
+            let parsed = req.match_info().get("code").map(str::parse::<RequestCode>);
+            match parsed {
+                Some(Err(ParseCodeError::Checksum)) => AppError::CodeTypo.into(),
+                _ => AppError::RequestNotFound(None).into(),
+            }
+        }))
+        .app_data(web::JsonConfig::default().limit(*MAX_BODY_BYTES).error_handler(|err, _req| match err {
+            JsonPayloadError::Overflow { limit } | JsonPayloadError::OverflowKnownLength { limit, .. } => {
+                AppError::BodyTooLarge { max_bytes: limit }.into()
+            }
+            err => AppError::InvalidBody(redact::parse_error(&err)).into(),
+        }))
+        .app_data(web::QueryConfig::default().error_handler(|err, _req| {
+            AppError::InvalidBody(redact::parse_error(&err)).into()
+        }))
+        .service(index_page)
+        .service(ws::listen)
+        .service(ws::follow)
+        .service(resolve)
+        .service(fetch)
+        .service(report)
+        .service(reject)
+        .service(report_client_error)
+        .service(deliveries)
+        .service(receipt_key)
+        .service(jwks)
+        .service(field_catalog)
+        .service(version)
+        .service(metrics_page)
+        .configure(uploads::configure)
+        .configure(devices::configure)
+        .configure(poll::configure)
+        .configure(oidc::configure)
+        .configure(oauth::configure)
+        .configure(graphql::configure)
+        .configure(openapi::configure)
+        .configure(demo::configure)
+        .configure(admin::configure)
+        .configure(health::configure)
+        .default_service(web::to(|| async { AppError::UnknownEndpoint.error_response() }));
+}
+
+/// the whole app with its middleware, each worker and the http/3 front build their own
+pub fn app() -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let alt_svc = quic::alt_svc();
+    App::new()
+        .configure(configure)
+        .wrap(from_fn(blocklist::guard))
+        .wrap(from_fn(request_id::tag))
+        .wrap(Condition::new(
+            !logging::json(),
+            Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o %{x-correlation-id}o"#)
+                .exclude(health::PATHS[0])
+                .exclude(health::PATHS[1])
+                .exclude(health::PATHS[2]),
+        ))
+        .wrap(Compress::default())
+        // tells clients they can switch to http/3 for their next requests
+        .wrap(Condition::new(
+            alt_svc.is_some(),
+            DefaultHeaders::new().add(("alt-svc", alt_svc.unwrap_or_default())),
+        ))
+        .wrap(cors::middleware())
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use utoipa::ToSchema;
+use crate::error::AppError;
+
+/// the fields a listener can check against a value it already has
+pub const CHECKABLE_FIELDS: [&str; 5] = ["email", "phone_number", "id", "date_of_birth", "nationality"];
+/// short salts would make the hashes easy to reverse for whoever sees them
+pub const MIN_SALT_LEN: usize = 16;
+
+/// salted hashes of values a listener already has, the resolver's answers are compared to them
+/// and only whether they match is relayed
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+pub struct HashChecks {
+    pub salt: String,
+    /// base64url of sha256 over the salt followed by the value, by field
+    pub hashes: BTreeMap<String, String>,
+}
+
+/// values are hashed trimmed, and emails lowercased, so formatting doesn't fail a match
+fn normalized(field: &str, value: &str) -> String {
+    match field {
+        "email" => value.trim().to_lowercase(),
+        _ => value.trim().to_owned(),
+    }
+}
+
+impl HashChecks {
+    pub fn check_declared(&self) -> Result<(), AppError> {
+        let valid = self.salt.len() >= MIN_SALT_LEN
+            && !self.hashes.is_empty()
+            && self.hashes.keys().all(|field| CHECKABLE_FIELDS.contains(&field.as_str()));
+
+        match valid {
+            true => Ok(()),
+            false => Err(AppError::InvalidHashChecks { min_salt_len: MIN_SALT_LEN }),
+        }
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = &str> {
+        self.hashes.keys().map(String::as_str)
+    }
+
+    /// whether `value` is what the listener hashed for `field`
+    pub fn matches(&self, field: &str, value: &str) -> bool {
+        let Some(expected) = self.hashes.get(field) else {
+            return false
+        };
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update(normalized(field, value).as_bytes());
+        let hash = BASE64_URL_SAFE_NO_PAD.encode(hasher.finalize());
+
+        bool::from(hash.as_bytes().ct_eq(expected.as_bytes()))
+    }
+}
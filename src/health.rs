@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+use actix_web::{get, web, HttpResponse};
+use dashmap::DashMap;
+use serde::Serialize;
+use crate::error::AppError;
+use crate::{history, shutdown, to_json_str};
+
+/// probed every few seconds, so they stay out of the access log
+pub const PATHS: [&str; 3] = ["/healthz", "/livez", "/readyz"];
+
+/// background tasks by name, and whether they are still going
+static TASKS: LazyLock<DashMap<&'static str, bool>> = LazyLock::new(DashMap::new);
+
+/// held by a background task for as long as it runs, a task that returns or panics lets go of it
+pub struct Watch(&'static str);
+
+pub fn watch(task: &'static str) -> Watch {
+    TASKS.insert(task, true);
+    Watch(task)
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        log::warn!("background task {} stopped", self.0);
+        TASKS.insert(self.0, false);
+    }
+}
+
+#[derive(Serialize)]
+struct Report {
+    ok: bool,
+    draining: bool,
+    tasks: BTreeMap<&'static str, bool>,
+    /// whether the request history database answers, left out when none is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<bool>,
+}
+
+fn report(draining_fails: bool, history: Option<bool>) -> Result<HttpResponse, AppError> {
+    let tasks = TASKS.iter().map(|task| (*task.key(), *task.value())).collect::<BTreeMap<_, _>>();
+    let draining = shutdown::draining();
+    let ok = tasks.values().all(|running| *running)
+        && !(draining_fails && draining)
+        && history != Some(false);
+
+    let mut response = match ok {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+    Ok(response.content_type("application/json").body(to_json_str(&Report { ok, draining, tasks, history })?))
+}
+
+/// answers as long as the process does
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// fails once a background task died, pruning and code pooling don't come back without a restart
+#[get("/livez")]
+async fn livez() -> Result<HttpResponse, AppError> {
+    report(false, None)
+}
+
+/// fails while this instance shouldn't be sent traffic, draining or cut off from the request history
+#[get("/readyz")]
+async fn readyz() -> Result<HttpResponse, AppError> {
+    // every other store lives in this process, the request history is the one thing to reach out to
+    report(true, history::reachable().await)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz).service(livez).service(readyz);
+}
@@ -0,0 +1,240 @@
+use std::sync::{LazyLock, OnceLock};
+use std::time::Duration;
+use serde::Serialize;
+use sqlx::any::{install_default_drivers, AnyPoolOptions};
+use sqlx::{AnyPool, Row};
+use tokio::sync::mpsc;
+use crate::config::setting;
+use crate::error::AppError;
+use crate::metrics::METRICS;
+use crate::model::RequestedAutofillFields;
+use crate::req_code::RequestCode;
+use crate::{env_or, health, unix_now};
+
+/// `sqlite://history.db` or `postgres://…`, no history is kept without one
+static URL: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_HISTORY_URL"));
+static RETENTION_DAYS: LazyLock<u64> = LazyLock::new(|| env_or("ABSHER_HISTORY_RETENTION_DAYS", 30));
+static PURGE_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_HISTORY_PURGE_INTERVAL_SECS", 60 * 60).max(1))
+});
+/// changes waiting for the writer, a slow database loses the ones past this rather than memory
+/// filling up
+static QUEUE_CAPACITY: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_HISTORY_QUEUE", 10_000).max(1));
+
+/// how long `/readyz` waits on the database before calling it unreachable
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// the most rows one look at the history returns, newest first
+const MAX_ROWS: i64 = 1000;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS request_history (
+    code_hash TEXT NOT NULL,
+    tenant TEXT,
+    fields TEXT NOT NULL,
+    encrypted BOOLEAN NOT NULL,
+    pin BOOLEAN NOT NULL,
+    created_at BIGINT NOT NULL,
+    ended_at BIGINT,
+    outcome TEXT
+)";
+const INDEX: &str = "CREATE INDEX IF NOT EXISTS request_history_created ON request_history (created_at)";
+/// every end of a request looks its row up by the code
+const CODE_INDEX: &str = "CREATE INDEX IF NOT EXISTS request_history_code_hash ON request_history (code_hash)";
+
+/// what happened to a request, nothing it carried
+enum Change {
+    Created { code_hash: String, tenant: Option<String>, fields: String, encrypted: bool, pin: bool, at: u64 },
+    Ended { code_hash: String, outcome: &'static str, at: u64 },
+}
+
+static POOL: OnceLock<AnyPool> = OnceLock::new();
+static QUEUE: OnceLock<mpsc::Sender<Change>> = OnceLock::new();
+
+/// opens the database and creates the table, then keeps writing changes and purging old rows
+pub async fn start() -> Result<(), String> {
+    let Some(url) = URL.as_deref() else {
+        return Ok(())
+    };
+
+    install_default_drivers();
+    let pool = AnyPoolOptions::new()
+        .max_connections(4)
+        .connect(url)
+        .await
+        .map_err(|err| format!("unable to open the request history: {err}"))?;
+    for statement in [SCHEMA, INDEX, CODE_INDEX] {
+        sqlx::query(statement).execute(&pool).await
+            .map_err(|err| format!("unable to set up the request history: {err}"))?;
+    }
+    let pool = POOL.get_or_init(|| pool);
+
+    let (tx, mut rx) = mpsc::channel(*QUEUE_CAPACITY);
+    if QUEUE.set(tx).is_err() {
+        return Ok(())
+    }
+
+    // one writer keeps the end of a request from overtaking its creation
+    tokio::spawn(async move {
+        while let Some(change) = rx.recv().await {
+            if let Err(err) = write(pool, change).await {
+                log::error!("unable to write to the request history: {err}");
+            }
+        }
+    });
+    tokio::spawn(purge(pool));
+    Ok(())
+}
+
+async fn write(pool: &AnyPool, change: Change) -> Result<(), sqlx::Error> {
+    match change {
+        Change::Created { code_hash, tenant, fields, encrypted, pin, at } => {
+            sqlx::query(
+                "INSERT INTO request_history (code_hash, tenant, fields, encrypted, pin, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+                .bind(code_hash)
+                .bind(tenant)
+                .bind(fields)
+                .bind(encrypted)
+                .bind(pin)
+                .bind(at as i64)
+                .execute(pool)
+                .await?;
+        }
+        Change::Ended { code_hash, outcome, at } => {
+            sqlx::query("UPDATE request_history SET outcome = $1, ended_at = $2 WHERE code_hash = $3 AND outcome IS NULL")
+                .bind(outcome)
+                .bind(at as i64)
+                .bind(code_hash)
+                .execute(pool)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// drops rows past the retention, for as long as the server runs
+async fn purge(pool: &AnyPool) {
+    let _watch = health::watch("history_purge");
+    loop {
+        let cutoff = unix_now().saturating_sub(*RETENTION_DAYS * 24 * 60 * 60);
+        match sqlx::query("DELETE FROM request_history WHERE created_at < $1").bind(cutoff as i64).execute(pool).await {
+            Ok(done) if done.rows_affected() > 0 => {
+                log::info!("purged {} requests from the history", done.rows_affected());
+            }
+            Ok(_) => {}
+            Err(err) => log::error!("unable to purge the request history: {err}"),
+        }
+        tokio::time::sleep(*PURGE_INTERVAL).await;
+    }
+}
+
+fn queue(change: Change) {
+    if let Some(queue) = QUEUE.get()
+        && let Err(mpsc::error::TrySendError::Full(_)) = queue.try_send(change)
+    {
+        METRICS.history_dropped.inc();
+    }
+}
+
+/// a request was opened, costs nothing without a history
+pub fn created(code: &RequestCode, tenant: Option<&str>, fields: &RequestedAutofillFields, encrypted: bool, pin: bool) {
+    if QUEUE.get().is_none() {
+        return
+    }
+
+    queue(Change::Created {
+        code_hash: code.correlation_id(),
+        tenant: tenant.map(str::to_owned),
+        fields: fields.names().join(","),
+        encrypted,
+        pin,
+        at: unix_now(),
+    });
+}
+
+/// a request ended, `outcome` says how
+pub fn ended(code: &RequestCode, outcome: &'static str) {
+    if QUEUE.get().is_none() {
+        return
+    }
+
+    queue(Change::Ended { code_hash: code.correlation_id(), outcome, at: unix_now() });
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    code_hash: String,
+    tenant: Option<String>,
+    fields: Vec<String>,
+    encrypted: bool,
+    pin: bool,
+    /// unix seconds
+    created_at: u64,
+    ended_at: Option<u64>,
+    /// missing while the request is open, or when the server stopped before it ended
+    outcome: Option<String>,
+}
+
+/// whether the database answers, `None` when no history is kept
+pub async fn reachable() -> Option<bool> {
+    URL.as_ref()?;
+    let Some(pool) = POOL.get().filter(|pool| !pool.is_closed()) else {
+        return Some(false)
+    };
+
+    match tokio::time::timeout(PING_TIMEOUT, sqlx::query("SELECT 1").execute(pool)).await {
+        Ok(Ok(_)) => Some(true),
+        Ok(Err(err)) => {
+            log::warn!("the request history isn't answering: {err}");
+            Some(false)
+        }
+        Err(_) => {
+            log::warn!("the request history didn't answer within {PING_TIMEOUT:?}");
+            Some(false)
+        }
+    }
+}
+
+/// the requests created from `from` through `to`, unix seconds, for one tenant or all of them
+pub async fn search(tenant: Option<&str>, from: Option<u64>, to: Option<u64>) -> Result<Vec<Entry>, AppError> {
+    let Some(pool) = POOL.get() else {
+        return Err(AppError::HistoryDisabled)
+    };
+
+    let rows = sqlx::query(
+        "SELECT code_hash, tenant, fields, encrypted, pin, created_at, ended_at, outcome FROM request_history
+         WHERE ($1 IS NULL OR tenant = $1) AND created_at >= $2 AND created_at <= $3
+         ORDER BY created_at DESC LIMIT $4",
+    )
+        .bind(tenant)
+        .bind(from.unwrap_or(0) as i64)
+        .bind(to.map_or(i64::MAX, |to| to as i64))
+        .bind(MAX_ROWS)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| {
+            log::error!("unable to read the request history: {err}");
+            AppError::HistoryUnavailable
+        })?;
+
+    rows.iter()
+        .map(|row| {
+            let fields: String = row.try_get("fields")?;
+            Ok(Entry {
+                code_hash: row.try_get("code_hash")?,
+                tenant: row.try_get("tenant")?,
+                fields: fields.split(',').filter(|field| !field.is_empty()).map(str::to_owned).collect(),
+                encrypted: row.try_get("encrypted")?,
+                pin: row.try_get("pin")?,
+                created_at: row.try_get::<i64, _>("created_at")? as u64,
+                ended_at: row.try_get::<Option<i64>, _>("ended_at")?.map(|at| at as u64),
+                outcome: row.try_get("outcome")?,
+            })
+        })
+        .collect::<Result<_, sqlx::Error>>()
+        .map_err(|err| {
+            log::error!("unable to read the request history: {err}");
+            AppError::HistoryUnavailable
+        })
+}
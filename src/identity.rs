@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use zeroize::Zeroize;
+use crate::images;
+
+/// as the civil registry records it
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Gender {
+    Male,
+    Female,
+}
+
+/// a given and a family name written in one script
+#[derive(Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct NameParts {
+    #[serde(default)]
+    pub given: Option<String>,
+    #[serde(default)]
+    pub family: Option<String>,
+}
+
+/// a name in arabic and in english, the arabic one is the legal name exactly as it is on the id
+#[derive(Clone, Default, Deserialize, Serialize, ToSchema)]
+#[serde(from = "NameForm")]
+pub struct Name {
+    pub arabic: Option<NameParts>,
+    pub english: Option<NameParts>,
+}
+
+/// older apps send a `[first, last]` pair, which were always the latin spelling
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NameForm {
+    Pair([String; 2]),
+    Scripts {
+        #[serde(default)]
+        arabic: Option<NameParts>,
+        #[serde(default)]
+        english: Option<NameParts>,
+    },
+}
+
+impl From<NameForm> for Name {
+    fn from(form: NameForm) -> Self {
+        match form {
+            NameForm::Pair([given, family]) => Name {
+                arabic: None,
+                english: Some(NameParts { given: Some(given), family: Some(family) }),
+            },
+            NameForm::Scripts { arabic, english } => Name { arabic, english },
+        }
+    }
+}
+
+impl Zeroize for NameParts {
+    fn zeroize(&mut self) {
+        self.given.zeroize();
+        self.family.zeroize();
+    }
+}
+
+impl Zeroize for Name {
+    fn zeroize(&mut self) {
+        self.arabic.zeroize();
+        self.english.zeroize();
+    }
+}
+
+/// which scripts of the name a listener asks for
+#[derive(Copy, Clone, Default, Deserialize, Serialize, ToSchema)]
+#[serde(from = "NameFieldsForm")]
+pub struct NameFields {
+    pub arabic: bool,
+    pub english: bool,
+}
+
+/// `name: true` from before names had scripts asks for both
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NameFieldsForm {
+    Both(bool),
+    Scripts {
+        #[serde(default)]
+        arabic: bool,
+        #[serde(default)]
+        english: bool,
+    },
+}
+
+impl From<NameFieldsForm> for NameFields {
+    fn from(form: NameFieldsForm) -> Self {
+        match form {
+            NameFieldsForm::Both(both) => NameFields { arabic: both, english: both },
+            NameFieldsForm::Scripts { arabic, english } => NameFields { arabic, english },
+        }
+    }
+}
+
+impl NameFields {
+    /// true if every script requested here is also requested by `allowed`
+    pub fn is_within(&self, allowed: &NameFields) -> bool {
+        (!self.arabic || allowed.arabic) && (!self.english || allowed.english)
+    }
+}
+
+/// a national address, every part is optional since older records only have the city
+#[derive(Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct Address {
+    #[serde(default)]
+    pub street: Option<String>,
+    #[serde(default)]
+    pub district: Option<String>,
+    #[serde(default)]
+    pub city: Option<String>,
+    #[serde(default)]
+    pub postal_code: Option<String>,
+}
+
+impl Zeroize for Address {
+    fn zeroize(&mut self) {
+        self.street.zeroize();
+        self.district.zeroize();
+        self.city.zeroize();
+        self.postal_code.zeroize();
+    }
+}
+
+/// a passport or driving license as printed on it
+#[derive(Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct Document {
+    #[serde(default)]
+    pub number: Option<String>,
+    /// `YYYY-MM-DD`, like every date below
+    #[serde(default)]
+    pub issued_on: Option<String>,
+    #[serde(default)]
+    pub expires_on: Option<String>,
+    /// who issued it, like `General Department of Traffic`
+    #[serde(default)]
+    pub authority: Option<String>,
+    /// stored as a base64 image
+    #[serde(default, deserialize_with = "images::base64_or_bytes")]
+    pub image: Option<String>,
+}
+
+impl Zeroize for Document {
+    fn zeroize(&mut self) {
+        self.number.zeroize();
+        self.issued_on.zeroize();
+        self.expires_on.zeroize();
+        self.authority.zeroize();
+        self.image.zeroize();
+    }
+}
+
+/// which parts of a document a listener asks for, a rental desk may only need the expiry date
+#[derive(Copy, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct DocumentFields {
+    #[serde(default)]
+    pub number: bool,
+    #[serde(default)]
+    pub issued_on: bool,
+    #[serde(default)]
+    pub expires_on: bool,
+    #[serde(default)]
+    pub authority: bool,
+    #[serde(default)]
+    pub image: bool,
+}
+
+impl DocumentFields {
+    /// true if every part requested here is also requested by `allowed`
+    pub fn is_within(&self, allowed: &DocumentFields) -> bool {
+        let requested = [
+            (self.number, allowed.number),
+            (self.issued_on, allowed.issued_on),
+            (self.expires_on, allowed.expires_on),
+            (self.authority, allowed.authority),
+            (self.image, allowed.image),
+        ];
+
+        requested.into_iter().all(|(requested, allowed)| !requested || allowed)
+    }
+
+    /// whether any part of the document is asked for
+    pub fn any(&self) -> bool {
+        self.number || self.issued_on || self.expires_on || self.authority || self.image
+    }
+}
+
+/// whether the resolver is at least `age` years old, in place of the date of birth
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+pub struct AgeOver {
+    pub age: u8,
+    pub over: bool,
+    /// a JWS over the answer, so it can be kept and checked without the rest of the data
+    pub attestation: String,
+}
+
+/// `YYYY-MM-DD` as year, month and day
+pub fn parse_date(date: &str) -> Option<(i64, u32, u32)> {
+    let mut parts = date.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None
+    }
+
+    let (year, month, day) = (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+
+    (1..=days_in_month).contains(&day).then_some((year, month, day))
+}
+
+/// the utc date `unix` seconds fall on, after Howard Hinnant's `civil_from_days`
+pub(crate) fn civil_date(unix: u64) -> (i64, u32, u32) {
+    let days = (unix / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// full years since `date_of_birth` as of `unix`, in utc, which is never kinder than riyadh time
+pub fn age(date_of_birth: &str, unix: u64) -> Option<i64> {
+    let (year, month, day) = parse_date(date_of_birth)?;
+    let (today_year, today_month, today_day) = civil_date(unix);
+    let had_birthday = (today_month, today_day) >= (month, day);
+
+    Some(today_year - year - i64::from(!had_birthday))
+}
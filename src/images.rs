@@ -0,0 +1,185 @@
+use std::fmt;
+use std::io::Cursor;
+use std::sync::LazyLock;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use serde::de::{Deserializer, Visitor};
+use crate::config::setting;
+use crate::error::AppError;
+use crate::{env_or, uploads};
+
+/// largest decoded image each field takes inline, anything bigger has to go through an upload
+static MAX_PROFILE_PICTURE_BYTES: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_MAX_PROFILE_PICTURE_BYTES", 1 << 20));
+static MAX_LICENSE_BYTES: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_MAX_LICENSE_BYTES", 4 << 20));
+static MAX_ID_IMAGE_BYTES: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_MAX_ID_IMAGE_BYTES", 4 << 20));
+
+/// longest side an image is scaled down to, images keep their size when unset
+static MAX_DIMENSION: LazyLock<Option<u32>> = LazyLock::new(|| {
+    setting("ABSHER_IMAGE_MAX_DIMENSION").and_then(|max| max.parse().ok())
+});
+
+/// longest side a decoded image may have, a few kilobytes of png can claim to be gigapixels
+const MAX_DECODED_SIDE: u32 = 8192;
+/// most memory decoding one image may take, a 24 megapixel photo fits
+const MAX_DECODE_ALLOC: u64 = 128 << 20;
+
+/// what listeners can count on getting, everything else is turned away
+const ALLOWED_FORMATS: [ImageFormat; 3] = [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP];
+const JPEG_QUALITY: u8 = 90;
+
+fn max_bytes(field: &str) -> usize {
+    match field {
+        "profile_picture" => *MAX_PROFILE_PICTURE_BYTES,
+        "license" | "driving_license.image" => *MAX_LICENSE_BYTES,
+        _ => *MAX_ID_IMAGE_BYTES,
+    }
+}
+
+/// makes sure an inline image is base64 and within its field's size, uploads were checked
+/// when they were assembled
+pub fn check(field: &'static str, image: &str) -> Result<(), AppError> {
+    if image.starts_with(uploads::REFERENCE_PREFIX) {
+        return Ok(())
+    }
+
+    // every 4 characters hold 3 bytes, so hopeless ones are turned away before decoding
+    let max_bytes = max_bytes(field);
+    if (image.len() / 4 * 3).saturating_sub(2) > max_bytes {
+        return Err(AppError::ImageTooLarge { field, max_bytes })
+    }
+
+    let decoded = BASE64_STANDARD.decode(image).map_err(|_| AppError::InvalidImage { field })?;
+    if decoded.len() > max_bytes {
+        return Err(AppError::ImageTooLarge { field, max_bytes })
+    }
+
+    Ok(())
+}
+
+/// what decoding a shared image may take, whatever its header claims
+pub(crate) fn limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DECODED_SIDE);
+    limits.max_image_height = Some(MAX_DECODED_SIDE);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    limits
+}
+
+/// decodes an image, turns it upright and encodes it again in its own format, which leaves exif
+/// and every other bit of metadata behind, like where the phone was when it took the picture
+pub fn normalize(field: &'static str, image: &str) -> Result<String, AppError> {
+    let bytes = BASE64_STANDARD.decode(image).map_err(|_| AppError::InvalidImage { field })?;
+    let format = image::guess_format(&bytes).ok()
+        .filter(|format| ALLOWED_FORMATS.contains(format))
+        .ok_or(AppError::UnsupportedImage { field })?;
+
+    let invalid = |_| AppError::InvalidImage { field };
+    let mut reader = ImageReader::with_format(Cursor::new(&bytes), format);
+    reader.limits(limits());
+    let mut decoder = reader.into_decoder().map_err(invalid)?;
+    let orientation = decoder.orientation().map_err(invalid)?;
+    let mut pixels = DynamicImage::from_decoder(decoder).map_err(invalid)?;
+    pixels.apply_orientation(orientation);
+
+    if let Some(max) = *MAX_DIMENSION
+        && pixels.width().max(pixels.height()) > max
+    {
+        pixels = pixels.resize(max, max, FilterType::Lanczos3);
+    }
+
+    let mut out = Cursor::new(Vec::new());
+    let encoded = match format {
+        // neither takes every pixel layout png does
+        ImageFormat::Jpeg => pixels.to_rgb8().write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY)),
+        ImageFormat::WebP => pixels.to_rgba8().write_with_encoder(WebPEncoder::new_lossless(&mut out)),
+        _ => pixels.write_to(&mut out, format),
+    };
+
+    encoded.map_err(|err| {
+        log::error!("unable to re-encode {field}: {err}");
+        AppError::Serialization
+    })?;
+
+    Ok(BASE64_STANDARD.encode(out.into_inner()))
+}
+
+struct ImageField;
+
+impl<'de> Visitor<'de> for ImageField {
+    type Value = Option<String>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a base64 string or bytes")
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_str<E>(self, image: &str) -> Result<Self::Value, E> {
+        Ok(Some(image.to_owned()))
+    }
+
+    fn visit_string<E>(self, image: String) -> Result<Self::Value, E> {
+        Ok(Some(image))
+    }
+
+    fn visit_bytes<E>(self, image: &[u8]) -> Result<Self::Value, E> {
+        Ok(Some(BASE64_STANDARD.encode(image)))
+    }
+}
+
+/// an image field as base64, cbor clients can send the bytes themselves instead
+pub fn base64_or_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    deserializer.deserialize_option(ImageField)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+    use super::*;
+
+    fn png(width: u32, height: u32) -> String {
+        let mut out = Cursor::new(Vec::new());
+        RgbImage::from_pixel(width, height, Rgb([200, 30, 30])).write_to(&mut out, ImageFormat::Png).unwrap();
+        BASE64_STANDARD.encode(out.into_inner())
+    }
+
+    #[test]
+    fn oversized_and_broken_images_are_turned_away() {
+        let huge = "A".repeat(2 << 20);
+        assert!(matches!(check("profile_picture", &huge), Err(AppError::ImageTooLarge { max_bytes, .. }) if max_bytes == 1 << 20));
+        assert!(check("license", &huge).is_ok());
+        assert!(matches!(check("profile_picture", "not base64!"), Err(AppError::InvalidImage { .. })));
+        assert!(check("profile_picture", &format!("{}whatever", uploads::REFERENCE_PREFIX)).is_ok());
+    }
+
+    #[test]
+    fn images_are_decoded_within_bounds() {
+        let normalized = normalize("profile_picture", &png(4, 3)).unwrap();
+        let decoded = image::load_from_memory(&BASE64_STANDARD.decode(normalized).unwrap()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (4, 3));
+
+        // tiny to send, but past what any decode may claim
+        assert!(matches!(normalize("profile_picture", &png(MAX_DECODED_SIDE + 1, 1)), Err(AppError::InvalidImage { .. })));
+    }
+
+    #[test]
+    fn only_listed_formats_are_taken() {
+        let gif = BASE64_STANDARD.encode(b"GIF89a\x01\x00\x01\x00\x00\x00\x00;");
+        assert!(matches!(normalize("profile_picture", &gif), Err(AppError::UnsupportedImage { .. })));
+        let truncated = &png(4, 3)[..40];
+        assert!(matches!(normalize("profile_picture", truncated), Err(AppError::InvalidImage { .. })));
+    }
+}
@@ -0,0 +1,122 @@
+//! relays identity data from the absher app to the pages that asked for it, the binary only
+//! wires these modules into a server
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::LazyLock;
+use serde::Serialize;
+use crate::error::AppError;
+
+pub mod req_code;
+pub mod error;
+pub mod admin;
+pub mod rate_limit;
+pub mod lockout;
+pub mod protocol;
+pub mod ttl;
+pub mod challenge;
+pub mod timing;
+pub mod tls;
+pub mod sessions;
+pub mod denylist;
+pub mod blocklist;
+pub mod geo;
+pub mod attestation;
+pub mod signing;
+pub mod receipt;
+pub mod app_version;
+pub mod uploads;
+pub mod classify;
+pub mod ocr;
+pub mod watermark;
+pub mod pin;
+pub mod client_errors;
+pub mod metrics;
+pub mod stats;
+pub mod events;
+pub mod audit;
+pub mod broker;
+pub mod metering;
+pub mod history;
+pub mod cache;
+pub mod telemetry;
+pub mod code_pool;
+pub mod request_id;
+pub mod peer;
+pub mod webhooks;
+pub mod app_auth;
+pub mod devices;
+pub mod push;
+pub mod sms;
+pub mod logging;
+pub mod maintenance;
+pub mod redact;
+pub mod sensitive;
+pub mod health;
+pub mod build_info;
+pub mod shutdown;
+pub mod cli;
+pub mod config;
+pub mod cors;
+pub mod expiry;
+pub mod clock;
+pub mod capacity;
+pub mod connections;
+pub mod images;
+pub mod cbor;
+pub mod identity;
+pub mod custom_fields;
+pub mod hash_checks;
+pub mod validate;
+pub mod openapi;
+pub mod typegen;
+pub mod model;
+pub mod store;
+pub mod parked;
+pub mod poll;
+pub mod oidc;
+pub mod oauth;
+pub mod ws;
+pub mod grpc;
+pub mod quic;
+pub mod systemd;
+pub mod graphql;
+pub mod handlers;
+pub mod client;
+pub mod test_utils;
+pub mod demo;
+pub mod simulate;
+pub mod ops;
+
+/// reads an environment variable, falling back to `default` if it is unset or malformed
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    config::setting(name)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+pub fn to_json_str(ser: &impl Serialize) -> Result<String, AppError> {
+    serde_json::to_string(ser).map_err(|err| {
+        let type_name = core::any::type_name_of_val(ser);
+        log::error!("unable to turn {type_name} to a json: {err}");
+        AppError::Serialization
+    })
+}
+
+thread_local! {
+    static HTTP_CLIENT: awc::Client = awc::Client::default();
+}
+
+/// the worker's client for calls out to the operator's services, so connections to them are kept
+/// and reused; each request sets its own timeout
+pub fn http_client() -> awc::Client {
+    HTTP_CLIENT.with(awc::Client::clone)
+}
+
+/// for every json body, enough for a resolve with all its images inline
+pub static MAX_BODY_BYTES: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_MAX_BODY_BYTES", 16 << 20));
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |dur| dur.as_secs())
+}
@@ -0,0 +1,180 @@
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use dashmap::DashMap;
+use crate::{blocklist, env_or, peer};
+use crate::error::AppError;
+use crate::metrics::METRICS;
+
+struct Policy {
+    /// misses allowed inside one window before the source gets banned
+    max_misses: u32,
+    window: Duration,
+    /// first ban length, doubled on every repeat offence
+    base_ban: Duration,
+    max_ban: Duration,
+}
+
+static POLICY: LazyLock<Policy> = LazyLock::new(|| Policy {
+    max_misses: env_or("ABSHER_LOCKOUT_MISSES", 10),
+    window: Duration::from_secs(env_or("ABSHER_LOCKOUT_WINDOW_SECS", 60)),
+    base_ban: Duration::from_secs(env_or("ABSHER_LOCKOUT_BAN_SECS", 60)),
+    max_ban: Duration::from_secs(env_or("ABSHER_LOCKOUT_MAX_BAN_SECS", 60 * 60)),
+});
+
+static STRIKES: LazyLock<DashMap<IpAddr, Strikes>> = LazyLock::new(DashMap::new);
+
+struct Strikes {
+    misses: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+    /// how many times this source has been banned, drives the escalation
+    bans: u32,
+}
+
+fn banned_for(ip: IpAddr, now: Instant) -> Option<Duration> {
+    let strikes = STRIKES.get(&ip)?;
+    let until = strikes.banned_until?;
+    (now < until).then(|| until - now)
+}
+
+fn record_miss(ip: IpAddr, now: Instant) {
+    let policy = &*POLICY;
+    let mut strikes = STRIKES.entry(ip).or_insert_with(|| Strikes {
+        misses: 0,
+        window_start: now,
+        banned_until: None,
+        bans: 0,
+    });
+
+    if now.saturating_duration_since(strikes.window_start) > policy.window {
+        strikes.misses = 0;
+        strikes.window_start = now;
+    }
+
+    strikes.misses += 1;
+    if strikes.misses < policy.max_misses {
+        return
+    }
+
+    let ban = policy.base_ban
+        .saturating_mul(1 << strikes.bans.min(16))
+        .min(policy.max_ban);
+
+    strikes.bans += 1;
+    strikes.misses = 0;
+    strikes.banned_until = Some(now + ban);
+
+    // the lockout only guards code lookups, the block keeps the source off everything else too
+    blocklist::block_banned(ip, ban);
+    METRICS.lockouts.inc();
+    let total = METRICS.lockouts.get();
+    log::warn!("locked out {ip} for {}s after repeated code misses ({total} lockouts so far)", ban.as_secs());
+}
+
+/// the fastest one source can try unknown codes, right at the edge of being banned
+pub fn max_guesses_per_hour() -> f64 {
+    let policy = &*POLICY;
+    f64::from(policy.max_misses) / policy.window.as_secs_f64().max(1.0) * 3600.0
+}
+
+/// counts unknown code lookups per source and turns repeat offenders away
+pub async fn guard(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(ip) = peer::ip(req.request()) else {
+        return next.call(req).await
+    };
+
+    check(ip)?;
+    let response = next.call(req).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        missed(ip);
+    }
+
+    Ok(response)
+}
+
+/// turns `ip` away while it is banned, for callers that don't come through the middleware
+pub(crate) fn check(ip: IpAddr) -> Result<(), AppError> {
+    match banned_for(ip, Instant::now()) {
+        Some(retry_after) => Err(AppError::RateLimited { retry_after }),
+        None => Ok(()),
+    }
+}
+
+/// counts a lookup of a code that doesn't exist against `ip`
+pub(crate) fn missed(ip: IpAddr) {
+    record_miss(ip, Instant::now());
+}
+
+/// forgets sources that are neither banned nor inside an active window
+pub fn prune() {
+    let now = Instant::now();
+    let policy = &*POLICY;
+
+    STRIKES.retain(|_ip, strikes| {
+        let banned = strikes.banned_until.is_some_and(|until| now < until);
+        // keep ban history around for a while so returning offenders still escalate
+        let recent = now.saturating_duration_since(strikes.window_start) < policy.max_ban;
+        banned || recent
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    fn miss(ip: IpAddr, now: Instant, times: u32) {
+        for _ in 0..times {
+            record_miss(ip, now);
+        }
+    }
+
+    #[test]
+    fn repeated_misses_ban_and_bans_escalate() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10));
+        let now = Instant::now();
+
+        miss(ip, now, 9);
+        assert_eq!(banned_for(ip, now), None);
+        miss(ip, now, 1);
+        assert_eq!(banned_for(ip, now), Some(Duration::from_secs(60)));
+        assert_eq!(banned_for(ip, now + Duration::from_secs(60)), None);
+
+        let again = now + Duration::from_secs(61);
+        miss(ip, again, 10);
+        assert_eq!(banned_for(ip, again), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn bans_stop_growing_at_the_max() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 11));
+        let mut now = Instant::now();
+        for _ in 0..10 {
+            miss(ip, now, 10);
+            now += banned_for(ip, now).expect("ten misses in a row to ban");
+        }
+
+        miss(ip, now, 10);
+        assert_eq!(banned_for(ip, now), Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn misses_outside_the_window_start_over() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 12));
+        let now = Instant::now();
+
+        miss(ip, now, 9);
+        let later = now + Duration::from_secs(61);
+        miss(ip, later, 9);
+        assert_eq!(banned_for(ip, later), None);
+        assert!(banned_for(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 13)), later).is_none());
+    }
+}
@@ -0,0 +1,86 @@
+use std::io::Write;
+use std::sync::{LazyLock, OnceLock};
+use log::kv::{Error, Key, Value, VisitSource};
+use log::LevelFilter;
+use serde_json::{Map, Value as Json};
+use crate::config::setting;
+use crate::telemetry;
+
+/// `json` for one object per line, anything else keeps the pretty format meant for terminals
+static JSON: LazyLock<bool> = LazyLock::new(|| {
+    setting("ABSHER_LOG_FORMAT").is_some_and(|format| format.eq_ignore_ascii_case("json"))
+});
+
+pub fn json() -> bool {
+    *JSON
+}
+
+/// copies a record's structured fields, like `request_id` and `code_hash`, into the line,
+/// leaving out empty ones so a missing tenant is just missing
+struct Fields<'a>(&'a mut Map<String, Json>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = match (value.to_u64(), value.to_bool()) {
+            (Some(number), _) => Json::from(number),
+            (_, Some(bool)) => Json::from(bool),
+            _ => match value.to_string() {
+                text if text.is_empty() => return Ok(()),
+                text => Json::from(text),
+            },
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// set when `--log-level` was passed, which a config reload mustn't override
+static PINNED: OnceLock<LevelFilter> = OnceLock::new();
+
+fn configured_level() -> LevelFilter {
+    setting("ABSHER_LOG_LEVEL").and_then(|level| level.parse().ok()).unwrap_or(LevelFilter::Info)
+}
+
+pub fn reload() {
+    log::set_max_level(PINNED.get().copied().unwrap_or_else(configured_level));
+}
+
+/// `pinned` is the level from the command line, if there was one
+pub fn init(level: LevelFilter, pinned: bool) {
+    if pinned {
+        let _ = PINNED.set(level);
+    }
+
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder
+        // the level is only enforced through `log::set_max_level`, so reloads can change it
+        .filter_level(LevelFilter::Trace)
+        // actix turns on tracing's log output, spans belong in the trace exporter instead
+        .filter_module(telemetry::TARGET, LevelFilter::Off)
+        .filter_module("tracing::span", LevelFilter::Off);
+
+    if json() {
+        builder.format(|buf, record| {
+            let mut line = Map::new();
+            line.insert("timestamp".to_owned(), Json::from(buf.timestamp_millis().to_string()));
+            line.insert("level".to_owned(), Json::from(record.level().as_str()));
+            line.insert("target".to_owned(), Json::from(record.target()));
+
+            let mut message = record.args().to_string();
+            let _ = record.key_values().visit(&mut Fields(&mut line));
+
+            // flow lines lead with the code hash for the text format, here it is a field already
+            if let Some(Json::String(hash)) = line.get("code_hash")
+                && let Some(rest) = message.strip_prefix(&format!("[{hash}] "))
+            {
+                message = rest.to_owned();
+            }
+            line.insert("message".to_owned(), Json::from(message));
+
+            writeln!(buf, "{}", Json::Object(line))
+        });
+    }
+
+    builder.init();
+    log::set_max_level(level);
+}
@@ -1,217 +1,139 @@
-use std::net::{Ipv4Addr, SocketAddr};
-use std::sync::LazyLock;
-use std::time::{Duration, Instant};
-use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use actix_web::middleware::Logger;
-use actix_ws::{CloseCode, Message};
-use dashmap::{DashMap, Entry};
+use actix_web::HttpServer;
 use tokio::sync::oneshot;
-use log::LevelFilter;
-use serde::{Deserialize, Serialize};
-use crate::req_code::RequestCode;
+use absher_zt_backend::tls::TlsSettings;
+use absher_zt_backend::{
+    audit, blocklist, broker, cli, code_pool, config, demo, denylist, devices, expiry, geo, grpc, handlers, history, lockout, logging, metering, ops, quic, req_code,
+    shutdown, signing, store, systemd, telemetry, tls,
+};
 
-pub mod req_code;
-
-pub fn to_json_str(ser: &impl Serialize) -> String {
-    serde_json::to_string(ser).unwrap_or_else(|_| {
-        panic!("unable to turn {} to a json", core::any::type_name_of_val(ser))
-    })
-}
-
-#[derive(Clone, Deserialize, Serialize)]
-pub struct AutofillData {
-    name: Option<[String; 2]>,
-    email: Option<String>,
-    phone_number: Option<String>,
-    id: Option<String>,
-    /// stored as a base64 image
-    profile_picture: Option<String>,
-    /// stored as a base64 image
-    license: Option<String>,
-    id_image: Option<String>,
-}
-
-
-#[derive(Copy, Clone, Deserialize, Serialize)]
-pub struct RequestedAutofillFields {
-    #[serde(default)]
-    name: bool,
-    #[serde(default)]
-    email: bool,
-    #[serde(default)]
-    phone_number: bool,
-    #[serde(default)]
-    id: bool,
-    #[serde(default)]
-    profile_picture: bool,
-    #[serde(default)]
-    license: bool,
-    #[serde(default)]
-    id_image: bool,
-}
-
-
-struct PendingRequest {
-    notify: oneshot::Sender<AutofillData>,
-    data_requested: RequestedAutofillFields,
-    expires_at: Instant,
-}
-
-static MAP: LazyLock<DashMap<RequestCode, PendingRequest>> = LazyLock::new(DashMap::new);
-
-pub fn new_request(
-    selected: RequestedAutofillFields
-) -> (RequestCode, tokio::time::Timeout<oneshot::Receiver<AutofillData>>) {
-    loop {
-        let code = RequestCode::new_rand();
-        match MAP.entry(code) {
-            Entry::Occupied(_) => continue,
-            Entry::Vacant(vacant) => {
-                let (tx, rx) = oneshot::channel();
-                let timeout = Instant::now()
-                    // 3 minutes offically but also add undocumented non guarenteed 3s
-                    // grace
-                    .checked_add(Duration::from_secs(3 * 60 + 3))
-                    .unwrap();
-
-                vacant.insert(PendingRequest {
-                    notify: tx,
-                    data_requested: selected,
-                    expires_at: timeout,
-                });
-
-                break (code, tokio::time::timeout_at(timeout.into(), rx))
-            }
-        }
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let args = cli::Args::load();
+    logging::init(args.log_level, args.log_level_pinned);
+    if let Some(seed) = args.code_seed {
+        req_code::seed(seed);
     }
-}
-
-
-#[get("/listen")]
-async fn listen(req: HttpRequest, body: web::Payload) -> actix_web::Result<impl Responder> {
-    let (response, mut session, mut msg_stream) =
-        actix_ws::handle(&req, body)?;
-
-    actix_web::rt::spawn(async move {
-        let Some(Ok(Message::Text(json))) = msg_stream.recv().await else {
-            let _ = session.close(Some((CloseCode::Error, "expected JSON specification").into())).await;
-            return;
-        };
-
-        let Ok(request) = serde_json::from_str(&json) else {
-            let _ = session.close(Some((CloseCode::Error, "invalid data specification JSON").into())).await;
-            return;
-        };
-
-        let (code, data_rcv) = new_request(request);
-
-        let Ok(()) = session.text(code.as_str()).await else {
-            // web socket closed
-            return;
-        };
-
-        let close = match data_rcv.await {
-            Ok(Ok(data)) => {
-                let _ = session.text(to_json_str(&data)).await;
-                session.close(None)
-            }
-            Ok(Err(_)) | Err(_) => {
-                session.close(Some((CloseCode::Error, "request timed out").into()))
-            }
-        };
-
-        let _ = close.await;
-    });
-
-    Ok(response)
-}
-
-#[post("/requests/{code}")]
-async fn resolve(code: web::Path<RequestCode>, data: web::Json<AutofillData>) -> impl Responder {
-    let code = code.into_inner();
-    let entry = MAP.remove(&code)
-        .map(|(_, data)| data)
-        .filter(|data| Instant::now() <= data.expires_at);
-
-    let Some(request) = entry else {
-        return HttpResponse::NotFound()
-    };
-
-    if request.notify.send(data.into_inner()).is_err() {
-        // it was aproved, but nobody is listening
-        return HttpResponse::Accepted()
+    if let Some(command) = &args.command
+        && !matches!(command, cli::Command::Serve)
+    {
+        return ops::run(command).await
     }
 
-    HttpResponse::Ok()
-}
-
-
-#[get("/requests/{code}")]
-async fn fetch(code: web::Path<RequestCode>) -> impl Responder {
-    let code = code.into_inner();
-    let entry = MAP.get(&code)
-        .filter(|data| Instant::now() <= data.expires_at)
-        .map(|entry| entry.data_requested);
-
-    let Some(request) = entry else {
-        return HttpResponse::NotFound().finish()
-    };
-
-    HttpResponse::Ok().body(to_json_str(&request))
-}
-
-
-#[get("/")]
-async fn index_page() -> impl Responder {
-    "why are you on the index page of an API????"
-}
-
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    pretty_env_logger::formatted_builder()
-        .filter_level(LevelFilter::Info)
-        .init();
-    log::set_max_level(LevelFilter::Info);
+    log::info!("starting {} {} ({})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), env!("ABSHER_GIT_SHA"));
+    let _telemetry = telemetry::init();
+
+    denylist::load();
+    blocklist::load();
+    devices::load();
+    metering::load();
+    signing::init();
+    audit::start();
+    broker::start();
+    geo::init();
+    req_code::report_guess_budget(lockout::max_guesses_per_hour());
+    if args.dev {
+        demo::enable();
+    }
 
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
-    defer::defer(move || {
+    let _shutdown = defer::defer(move || {
         let _ = shutdown_tx.send(());
     });
 
-
-    let remove_expried = async move {
-        let map = LazyLock::force(&MAP);
-        loop {
-            tokio::time::sleep(Duration::from_secs(360)).await;
-            let now = Instant::now();
-            map.retain(|_code, data| now < data.expires_at);
-        }
-    };
-
     tokio::spawn(async move {
         tokio::select! {
             _ = shutdown_rx => {},
-            _ = remove_expried => {}
+            _ = store::sweep() => {}
         }
     });
 
-    let app_builder = || {
-        App::new()
-            .service(index_page)
-            .service(listen)
-            .service(resolve)
-            .service(fetch)
-            .wrap(Logger::default())
-            .wrap(actix_web::middleware::Compress::default())
-            .wrap(actix_cors::Cors::permissive())
-    };
+    tokio::spawn(expiry::run(store::expire_due));
+    tokio::spawn(config::reload_on_hangup());
+    tokio::spawn(code_pool::run(store::is_free));
+    tokio::spawn(metering::run());
+    history::start().await.map_err(std::io::Error::other)?;
+
+    // signals are ours to handle, listeners have to be told before the sockets go away
+    let mut server = HttpServer::new(handlers::app)
+        .disable_signals()
+        .shutdown_timeout(shutdown::GRACE.as_secs());
+    if let Some(workers) = args.workers {
+        server = server.workers(workers);
+    }
 
-    let sock = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 80));
+    let tls = TlsSettings::from_env();
+    let config = tls.as_ref().map(TlsSettings::server_config).transpose()?;
+    let sockets = args.sockets(if tls.is_some() { 443 } else { 80 });
+    if let Some(port) = args.grpc_port {
+        grpc::start(&args.ips(), port, config.clone())?;
+    }
+    let passed = systemd::listeners();
+    if args.wants_tcp(!passed.is_empty()) {
+        for &sock in &sockets {
+            server = match &config {
+                Some(config) => {
+                    log::info!("listening on {sock} with tls");
+                    server.bind_rustls_0_23(sock, config.clone())?
+                }
+                None => {
+                    log::info!("listening on {sock}");
+                    server.bind(sock)?
+                }
+            };
+        }
+    }
 
-    log::info!("listening on {sock}");
+    // a unix socket peer has no address, only the proxy behind it can say who its clients are
+    #[cfg(unix)]
+    if !absher_zt_backend::peer::trusts_proxy()
+        && (args.unix_socket.is_some() || passed.iter().any(|listener| matches!(listener, systemd::Listener::Unix(_))))
+    {
+        log::warn!("no ABSHER_TRUSTED_PROXY, lockout, rate limits and connection caps are off for clients on unix sockets");
+    }
+
+    // unix sockets are for a proxy on the same host, which does the tls
+    for listener in passed {
+        server = match listener {
+            systemd::Listener::Tcp(listener) => {
+                log::info!("listening on {} passed in by systemd", listener.local_addr()?);
+                match &config {
+                    Some(config) => server.listen_rustls_0_23(listener, config.clone())?,
+                    None => server.listen(listener)?,
+                }
+            }
+            #[cfg(unix)]
+            systemd::Listener::Unix(listener) => {
+                log::info!("listening on a unix socket passed in by systemd");
+                server.listen_uds(listener)?
+            }
+        };
+    }
+    #[cfg(unix)]
+    if let Some(path) = &args.unix_socket {
+        use std::os::unix::fs::PermissionsExt;
+        log::info!("listening on {}", path.display());
+        server = server.bind_uds(path)?;
+        if let Some(mode) = args.unix_socket_mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
 
-    HttpServer::new(app_builder)
-        .bind(sock)?
-        .run()
-        .await
-}
\ No newline at end of file
+    if let (Some(port), Some(config)) = (tls.as_ref().and_then(|tls| tls.http3_port), &config) {
+        quic::start(&args.ips(), port, config.clone())?;
+    }
+    let server = server.run();
+    systemd::notify("READY=1");
+
+    match tls.and_then(|tls| tls.redirect_port) {
+        Some(port) => {
+            log::info!("redirecting plain http on port {port} to https");
+            let redirect = tls::redirect_server(&args.ips(), port, sockets[0].port())?;
+            actix_web::rt::spawn(shutdown::on_signal(vec![server.handle(), redirect.handle()]));
+            tokio::try_join!(server, redirect).map(|_| ())
+        }
+        None => {
+            actix_web::rt::spawn(shutdown::on_signal(vec![server.handle()]));
+            server.await
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::Duration;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::config::setting;
+use crate::error::AppError;
+use crate::model::RequestedAutofillFields;
+use crate::{broker, env_or, health, identity, unix_now};
+
+/// how often each tenant's usage since the last record is published
+static INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_METERING_INTERVAL_SECS", 60 * 60).max(1))
+});
+/// how many days of usage the report goes back
+static RETENTION_DAYS: LazyLock<u64> = LazyLock::new(|| env_or("ABSHER_METERING_RETENTION_DAYS", 400));
+/// where daily usage survives restarts, nothing is persisted when unset
+static FILE: LazyLock<Option<PathBuf>> = LazyLock::new(|| setting("ABSHER_METERING_FILE").map(PathBuf::from));
+
+/// what a tenant used, requests are billed by the fields they ask for
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub resolved: u64,
+    /// requests that asked for each field
+    pub fields: BTreeMap<String, u64>,
+    /// resolved requests that asked for each field
+    pub resolved_fields: BTreeMap<String, u64>,
+}
+
+impl Usage {
+    fn count(&mut self, resolved: bool, fields: &[&'static str]) {
+        let (total, by_field) = match resolved {
+            true => (&mut self.resolved, &mut self.resolved_fields),
+            false => (&mut self.requests, &mut self.fields),
+        };
+
+        *total += 1;
+        for field in fields {
+            *by_field.entry((*field).to_owned()).or_default() += 1;
+        }
+    }
+}
+
+/// usage by tenant and utc day
+static DAYS: LazyLock<DashMap<(String, String), Usage>> = LazyLock::new(DashMap::new);
+/// usage by tenant since the last record was published
+static UNREPORTED: LazyLock<DashMap<String, Usage>> = LazyLock::new(DashMap::new);
+/// serializes writers so the file always matches some recent state of the map
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+fn date(unix: u64) -> String {
+    let (year, month, day) = identity::civil_date(unix);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn count(tenant: Option<&str>, resolved: bool, fields: &RequestedAutofillFields) {
+    // keyless listeners aren't anyone's to bill
+    let Some(tenant) = tenant else {
+        return
+    };
+
+    let fields = fields.names();
+    DAYS.entry((tenant.to_owned(), date(unix_now()))).or_default().count(resolved, &fields);
+    UNREPORTED.entry(tenant.to_owned()).or_default().count(resolved, &fields);
+}
+
+/// a request opened for `tenant`
+pub fn created(tenant: Option<&str>, fields: &RequestedAutofillFields) {
+    count(tenant, false, fields);
+}
+
+/// a request of `tenant` that was answered
+pub fn resolved(tenant: Option<&str>, fields: &RequestedAutofillFields) {
+    count(tenant, true, fields);
+}
+
+#[derive(Serialize)]
+pub struct DailyUsage {
+    tenant: String,
+    /// `YYYY-MM-DD`, utc
+    date: String,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+/// daily usage from `from` through `to`, both `YYYY-MM-DD` and either left open, for one tenant
+/// or all of them
+pub fn report(tenant: Option<&str>, from: Option<&str>, to: Option<&str>) -> Result<Vec<DailyUsage>, AppError> {
+    if from.is_some_and(|from| identity::parse_date(from).is_none()) {
+        return Err(AppError::InvalidDate { field: "from" })
+    }
+    if to.is_some_and(|to| identity::parse_date(to).is_none()) {
+        return Err(AppError::InvalidDate { field: "to" })
+    }
+
+    // dates written this way sort the way the days do
+    let mut days = DAYS.iter()
+        .filter(|entry| tenant.is_none_or(|tenant| entry.key().0 == tenant))
+        .filter(|entry| from.is_none_or(|from| entry.key().1.as_str() >= from))
+        .filter(|entry| to.is_none_or(|to| entry.key().1.as_str() <= to))
+        .map(|entry| DailyUsage { tenant: entry.key().0.clone(), date: entry.key().1.clone(), usage: entry.value().clone() })
+        .collect::<Vec<_>>();
+    days.sort_by(|a, b| (&a.date, &a.tenant).cmp(&(&b.date, &b.tenant)));
+    Ok(days)
+}
+
+/// publishes each tenant's usage since the last time, drops days past the retention and saves
+/// the rest, for as long as the server runs
+pub async fn run() {
+    let _watch = health::watch("metering");
+    let mut since = unix_now();
+    loop {
+        tokio::time::sleep(*INTERVAL).await;
+        let now = unix_now();
+
+        let tenants = UNREPORTED.iter().map(|entry| entry.key().clone()).collect::<Vec<_>>();
+        for tenant in tenants {
+            let Some((_, usage)) = UNREPORTED.remove(&tenant) else {
+                continue
+            };
+            let Ok(Value::Object(mut details)) = serde_json::to_value(&usage) else {
+                continue
+            };
+            details.insert("from".to_owned(), since.into());
+            details.insert("to".to_owned(), now.into());
+            broker::publish("usage", Some(&tenant), details);
+        }
+        since = now;
+
+        let oldest = date(now.saturating_sub(*RETENTION_DAYS * 24 * 60 * 60));
+        DAYS.retain(|(_tenant, day), _usage| *day >= oldest);
+        save();
+    }
+}
+
+/// reads back the daily usage from the last run
+pub fn load() {
+    let Some(path) = FILE.as_deref() else {
+        return
+    };
+
+    let days = match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<Vec<(String, String, Usage)>>(&bytes),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return,
+        Err(err) => {
+            log::error!("unable to read usage {}: {err}", path.display());
+            return
+        }
+    };
+
+    match days {
+        Ok(days) => {
+            for (tenant, day, usage) in days {
+                DAYS.insert((tenant, day), usage);
+            }
+        }
+        Err(err) => log::error!("usage {} is corrupt: {err}", path.display()),
+    }
+}
+
+fn save() {
+    let Some(path) = FILE.as_deref() else {
+        return
+    };
+
+    let _guard = SAVE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let days = DAYS.iter()
+        .map(|entry| (entry.key().0.clone(), entry.key().1.clone(), entry.value().clone()))
+        .collect::<Vec<_>>();
+
+    // write then rename so a crash mid write never leaves half a file behind
+    let tmp = path.with_extension("tmp");
+    let written = serde_json::to_vec(&days)
+        .map_err(std::io::Error::other)
+        .and_then(|bytes| std::fs::write(&tmp, bytes))
+        .and_then(|()| std::fs::rename(&tmp, path));
+
+    if let Err(err) = written {
+        log::error!("unable to persist usage to {}: {err}", path.display());
+    }
+}
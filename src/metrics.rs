@@ -0,0 +1,249 @@
+use std::fmt;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use actix_web::http::StatusCode;
+use awc::error::SendRequestError;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use crate::error::AppError;
+use crate::stats;
+
+pub struct Metrics {
+    registry: Registry,
+    pub pending: IntGauge,
+    pub created: IntCounter,
+    /// by how requests left the pending map, everything but resolved and expired is a cancellation
+    pub ended: IntCounterVec,
+    /// listener websockets that are open right now
+    pub listeners: IntGauge,
+    /// listeners by which protocol they spoke
+    pub protocols: IntCounterVec,
+    /// from a resolve arriving to its data being handed to the listener
+    pub resolve_seconds: Histogram,
+    /// fetches of codes that don't exist, which is what guessing looks like
+    pub fetch_misses: IntCounter,
+    pub lockouts: IntCounter,
+    /// entries in the in-memory stores besides the pending map
+    pub store_entries: IntGaugeVec,
+    pub code_pool_depth: IntGaugeVec,
+    /// codes that had to be drawn on the spot because the pool ran dry
+    pub code_pool_misses: IntCounterVec,
+    /// listeners turned away because too many requests were open, globally or for their tenant
+    pub capacity_rejections: IntCounterVec,
+    /// answers from another country than the listener's, by whether they were let through
+    pub country_mismatches: IntCounterVec,
+    /// from a text, push or webhook being queued to its receiver taking it, by channel
+    pub delivery_seconds: HistogramVec,
+    /// failed attempts by channel and cause
+    pub delivery_failures: IntCounterVec,
+    /// texts, pushes and webhooks on their way, by channel
+    pub delivery_queue: IntGaugeVec,
+    /// deliveries waiting out a pause before their next attempt, by channel
+    pub delivery_retries: IntGaugeVec,
+    /// request history changes lost because the writer fell behind
+    pub history_dropped: IntCounter,
+    /// tenants that used up most of a day's texts
+    pub sms_quota_warnings: IntCounter,
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(registry: &Registry, metric: T) -> T {
+    registry.register(Box::new(metric.clone())).expect("metric names to be unique");
+    metric
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new_custom(Some("absher".to_owned()), None).expect("a valid prefix");
+
+    let pending = IntGauge::new("pending_requests", "requests waiting for an answer").unwrap();
+    let created = IntCounter::new("requests_created_total", "requests handed out to listeners").unwrap();
+    let ended = IntCounterVec::new(
+        Opts::new("requests_ended_total", "requests that stopped being pending"),
+        &["outcome"],
+    ).unwrap();
+    let listeners = IntGauge::new("websocket_connections", "open listener websockets").unwrap();
+    let protocols = IntCounterVec::new(
+        Opts::new("listeners_total", "listeners that sent a valid specification"),
+        &["protocol"],
+    ).unwrap();
+    let resolve_seconds = Histogram::with_opts(HistogramOpts::new(
+        "resolve_duration_seconds",
+        "time to accept a resolve and hand its data to the listener",
+    ).buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0])).unwrap();
+    let fetch_misses = IntCounter::new("fetch_misses_total", "fetches of unknown codes").unwrap();
+    let lockouts = IntCounter::new("lockouts_total", "clients locked out for guessing codes").unwrap();
+    let store_entries = IntGaugeVec::new(
+        Opts::new("store_entries", "entries held in memory"),
+        &["store"],
+    ).unwrap();
+    let code_pool_depth = IntGaugeVec::new(
+        Opts::new("code_pool_depth", "pregenerated codes ready to be handed out"),
+        &["kind"],
+    ).unwrap();
+    let code_pool_misses = IntCounterVec::new(
+        Opts::new("code_pool_misses_total", "codes generated on the spot because the pool was empty"),
+        &["kind"],
+    ).unwrap();
+    let capacity_rejections = IntCounterVec::new(
+        Opts::new("capacity_rejections_total", "listeners turned away because too many requests were open"),
+        &["scope"],
+    ).unwrap();
+    let country_mismatches = IntCounterVec::new(
+        Opts::new("country_mismatches_total", "answers from another country than the listener was in"),
+        &["action"],
+    ).unwrap();
+    let delivery_seconds = HistogramVec::new(HistogramOpts::new(
+        "delivery_duration_seconds",
+        "time from queueing a text, push or webhook to its receiver taking it",
+    ).buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 180.0]), &["channel"]).unwrap();
+    let delivery_failures = IntCounterVec::new(
+        Opts::new("delivery_failures_total", "failed attempts at texting, pushing or posting a webhook"),
+        &["channel", "cause"],
+    ).unwrap();
+    let delivery_queue = IntGaugeVec::new(
+        Opts::new("delivery_queue", "texts, pushes and webhooks not delivered or given up on yet"),
+        &["channel"],
+    ).unwrap();
+    let delivery_retries = IntGaugeVec::new(
+        Opts::new("delivery_retries", "deliveries waiting to be tried again"),
+        &["channel"],
+    ).unwrap();
+    let history_dropped = IntCounter::new(
+        "history_dropped_total",
+        "request history changes dropped because the database fell behind",
+    ).unwrap();
+    let sms_quota_warnings = IntCounter::new(
+        "sms_quota_warnings_total",
+        "tenants that reached the warning share of their daily text quota",
+    ).unwrap();
+
+    Metrics {
+        pending: register(&registry, pending),
+        created: register(&registry, created),
+        ended: register(&registry, ended),
+        listeners: register(&registry, listeners),
+        protocols: register(&registry, protocols),
+        resolve_seconds: register(&registry, resolve_seconds),
+        fetch_misses: register(&registry, fetch_misses),
+        lockouts: register(&registry, lockouts),
+        store_entries: register(&registry, store_entries),
+        code_pool_depth: register(&registry, code_pool_depth),
+        code_pool_misses: register(&registry, code_pool_misses),
+        capacity_rejections: register(&registry, capacity_rejections),
+        country_mismatches: register(&registry, country_mismatches),
+        delivery_seconds: register(&registry, delivery_seconds),
+        delivery_failures: register(&registry, delivery_failures),
+        delivery_queue: register(&registry, delivery_queue),
+        delivery_retries: register(&registry, delivery_retries),
+        history_dropped: register(&registry, history_dropped),
+        sms_quota_warnings: register(&registry, sms_quota_warnings),
+        registry,
+    }
+});
+
+/// counts an open websocket for as long as it is alive, for its tenant as well
+pub struct ListenerGuard {
+    tenant: Option<String>,
+}
+
+impl ListenerGuard {
+    pub fn new(tenant: Option<&str>) -> Self {
+        METRICS.listeners.inc();
+        if let Some(tenant) = tenant {
+            stats::listener_opened(tenant);
+        }
+        Self { tenant: tenant.map(str::to_owned) }
+    }
+}
+
+impl Drop for ListenerGuard {
+    fn drop(&mut self) {
+        METRICS.listeners.dec();
+        if let Some(tenant) = &self.tenant {
+            stats::listener_closed(tenant);
+        }
+    }
+}
+
+/// why a text, push or webhook didn't go through
+pub struct DeliveryError {
+    /// what the failure is counted under, `timeout`, `connect`, `send` or `status`
+    pub cause: &'static str,
+    pub message: String,
+}
+
+impl DeliveryError {
+    /// the receiver answered, but not with a success
+    pub fn status(status: StatusCode) -> Self {
+        Self { cause: "status", message: format!("answered {status}") }
+    }
+}
+
+impl From<SendRequestError> for DeliveryError {
+    fn from(err: SendRequestError) -> Self {
+        let cause = match err {
+            SendRequestError::Timeout => "timeout",
+            SendRequestError::Connect(_) => "connect",
+            _ => "send",
+        };
+        Self { cause, message: err.to_string() }
+    }
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// one text, push or webhook on its way, queued until it is dropped
+pub struct Delivery {
+    channel: &'static str,
+    queued: Instant,
+}
+
+impl Delivery {
+    /// `channel` is `sms`, `push` or `webhook`
+    pub fn queue(channel: &'static str) -> Self {
+        METRICS.delivery_queue.with_label_values(&[channel]).inc();
+        Self { channel, queued: Instant::now() }
+    }
+
+    pub fn delivered(&self) {
+        METRICS.delivery_seconds.with_label_values(&[self.channel]).observe(self.queued.elapsed().as_secs_f64());
+    }
+
+    pub fn failed(&self, cause: &str) {
+        METRICS.delivery_failures.with_label_values(&[self.channel, cause]).inc();
+    }
+
+    /// waits out `pause` before the next attempt, counted as a retry meanwhile
+    pub async fn retry_after(&self, pause: Duration) {
+        let retries = METRICS.delivery_retries.with_label_values(&[self.channel]);
+        retries.inc();
+        tokio::time::sleep(pause).await;
+        retries.dec();
+    }
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        METRICS.delivery_queue.with_label_values(&[self.channel]).dec();
+    }
+}
+
+/// everything in the prometheus text format, with gauges that are sampled at scrape time set first
+pub fn render(pending: usize, stores: &[(&str, usize)]) -> Result<String, AppError> {
+    METRICS.pending.set(pending as i64);
+    for (store, entries) in stores {
+        METRICS.store_entries.with_label_values(&[store]).set(*entries as i64);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer).map_err(|err| {
+        log::error!("unable to encode metrics: {err}");
+        AppError::Serialization
+    })?;
+
+    String::from_utf8(buffer).map_err(|_| AppError::Serialization)
+}
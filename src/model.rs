@@ -0,0 +1,594 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use actix_web::web;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use zeroize::Zeroize;
+use crate::classify::DocumentKind;
+use crate::custom_fields::CustomValue;
+use crate::error::AppError;
+use crate::hash_checks::HashChecks;
+use crate::identity::{AgeOver, Address, Document, DocumentFields, Gender, Name, NameFields};
+use crate::ocr::Verification;
+use crate::redact::Presence;
+use crate::req_code::RequestCode;
+use crate::{admin, classify, identity, images, ocr, receipt, uploads, validate, watermark};
+
+#[derive(Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct AutofillData {
+    pub name: Option<Name>,
+    pub email: Option<String>,
+    pub phone_number: Option<String>,
+    pub id: Option<String>,
+    /// `YYYY-MM-DD`, gregorian
+    pub date_of_birth: Option<String>,
+    /// ISO 3166-1 alpha-2, like `SA`
+    pub nationality: Option<String>,
+    pub gender: Option<Gender>,
+    pub address: Option<Address>,
+    /// stored as a base64 image
+    #[serde(default, deserialize_with = "images::base64_or_bytes")]
+    pub profile_picture: Option<String>,
+    /// stored as a base64 image, `driving_license` carries it along with its details
+    #[serde(default, deserialize_with = "images::base64_or_bytes")]
+    pub license: Option<String>,
+    #[serde(default, deserialize_with = "images::base64_or_bytes")]
+    pub id_image: Option<String>,
+    pub passport: Option<Document>,
+    pub driving_license: Option<Document>,
+    /// answers to the listener's custom fields, by key
+    #[serde(default)]
+    pub custom_fields: BTreeMap<String, CustomValue>,
+    /// worked out here from the date of birth, apps can't send it themselves
+    #[serde(default, deserialize_with = "server_only")]
+    #[schema(read_only)]
+    pub age_over: Option<AgeOver>,
+    /// whether the resolver's values match the listener's hash checks, by field
+    #[serde(default, deserialize_with = "server_only", skip_serializing_if = "BTreeMap::is_empty")]
+    #[schema(read_only)]
+    pub hash_matches: BTreeMap<String, bool>,
+}
+
+/// drops whatever an app sent for a field only the server fills in; `skip_deserializing` would do
+/// the same but also leave the field out of the schema listeners are generated from
+fn server_only<'de, D: serde::Deserializer<'de>, T: Default>(deserializer: D) -> Result<T, D::Error> {
+    serde::de::IgnoredAny::deserialize(deserializer)?;
+    Ok(T::default())
+}
+
+/// wipes everything that was shared once the data is dropped, whether it was relayed, expired
+/// with its request or turned away, so no id image lingers in freed memory
+impl Drop for AutofillData {
+    fn drop(&mut self) {
+        self.name.zeroize();
+        self.email.zeroize();
+        self.phone_number.zeroize();
+        self.id.zeroize();
+        self.date_of_birth.zeroize();
+        self.nationality.zeroize();
+        self.address.zeroize();
+        self.profile_picture.zeroize();
+        self.license.zeroize();
+        self.id_image.zeroize();
+        self.passport.zeroize();
+        self.driving_license.zeroize();
+        self.custom_fields.values_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+/// only says which fields were shared, a stray debug log must not dump an id image
+impl fmt::Debug for AutofillData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AutofillData")
+            .field("name", &Presence(&self.name))
+            .field("email", &Presence(&self.email))
+            .field("phone_number", &Presence(&self.phone_number))
+            .field("id", &Presence(&self.id))
+            .field("date_of_birth", &Presence(&self.date_of_birth))
+            .field("nationality", &Presence(&self.nationality))
+            .field("gender", &Presence(&self.gender))
+            .field("address", &Presence(&self.address))
+            .field("profile_picture", &Presence(&self.profile_picture))
+            .field("license", &Presence(&self.license))
+            .field("id_image", &Presence(&self.id_image))
+            .field("passport", &Presence(&self.passport))
+            .field("driving_license", &Presence(&self.driving_license))
+            .field("custom_fields", &self.custom_fields.keys())
+            .field("age_over", &Presence(&self.age_over))
+            .field("hash_matches", &self.hash_matches.len())
+            .finish()
+    }
+}
+
+impl AutofillData {
+    /// the fields that carry a value, the way `RequestedAutofillFields::names` names them
+    pub(crate) fn shared_fields(&self) -> Vec<&'static str> {
+        let shared = [
+            ("name", self.name.is_some()),
+            ("email", self.email.is_some()),
+            ("phone_number", self.phone_number.is_some()),
+            ("id", self.id.is_some()),
+            ("date_of_birth", self.date_of_birth.is_some()),
+            ("age_over", self.age_over.is_some()),
+            ("nationality", self.nationality.is_some()),
+            ("gender", self.gender.is_some()),
+            ("address", self.address.is_some()),
+            ("profile_picture", self.profile_picture.is_some()),
+            ("license", self.license.is_some()),
+            ("id_image", self.id_image.is_some()),
+            ("passport", self.passport.is_some()),
+            ("driving_license", self.driving_license.is_some()),
+            ("custom_fields", !self.custom_fields.is_empty()),
+        ];
+
+        shared.into_iter().filter(|(_, shared)| *shared).map(|(name, _)| name).collect()
+    }
+
+    /// compares the typed id number with the one printed on the id image, if both were shared
+    #[tracing::instrument(target = "absher::trace", skip_all)]
+    pub(crate) async fn cross_check(&self) -> Option<Verification> {
+        let (Some(id), Some(image)) = (&self.id, &self.id_image) else {
+            return None
+        };
+
+        Some(Verification { id_number: ocr::cross_check(id, image).await? })
+    }
+
+    /// a date of birth an age can be told from, when the listener asked for one
+    pub(crate) fn check_age(&self, fields: &RequestedAutofillFields) -> Result<(), AppError> {
+        let told = self.date_of_birth.as_deref().and_then(identity::parse_date);
+        match fields.age_over.is_some() && told.is_none() {
+            true => Err(AppError::InvalidDate { field: "date_of_birth" }),
+            false => Ok(()),
+        }
+    }
+
+    /// answers the listener's `age_over` and drops the date of birth, unless it was asked for too
+    pub(crate) fn attest_age(&mut self, fields: &RequestedAutofillFields, code: RequestCode, at: u64) -> Result<(), AppError> {
+        let Some(age) = fields.age_over else {
+            return Ok(())
+        };
+        let years = self.date_of_birth.as_deref()
+            .and_then(|date| identity::age(date, at))
+            .ok_or(AppError::InvalidDate { field: "date_of_birth" })?;
+
+        let over = years >= i64::from(age);
+        let attestation = receipt::attest_age(code, age, over, at)?;
+        self.age_over = Some(AgeOver { age, over, attestation });
+        if !fields.date_of_birth {
+            self.date_of_birth.zeroize();
+        }
+
+        Ok(())
+    }
+
+    /// checks the formats of the text fields, all of them at once so the app can point at every
+    /// entry to fix, and writes phone numbers in E.164
+    pub fn validate(&mut self) -> Result<(), AppError> {
+        let mut invalid = BTreeMap::new();
+        let mut check = |field: &'static str, value: &Option<String>, problem: fn(&str) -> Option<&'static str>| {
+            if let Some(reason) = value.as_deref().and_then(problem) {
+                invalid.insert(field, reason);
+            }
+        };
+
+        check("email", &self.email, validate::email);
+        check("id", &self.id, validate::national_id);
+        check("date_of_birth", &self.date_of_birth, validate::date);
+        check("nationality", &self.nationality, validate::country);
+        let documents = [
+            ("passport.issued_on", "passport.expires_on", &self.passport),
+            ("driving_license.issued_on", "driving_license.expires_on", &self.driving_license),
+        ];
+        for (issued_on, expires_on, document) in documents {
+            if let Some(document) = document {
+                check(issued_on, &document.issued_on, validate::date);
+                check(expires_on, &document.expires_on, validate::date);
+            }
+        }
+
+        if let Some(phone) = &mut self.phone_number {
+            match validate::phone_number(phone) {
+                Ok(e164) => *phone = e164,
+                Err(reason) => {
+                    invalid.insert("phone_number", reason);
+                }
+            }
+        }
+
+        match invalid.is_empty() {
+            true => Ok(()),
+            false => Err(AppError::InvalidFields(invalid)),
+        }
+    }
+
+    /// compares the resolver's values to the listener's hashes, values that were only shared to be
+    /// compared are dropped
+    pub(crate) fn check_hashes(&mut self, checks: &HashChecks, fields: &RequestedAutofillFields) {
+        for field in checks.fields() {
+            let (value, requested) = match field {
+                "email" => (&mut self.email, fields.email),
+                "phone_number" => (&mut self.phone_number, fields.phone_number),
+                "id" => (&mut self.id, fields.id),
+                "date_of_birth" => (&mut self.date_of_birth, fields.date_of_birth),
+                "nationality" => (&mut self.nationality, fields.nationality),
+                _ => continue,
+            };
+
+            let matched = value.as_deref().is_some_and(|value| checks.matches(field, value));
+            if !requested {
+                value.zeroize();
+            }
+            self.hash_matches.insert(field.to_owned(), matched);
+        }
+    }
+
+    /// every image that was shared, by its field and the document it has to show
+    pub(crate) fn images(&self) -> Vec<(&'static str, DocumentKind, &str)> {
+        fn document_image(document: &Option<Document>) -> Option<&str> {
+            document.as_ref().and_then(|document| document.image.as_deref())
+        }
+        let images = [
+            ("profile_picture", DocumentKind::Portrait, self.profile_picture.as_deref()),
+            ("license", DocumentKind::License, self.license.as_deref()),
+            ("id_image", DocumentKind::IdCard, self.id_image.as_deref()),
+            ("passport.image", DocumentKind::Passport, document_image(&self.passport)),
+            ("driving_license.image", DocumentKind::License, document_image(&self.driving_license)),
+        ];
+
+        images.into_iter().filter_map(|(field, kind, image)| Some((field, kind, image?))).collect()
+    }
+
+    pub(crate) fn images_mut(&mut self) -> Vec<(&'static str, DocumentKind, &mut String)> {
+        let images = [
+            ("profile_picture", DocumentKind::Portrait, self.profile_picture.as_mut()),
+            ("license", DocumentKind::License, self.license.as_mut()),
+            ("id_image", DocumentKind::IdCard, self.id_image.as_mut()),
+            ("passport.image", DocumentKind::Passport, self.passport.as_mut().and_then(|document| document.image.as_mut())),
+            ("driving_license.image", DocumentKind::License, self.driving_license.as_mut().and_then(|document| document.image.as_mut())),
+        ];
+
+        images.into_iter().filter_map(|(field, kind, image)| Some((field, kind, image?))).collect()
+    }
+
+    pub(crate) fn normalize_images(&mut self) -> Result<(), AppError> {
+        for (field, _, image) in self.images_mut() {
+            let normalized = images::normalize(field, image)?;
+            std::mem::replace(image, normalized).zeroize();
+        }
+
+        Ok(())
+    }
+
+    /// stamps the document images, images that fail to decode are relayed as they are
+    pub(crate) fn watermark(&mut self, mark: &watermark::Mark) {
+        for (_, kind, image) in self.images_mut() {
+            if kind != DocumentKind::Portrait
+                && let Some(marked) = mark.apply(image)
+            {
+                std::mem::replace(image, marked).zeroize();
+            }
+        }
+    }
+}
+
+/// checks and re-encodes every image in `payload`, off the async threads for the same reason
+/// watermarking is
+#[tracing::instrument(target = "absher::trace", skip_all)]
+pub(crate) async fn normalize_payload(payload: Payload) -> Result<Payload, AppError> {
+    let Payload::Plain(mut data) = payload else {
+        return Ok(payload)
+    };
+
+    let normalized = web::block(move || {
+        data.normalize_images()?;
+        Ok(data)
+    });
+
+    match normalized.await {
+        Ok(data) => data.map(Payload::Plain),
+        Err(err) => {
+            log::error!("image normalizing thread failed: {err}");
+            Err(AppError::Serialization)
+        }
+    }
+}
+
+/// marks the documents in `payload` for the tenant that asked for them, off the async threads
+/// since it decodes and re-encodes every image
+#[tracing::instrument(target = "absher::trace", skip_all)]
+pub(crate) async fn watermark_payload(
+    payload: Payload,
+    tenant: Option<&str>,
+    code: RequestCode,
+    at: u64,
+) -> Result<Payload, AppError> {
+    // sealed data can't be touched, which is the point of it
+    let Payload::Plain(mut data) = payload else {
+        return Ok(payload)
+    };
+
+    let Some((mode, name)) = tenant.and_then(admin::tenant_watermark) else {
+        return Ok(Payload::Plain(data))
+    };
+
+    if data.images().iter().all(|(_, kind, _)| *kind == DocumentKind::Portrait) {
+        return Ok(Payload::Plain(data))
+    }
+
+    let mark = watermark::Mark::new(mode, &name, tenant.unwrap_or_default(), code, at);
+    let marked = web::block(move || {
+        data.watermark(&mark);
+        data
+    });
+
+    marked.await.map(Payload::Plain).map_err(|err| {
+        log::error!("watermarking thread failed: {err}");
+        AppError::Serialization
+    })
+}
+
+#[derive(Copy, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct RequestedAutofillFields {
+    #[serde(default)]
+    pub name: NameFields,
+    #[serde(default)]
+    pub email: bool,
+    #[serde(default)]
+    pub phone_number: bool,
+    #[serde(default)]
+    pub id: bool,
+    #[serde(default)]
+    pub date_of_birth: bool,
+    /// only whether the resolver is at least this old, without the date of birth itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age_over: Option<u8>,
+    #[serde(default)]
+    pub nationality: bool,
+    #[serde(default)]
+    pub gender: bool,
+    #[serde(default)]
+    pub address: bool,
+    #[serde(default)]
+    pub profile_picture: bool,
+    #[serde(default)]
+    pub license: bool,
+    #[serde(default)]
+    pub id_image: bool,
+    #[serde(default)]
+    pub passport: DocumentFields,
+    #[serde(default)]
+    pub driving_license: DocumentFields,
+}
+
+impl RequestedAutofillFields {
+    /// true if every field requested here is also requested by `allowed`
+    pub fn is_within(&self, allowed: &RequestedAutofillFields) -> bool {
+        let requested = [
+            (self.email, allowed.email),
+            (self.phone_number, allowed.phone_number),
+            (self.id, allowed.id),
+            (self.date_of_birth, allowed.date_of_birth),
+            (self.nationality, allowed.nationality),
+            (self.gender, allowed.gender),
+            (self.address, allowed.address),
+            (self.profile_picture, allowed.profile_picture),
+            (self.license, allowed.license),
+            (self.id_image, allowed.id_image),
+        ];
+
+        requested.into_iter().all(|(requested, allowed)| !requested || allowed)
+            && self.name.is_within(&allowed.name)
+            // the date itself tells more than any age does
+            && (self.age_over.is_none() || allowed.age_over.is_some() || allowed.date_of_birth)
+            && self.passport.is_within(&allowed.passport)
+            && self.driving_license.is_within(&allowed.driving_license)
+    }
+
+    /// the fields asked for by the names the catalog lists them under, for the audit log
+    pub fn names(&self) -> Vec<&'static str> {
+        let requested = [
+            ("name", self.name.arabic || self.name.english),
+            ("email", self.email),
+            ("phone_number", self.phone_number),
+            ("id", self.id),
+            ("date_of_birth", self.date_of_birth),
+            ("age_over", self.age_over.is_some()),
+            ("nationality", self.nationality),
+            ("gender", self.gender),
+            ("address", self.address),
+            ("profile_picture", self.profile_picture),
+            ("license", self.license),
+            ("id_image", self.id_image),
+            ("passport", self.passport.any()),
+            ("driving_license", self.driving_license.any()),
+        ];
+
+        requested.into_iter().filter(|(_, requested)| *requested).map(|(name, _)| name).collect()
+    }
+
+    /// just the fields `names` lists, by the names the catalog lists them under; `None` when one of
+    /// them isn't a field
+    pub fn only(&self, names: &[&str]) -> Option<RequestedAutofillFields> {
+        let known = |name: &str| name == "age_over" || FIELD_CATALOG.iter().any(|field| field.name == name);
+        if !names.iter().all(|name| known(name)) {
+            return None
+        }
+
+        let keep = |name: &str| names.contains(&name);
+        Some(RequestedAutofillFields {
+            name: if keep("name") { self.name } else { NameFields::default() },
+            email: self.email && keep("email"),
+            phone_number: self.phone_number && keep("phone_number"),
+            id: self.id && keep("id"),
+            date_of_birth: self.date_of_birth && keep("date_of_birth"),
+            age_over: self.age_over.filter(|_| keep("age_over")),
+            nationality: self.nationality && keep("nationality"),
+            gender: self.gender && keep("gender"),
+            address: self.address && keep("address"),
+            profile_picture: self.profile_picture && keep("profile_picture"),
+            license: self.license && keep("license"),
+            id_image: self.id_image && keep("id_image"),
+            passport: if keep("passport") { self.passport } else { DocumentFields::default() },
+            driving_license: if keep("driving_license") { self.driving_license } else { DocumentFields::default() },
+        })
+    }
+
+    /// whether one of the plain text fields is asked for, by name
+    pub fn requests_text(&self, field: &str) -> bool {
+        match field {
+            "email" => self.email,
+            "phone_number" => self.phone_number,
+            "id" => self.id,
+            "date_of_birth" => self.date_of_birth,
+            "nationality" => self.nationality,
+            _ => false,
+        }
+    }
+}
+
+/// how a field's value is shaped, for frontends building their forms from the catalog
+#[derive(Copy, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    Text,
+    /// given and family names in arabic and english, requested by script
+    Name,
+    /// `YYYY-MM-DD`
+    Date,
+    /// ISO 3166-1 alpha-2
+    Country,
+    /// `male` or `female`
+    Gender,
+    /// street, district, city and postal code, any of them may be missing
+    Address,
+    /// number, issue and expiry dates, issuing authority and an image, requested part by part
+    Document,
+    /// base64 image
+    Image,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FieldInfo {
+    pub name: &'static str,
+    pub kind: FieldKind,
+    /// the document an image field must show
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<DocumentKind>,
+}
+
+pub const FIELD_CATALOG: [FieldInfo; 13] = [
+    FieldInfo { name: "name", kind: FieldKind::Name, document: None },
+    FieldInfo { name: "email", kind: FieldKind::Text, document: None },
+    FieldInfo { name: "phone_number", kind: FieldKind::Text, document: None },
+    FieldInfo { name: "id", kind: FieldKind::Text, document: None },
+    FieldInfo { name: "date_of_birth", kind: FieldKind::Date, document: None },
+    FieldInfo { name: "nationality", kind: FieldKind::Country, document: None },
+    FieldInfo { name: "gender", kind: FieldKind::Gender, document: None },
+    FieldInfo { name: "address", kind: FieldKind::Address, document: None },
+    FieldInfo { name: "profile_picture", kind: FieldKind::Image, document: Some(DocumentKind::Portrait) },
+    FieldInfo { name: "license", kind: FieldKind::Image, document: Some(DocumentKind::License) },
+    FieldInfo { name: "id_image", kind: FieldKind::Image, document: Some(DocumentKind::IdCard) },
+    FieldInfo { name: "passport", kind: FieldKind::Document, document: Some(DocumentKind::Passport) },
+    FieldInfo { name: "driving_license", kind: FieldKind::Document, document: Some(DocumentKind::License) },
+];
+
+/// what a resolver submits, relayed to the listener as is
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum Payload {
+    /// sealed to the listener's public key, the server can't read it
+    Sealed { ciphertext: String },
+    Plain(Box<AutofillData>),
+    /// plain data the server signed as a verifiable credential or an sd-jwt, only ever made here
+    #[serde(skip_deserializing)]
+    Credential { jwt: String },
+}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Payload::Sealed { ciphertext } => f.debug_struct("Sealed")
+                .field("ciphertext_len", &ciphertext.len())
+                .finish(),
+            Payload::Plain(data) => f.debug_tuple("Plain").field(data).finish(),
+            Payload::Credential { jwt } => f.debug_struct("Credential")
+                .field("jwt_len", &jwt.len())
+                .finish(),
+        }
+    }
+}
+
+impl Payload {
+    pub fn is_sealed(&self) -> bool {
+        matches!(self, Payload::Sealed { .. })
+    }
+
+    /// swaps `upload:{id}` references for the documents uploaded to this request
+    pub(crate) fn attach_uploads(&mut self, code: RequestCode) -> Result<(), AppError> {
+        match self {
+            Payload::Sealed { ciphertext } => {
+                if let Some(bytes) = uploads::referenced(code, ciphertext)? {
+                    *ciphertext = String::from_utf8(bytes).map_err(|_| {
+                        AppError::InvalidBody("uploaded ciphertext must be text".to_owned())
+                    })?;
+                }
+            }
+            Payload::Plain(data) => {
+                for (_, _, image) in data.images_mut() {
+                    if let Some(bytes) = uploads::referenced(code, image)? {
+                        *image = BASE64_STANDARD.encode(bytes);
+                    }
+                }
+            }
+            Payload::Credential { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    /// garbage is turned away here instead of reaching the listener, sealed data is the listener's
+    /// to check
+    pub fn validate(&mut self) -> Result<(), AppError> {
+        let Payload::Plain(data) = self else {
+            return Ok(())
+        };
+
+        data.validate()
+    }
+
+    /// inline images have to decode and fit their field before anything else looks at them
+    pub fn check_images(&self) -> Result<(), AppError> {
+        let Payload::Plain(data) = self else {
+            return Ok(())
+        };
+
+        for (field, _, image) in data.images() {
+            images::check(field, image)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn has_images(&self) -> bool {
+        match self {
+            Payload::Plain(data) => !data.images().is_empty(),
+            Payload::Sealed { .. } | Payload::Credential { .. } => false,
+        }
+    }
+
+    /// makes sure every image shows the document its field asks for, sealed data can't be looked at
+    #[tracing::instrument(target = "absher::trace", skip_all)]
+    pub(crate) async fn classify_documents(&self) -> Result<(), AppError> {
+        let Payload::Plain(data) = self else {
+            return Ok(())
+        };
+
+        for (field, expected, image) in data.images() {
+            classify::check(field, expected, image).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::prelude::{Engine, BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use crate::admin::{self, ApiKey};
+use crate::error::AppError;
+use crate::model::RequestedAutofillFields;
+use crate::{env_or, rate_limit, signing, telemetry, to_json_str, unix_now};
+
+static TOKEN_TTL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_OAUTH_TOKEN_TTL_SECS", 10 * 60).max(1))
+});
+
+/// what access tokens are typed as in their header, RFC 9068
+const TYP: &str = "at+jwt";
+
+/// what an access token stands for, a key narrowed down to some of its fields for a short while
+#[derive(Deserialize, Serialize)]
+struct AccessClaims {
+    /// the key the token was issued to
+    client_id: String,
+    tenant: String,
+    fields: RequestedAutofillFields,
+    iat: u64,
+    exp: u64,
+    jti: String,
+}
+
+/// the key behind an access token, with only the fields the token was issued for; tokens of keys
+/// revoked since, or narrowed below what the token asks, stop working right away
+pub(crate) fn authenticate(token: &str) -> Option<ApiKey> {
+    let claims = signing::verify_compact::<AccessClaims>(TYP, token)?;
+    if unix_now() >= claims.exp {
+        return None
+    }
+
+    let mut key = admin::key(&claims.client_id).filter(|key| key.tenant == claims.tenant)?;
+    if !claims.fields.is_within(&key.scopes) {
+        return None
+    }
+    key.scopes = claims.fields;
+    Some(key)
+}
+
+/// the key id an access token names, without checking it, for turning blocked keys away early
+pub(crate) fn client_id(token: &str) -> Option<String> {
+    let claims = token.split('.').nth(1)?;
+    let claims = BASE64_URL_SAFE_NO_PAD.decode(claims).ok()?;
+    serde_json::from_slice::<AccessClaims>(&claims).ok().map(|claims| claims.client_id)
+}
+
+#[derive(Serialize)]
+struct OAuthError {
+    error: &'static str,
+}
+
+/// token endpoint errors are answered the oauth way rather than ours, clients expect it
+pub(crate) fn error(error: &'static str) -> HttpResponse {
+    let mut response = match error {
+        "invalid_client" => HttpResponse::Unauthorized(),
+        _ => HttpResponse::BadRequest(),
+    };
+    response
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(OAuthError { error })
+}
+
+/// the client an api key was issued to, its id and secret from basic auth or the form; access
+/// tokens don't count, they can't be traded for fresh ones
+pub(crate) fn client(req: &HttpRequest, client_id: Option<&str>, client_secret: Option<&str>) -> Option<ApiKey> {
+    let basic = req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|credentials| BASE64_STANDARD.decode(credentials).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| credentials.split_once(':').map(|(id, secret)| (id.to_owned(), secret.to_owned())));
+    let (id, secret) = basic.or_else(|| client_id.map(str::to_owned).zip(client_secret.map(str::to_owned)))?;
+
+    admin::authenticate_key(&secret).filter(|key| key.id == id)
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    /// field names from the catalog, space separated; all of the key's when left out
+    scope: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: u64,
+    scope: String,
+}
+
+/// trades a key for a short lived access token, which works wherever the key does
+async fn token(req: HttpRequest, form: web::Form<TokenForm>) -> Result<HttpResponse, AppError> {
+    let form = form.into_inner();
+    let Some(key) = client(&req, form.client_id.as_deref(), form.client_secret.as_deref()) else {
+        return Ok(error("invalid_client"))
+    };
+    if form.grant_type != "client_credentials" {
+        return Ok(error("unsupported_grant_type"))
+    }
+
+    let fields = match form.scope.as_deref() {
+        Some(scope) => match key.scopes.only(&scope.split_ascii_whitespace().collect::<Vec<_>>()) {
+            Some(fields) => fields,
+            None => return Ok(error("invalid_scope")),
+        },
+        None => key.scopes,
+    };
+
+    let issued_at = unix_now();
+    let claims = AccessClaims {
+        client_id: key.id,
+        tenant: key.tenant,
+        fields,
+        iat: issued_at,
+        exp: issued_at + TOKEN_TTL.as_secs(),
+        jti: Alphanumeric.sample_string(&mut rand::rng(), 20),
+    };
+    let response = TokenResponse {
+        access_token: signing::compact(TYP, &claims)?,
+        token_type: "Bearer",
+        expires_in: TOKEN_TTL.as_secs(),
+        scope: fields.names().join(" "),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(to_json_str(&response)?))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/oauth/token")
+            .route(web::post().to(token))
+            .wrap(from_fn(rate_limit::limit))
+            .wrap(from_fn(telemetry::trace))
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+    use super::*;
+
+    fn key_scopes() -> RequestedAutofillFields {
+        RequestedAutofillFields { email: true, phone_number: true, ..Default::default() }
+    }
+
+    /// what the token endpoint answers to `form`, sent with `basic` credentials if any
+    async fn exchange(form: &[(&str, &str)], basic: Option<(&str, &str)>) -> (u16, Value) {
+        let app = test::init_service(App::new().route("/oauth/token", web::post().to(token))).await;
+        let mut req = TestRequest::post().uri("/oauth/token").set_form(form);
+        if let Some((id, secret)) = basic {
+            let credentials = BASE64_STANDARD.encode(format!("{id}:{secret}"));
+            req = req.insert_header((header::AUTHORIZATION, format!("Basic {credentials}")));
+        }
+
+        let response = test::call_service(&app, req.to_request()).await;
+        let status = response.status().as_u16();
+        (status, test::read_body_json(response).await)
+    }
+
+    #[actix_web::test]
+    async fn keys_trade_for_narrower_tokens() {
+        let (key, secret) = admin::issue_test_key("oauth-test", key_scopes(), Vec::new());
+        let form = [("grant_type", "client_credentials"), ("scope", "email")];
+        let (status, body) = exchange(&form, Some((&key.id, &secret))).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["scope"], "email");
+
+        let access_token = body["access_token"].as_str().unwrap();
+        assert_eq!(client_id(access_token).as_deref(), Some(key.id.as_str()));
+        let authenticated = authenticate(access_token).expect("a fresh token to authenticate");
+        assert_eq!(authenticated.tenant, "oauth-test");
+        assert!(authenticated.scopes.email && !authenticated.scopes.phone_number);
+
+        // tokens don't count as secrets, and die with their key
+        let form = [("grant_type", "client_credentials"), ("client_id", &key.id), ("client_secret", access_token)];
+        assert_eq!(exchange(&form, None).await, (401, serde_json::json!({"error": "invalid_client"})));
+        admin::revoke_test_key(&key.id);
+        assert!(authenticate(access_token).is_none());
+    }
+
+    #[actix_web::test]
+    async fn token_requests_are_refused_the_oauth_way() {
+        let (key, secret) = admin::issue_test_key("oauth-test", key_scopes(), Vec::new());
+        let error = |body: Value| body["error"].as_str().map(str::to_owned);
+
+        let (status, body) = exchange(&[("grant_type", "client_credentials")], Some((&key.id, "azt_wrong_secret"))).await;
+        assert_eq!((status, error(body).as_deref()), (401, Some("invalid_client")));
+
+        let form = [("grant_type", "password"), ("client_id", &key.id), ("client_secret", &secret)];
+        let (status, body) = exchange(&form, None).await;
+        assert_eq!((status, error(body).as_deref()), (400, Some("unsupported_grant_type")));
+
+        let form = [("grant_type", "client_credentials"), ("scope", "email shoe_size")];
+        let (status, body) = exchange(&form, Some((&key.id, &secret))).await;
+        assert_eq!((status, error(body).as_deref()), (400, Some("invalid_scope")));
+    }
+
+    #[test]
+    fn other_tokens_dont_pass_for_access_tokens() {
+        let (key, _secret) = admin::issue_test_key("oauth-test", key_scopes(), Vec::new());
+        let claims = AccessClaims {
+            client_id: key.id.clone(),
+            tenant: key.tenant.clone(),
+            fields: key.scopes,
+            iat: unix_now(),
+            exp: unix_now() + 60,
+            jti: "jti".to_owned(),
+        };
+        assert!(authenticate(&signing::compact(TYP, &claims).unwrap()).is_some());
+        assert!(authenticate(&signing::compact("JWT", &claims).unwrap()).is_none());
+
+        let other_tenant = AccessClaims { tenant: "someone-else".to_owned(), ..claims };
+        assert!(authenticate(&signing::compact(TYP, &other_tenant).unwrap()).is_none());
+        let expired = AccessClaims { exp: unix_now() - 1, tenant: key.tenant, ..other_tenant };
+        assert!(authenticate(&signing::compact(TYP, &expired).unwrap()).is_none());
+    }
+}
@@ -0,0 +1,110 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::time::Duration;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::config::setting;
+use crate::{env_or, http_client};
+
+pub type OcrFuture<'a> = Pin<Box<dyn Future<Output = Result<String, String>> + 'a>>;
+
+/// anything that can read the printed text off a document image
+pub trait OcrEngine: Send + Sync {
+    fn read_text<'a>(&'a self, image: &'a [u8]) -> OcrFuture<'a>;
+}
+
+#[derive(Deserialize)]
+struct OcrResponse {
+    text: String,
+}
+
+/// posts the raw image to a service that answers with `{"text": ...}`
+pub struct HttpOcr {
+    url: String,
+    timeout: Duration,
+}
+
+impl OcrEngine for HttpOcr {
+    fn read_text<'a>(&'a self, image: &'a [u8]) -> OcrFuture<'a> {
+        Box::pin(async move {
+            let mut response = http_client().post(&self.url)
+                .timeout(self.timeout)
+                .content_type("application/octet-stream")
+                .send_body(image.to_vec())
+                .await
+                .map_err(|err| err.to_string())?;
+
+            if !response.status().is_success() {
+                return Err(format!("ocr engine answered {}", response.status()))
+            }
+
+            let response = response.json::<OcrResponse>().await.map_err(|err| err.to_string())?;
+            Ok(response.text)
+        })
+    }
+}
+
+/// cross checks are off unless an engine is configured
+static ENGINE: LazyLock<Option<Box<dyn OcrEngine>>> = LazyLock::new(|| {
+    let url = setting("ABSHER_OCR_URL")?;
+    let timeout = Duration::from_millis(env_or("ABSHER_OCR_TIMEOUT_MS", 5000));
+    Some(Box::new(HttpOcr { url, timeout }))
+});
+
+pub fn enabled() -> bool {
+    ENGINE.is_some()
+}
+
+/// how a structured field compares with what is printed on the document
+#[derive(Copy, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Consistency {
+    Match,
+    Mismatch,
+    /// the engine failed or found no text, which says nothing either way
+    Unreadable,
+}
+
+/// consistency signals for the listener, the data itself is relayed untouched
+#[derive(Serialize, ToSchema)]
+pub struct Verification {
+    pub id_number: Consistency,
+}
+
+/// letters and digits only, so spacing and dashes on the card don't matter
+fn normalize(text: &str) -> String {
+    text.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|char| char.to_ascii_uppercase())
+        .collect()
+}
+
+/// whether the id number printed on `image` is the one that was typed in
+pub async fn cross_check(id: &str, image: &str) -> Option<Consistency> {
+    let engine = ENGINE.as_deref()?;
+
+    let id = normalize(id);
+    let Ok(image) = BASE64_STANDARD.decode(image) else {
+        return Some(Consistency::Unreadable)
+    };
+
+    let text = match engine.read_text(&image).await {
+        Ok(text) => normalize(&text),
+        Err(err) => {
+            log::warn!("unable to read the id image: {err}");
+            return Some(Consistency::Unreadable)
+        }
+    };
+
+    let consistency = if id.is_empty() || text.is_empty() {
+        Consistency::Unreadable
+    } else if text.contains(&id) {
+        Consistency::Match
+    } else {
+        Consistency::Mismatch
+    };
+
+    Some(consistency)
+}
@@ -0,0 +1,746 @@
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use crate::admin::{self, ApiKey};
+use crate::audit::Actor;
+use crate::challenge::{self, Challenge};
+use crate::config::setting;
+use crate::connections::{self, Connection};
+use crate::error::AppError;
+use crate::identity::{NameFields, NameParts};
+use crate::model::{AutofillData, Payload, RequestedAutofillFields};
+use crate::protocol::ListenSpec;
+use crate::req_code::RequestCode;
+use crate::store::Resolution;
+use crate::{
+    blocklist, capacity, clock, env_or, maintenance, oauth, parked, peer, rate_limit, request_id, shutdown, signing, to_json_str,
+    unix_now, ws,
+};
+
+/// the url this server is known by to relying parties, openid connect is off without one
+static ISSUER: LazyLock<Option<String>> = LazyLock::new(|| {
+    setting("ABSHER_OIDC_ISSUER").map(|issuer| issuer.trim_end_matches('/').to_owned())
+});
+static ID_TOKEN_TTL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_OIDC_ID_TOKEN_TTL_SECS", 5 * 60))
+});
+/// keys subjects, so nobody can tell whose they are by hashing national ids
+static SUBJECT_KEY: LazyLock<[u8; 32]> = LazyLock::new(|| signing::derived_secret("oidc-sub"));
+
+/// how long an authorization code can be exchanged, it holds the approved claims until then
+const GRANT_TTL: Duration = Duration::from_secs(60);
+/// how long the sign in page's wait holds on before asking again
+const WAIT: Duration = Duration::from_secs(25);
+/// how long a browser gets to solve a proof of work before it has to fetch a new one
+const CHALLENGE_TTL: Duration = Duration::from_secs(2 * 60);
+
+/// the scopes that mean something here, relying parties asking for others have them ignored
+const SCOPES: [&str; 7] = ["openid", "profile", "email", "phone", "address", "national_id", "nationality"];
+
+pub fn enabled() -> bool {
+    ISSUER.is_some()
+}
+
+/// a sign in page waiting for the user to approve its code in the app
+struct Session {
+    client_id: String,
+    tenant: String,
+    redirect_uri: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code: RequestCode,
+    watch_token: String,
+    until: Instant,
+    /// a sign in page waits like a listener, so it counts against its address like one
+    _connection: Connection,
+}
+
+/// what an authorization code is exchanged for, at most once
+struct Grant {
+    client_id: String,
+    redirect_uri: String,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    sub: String,
+    claims: Map<String, Value>,
+    auth_time: u64,
+    until: Instant,
+}
+
+static SESSIONS: LazyLock<DashMap<String, Session>> = LazyLock::new(DashMap::new);
+/// proofs of work handed to sign in pages, by nonce, each good for one sign in
+static CHALLENGES: LazyLock<DashMap<String, (Challenge, Instant)>> = LazyLock::new(DashMap::new);
+/// by the hash of the authorization code
+static GRANTS: LazyLock<DashMap<String, Grant>> = LazyLock::new(DashMap::new);
+
+fn hash_token(token: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+/// `uri` with `params` added to its query
+fn redirect_to(uri: &str, params: &[(&str, &str)]) -> String {
+    let query = serde_urlencoded::to_string(params).unwrap_or_default();
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    format!("{uri}{separator}{query}")
+}
+
+fn found(location: String) -> HttpResponse {
+    HttpResponse::Found().insert_header((header::LOCATION, location)).finish()
+}
+
+/// sends the user back to the relying party with an oauth error, keeping its state
+fn refuse(redirect_uri: &str, state: Option<&str>, error: &str) -> HttpResponse {
+    let mut params = vec![("error", error)];
+    if let Some(state) = state {
+        params.push(("state", state));
+    }
+    found(redirect_to(redirect_uri, &params))
+}
+
+/// the fields `scope` asks for, `None` without `openid`
+fn requested_fields(scope: &str) -> Option<RequestedAutofillFields> {
+    let mut fields = RequestedAutofillFields::default();
+    let mut openid = false;
+    for scope in scope.split_ascii_whitespace() {
+        match scope {
+            "openid" => openid = true,
+            "profile" => {
+                fields.name = NameFields { arabic: true, english: true };
+                fields.gender = true;
+                fields.date_of_birth = true;
+            }
+            "email" => fields.email = true,
+            "phone" => fields.phone_number = true,
+            "address" => fields.address = true,
+            "national_id" => fields.id = true,
+            "nationality" => fields.nationality = true,
+            _ => {}
+        }
+    }
+    openid.then_some(fields)
+}
+
+fn insert(claims: &mut Map<String, Value>, name: &str, value: Option<&str>) {
+    if let Some(value) = value.filter(|value| !value.is_empty()) {
+        claims.insert(name.to_owned(), Value::String(value.to_owned()));
+    }
+}
+
+fn insert_name(claims: &mut Map<String, Value>, suffix: &str, parts: &NameParts) {
+    insert(claims, &format!("given_name{suffix}"), parts.given.as_deref());
+    insert(claims, &format!("family_name{suffix}"), parts.family.as_deref());
+    let full = [parts.given.as_deref(), parts.family.as_deref()].into_iter().flatten().collect::<Vec<_>>().join(" ");
+    insert(claims, &format!("name{suffix}"), Some(&full));
+}
+
+/// the shared data as standard claims, arabic names tagged `#ar` and the national id under its own
+fn standard_claims(data: &AutofillData) -> Map<String, Value> {
+    let mut claims = Map::new();
+    if let Some(name) = &data.name {
+        if let Some(english) = &name.english {
+            insert_name(&mut claims, "", english);
+        }
+        if let Some(arabic) = &name.arabic {
+            insert_name(&mut claims, "#ar", arabic);
+        }
+    }
+    insert(&mut claims, "email", data.email.as_deref());
+    insert(&mut claims, "phone_number", data.phone_number.as_deref());
+    insert(&mut claims, "birthdate", data.date_of_birth.as_deref());
+    if let Some(gender) = data.gender {
+        claims.insert("gender".to_owned(), json!(gender));
+    }
+    insert(&mut claims, "national_id", data.id.as_deref());
+    insert(&mut claims, "nationality", data.nationality.as_deref());
+    if let Some(address) = &data.address {
+        let street = [address.street.as_deref(), address.district.as_deref()].into_iter().flatten().collect::<Vec<_>>().join(", ");
+        let mut claim = Map::new();
+        insert(&mut claim, "street_address", Some(&street));
+        insert(&mut claim, "locality", address.city.as_deref());
+        insert(&mut claim, "postal_code", address.postal_code.as_deref());
+        if !claim.is_empty() {
+            claims.insert("address".to_owned(), Value::Object(claim));
+        }
+    }
+    claims
+}
+
+/// the same user gets the same subject at one tenant and unrelated ones at others; without their
+/// national id there is nothing to tell them by, so the subject only stands for this sign in
+fn subject(tenant: &str, data: &AutofillData, code: RequestCode) -> String {
+    match &data.id {
+        Some(id) => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(&*SUBJECT_KEY).expect("hmac to take keys of any length");
+            // tenant ids never hold a colon, so no two tenant and id pairs run together the same
+            mac.update(tenant.as_bytes());
+            mac.update(b":");
+            mac.update(id.as_bytes());
+            BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+        }
+        None => code.correlation_id(),
+    }
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    scope: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    /// the proof of work the page was sent, and its solution
+    pow_nonce: Option<String>,
+    pow_solution: Option<String>,
+}
+
+/// the sign in page, the code on it is answered in the app like any other request
+const PAGE: &str = r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>sign in with absher</title>
+<style>
+body { font-family: system-ui, sans-serif; margin: 3em auto; max-width: 28em; text-align: center; }
+#code { font-size: 2.4em; letter-spacing: .1em; }
+</style>
+</head>
+<body>
+<p>open the absher app and enter this code</p>
+<p id="code">{display}</p>
+<p id="status">expires in {expires_in} seconds</p>
+<script>
+async function wait() {
+  const response = await fetch("{wait_path}");
+  if (response.status === 204) return wait();
+  if (!response.ok) {
+    document.getElementById("status").textContent = "this sign in ended, go back and try again";
+    return;
+  }
+  window.location.replace((await response.json()).redirect);
+}
+wait();
+</script>
+</body>
+</html>
+"##;
+
+/// sent instead of the sign in page while proof of work is required, it comes back to the same
+/// url with the solution once the browser found one
+const CHALLENGE_PAGE: &str = r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>sign in with absher</title>
+<style>
+body { font-family: system-ui, sans-serif; margin: 3em auto; max-width: 28em; text-align: center; }
+</style>
+</head>
+<body>
+<p>getting your sign in ready…</p>
+<script>
+const nonce = "{nonce}";
+const difficulty = {difficulty};
+function leadingZeros(digest) {
+  let zeros = 0;
+  for (const byte of digest) {
+    zeros += byte === 0 ? 8 : Math.clz32(byte) - 24;
+    if (byte !== 0) break;
+  }
+  return zeros;
+}
+async function solve() {
+  const encoder = new TextEncoder();
+  for (let counter = 0; ; counter++) {
+    const digest = await crypto.subtle.digest("SHA-256", encoder.encode(nonce + counter));
+    if (leadingZeros(new Uint8Array(digest)) >= difficulty) return String(counter);
+  }
+}
+solve().then(solution => {
+  const url = new URL(window.location.href);
+  url.searchParams.set("pow_nonce", nonce);
+  url.searchParams.set("pow_solution", solution);
+  window.location.replace(url);
+});
+</script>
+</body>
+</html>
+"##;
+
+/// a fresh proof of work, on a page that solves it
+fn challenge_page() -> HttpResponse {
+    let challenge = Challenge::new();
+    let page = CHALLENGE_PAGE
+        .replace("{nonce}", &challenge.nonce)
+        .replace("{difficulty}", &challenge.difficulty.to_string());
+    CHALLENGES.insert(challenge.nonce.clone(), (challenge, clock::now() + CHALLENGE_TTL));
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(page)
+}
+
+/// whether `solution` solves a challenge this server handed out, which is used up either way
+fn solved(nonce: Option<&str>, solution: Option<&str>) -> bool {
+    let (Some(nonce), Some(solution)) = (nonce, solution) else {
+        return false
+    };
+
+    CHALLENGES.remove(nonce)
+        .is_some_and(|(_, (challenge, until))| clock::now() <= until && challenge.verify(solution))
+}
+
+async fn authorize(req: HttpRequest, query: web::Query<AuthorizeQuery>) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    // without a registered redirect uri there is nowhere safe to send errors to
+    let key = admin::key(&query.client_id)
+        .filter(|key| key.redirect_uris.contains(&query.redirect_uri))
+        .ok_or(AppError::UnknownClient)?;
+    // the client id names a key without presenting it, the blocklist middleware can't see it
+    blocklist::check_key_id(&key.id)?;
+    let state = query.state.as_deref();
+
+    if query.response_type != "code" {
+        return Ok(refuse(&query.redirect_uri, state, "unsupported_response_type"))
+    }
+    if query.code_challenge_method.as_deref().is_some_and(|method| method != "S256") {
+        return Ok(refuse(&query.redirect_uri, state, "invalid_request"))
+    }
+    let Some(fields) = requested_fields(&query.scope) else {
+        return Ok(refuse(&query.redirect_uri, state, "invalid_scope"))
+    };
+    if shutdown::draining() || maintenance::enabled() {
+        return Ok(refuse(&query.redirect_uri, state, "temporarily_unavailable"))
+    }
+
+    let spec = ListenSpec { fields, ..ListenSpec::default() };
+    match ws::check_spec(&spec, Some(&key)) {
+        Ok(()) => {}
+        Err(AppError::ScopeExceeded) => return Ok(refuse(&query.redirect_uri, state, "invalid_scope")),
+        Err(_) => return Ok(refuse(&query.redirect_uri, state, "invalid_request")),
+    }
+
+    // client ids are public, anyone can open sign ins with one, so they pay for codes under attack
+    // just like keyless listeners do
+    if challenge::required() && !solved(query.pow_nonce.as_deref(), query.pow_solution.as_deref()) {
+        return Ok(challenge_page())
+    }
+
+    let ip = peer::ip(&req);
+    let connection = connections::open(ip)?;
+    let Ok(slot) = capacity::reserve(Some(&key.tenant)) else {
+        return Ok(refuse(&query.redirect_uri, state, "temporarily_unavailable"))
+    };
+
+    let request_id = request_id::of(&req);
+    let actor = Actor { by: Some(key.id.clone()), ip, request_id: Some(request_id.clone()) };
+    let mut opened = ws::open(spec, Some(&key.tenant), slot, &actor)?;
+    let (code, ttl) = (opened.code, opened.ttl);
+    let correlation = code.correlation_id();
+    log::info!(
+        code_hash = correlation.as_str(),
+        request_id = request_id.as_str(),
+        tenant = key.tenant.as_str();
+        "[{correlation}] issued to the sign in page of {} for {}s", key.id, ttl.as_secs()
+    );
+
+    opened.announce();
+    let watch_token = parked::park(code, ttl, opened.resolution);
+    let ApiKey { id: client_id, tenant, .. } = key;
+    let session = Alphanumeric.sample_string(&mut rand::rng(), 32);
+    SESSIONS.insert(session.clone(), Session {
+        client_id,
+        tenant,
+        redirect_uri: query.redirect_uri,
+        state: query.state,
+        nonce: query.nonce,
+        code_challenge: query.code_challenge,
+        code,
+        watch_token,
+        until: clock::now() + ttl + GRANT_TTL,
+        _connection: connection,
+    });
+
+    let page = PAGE
+        .replace("{display}", &code.display_grouped())
+        .replace("{expires_in}", &ttl.as_secs().to_string())
+        .replace("{wait_path}", &format!("/oidc/authorize/{session}/wait"));
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(page))
+}
+
+#[derive(Serialize)]
+struct Redirect {
+    redirect: String,
+}
+
+/// holds on until the code on the sign in page is answered, then tells the page where to go
+async fn wait(session: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let (code, watch_token) = SESSIONS.get(session.as_str())
+        .map(|session| (session.code, session.watch_token.clone()))
+        .ok_or(AppError::RequestNotFound(None))?;
+
+    let collected = tokio::select! {
+        collected = parked::collect(code, &watch_token, Some(WAIT)) => collected,
+        () = shutdown::drained() => return Err(AppError::ShuttingDown),
+    };
+    let redirect = match collected {
+        Ok(None) => return Ok(HttpResponse::NoContent().finish()),
+        Ok(Some(resolution)) => {
+            let (_, session) = SESSIONS.remove(session.as_str()).ok_or(AppError::RequestNotFound(None))?;
+            grant(session, resolution)?
+        }
+        // declined in the app or ran out, either way the relying party hears it was denied
+        Err(_) => {
+            let (_, session) = SESSIONS.remove(session.as_str()).ok_or(AppError::RequestNotFound(None))?;
+            let mut params = vec![("error", "access_denied")];
+            if let Some(state) = session.state.as_deref() {
+                params.push(("state", state));
+            }
+            redirect_to(&session.redirect_uri, &params)
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(to_json_str(&Redirect { redirect })?))
+}
+
+/// turns the answer into an authorization code, returns where the user goes with it
+fn grant(session: Session, resolution: Resolution) -> Result<String, AppError> {
+    let Payload::Plain(data) = &resolution.data else {
+        // requests opened here are never sealed and always answered in json
+        return Err(AppError::Serialization)
+    };
+
+    let authorization_code = Alphanumeric.sample_string(&mut rand::rng(), 32);
+    GRANTS.insert(hash_token(&authorization_code), Grant {
+        client_id: session.client_id,
+        redirect_uri: session.redirect_uri.clone(),
+        nonce: session.nonce,
+        code_challenge: session.code_challenge,
+        sub: subject(&session.tenant, data, session.code),
+        claims: standard_claims(data),
+        auth_time: unix_now(),
+        until: clock::now() + GRANT_TTL,
+    });
+
+    let mut params = vec![("code", authorization_code.as_str())];
+    if let Some(state) = session.state.as_deref() {
+        params.push(("state", state));
+    }
+    Ok(redirect_to(&session.redirect_uri, &params))
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    /// the claims are all in the id token, this opens nothing here
+    access_token: String,
+    token_type: &'static str,
+    expires_in: u64,
+    id_token: String,
+}
+
+async fn token(req: HttpRequest, form: web::Form<TokenForm>) -> Result<HttpResponse, AppError> {
+    let form = form.into_inner();
+    let Some(key) = oauth::client(&req, form.client_id.as_deref(), form.client_secret.as_deref()) else {
+        return Ok(oauth::error("invalid_client"))
+    };
+    if form.grant_type != "authorization_code" {
+        return Ok(oauth::error("unsupported_grant_type"))
+    }
+
+    // taken out whatever happens next, a code is good for one try
+    let now = clock::now();
+    let grant = form.code.as_deref()
+        .and_then(|code| GRANTS.remove(&hash_token(code)))
+        .map(|(_, grant)| grant)
+        .filter(|grant| now <= grant.until && grant.client_id == key.id)
+        .filter(|grant| form.redirect_uri.as_deref() == Some(grant.redirect_uri.as_str()));
+    let Some(grant) = grant else {
+        return Ok(oauth::error("invalid_grant"))
+    };
+
+    if let Some(challenge) = &grant.code_challenge {
+        let verified = form.code_verifier.as_deref()
+            .map(|verifier| BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())))
+            .is_some_and(|computed| bool::from(computed.as_bytes().ct_eq(challenge.as_bytes())));
+        if !verified {
+            return Ok(oauth::error("invalid_grant"))
+        }
+    }
+
+    let issuer = ISSUER.as_deref().unwrap_or_default();
+    let issued_at = unix_now();
+    let mut claims = Map::new();
+    claims.insert("iss".to_owned(), json!(issuer));
+    claims.insert("sub".to_owned(), json!(grant.sub));
+    claims.insert("aud".to_owned(), json!(grant.client_id));
+    claims.insert("iat".to_owned(), json!(issued_at));
+    claims.insert("exp".to_owned(), json!(issued_at + ID_TOKEN_TTL.as_secs()));
+    claims.insert("auth_time".to_owned(), json!(grant.auth_time));
+    if let Some(nonce) = grant.nonce {
+        claims.insert("nonce".to_owned(), json!(nonce));
+    }
+    claims.extend(grant.claims);
+
+    let response = TokenResponse {
+        access_token: Alphanumeric.sample_string(&mut rand::rng(), 32),
+        token_type: "Bearer",
+        expires_in: ID_TOKEN_TTL.as_secs(),
+        id_token: signing::compact("JWT", &claims)?,
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(to_json_str(&response)?))
+}
+
+async fn jwks() -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().content_type("application/json").body(to_json_str(&signing::jwks())?))
+}
+
+async fn discovery() -> Result<HttpResponse, AppError> {
+    let issuer = ISSUER.as_deref().unwrap_or_default();
+    let document = json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/oidc/authorize"),
+        "token_endpoint": format!("{issuer}/oidc/token"),
+        "jwks_uri": format!("{issuer}/.well-known/jwks.json"),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["pairwise"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post"],
+        "code_challenge_methods_supported": ["S256"],
+        "scopes_supported": SCOPES,
+        "claims_supported": [
+            "sub", "name", "given_name", "family_name", "email", "phone_number", "birthdate", "gender", "address",
+            "national_id", "nationality",
+        ],
+        "claims_locales_supported": ["en", "ar"],
+    });
+    Ok(HttpResponse::Ok().content_type("application/json").body(to_json_str(&document)?))
+}
+
+/// drops sign ins nobody finished, challenges nobody solved and codes nobody exchanged
+pub fn prune() {
+    let now = clock::now();
+    SESSIONS.retain(|_session, session| now <= session.until);
+    CHALLENGES.retain(|_nonce, (_challenge, until)| now <= *until);
+    GRANTS.retain(|_code, grant| now <= grant.until);
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    if enabled() {
+        cfg
+            .route("/.well-known/openid-configuration", web::get().to(discovery))
+            .service(
+                web::resource("/oidc/authorize")
+                    .route(web::get().to(authorize))
+                    .wrap(from_fn(rate_limit::limit))
+            )
+            .route("/oidc/authorize/{session}/wait", web::get().to(wait))
+            .route("/oidc/token", web::post().to(token))
+            .route("/oidc/jwks", web::get().to(jwks));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{App, ResponseError};
+    use base64::prelude::BASE64_STANDARD;
+    use crate::{handlers, test_utils};
+    use super::*;
+
+    const REDIRECT_URI: &str = "https://rp.example.com/callback";
+    const VERIFIER: &str = "a-code-verifier-long-enough-to-pass-for-a-real-one";
+
+    fn routes(cfg: &mut web::ServiceConfig) {
+        cfg
+            .route("/oidc/authorize", web::get().to(authorize))
+            .route("/oidc/authorize/{session}/wait", web::get().to(wait))
+            .route("/oidc/token", web::post().to(token))
+            .service(handlers::resolve);
+    }
+
+    fn client() -> (ApiKey, String) {
+        let scopes = RequestedAutofillFields { email: true, id: true, ..Default::default() };
+        admin::issue_test_key("oidc-test", scopes, vec![REDIRECT_URI.to_owned()])
+    }
+
+    fn get(uri: &str) -> TestRequest {
+        TestRequest::get().uri(uri).peer_addr("198.51.100.7:40000".parse().unwrap())
+    }
+
+    fn authorize_uri(client_id: &str, scope: &str, extra: &[(&str, &str)]) -> String {
+        let mut params = vec![
+            ("response_type", "code"), ("client_id", client_id), ("redirect_uri", REDIRECT_URI), ("scope", scope),
+            ("state", "st"),
+        ];
+        params.extend_from_slice(extra);
+        format!("/oidc/authorize?{}", serde_urlencoded::to_string(params).unwrap())
+    }
+
+    /// the query of a redirect back to the relying party
+    fn query_of(location: &str) -> HashMap<String, String> {
+        let query = location.strip_prefix(&format!("{REDIRECT_URI}?")).expect("a redirect to the relying party");
+        serde_urlencoded::from_str(query).unwrap()
+    }
+
+    fn between<'a>(page: &'a str, before: &str, after: &str) -> &'a str {
+        let (_, rest) = page.split_once(before).expect("the sign in page to hold it");
+        rest.split_once(after).expect("the sign in page to hold it").0
+    }
+
+    fn token_form(code: &str, verifier: &str) -> [(&'static str, String); 4] {
+        [
+            ("grant_type", "authorization_code".to_owned()),
+            ("code", code.to_owned()),
+            ("redirect_uri", REDIRECT_URI.to_owned()),
+            ("code_verifier", verifier.to_owned()),
+        ]
+    }
+
+    fn basic(id: &str, secret: &str) -> (header::HeaderName, String) {
+        (header::AUTHORIZATION, format!("Basic {}", BASE64_STANDARD.encode(format!("{id}:{secret}"))))
+    }
+
+    #[actix_web::test]
+    async fn sign_ins_end_in_an_id_token() {
+        let _turn = test_utils::clock_turn().await;
+        let app = test::init_service(App::new().configure(routes)).await;
+        let (key, secret) = client();
+        let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(VERIFIER));
+
+        let pkce = [("nonce", "n-0"), ("code_challenge", challenge.as_str()), ("code_challenge_method", "S256")];
+        let uri = authorize_uri(&key.id, "openid email national_id", &pkce);
+        let page = test::call_and_read_body(&app, get(&uri).to_request()).await;
+        let page = std::str::from_utf8(&page).unwrap();
+        let code = between(page, r#"<p id="code">"#, "</p>").parse::<RequestCode>().unwrap();
+        let wait_path = between(page, r#"fetch(""#, r#"")"#).to_owned();
+
+        let answer = json!({"email": "user@example.com", "id": "1000000008"});
+        let resolve = TestRequest::post().uri(&format!("/requests/{}", code.as_str())).set_json(&answer).to_request();
+        assert!(test::call_service(&app, resolve).await.status().is_success());
+
+        let redirect = test::call_and_read_body_json::<_, _, Value>(&app, get(&wait_path).to_request()).await;
+        let query = query_of(redirect["redirect"].as_str().unwrap());
+        assert_eq!(query["state"], "st");
+
+        let form = token_form(&query["code"], VERIFIER);
+        let exchange = || TestRequest::post().uri("/oidc/token").insert_header(basic(&key.id, &secret)).set_form(&form);
+        let tokens = test::call_and_read_body_json::<_, _, Value>(&app, exchange().to_request()).await;
+        let claims = signing::verify_compact::<Value>("JWT", tokens["id_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims["aud"], key.id.as_str());
+        assert_eq!(claims["nonce"], "n-0");
+        assert_eq!(claims["email"], "user@example.com");
+        assert_eq!(claims["sub"], subject("oidc-test", &serde_json::from_value(answer).unwrap(), code).as_str());
+
+        // the code is spent
+        let again = test::call_service(&app, exchange().to_request()).await;
+        assert_eq!(test::read_body_json::<Value, _>(again).await, json!({"error": "invalid_grant"}));
+    }
+
+    #[actix_web::test]
+    async fn codes_only_go_to_their_client_with_their_verifier() {
+        let _turn = test_utils::clock_turn().await;
+        let app = test::init_service(App::new().configure(routes)).await;
+        let (key, secret) = client();
+        let (other, other_secret) = client();
+
+        let exchange = async |id: &str, secret: &str, verifier: &str| {
+            let code = Alphanumeric.sample_string(&mut rand::rng(), 32);
+            GRANTS.insert(hash_token(&code), Grant {
+                client_id: key.id.clone(),
+                redirect_uri: REDIRECT_URI.to_owned(),
+                nonce: None,
+                code_challenge: Some(BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(VERIFIER))),
+                sub: "sub".to_owned(),
+                claims: Map::new(),
+                auth_time: unix_now(),
+                until: clock::now() + GRANT_TTL,
+            });
+
+            let req = TestRequest::post().uri("/oidc/token").insert_header(basic(id, secret)).set_form(token_form(&code, verifier));
+            let response = test::call_service(&app, req.to_request()).await;
+            (response.status().as_u16(), test::read_body_json::<Value, _>(response).await)
+        };
+
+        assert_eq!(exchange(&key.id, &secret, VERIFIER).await.0, 200);
+        assert_eq!(exchange(&key.id, &secret, "some-other-verifier").await, (400, json!({"error": "invalid_grant"})));
+        assert_eq!(exchange(&other.id, &other_secret, VERIFIER).await, (400, json!({"error": "invalid_grant"})));
+        assert_eq!(exchange(&key.id, "azt_not_the_secret", VERIFIER).await, (401, json!({"error": "invalid_client"})));
+    }
+
+    #[actix_web::test]
+    async fn bad_sign_ins_are_sent_back_with_an_error() {
+        let _turn = test_utils::clock_turn().await;
+        let app = test::init_service(App::new().configure(routes)).await;
+        let (key, _secret) = client();
+
+        let refused = async |uri: String| {
+            let response = test::call_service(&app, get(&uri).to_request()).await;
+            assert_eq!(response.status(), 302);
+            let location = response.headers().get(header::LOCATION).unwrap().to_str().unwrap();
+            query_of(location)["error"].clone()
+        };
+
+        let uri = authorize_uri(&key.id, "openid email", &[]).replace("response_type=code", "response_type=token");
+        assert_eq!(refused(uri).await, "unsupported_response_type");
+        assert_eq!(refused(authorize_uri(&key.id, "email", &[])).await, "invalid_scope");
+        assert_eq!(refused(authorize_uri(&key.id, "openid phone", &[])).await, "invalid_scope");
+        assert_eq!(refused(authorize_uri(&key.id, "openid", &[("code_challenge_method", "plain")])).await, "invalid_request");
+
+        // without a registered redirect uri there is nowhere to send the error to
+        let uri = authorize_uri(&key.id, "openid", &[]).replace("rp.example.com", "evil.example.com");
+        assert_eq!(test::call_service(&app, get(&uri).to_request()).await.status(), AppError::UnknownClient.status_code());
+    }
+
+    #[test]
+    fn subjects_are_per_tenant_and_scopes_pick_fields() {
+        let data = serde_json::from_value::<AutofillData>(json!({"id": "1000000008"})).unwrap();
+        let (code, other_code) = ("ABCDEFGHI".parse().unwrap(), "JKLMNPQRS".parse().unwrap());
+        assert_eq!(subject("tenant-a", &data, code), subject("tenant-a", &data, other_code));
+        assert_ne!(subject("tenant-a", &data, code), subject("tenant-b", &data, code));
+        assert!(!subject("tenant-a", &data, code).contains("1000000008"));
+
+        let anonymous = AutofillData::default();
+        assert_ne!(subject("tenant-a", &anonymous, code), subject("tenant-a", &anonymous, other_code));
+
+        assert!(requested_fields("profile email").is_none());
+        let fields = requested_fields("openid profile phone unknown").unwrap();
+        assert!(fields.name.english && fields.gender && fields.phone_number && !fields.email);
+    }
+}
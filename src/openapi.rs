@@ -0,0 +1,114 @@
+use std::sync::LazyLock;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use utoipa::OpenApi;
+use crate::config::setting;
+use crate::error::AppError;
+use crate::{cache, to_json_str};
+
+/// the swagger ui is for local development, it pulls its scripts from a cdn
+static SWAGGER_UI: LazyLock<bool> = LazyLock::new(|| {
+    setting("ABSHER_SWAGGER_UI").is_some_and(|enabled| enabled == "1" || enabled.eq_ignore_ascii_case("true"))
+});
+
+/// websockets are out of openapi's reach, so the listener side is spelled out here
+const LISTEN_PROTOCOL: &str = r#"Relays identity data from the Absher app (the resolver) to a website (the listener).
+
+## Listener protocol
+
+A listener opens `GET /listen` as a websocket. Its first frame is the specification: a `request`
+message in json, or the same in cbor as a binary frame.
+
+```json
+{"type": "request", "fields": {"email": true, "name": {"arabic": true}}, "ttl_secs": 180}
+```
+
+See the `ListenSpec` schema for every option. A bare `fields` object without a `type` is still
+understood as the frozen legacy protocol, which only ever gets the code and the data frame.
+
+The server answers with messages tagged by `type`, in the same encoding the specification came in:
+
+- `challenge` `{nonce, difficulty}`: keyless listeners may have to answer with
+  `{"type": "solution", "solution": ...}` before getting a code.
+- `code` `{code, display, expires_in, pin?}`: what the website shows the user.
+- `data` `{data, verification?}`: the resolver's `AutofillData`.
+- `sealed` `{ciphertext}`: when the specification had a `public_key`.
+- `credential` `{credential, verification?}`: for `jwt_vc` and `sd_jwt` formats.
+- `stream` `{bytes, chunks}`: the data message follows in `chunks` binary frames, each a big
+  endian u32 length followed by that many bytes.
+- after the data: `timing`, `receipt` `{receipt}`, `linked` `{user_hint}` when the app answered
+  from a registered device, and `integrity` `{sha256, bytes}` over the data frames exactly as
+  they were sent. Keyed listeners that send the hint back as `user_hint` get their code pushed
+  to that device.
+- `superseded`, `expired` and `error` `{error}`: the socket closes right after, an expired request
+  closes with code 4000. A user declining in the app is an `error` with code `REQUEST_REJECTED`.
+
+Listeners can report frames they failed to handle with `{"type": "client_error", "error": ...}`.
+"#;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "absher-zt", description = LISTEN_PROTOCOL),
+    paths(
+        crate::ws::listen,
+        crate::ws::follow,
+        crate::poll::create,
+        crate::poll::wait,
+        crate::handlers::fetch,
+        crate::handlers::resolve,
+        crate::handlers::report,
+        crate::handlers::reject,
+        crate::handlers::deliveries,
+        crate::handlers::report_client_error,
+        crate::handlers::receipt_key,
+        crate::handlers::jwks,
+        crate::handlers::field_catalog,
+        crate::handlers::version,
+        crate::uploads::create,
+        crate::uploads::attach,
+        crate::uploads::status,
+        crate::uploads::append,
+        crate::uploads::finalize,
+        crate::devices::register,
+        crate::devices::list,
+        crate::devices::update,
+        crate::devices::unregister,
+        crate::devices::remove,
+    ),
+    components(schemas(crate::protocol::ListenSpec, crate::protocol::ClientMessage, crate::protocol::ServerMessage)),
+)]
+struct ApiDoc;
+
+pub fn document() -> utoipa::openapi::OpenApi {
+    ApiDoc::openapi()
+}
+
+#[get("/openapi.json")]
+async fn openapi_json(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    cache::serve(&req, "openapi", || to_json_str(&ApiDoc::openapi()))
+}
+
+const SWAGGER_PAGE: &str = r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>absher-zt api</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+async fn swagger_ui() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(SWAGGER_PAGE)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(openapi_json);
+    if *SWAGGER_UI {
+        cfg.route("/docs", web::get().to(swagger_ui));
+    }
+}
@@ -0,0 +1,152 @@
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use crate::cli::{BenchArgs, Command, GenCodeArgs};
+use crate::challenge::Challenge;
+use crate::config::setting;
+use crate::model::{AutofillData, Payload, RequestedAutofillFields};
+use crate::protocol::ListenSpec;
+use crate::req_code::{CodeKind, RequestCode};
+use crate::test_utils::TestServer;
+use crate::tls::TlsSettings;
+use crate::{app_auth, config, lockout, openapi, receipt, req_code, signing, simulate, typegen, unix_now};
+
+/// runs one of the subcommands that aren't the server
+pub async fn run(command: &Command) -> io::Result<()> {
+    match command {
+        Command::Serve => Ok(()),
+        Command::GenCode(args) => {
+            gen_code(args);
+            Ok(())
+        }
+        Command::CheckConfig => check_config(),
+        Command::ExportOpenapi { output } => {
+            let document = openapi::document().to_pretty_json().map_err(io::Error::other)?;
+            match output {
+                Some(path) => std::fs::write(path, document + "\n"),
+                None => writeln!(io::stdout().lock(), "{document}"),
+            }
+        }
+        Command::ExportTypes { dir } => typegen::export(dir),
+        Command::Bench(args) => bench(args).await,
+        Command::Simulate(args) => simulate::run(args).await,
+    }
+}
+
+fn gen_code(args: &GenCodeArgs) {
+    let kind = if args.numeric { CodeKind::Numeric } else { CodeKind::Letters };
+    for _ in 0..args.count {
+        let code = RequestCode::new_rand(kind);
+        println!("{}\t{}", code.as_str(), code.display_grouped());
+    }
+}
+
+/// prints everything wrong with the configuration and exits with 1 if there was anything
+fn check_config() -> io::Result<()> {
+    let mut errors = config::problems();
+    let mut warnings = Vec::new();
+
+    match setting("ABSHER_SIGNING_KEY") {
+        Some(key) if signing::parse_signing_key(&key).is_none() => {
+            errors.push("ABSHER_SIGNING_KEY isn't 32 bytes of base64, a random key would be used".to_owned());
+        }
+        Some(_) => {}
+        None => warnings.push("no ABSHER_SIGNING_KEY, receipts will stop verifying after a restart".to_owned()),
+    }
+
+    if let Some(seeds) = setting("ABSHER_SIGNING_PREVIOUS_KEYS")
+        && seeds.split(',').map(str::trim).any(|seed| !seed.is_empty() && signing::parse_signing_key(seed).is_none())
+    {
+        errors.push("one of ABSHER_SIGNING_PREVIOUS_KEYS isn't 32 bytes of base64, it wouldn't be published".to_owned());
+    }
+
+    if let Some(key) = setting("ABSHER_APP_AUTH_KEY")
+        && app_auth::parse_key(&key).is_none()
+    {
+        errors.push("ABSHER_APP_AUTH_KEY isn't an ed25519 public key in base64, app users couldn't sign in".to_owned());
+    }
+
+    if let Some(tls) = TlsSettings::from_env()
+        && let Err(err) = tls.server_config()
+    {
+        errors.push(err.to_string());
+    }
+
+    req_code::report_guess_budget(lockout::max_guesses_per_hour());
+    for warning in &warnings {
+        println!("warning: {warning}");
+    }
+    for error in &errors {
+        println!("error: {error}");
+    }
+
+    if !errors.is_empty() {
+        std::process::exit(1)
+    }
+    println!("the configuration is fine");
+    Ok(())
+}
+
+/// how long `runs` of `work` took on average
+fn time(runs: u32, mut work: impl FnMut()) -> Duration {
+    let started = Instant::now();
+    for _ in 0..runs {
+        work();
+    }
+    started.elapsed() / runs.max(1)
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    sorted.get((sorted.len() * percent / 100).min(sorted.len().saturating_sub(1))).copied().unwrap_or_default()
+}
+
+async fn bench(args: &BenchArgs) -> io::Result<()> {
+    let code = time(100_000, || {
+        RequestCode::new_rand(CodeKind::Letters);
+    });
+    println!("code generation   {code:>12.2?} per code");
+
+    let challenge = Challenge::new();
+    let solve = time(3, || {
+        Challenge::new().solve();
+    });
+    println!("proof of work     {solve:>12.2?} per solution at difficulty {}", challenge.difficulty);
+
+    signing::init();
+    let payload = Payload::Plain(Box::default());
+    let fields = RequestedAutofillFields::default();
+    let now = unix_now();
+    let sign = time(1000, || {
+        let _ = receipt::sign(RequestCode::new_rand(CodeKind::Letters), &fields, &payload, now, now);
+    });
+    println!("receipt signing   {sign:>12.2?} per receipt");
+
+    let server = TestServer::start().await?;
+    let resolver = server.resolver();
+    // data that wipes itself on drop can't be built with `..AutofillData::default()`
+    let mut answer = AutofillData::default();
+    answer.email.replace("bench@example.com".to_owned());
+
+    let mut flows = Vec::with_capacity(args.flows);
+    for _ in 0..args.flows {
+        let mut spec = ListenSpec::default();
+        spec.fields.email = true;
+
+        let started = Instant::now();
+        let flow = async {
+            let (issued, data) = server.listener().request_fields(spec).await?;
+            let code = issued.code.parse().map_err(|_| crate::client::Error::Protocol(issued.code))?;
+            resolver.resolve(code, &answer, None).await?;
+            data.await
+        };
+        flow.await.map_err(io::Error::other)?;
+        flows.push(started.elapsed());
+    }
+    server.stop().await;
+
+    flows.sort();
+    println!(
+        "listen to data    {:>12.2?} p50, {:.2?} p99 over {} flows",
+        percentile(&flows, 50), percentile(&flows, 99), flows.len(),
+    );
+    Ok(())
+}
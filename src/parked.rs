@@ -0,0 +1,76 @@
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use rand::distr::{Alphanumeric, SampleString};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::sync::{oneshot, Mutex};
+use crate::error::AppError;
+use crate::req_code::RequestCode;
+use crate::store::{Resolution, TOMBSTONE_TTL};
+use crate::{clock, ttl, ws};
+
+/// requests whose listener doesn't hold a connection open, their answer waits here until the
+/// listener comes back for it with the token it got along with the code
+struct Parked {
+    token_hash: String,
+    /// taken by whoever collects the answer, one waiter at a time
+    resolution: Arc<Mutex<Option<oneshot::Receiver<Resolution>>>>,
+    /// the request's lifetime and then as long as its tombstone stays
+    until: Instant,
+}
+
+static PARKED: LazyLock<DashMap<RequestCode, Parked>> = LazyLock::new(DashMap::new);
+
+fn hash_token(token: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+/// keeps the answer to `code` for later, returns the token it can be collected with
+pub(crate) fn park(code: RequestCode, ttl: Duration, resolution: oneshot::Receiver<Resolution>) -> String {
+    let token = Alphanumeric.sample_string(&mut rand::rng(), 32);
+    let until = clock::now() + ttl + ttl::GRACE + *TOMBSTONE_TTL;
+    PARKED.insert(code, Parked {
+        token_hash: hash_token(&token),
+        resolution: Arc::new(Mutex::new(Some(resolution))),
+        until,
+    });
+    token
+}
+
+/// the answer to `code` once there is one, `None` if `wait` ran out first; fails if the request
+/// ended without an answer, or for a token that isn't the one it was parked with
+pub(crate) async fn collect(code: RequestCode, token: &str, wait: Option<Duration>) -> Result<Option<Resolution>, AppError> {
+    let resolution = PARKED.get(&code)
+        .filter(|parked| bool::from(parked.token_hash.as_bytes().ct_eq(hash_token(token).as_bytes())))
+        .map(|parked| parked.resolution.clone())
+        .ok_or(AppError::RequestNotFound(None))?;
+
+    // another waiter holding the answer counts against the wait too
+    let taking = async {
+        let mut resolution = resolution.lock().await;
+        let Some(receiver) = resolution.as_mut() else {
+            return Err(AppError::RequestAlreadyResolved(code))
+        };
+        let outcome = receiver.await;
+        *resolution = None;
+        Ok(outcome)
+    };
+    let outcome = match wait {
+        Some(wait) => match tokio::time::timeout(wait, taking).await {
+            Ok(outcome) => outcome?,
+            Err(_) => return Ok(None),
+        },
+        None => taking.await?,
+    };
+
+    PARKED.remove(&code);
+    outcome.map(Some).map_err(|_| ws::unanswered(code))
+}
+
+/// drops answers nobody came back for
+pub fn prune() {
+    let now = clock::now();
+    PARKED.retain(|_code, parked| now <= parked.until);
+}
@@ -0,0 +1,51 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::LazyLock;
+use actix_web::HttpRequest;
+use actix_web::http::header::{self, HeaderName};
+use crate::env_or;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// whether a proxy on the same host tells us who its clients are; only ever believed from a unix
+/// socket or loopback peer, anyone else could make the headers up
+static TRUSTED_PROXY: LazyLock<bool> = LazyLock::new(|| env_or("ABSHER_TRUSTED_PROXY", false));
+
+pub fn trusts_proxy() -> bool {
+    *TRUSTED_PROXY
+}
+
+/// the address of whoever sent `req`, as the proxy in front of us saw it when it is trusted; `None`
+/// over a unix socket without one, which lockout, rate limits and connection caps then skip
+pub fn ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if !*TRUSTED_PROXY || peer.is_some_and(|ip| !ip.is_loopback()) {
+        return peer
+    }
+
+    forwarded(req).or(peer)
+}
+
+/// the client the nearest proxy saw, which is the last hop it appended
+fn forwarded(req: &HttpRequest) -> Option<IpAddr> {
+    if let Some(value) = req.headers().get(header::FORWARDED).and_then(|value| value.to_str().ok()) {
+        let last = value.rsplit(',').next()?;
+        let node = last.split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+            .map(|(_, node)| node.trim().trim_matches('"'))?;
+        return parse_node(node)
+    }
+
+    let value = req.headers().get(X_FORWARDED_FOR)?.to_str().ok()?;
+    parse_node(value.rsplit(',').next()?.trim())
+}
+
+/// an address with or without a port, v6 ones in brackets when they have one; obfuscated and
+/// `unknown` nodes aren't addresses
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok()
+    }
+
+    node.parse::<IpAddr>().ok().or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
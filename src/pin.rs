@@ -0,0 +1,54 @@
+use std::sync::LazyLock;
+use rand::rngs::OsRng;
+use rand::{Rng, TryRngCore};
+use subtle::ConstantTimeEq;
+use crate::env_or;
+
+/// where a resolver puts the pin the website shows next to the code
+pub const HEADER: &str = "x-request-pin";
+
+/// wrong pins a request survives before it is cancelled
+static ATTEMPTS: LazyLock<u32> = LazyLock::new(|| env_or("ABSHER_PIN_ATTEMPTS", 3).max(1));
+
+/// a second factor only the person looking at the website knows
+pub struct Pin {
+    value: String,
+    attempts_left: u32,
+}
+
+pub enum Attempt {
+    Accepted,
+    Rejected { attempts_left: u32 },
+    /// that was the last allowed wrong pin
+    Exhausted,
+}
+
+impl Pin {
+    pub fn new() -> Self {
+        let value = OsRng.unwrap_err().random_range(0..10_000);
+        Self { value: format!("{value:04}"), attempts_left: *ATTEMPTS }
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// checks a presented pin, every wrong one costs an attempt
+    pub fn attempt(&mut self, presented: &str) -> Attempt {
+        if bool::from(presented.trim().as_bytes().ct_eq(self.value.as_bytes())) {
+            return Attempt::Accepted
+        }
+
+        self.attempts_left = self.attempts_left.saturating_sub(1);
+        match self.attempts_left {
+            0 => Attempt::Exhausted,
+            attempts_left => Attempt::Rejected { attempts_left },
+        }
+    }
+}
+
+impl Default for Pin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,193 @@
+use std::time::Duration;
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::audit::Actor;
+use crate::error::{AppError, ErrorResponse};
+use crate::protocol::{ListenSpec, ServerMessage};
+use crate::req_code::RequestCode;
+use crate::{
+    admin, capacity, connections, lockout, maintenance, parked, peer, protocol, rate_limit, request_id, sensitive, shutdown, telemetry,
+    to_json_str, ws,
+};
+
+/// what the wait takes the watch token in
+pub const TOKEN_HEADER: &str = "x-watch-token";
+
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+/// proxies and captive portals tend to cut idle responses off after a minute
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, ToSchema)]
+struct Created {
+    code: String,
+    /// the code the way people should see it
+    display: String,
+    expires_in: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pin: Option<String>,
+    correlation_id: String,
+    /// what `GET /requests/{code}/wait` takes as `x-watch-token`, only for whoever opened the request
+    watch_token: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct WaitQuery {
+    /// how long to hold on before answering 204, like `30s`, at most a minute
+    timeout: Option<String>,
+}
+
+/// the answer, the same a websocket listener gets
+#[derive(Serialize, ToSchema)]
+struct Resolved<'a> {
+    /// the `data`, `sealed` or `credential` message
+    #[schema(value_type = Object)]
+    message: ServerMessage<'a>,
+    receipt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_hint: Option<&'a str>,
+}
+
+/// `30s`, `2m` or plain seconds
+fn parse_wait(timeout: Option<&str>) -> Result<Duration, AppError> {
+    let Some(timeout) = timeout else {
+        return Ok(DEFAULT_WAIT)
+    };
+
+    let (number, unit) = match timeout.strip_suffix('s') {
+        Some(number) => (number, 1),
+        None => timeout.strip_suffix('m').map_or((timeout, 1), |number| (number, 60)),
+    };
+    let wait = number.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(unit))
+        .map(Duration::from_secs)
+        .filter(|wait| *wait <= MAX_WAIT);
+    wait.ok_or_else(|| AppError::InvalidBody(format!("timeout must be like 30s and at most {}s", MAX_WAIT.as_secs())))
+}
+
+#[utoipa::path(
+    post,
+    path = "/requests",
+    tag = "listener",
+    description = "Opens a request without holding a connection, for pages that can't keep a websocket or event stream open. The answer is collected with `GET /requests/{code}/wait`.",
+    params(("x-api-key" = String, Header, description = "the tenant's api key")),
+    request_body = ListenSpec,
+    responses(
+        (status = 201, body = Created),
+        (status = 400, description = "the specification isn't valid", body = ErrorResponse),
+        (status = 401, description = "no api key, or one that isn't valid", body = ErrorResponse),
+        (status = 403, description = "asks for more than the key's scopes", body = ErrorResponse),
+        (status = 503, description = "at capacity, under maintenance or shutting down", body = ErrorResponse),
+    ),
+)]
+async fn create(req: HttpRequest, spec: web::Json<ListenSpec>) -> Result<HttpResponse, AppError> {
+    if shutdown::draining() {
+        return Err(AppError::ShuttingDown)
+    }
+    if maintenance::enabled() {
+        return Err(AppError::UnderMaintenance)
+    }
+
+    // keyless listeners can be asked for proof of work, which only the websocket hands out
+    let key = admin::presented(req.headers())
+        .and_then(admin::authenticate)
+        .ok_or(AppError::InvalidApiKey)?;
+    let spec = spec.into_inner();
+    ws::check_spec(&spec, Some(&key))?;
+
+    let slot = capacity::reserve(Some(&key.tenant))?;
+    let request_id = request_id::of(&req);
+    let actor = Actor {
+        by: Some(key.id.clone()),
+        ip: peer::ip(&req),
+        request_id: Some(request_id.clone()),
+    };
+    let mut opened = ws::open(spec, Some(&key.tenant), slot, &actor)?;
+    let (code, ttl) = (opened.code, opened.ttl);
+    let correlation = code.correlation_id();
+    log::info!(
+        code_hash = correlation.as_str(),
+        request_id = request_id.as_str(),
+        tenant = key.tenant.as_str();
+        "[{correlation}] issued to polling listener {request_id} for {}s", ttl.as_secs()
+    );
+
+    opened.announce();
+    let pin = opened.pin.take();
+    let watch_token = parked::park(code, ttl, opened.resolution);
+    let created = Created {
+        code: code.as_str().to_owned(),
+        display: code.display_grouped(),
+        expires_in: ttl.as_secs(),
+        pin,
+        correlation_id: correlation,
+        watch_token,
+    };
+    Ok(HttpResponse::Created().content_type("application/json").body(to_json_str(&created)?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/requests/{code}/wait",
+    tag = "listener",
+    description = "Holds on until the request opened with `POST /requests` is answered or the timeout runs out, then asks to be called again with 204.",
+    params(
+        ("code" = String, Path, description = "the request code"),
+        ("x-watch-token" = String, Header, description = "the `watch_token` the request was opened with"),
+        WaitQuery,
+    ),
+    responses(
+        (status = 200, body = Resolved),
+        (status = 204, description = "not answered yet, wait again"),
+        (status = 403, description = "the user rejected the request", body = ErrorResponse),
+        (status = 404, description = "no such request, or the token isn't its own", body = ErrorResponse),
+        (status = 409, description = "another wait collected the answer meanwhile", body = ErrorResponse),
+        (status = 410, description = "expired, superseded or withdrawn", body = ErrorResponse),
+        (status = 503, description = "shutting down, wait again elsewhere", body = ErrorResponse),
+    ),
+)]
+async fn wait(
+    req: HttpRequest,
+    code: web::Path<RequestCode>,
+    query: web::Query<WaitQuery>,
+) -> Result<HttpResponse, AppError> {
+    let wait = parse_wait(query.timeout.as_deref())?;
+    let token = req.headers().get(TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(AppError::RequestNotFound(None))?;
+    // a waiter holds a connection just like a websocket does
+    let _connection = connections::open(peer::ip(&req))?;
+
+    let collected = tokio::select! {
+        collected = parked::collect(code.into_inner(), token, Some(wait)) => collected?,
+        () = shutdown::drained() => return Err(AppError::ShuttingDown),
+    };
+    let Some(resolution) = collected else {
+        return Ok(HttpResponse::NoContent().finish())
+    };
+
+    let resolved = Resolved {
+        message: protocol::answer_message(&resolution.data, resolution.verification.as_ref()),
+        receipt: &resolution.receipt,
+        user_hint: resolution.user_hint.as_deref(),
+    };
+    Ok(HttpResponse::Ok().content_type("application/json").body(sensitive::bytes(to_json_str(&resolved)?.into_bytes())))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(
+            web::resource("/requests")
+                .route(web::post().to(create))
+                .wrap(from_fn(rate_limit::limit))
+                .wrap(from_fn(telemetry::trace))
+        )
+        // a wrong token answers 404 like a wrong code, so guessing both runs into the lockout
+        .service(
+            web::resource("/requests/{code}/wait")
+                .route(web::get().to(wait))
+                .wrap(from_fn(lockout::guard))
+                .wrap(from_fn(telemetry::trace))
+        );
+}
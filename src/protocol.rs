@@ -0,0 +1,405 @@
+use std::fmt::Write;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use zeroize::Zeroize;
+use crate::cbor;
+use crate::custom_fields::CustomField;
+use crate::hash_checks::HashChecks;
+use crate::challenge::Challenge;
+use crate::client_errors::ClientError;
+use crate::error::{AppError, ErrorBody};
+use crate::ocr::Verification;
+use crate::req_code::{CodeKind, RequestCode};
+use crate::timing::Timing;
+use crate::model::{AutofillData, Payload, RequestedAutofillFields};
+use crate::{env_or, to_json_str};
+
+/// legacy sessions since the last usage report
+static LEGACY_SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// data frames longer than this go out in binary chunks, some browsers and proxies drop text
+/// frames of a few megabytes
+static STREAM_THRESHOLD: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_STREAM_THRESHOLD_BYTES", 1 << 20));
+static STREAM_CHUNK_BYTES: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_STREAM_CHUNK_BYTES", 64 << 10).max(1));
+
+/// which dialect a `/listen` client speaks, decided by its first frame
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Protocol {
+    /// bare spec json in, bare code and data out, kept for old frontends
+    Legacy,
+    /// tagged `{"type": ...}` messages both ways, as json text frames or cbor binary ones
+    Envelope { binary: bool },
+}
+
+/// a frame as it goes over the socket
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Frame {
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Frame::Text(frame) => frame.as_bytes(),
+            Frame::Binary(frame) => frame,
+        }
+    }
+}
+
+impl Zeroize for Frame {
+    fn zeroize(&mut self) {
+        match self {
+            Frame::Text(frame) => frame.zeroize(),
+            Frame::Binary(frame) => frame.zeroize(),
+        }
+    }
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Legacy => "legacy",
+            Protocol::Envelope { binary: false } => "envelope",
+            Protocol::Envelope { binary: true } => "cbor",
+        }
+    }
+
+    pub fn binary(self) -> bool {
+        matches!(self, Protocol::Envelope { binary: true })
+    }
+
+    /// frames are all built as json, cbor listeners get them converted
+    pub fn encode(self, mut json: String) -> Result<Frame, AppError> {
+        match self.binary() {
+            true => {
+                let frame = cbor::from_json(&json).map(Frame::Binary);
+                json.zeroize();
+                frame
+            }
+            false => Ok(Frame::Text(json)),
+        }
+    }
+}
+
+/// what a listener asks for when opening a request
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+pub struct ListenSpec {
+    pub fields: RequestedAutofillFields,
+    /// bounded by the tenant's ttl policy, keyless listeners always get the operator's default
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// base64 x25519 key, when set resolvers must seal the data to it
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// stable per browser session, newer listeners with the same one supersede older ones
+    #[serde(default)]
+    pub session: Option<String>,
+    #[serde(default)]
+    pub code_format: CodeKind,
+    /// also have the server make up a pin that resolvers must type in
+    #[serde(default)]
+    pub pin: bool,
+    /// urls that get the resolved data posted to them as well, only for keyed listeners
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    /// other websockets that may follow the request at `/requests/{code}/follow` with the same
+    /// api key, like the tenant's backend next to the page showing the code; they get what
+    /// webhooks get
+    #[serde(default)]
+    pub followers: usize,
+    /// one-off fields the app asks for by their label
+    #[serde(default)]
+    pub custom_fields: Vec<CustomField>,
+    /// values the listener already has, the resolver's are only compared to them
+    #[serde(default)]
+    pub hash_checks: Option<HashChecks>,
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// what an earlier request's `linked` message said, pushes the code to that user's phone
+    #[serde(default)]
+    pub user_hint: Option<String>,
+    /// only the devices behind `user_hint` may answer, typing the code on any other phone fails
+    #[serde(default)]
+    pub linked_devices_only: bool,
+    /// a number the user typed in, the code is texted to it, only for keyed listeners
+    #[serde(default)]
+    pub sms_to: Option<String>,
+}
+
+/// how shared data reaches the listener
+#[derive(Copy, Clone, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    /// signed by this server as a W3C verifiable credential, for wallet compatible flows
+    JwtVc,
+    /// an SD-JWT with every field disclosable on its own
+    SdJwt,
+}
+
+/// messages a listener sends
+#[derive(Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Request(ListenSpec),
+    Solution { solution: String },
+    /// something we sent couldn't be parsed or shown, only counted and logged
+    ClientError(ClientError),
+}
+
+/// messages sent back to an envelope listener
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    Challenge { nonce: &'a str, difficulty: u32 },
+    Code {
+        code: &'a str,
+        display: String,
+        expires_in: u64,
+        /// only for the website to show, never sent anywhere else
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pin: Option<&'a str>,
+    },
+    Data {
+        data: &'a AutofillData,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        verification: Option<&'a Verification>,
+    },
+    Sealed { ciphertext: &'a str },
+    Credential {
+        credential: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        verification: Option<&'a Verification>,
+    },
+    /// the data frame follows in `chunks` binary frames, put back together it is the `data` or
+    /// `sealed` message it would have been
+    Stream { bytes: usize, chunks: usize },
+    Error { error: ErrorBody },
+    Timing(Timing),
+    Superseded,
+    /// the request ran out before anyone answered it
+    Expired,
+    Receipt { receipt: &'a str },
+    /// the user's phone can now be reached by sending this back as the `user_hint` of a request
+    Linked { user_hint: &'a str },
+    /// closes out a delivery, over the data frames exactly as they were sent
+    Integrity { sha256: String, bytes: usize },
+}
+
+/// what webhooks get, the message an envelope listener would plus the receipt
+#[derive(Serialize)]
+struct WebhookBody<'a> {
+    correlation_id: String,
+    #[serde(flatten)]
+    message: ServerMessage<'a>,
+    receipt: &'a str,
+}
+
+pub fn webhook_body(
+    payload: &Payload,
+    verification: Option<&Verification>,
+    receipt: &str,
+    correlation_id: String,
+) -> Result<String, AppError> {
+    let message = answer_message(payload, verification);
+    to_json_str(&WebhookBody { correlation_id, message, receipt })
+}
+
+pub fn answer_message<'a>(payload: &'a Payload, verification: Option<&'a Verification>) -> ServerMessage<'a> {
+    match payload {
+        Payload::Plain(data) => ServerMessage::Data { data, verification },
+        Payload::Sealed { ciphertext } => ServerMessage::Sealed { ciphertext },
+        Payload::Credential { jwt } => ServerMessage::Credential { credential: jwt, verification },
+    }
+}
+
+/// the `data`, `sealed` or `credential` message an envelope listener gets, for listeners that
+/// aren't on a websocket
+pub fn answer_json(payload: &Payload, verification: Option<&Verification>) -> Result<String, AppError> {
+    to_json_str(&answer_message(payload, verification))
+}
+
+/// a data frame split up for sending
+pub struct Stream {
+    pub announcement: String,
+    pub chunks: Vec<Vec<u8>>,
+}
+
+/// works out the protocol from the first frame and pulls the spec out of it
+pub fn parse_first_frame(json: &str, binary: bool) -> Option<(Protocol, ListenSpec)> {
+    let value = serde_json::from_str::<serde_json::Value>(json).ok()?;
+
+    if value.get("type").is_some() {
+        let ClientMessage::Request(spec) = serde_json::from_value(value).ok()? else {
+            return None
+        };
+        return Some((Protocol::Envelope { binary }, spec))
+    }
+
+    // cbor came after the legacy protocol was frozen
+    if binary {
+        return None
+    }
+
+    let fields = serde_json::from_value(value).ok()?;
+    LEGACY_SESSIONS.fetch_add(1, Ordering::Relaxed);
+
+    Some((Protocol::Legacy, ListenSpec {
+        fields,
+        ttl_secs: None,
+        public_key: None,
+        session: None,
+        code_format: CodeKind::default(),
+        pin: false,
+        webhooks: Vec::new(),
+        followers: 0,
+        custom_fields: Vec::new(),
+        hash_checks: None,
+        format: OutputFormat::Json,
+        user_hint: None,
+        linked_devices_only: false,
+        sms_to: None,
+    }))
+}
+
+/// parses any frame after the first one, only envelope listeners send those
+pub fn parse_message(json: &str) -> Option<ClientMessage> {
+    serde_json::from_str(json).ok()
+}
+
+impl Protocol {
+    /// legacy listeners can't be challenged, so there is no legacy form
+    pub fn challenge_frame(challenge: &Challenge) -> Result<String, AppError> {
+        to_json_str(&ServerMessage::Challenge {
+            nonce: &challenge.nonce,
+            difficulty: challenge.difficulty,
+        })
+    }
+
+    pub fn code_frame(self, code: &RequestCode, ttl: Duration, pin: Option<&str>) -> Result<String, AppError> {
+        match self {
+            Protocol::Legacy => Ok(code.as_str().to_owned()),
+            Protocol::Envelope { .. } => to_json_str(&ServerMessage::Code {
+                code: code.as_str(),
+                display: code.display_grouped(),
+                expires_in: ttl.as_secs(),
+                pin,
+            }),
+        }
+    }
+
+    /// legacy listeners only ever get the bare data, without any verification block
+    pub fn data_frame(self, payload: &Payload, verification: Option<&Verification>) -> Result<String, AppError> {
+        match (self, payload) {
+            (Protocol::Legacy, Payload::Plain(data)) => to_json_str(data),
+            (Protocol::Envelope { .. }, Payload::Plain(data)) => to_json_str(&ServerMessage::Data { data, verification }),
+            // only envelope listeners can hand out a public key, so only they get sealed data,
+            // but the relay stays opaque either way
+            (_, Payload::Sealed { ciphertext }) => to_json_str(&ServerMessage::Sealed { ciphertext }),
+            // legacy specifications can't ask for one
+            (_, Payload::Credential { jwt }) => to_json_str(&ServerMessage::Credential { credential: jwt, verification }),
+        }
+    }
+
+    /// a data frame too big to send whole, as the frame announcing it and the binary frames that
+    /// carry it, each starting with its length as a big endian u32; the integrity frame still
+    /// covers the data frame itself
+    pub fn stream_frames(self, data_frame: &[u8]) -> Option<Result<Stream, AppError>> {
+        if self == Protocol::Legacy || data_frame.len() <= *STREAM_THRESHOLD {
+            return None
+        }
+
+        let chunks = data_frame
+            .chunks(*STREAM_CHUNK_BYTES)
+            .map(|chunk| {
+                let mut frame = Vec::with_capacity(4 + chunk.len());
+                frame.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+                frame.extend_from_slice(chunk);
+                frame
+            })
+            .collect::<Vec<_>>();
+
+        let announcement = ServerMessage::Stream { bytes: data_frame.len(), chunks: chunks.len() };
+        Some(to_json_str(&announcement).map(|announcement| Stream { announcement, chunks }))
+    }
+
+    /// sent after the data so listeners can tell where time went
+    pub fn timing_frame(self, timing: &Timing) -> Option<Result<String, AppError>> {
+        match self {
+            Protocol::Legacy => None,
+            Protocol::Envelope { .. } => Some(to_json_str(&ServerMessage::Timing(*timing))),
+        }
+    }
+
+    /// hands the listener the same signed receipt the resolver got
+    pub fn receipt_frame(self, receipt: &str) -> Option<Result<String, AppError>> {
+        match self {
+            Protocol::Legacy => None,
+            Protocol::Envelope { .. } => Some(to_json_str(&ServerMessage::Receipt { receipt })),
+        }
+    }
+
+    pub fn linked_frame(self, user_hint: &str) -> Option<Result<String, AppError>> {
+        match self {
+            Protocol::Legacy => None,
+            Protocol::Envelope { .. } => Some(to_json_str(&ServerMessage::Linked { user_hint })),
+        }
+    }
+
+    /// lets the listener check it got every byte of the data frames, sent last
+    pub fn integrity_frame(self, data_frames: &[&[u8]]) -> Option<Result<String, AppError>> {
+        match self {
+            Protocol::Legacy => None,
+            Protocol::Envelope { .. } => {
+                let mut hasher = Sha256::new();
+                for frame in data_frames {
+                    hasher.update(frame);
+                }
+                let sha256 = hasher.finalize().iter().fold(String::with_capacity(64), |mut hex, byte| {
+                    let _ = write!(hex, "{byte:02x}");
+                    hex
+                });
+
+                let bytes = data_frames.iter().map(|frame| frame.len()).sum();
+                Some(to_json_str(&ServerMessage::Integrity { sha256, bytes }))
+            }
+        }
+    }
+
+    /// tells a listener a newer one for the same session replaced its code
+    pub fn superseded_frame(self) -> Option<Result<String, AppError>> {
+        match self {
+            Protocol::Legacy => None,
+            Protocol::Envelope { .. } => Some(to_json_str(&ServerMessage::Superseded)),
+        }
+    }
+
+    /// tells a listener its request ran out, legacy ones only get the close code
+    pub fn expired_frame(self) -> Option<Result<String, AppError>> {
+        match self {
+            Protocol::Legacy => None,
+            Protocol::Envelope { .. } => Some(to_json_str(&ServerMessage::Expired)),
+        }
+    }
+
+    /// legacy listeners only ever see the close frame
+    pub fn error_frame(self, err: &AppError) -> Option<String> {
+        match self {
+            Protocol::Legacy => None,
+            Protocol::Envelope { .. } => to_json_str(&ServerMessage::Error { error: err.body() }).ok(),
+        }
+    }
+}
+
+/// logs how many listeners used the legacy protocol since the last call
+pub fn report_legacy_usage() {
+    let count = LEGACY_SESSIONS.swap(0, Ordering::Relaxed);
+    if count > 0 {
+        log::warn!("{count} listeners used the deprecated bare spec protocol since the last report");
+    }
+}
@@ -0,0 +1,147 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{LazyLock, OnceLock};
+use std::time::Duration;
+use actix_web::http::StatusCode;
+use serde::Serialize;
+use crate::config::setting;
+use crate::devices::{self, Owner, Platform, Target};
+use crate::metrics::{Delivery, DeliveryError};
+use crate::req_code::RequestCode;
+use crate::{demo, env_or, http_client};
+
+static GATEWAY_URL: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_PUSH_GATEWAY_URL"));
+static GATEWAY_TOKEN: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_PUSH_GATEWAY_TOKEN"));
+static TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(env_or("ABSHER_PUSH_TIMEOUT_MS", 5000))
+});
+
+/// what the app gets to open a request without the user typing the code
+#[derive(Serialize)]
+pub struct Notification {
+    pub code: String,
+    pub display: String,
+    pub expires_in: u64,
+    pub correlation_id: String,
+}
+
+pub enum PushError {
+    /// the token is gone for good, the device gets forgotten
+    Unregistered,
+    Failed(DeliveryError),
+}
+
+pub type Sending<'a> = Pin<Box<dyn Future<Output = Result<(), PushError>> + 'a>>;
+
+/// delivers notifications to fcm or apns, runs on the worker that issued the code
+pub trait Provider: Send + Sync {
+    fn send<'a>(&'a self, platform: Platform, push_token: &'a str, notification: &'a Notification) -> Sending<'a>;
+}
+
+/// posts every notification to a service of the operator's that holds the fcm and apns
+/// credentials, answering 404 or 410 for tokens that aren't valid anymore
+pub struct Gateway {
+    pub url: String,
+    pub token: Option<String>,
+    pub timeout: Duration,
+}
+
+#[derive(Serialize)]
+struct GatewayBody<'a> {
+    platform: Platform,
+    push_token: &'a str,
+    notification: &'a Notification,
+}
+
+impl Provider for Gateway {
+    fn send<'a>(&'a self, platform: Platform, push_token: &'a str, notification: &'a Notification) -> Sending<'a> {
+        Box::pin(async move {
+            let mut request = http_client().post(&self.url).timeout(self.timeout);
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+
+            let response = request
+                .send_json(&GatewayBody { platform, push_token, notification })
+                .await
+                .map_err(|err| PushError::Failed(err.into()))?;
+
+            match response.status() {
+                status if status.is_success() => Ok(()),
+                StatusCode::NOT_FOUND | StatusCode::GONE => Err(PushError::Unregistered),
+                status => Err(PushError::Failed(DeliveryError::status(status))),
+            }
+        })
+    }
+}
+
+/// only logs what would have been sent, for dev mode
+pub struct LogOnly;
+
+impl Provider for LogOnly {
+    fn send<'a>(&'a self, platform: Platform, _push_token: &'a str, notification: &'a Notification) -> Sending<'a> {
+        let Notification { display, correlation_id, .. } = notification;
+        log::info!("[{correlation_id}] would push {display} to an {} device", platform.name());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+static PROVIDER: OnceLock<Option<Box<dyn Provider>>> = OnceLock::new();
+
+/// replaces the provider the settings pick, only before the first push; hands `provider` back
+/// if that is too late
+pub fn install<P: Provider + 'static>(provider: P) -> Result<(), P> {
+    let mut provider = Some(provider);
+    PROVIDER.get_or_init(|| provider.take().map(|provider| Box::new(provider) as Box<dyn Provider>));
+    match provider {
+        None => Ok(()),
+        Some(provider) => Err(provider),
+    }
+}
+
+/// the gateway if one is set, logging in dev mode, otherwise codes are never pushed
+fn provider() -> Option<&'static dyn Provider> {
+    PROVIDER.get_or_init(|| match GATEWAY_URL.clone() {
+        Some(url) => Some(Box::new(Gateway { url, token: GATEWAY_TOKEN.clone(), timeout: *TIMEOUT })),
+        None if demo::enabled() => Some(Box::new(LogOnly)),
+        None => None,
+    }).as_deref()
+}
+
+/// sends `code` to every device of `owner` in the background
+pub fn notify(owner: &Owner, code: &RequestCode, expires_in: Duration) {
+    let Some(provider) = provider() else {
+        return
+    };
+
+    let notification = Rc::new(Notification {
+        code: code.as_str().to_owned(),
+        display: code.display_grouped(),
+        expires_in: expires_in.as_secs(),
+        correlation_id: code.correlation_id(),
+    });
+    for target in devices::targets(owner) {
+        // awc clients are bound to the current thread, so is this
+        actix_web::rt::spawn(push(provider, target, notification.clone(), Delivery::queue("push")));
+    }
+}
+
+async fn push(provider: &dyn Provider, target: Target, notification: Rc<Notification>, delivery: Delivery) {
+    let correlation = notification.correlation_id.as_str();
+    match provider.send(target.platform, &target.push_token, &notification).await {
+        Ok(()) => {
+            delivery.delivered();
+            log::info!(code_hash = correlation; "[{correlation}] pushed to linked device {}", target.device);
+        }
+        Err(PushError::Unregistered) => {
+            delivery.failed("unregistered");
+            log::info!(code_hash = correlation; "[{correlation}] the push token of device {} is gone, forgetting it", target.device);
+            devices::forget(&target.device);
+        }
+        Err(PushError::Failed(err)) => {
+            delivery.failed(err.cause);
+            log::warn!(code_hash = correlation; "[{correlation}] unable to push to device {}: {err}", target.device);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
+use actix_http::{BoxedPayloadStream, Request};
+use actix_rt::Arbiter;
+use actix_service::{IntoServiceFactory, Service, ServiceFactory};
+use actix_web::body::MessageBody;
+use actix_web::dev::{AppConfig, Payload, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::{Method, Version};
+use actix_web::HttpResponse;
+use bytes::{Buf, Bytes};
+use futures_util::stream;
+use h3::server::{RequestResolver, RequestStream};
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::ServerConfig;
+use crate::{handlers, shutdown};
+
+/// the `alt-svc` every response carries once http/3 is served
+static ALT_SVC: OnceLock<String> = OnceLock::new();
+
+/// how long clients may remember that http/3 is there
+const ALT_SVC_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// removed on the way out, http/3 has no connection level headers
+const HOP_BY_HOP: [HeaderName; 4] = [header::CONNECTION, header::TRANSFER_ENCODING, header::UPGRADE, header::TE];
+
+pub fn alt_svc() -> Option<String> {
+    ALT_SVC.get().cloned()
+}
+
+/// serves http/3 on a udp port with the https certificate, importantly for phones on flaky
+/// connections: one lost packet doesn't stall every upload and a connection survives a change of
+/// network. the websocket stays on https, http/3 has no upgrades
+pub fn start(ips: &[IpAddr], port: u16, mut crypto: ServerConfig) -> io::Result<()> {
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = QuicServerConfig::try_from(crypto)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("unable to use tls for http/3: {err}")))?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+    let sockets = ips.iter()
+        .map(|&ip| UdpSocket::bind(SocketAddr::new(ip, port)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let arbiter = Arbiter::new();
+    for socket in sockets {
+        let addr = socket.local_addr()?;
+        log::info!("serving http/3 on {addr}");
+        let config = config.clone();
+        arbiter.spawn_fn(move || {
+            actix_web::rt::spawn(serve(socket, addr, config));
+        });
+    }
+
+    let _ = ALT_SVC.set(format!("h3=\":{port}\"; ma={ALT_SVC_MAX_AGE_SECS}"));
+    Ok(())
+}
+
+async fn serve(socket: UdpSocket, addr: SocketAddr, config: quinn::ServerConfig) {
+    let endpoint = quinn::Endpoint::new(Default::default(), Some(config), socket, Arc::new(quinn::TokioRuntime));
+    let endpoint = match endpoint {
+        Ok(endpoint) => endpoint,
+        Err(err) => return log::error!("unable to serve http/3 on {addr}: {err}"),
+    };
+    // the same app the workers run, handed requests instead of sockets
+    let service = match handlers::app().into_factory().new_service(AppConfig::default()).await {
+        Ok(service) => Rc::new(service),
+        Err(()) => return log::error!("unable to build the app for http/3 on {addr}"),
+    };
+
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            () = shutdown::drained() => break,
+        };
+        let Some(incoming) = incoming else {
+            break
+        };
+        actix_web::rt::spawn(connection(incoming, service.clone()));
+    }
+
+    // connections still open get to finish, they were told to go away
+    endpoint.wait_idle().await;
+}
+
+async fn connection<S, B>(incoming: quinn::Incoming, service: Rc<S>)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    let peer = incoming.remote_address();
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(err) => return log::debug!("http/3 handshake with {peer} failed: {err}"),
+    };
+    let mut connection = match h3::server::Connection::new(h3_quinn::Connection::new(connection)).await {
+        Ok(connection) => connection,
+        Err(err) => return log::debug!("http/3 connection with {peer} failed: {err}"),
+    };
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept() => accepted,
+            () = shutdown::drained() => {
+                let _ = connection.shutdown(0).await;
+                break
+            }
+        };
+        match accepted {
+            Ok(Some(resolver)) => {
+                actix_web::rt::spawn(request(resolver, peer, service.clone()));
+            }
+            Ok(None) => break,
+            Err(err) => {
+                if !err.is_h3_no_error() {
+                    log::debug!("http/3 connection with {peer} ended: {err}");
+                }
+                break
+            }
+        }
+    }
+}
+
+/// the request body as actix reads it
+fn payload(mut recv: RequestStream<h3_quinn::RecvStream, Bytes>) -> Payload {
+    let chunks = stream::poll_fn(move |cx| recv.poll_recv_data(cx).map(|chunk| match chunk {
+        Ok(Some(mut chunk)) => Some(Ok(chunk.copy_to_bytes(chunk.remaining()))),
+        Ok(None) => None,
+        Err(err) => Some(Err(PayloadError::Io(io::Error::other(err)))),
+    }));
+    Payload::from(Box::pin(chunks) as BoxedPayloadStream)
+}
+
+async fn request<S, B>(resolver: RequestResolver<h3_quinn::Connection, Bytes>, peer: SocketAddr, service: Rc<S>)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody + 'static,
+{
+    let (head, stream) = match resolver.resolve_request().await {
+        Ok(resolved) => resolved,
+        Err(err) => return log::debug!("unable to read an http/3 request from {peer}: {err}"),
+    };
+    let (mut send, recv) = stream.split();
+
+    let mut req = Request::with_payload(payload(recv));
+    let into = req.head_mut();
+    into.method = Method::from_bytes(head.method().as_str().as_bytes()).unwrap_or_default();
+    into.uri = head.uri().to_string().parse().unwrap_or_default();
+    into.version = Version::HTTP_3;
+    into.peer_addr = Some(peer);
+    for (name, value) in head.headers() {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_ref()), HeaderValue::from_bytes(value.as_bytes())) {
+            into.headers.append(name, value);
+        }
+    }
+    // `:authority` stands in for the host header
+    if !into.headers.contains_key(header::HOST)
+        && let Some(authority) = head.uri().authority()
+        && let Ok(host) = HeaderValue::from_str(authority.as_str())
+    {
+        into.headers.insert(header::HOST, host);
+    }
+
+    let response = match service.call(req).await {
+        Ok(response) => response.map_into_boxed_body().into_parts().1,
+        Err(err) => HttpResponse::from_error(err),
+    };
+
+    let mut parts = http::Response::builder().status(response.status().as_u16());
+    for (name, value) in response.headers() {
+        if !HOP_BY_HOP.contains(name) {
+            parts = parts.header(name.as_str(), value.as_bytes());
+        }
+    }
+    let Ok(parts) = parts.body(()) else {
+        return
+    };
+    if let Err(err) = send.send_response(parts).await {
+        return log::debug!("unable to answer an http/3 request from {peer}: {err}")
+    }
+
+    let mut body = response.into_body();
+    loop {
+        let chunk = std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_next(cx)).await;
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(err)) => return log::debug!("the body of an http/3 answer to {peer} failed: {err}"),
+            None => break,
+        };
+        if let Err(err) = send.send_data(chunk).await {
+            return log::debug!("http/3 answer to {peer} cut off: {err}")
+        }
+    }
+    let _ = send.finish().await;
+}
@@ -0,0 +1,184 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use dashmap::DashMap;
+use crate::error::AppError;
+use crate::{admin, env_or, peer};
+
+#[derive(Copy, Clone)]
+struct Limit {
+    burst: f64,
+    per_sec: f64,
+}
+
+impl Limit {
+    fn from_env(prefix: &str, burst: f64, per_sec: f64) -> Self {
+        Self {
+            burst: env_or(&format!("{prefix}_BURST"), burst).max(1.0),
+            per_sec: env_or(&format!("{prefix}_PER_SEC"), per_sec).max(f64::MIN_POSITIVE),
+        }
+    }
+
+    /// how long an untouched bucket takes to fill back up
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.burst / self.per_sec)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Limits {
+    ip: Limit,
+    key: Limit,
+}
+
+impl Limits {
+    fn from_env() -> Self {
+        Self {
+            ip: Limit::from_env("ABSHER_IP_RATE", 30.0, 1.0),
+            key: Limit::from_env("ABSHER_KEY_RATE", 300.0, 20.0),
+        }
+    }
+}
+
+/// swapped whole on a config reload, buckets keep their tokens and refill at the new rate
+static LIMITS: LazyLock<RwLock<Limits>> = LazyLock::new(|| RwLock::new(Limits::from_env()));
+
+fn limits() -> Limits {
+    *LIMITS.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn reload() {
+    *LIMITS.write().unwrap_or_else(PoisonError::into_inner) = Limits::from_env();
+}
+
+/// set by servers that only ever see one local caller, like the one tests run against
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// lets every request through from now on
+pub(crate) fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+static IP_BUCKETS: LazyLock<DashMap<IpAddr, Bucket>> = LazyLock::new(DashMap::new);
+static KEY_BUCKETS: LazyLock<DashMap<String, Bucket>> = LazyLock::new(DashMap::new);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: Limit, now: Instant) -> Self {
+        Self { tokens: limit.burst, updated: now }
+    }
+
+    /// takes a token, or returns how long until one is available
+    fn take(&mut self, limit: Limit, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec).min(limit.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(())
+        }
+
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / limit.per_sec))
+    }
+}
+
+fn take(api_key: Option<&str>, ip: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+    if DISABLED.load(Ordering::Relaxed) {
+        return Ok(())
+    }
+
+    // keyed callers are limited per key, so many integrators behind one NAT don't starve each other
+    if let Some(key) = api_key.and_then(admin::authenticate) {
+        let limit = limits().key;
+        return KEY_BUCKETS.entry(key.id)
+            .or_insert_with(|| Bucket::full(limit, now))
+            .take(limit, now)
+    }
+
+    let Some(ip) = ip else {
+        return Ok(())
+    };
+
+    let limit = limits().ip;
+    IP_BUCKETS.entry(ip)
+        .or_insert_with(|| Bucket::full(limit, now))
+        .take(limit, now)
+}
+
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    check(admin::presented(req.headers()), peer::ip(req.request()))?;
+    next.call(req).await
+}
+
+/// takes a token for a caller that doesn't come through the middleware
+pub(crate) fn check(api_key: Option<&str>, ip: Option<IpAddr>) -> Result<(), AppError> {
+    take(api_key, ip, Instant::now()).map_err(|wait| AppError::RateLimited {
+        retry_after: Duration::from_secs(wait.as_secs_f64().ceil() as u64),
+    })
+}
+
+/// drops buckets that have been idle long enough to be full again
+pub fn prune() {
+    let now = Instant::now();
+    let limits = limits();
+
+    let ip_refill = limits.ip.refill_time();
+    IP_BUCKETS.retain(|_ip, bucket| now.saturating_duration_since(bucket.updated) < ip_refill);
+
+    let key_refill = limits.key.refill_time();
+    KEY_BUCKETS.retain(|_key, bucket| now.saturating_duration_since(bucket.updated) < key_refill);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit { burst: 3.0, per_sec: 0.5 };
+
+    #[test]
+    fn bursts_pass_then_wait_for_a_token() {
+        let now = Instant::now();
+        let mut bucket = Bucket::full(LIMIT, now);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(LIMIT, now), Ok(()));
+        }
+
+        assert_eq!(bucket.take(LIMIT, now), Err(Duration::from_secs(2)));
+        assert_eq!(bucket.take(LIMIT, now + Duration::from_secs(1)), Err(Duration::from_secs(1)));
+        assert_eq!(bucket.take(LIMIT, now + Duration::from_secs(2)), Ok(()));
+    }
+
+    #[test]
+    fn idle_buckets_fill_up_to_the_burst_only() {
+        let now = Instant::now();
+        let mut bucket = Bucket::full(LIMIT, now);
+        assert_eq!(bucket.take(LIMIT, now), Ok(()));
+
+        let later = now + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(LIMIT, later), Ok(()));
+        }
+        assert!(bucket.take(LIMIT, later).is_err());
+        assert_eq!(LIMIT.refill_time(), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn clocks_going_back_take_nothing_away() {
+        let now = Instant::now() + Duration::from_secs(10);
+        let mut bucket = Bucket::full(LIMIT, now);
+        assert_eq!(bucket.take(LIMIT, now - Duration::from_secs(10)), Ok(()));
+        assert!(bucket.tokens > 1.0);
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::LazyLock;
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::config::setting;
+use crate::error::AppError;
+use crate::model::{AutofillData, Payload, RequestedAutofillFields};
+use crate::req_code::RequestCode;
+use crate::{signing, to_json_str};
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    code: RequestCode,
+    fields: &'a RequestedAutofillFields,
+    /// sha256 of the relayed payload, base64url; for plain data that is its compact json,
+    /// for sealed data the ciphertext string and for credentials the jwt
+    digest: String,
+    created_at: u64,
+    resolved_at: u64,
+}
+
+/// what gets hashed into a receipt, exactly as the listener receives it
+fn payload_bytes(payload: &Payload) -> Result<Vec<u8>, AppError> {
+    match payload {
+        Payload::Plain(data) => to_json_str(data).map(String::into_bytes),
+        Payload::Sealed { ciphertext } => Ok(ciphertext.as_bytes().to_vec()),
+        Payload::Credential { jwt } => Ok(jwt.as_bytes().to_vec()),
+    }
+}
+
+/// a compact JWS proving what was shared for `code` and when
+pub fn sign(
+    code: RequestCode,
+    fields: &RequestedAutofillFields,
+    payload: &Payload,
+    created_at: u64,
+    resolved_at: u64,
+) -> Result<String, AppError> {
+    let claims = Claims {
+        code,
+        fields,
+        digest: BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(payload_bytes(payload)?)),
+        created_at,
+        resolved_at,
+    };
+
+    signing::compact("absher-receipt+jwt", &claims)
+}
+
+#[derive(Serialize)]
+struct AgeClaims {
+    code: RequestCode,
+    age_over: u8,
+    over: bool,
+    issued_at: u64,
+}
+
+/// a compact JWS saying whether the resolver of `code` is at least `age`, signed like receipts
+pub fn attest_age(code: RequestCode, age: u8, over: bool, issued_at: u64) -> Result<String, AppError> {
+    signing::compact("absher-age+jwt", &AgeClaims { code, age_over: age, over, issued_at })
+}
+
+/// who credentials say issued them, a did or url verifiers know this instance by
+static ISSUER: LazyLock<String> = LazyLock::new(|| {
+    setting("ABSHER_CREDENTIAL_ISSUER").unwrap_or_else(|| "urn:absher-zt:issuer".to_owned())
+});
+
+#[derive(Serialize)]
+struct Credential<'a> {
+    #[serde(rename = "@context")]
+    context: [&'static str; 1],
+    #[serde(rename = "type")]
+    kinds: [&'static str; 2],
+    #[serde(rename = "credentialSubject")]
+    subject: &'a AutofillData,
+}
+
+#[derive(Serialize)]
+struct CredentialClaims<'a> {
+    iss: &'a str,
+    jti: String,
+    nbf: u64,
+    iat: u64,
+    vc: Credential<'a>,
+}
+
+/// the shared data as a W3C verifiable credential in its JWT encoding, issued by this server
+pub fn issue_credential(code: RequestCode, data: &AutofillData, issued_at: u64) -> Result<String, AppError> {
+    let claims = CredentialClaims {
+        iss: &ISSUER,
+        jti: format!("urn:absher-zt:{}", code.correlation_id()),
+        nbf: issued_at,
+        iat: issued_at,
+        vc: Credential {
+            context: ["https://www.w3.org/2018/credentials/v1"],
+            kinds: ["VerifiableCredential", "AbsherIdentityCredential"],
+            subject: data,
+        },
+    };
+
+    signing::compact("JWT", &claims)
+}
+
+#[derive(Serialize)]
+struct SdJwtClaims<'a> {
+    iss: &'a str,
+    jti: String,
+    iat: u64,
+    vct: &'static str,
+    _sd: Vec<String>,
+    _sd_alg: &'static str,
+}
+
+/// the shared data as an SD-JWT, every field a disclosure of its own so the listener can later
+/// present only some of them; that is the issuer signed jwt followed by each disclosure, all
+/// separated by `~`
+pub fn issue_sd_jwt(code: RequestCode, data: &AutofillData, issued_at: u64) -> Result<String, AppError> {
+    let serde_json::Value::Object(fields) = serde_json::to_value(data).map_err(|err| {
+        log::error!("unable to turn the shared data into claims: {err}");
+        AppError::Serialization
+    })? else {
+        return Err(AppError::Serialization)
+    };
+
+    // fields that weren't shared are left out rather than disclosed as empty
+    let shared = fields.into_iter().filter(|(_, value)| match value {
+        serde_json::Value::Null => false,
+        serde_json::Value::Object(inner) => !inner.is_empty(),
+        _ => true,
+    });
+
+    let mut disclosures = Vec::new();
+    for (name, value) in shared {
+        let mut salt = [0; 16];
+        rand::rng().fill_bytes(&mut salt);
+        let disclosure = (BASE64_URL_SAFE_NO_PAD.encode(salt), name, value);
+        disclosures.push(BASE64_URL_SAFE_NO_PAD.encode(to_json_str(&disclosure)?));
+    }
+
+    // sorted, so the digests don't give away the order the fields were in
+    let mut digests = disclosures.iter()
+        .map(|disclosure| BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(disclosure.as_bytes())))
+        .collect::<Vec<_>>();
+    digests.sort();
+
+    let claims = SdJwtClaims {
+        iss: &ISSUER,
+        jti: format!("urn:absher-zt:{}", code.correlation_id()),
+        iat: issued_at,
+        vct: "urn:absher-zt:identity",
+        _sd: digests,
+        _sd_alg: "sha-256",
+    };
+
+    let mut sd_jwt = signing::compact("dc+sd-jwt", &claims)?;
+    for disclosure in disclosures {
+        sd_jwt.push('~');
+        sd_jwt.push_str(&disclosure);
+    }
+    sd_jwt.push('~');
+
+    Ok(sd_jwt)
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+
+/// debug formats an optional field as whether it is there, never what it holds
+pub struct Presence<'a, T>(pub &'a Option<T>);
+
+impl<T> fmt::Debug for Presence<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Some(<redacted>)"),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+/// a body that failed to deserialize, without the values serde quotes back, since a phone
+/// number sent as an integer would otherwise end up in the error body and the logs
+pub fn parse_error(err: &impl fmt::Display) -> String {
+    let message = err.to_string();
+
+    // serde words these as `invalid type: string "…", expected …`, possibly behind actix's own prefix
+    for marker in ["invalid type: ", "invalid value: "] {
+        if let Some(start) = message.find(marker) {
+            let (head, rest) = message.split_at(start + marker.len());
+            let kind = rest.split([' ', ',']).next().unwrap_or_default();
+            let expected = rest.rfind(", expected").map_or("", |at| &rest[at..]);
+            return format!("{head}{kind}{expected}")
+        }
+    }
+
+    message
+}
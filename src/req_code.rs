@@ -1,38 +1,335 @@
-use std::fmt::Formatter;
+use std::fmt::{Formatter, Write};
 use std::str::FromStr;
-use rand::Rng;
-use serde::{Deserialize, Deserializer};
+use std::sync::{LazyLock, Mutex, OnceLock, PoisonError};
+use rand::rngs::{OsRng, StdRng};
+use rand::{Rng, SeedableRng, TryRngCore};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{Error, Unexpected, Visitor};
+use sha2::Sha256;
+use utoipa::ToSchema;
+use crate::config::setting;
+use crate::{code_pool, env_or, signing};
 
+pub const MIN_LEN: usize = 6;
+pub const MAX_LEN: usize = 12;
+/// room for the longest code plus its check character
+const BUF_LEN: usize = MAX_LEN + 1;
+
+/// what correlation ids are keyed with
+static CORRELATION_KEY: LazyLock<[u8; 32]> = LazyLock::new(|| signing::derived_secret("correlation ids"));
+
+/// characters a code may be made of
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Alphabet {
+    /// A to Z
+    Full,
+    /// A to Z without I and O, which get read as 1 and 0
+    Unambiguous,
+    /// 0 to 9, for keypads and phone menus
+    Digits,
+}
+
+impl Alphabet {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "full" => Some(Alphabet::Full),
+            "unambiguous" => Some(Alphabet::Unambiguous),
+            _ => None,
+        }
+    }
+
+    pub fn contains(self, char: u8) -> bool {
+        match self {
+            Alphabet::Full => char.is_ascii_uppercase(),
+            Alphabet::Unambiguous => char.is_ascii_uppercase() && !matches!(char, b'I' | b'O'),
+            Alphabet::Digits => char.is_ascii_digit(),
+        }
+    }
+
+    fn chars(self) -> Vec<u8> {
+        (b'0'..=b'Z').filter(|&char| self.contains(char)).collect()
+    }
+}
+
+/// which of the two code formats a code uses, listeners pick one per request
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeKind {
+    #[default]
+    Letters,
+    /// for resolvers typing the code on a numeric keypad or over a phone menu
+    Numeric,
+}
+
+/// why a string isn't a code of this deployment's format
+#[derive(Copy, Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ParseCodeError {
+    #[error("mismatched keycode length")]
+    Length,
+    #[error("invalid character in key")]
+    Character,
+    /// well formed, but the check character doesn't match, so somebody mistyped it
+    #[error("check character mismatch")]
+    Checksum,
+}
+
+/// the shape of every code of one kind this deployment hands out and accepts
+pub struct CodeFormat {
+    /// random characters, not counting the check character
+    pub len: usize,
+    pub alphabet: Alphabet,
+    /// whether codes end in a luhn mod n check character
+    pub checksum: bool,
+    chars: Vec<u8>,
+}
+
+impl CodeFormat {
+    /// length of a code as users see it
+    pub fn total_len(&self) -> usize {
+        self.len + usize::from(self.checksum)
+    }
+
+    /// bits of entropy in the random part
+    pub fn bits(&self) -> f64 {
+        self.len as f64 * (self.chars.len() as f64).log2()
+    }
+
+    /// luhn mod n over the alphabet, catches every single character typo and most swaps
+    fn check_char(&self, code: &[u8]) -> u8 {
+        let n = self.chars.len();
+        let sum = code.iter().rev().enumerate().fold(0, |sum, (pos, char)| {
+            let value = self.chars.iter().position(|c| c == char).unwrap_or(0);
+            let addend = if pos % 2 == 0 { value * 2 } else { value };
+            sum + addend / n + addend % n
+        });
+
+        self.chars[(n - sum % n) % n]
+    }
+}
+
+fn code_len(var: &str, default: usize) -> usize {
+    let len = env_or(var, default);
+    if !(MIN_LEN..=MAX_LEN).contains(&len) {
+        log::error!("{var} must be between {MIN_LEN} and {MAX_LEN}, clamping {len}");
+    }
+    len.clamp(MIN_LEN, MAX_LEN)
+}
+
+static CHECKSUM: LazyLock<bool> = LazyLock::new(|| env_or("ABSHER_CODE_CHECKSUM", false));
+
+static LETTERS: LazyLock<CodeFormat> = LazyLock::new(|| {
+    let name = setting("ABSHER_CODE_ALPHABET").unwrap_or_default();
+    let alphabet = match name.as_str() {
+        "" => Alphabet::Full,
+        name => Alphabet::from_name(name).unwrap_or_else(|| {
+            log::error!("unknown ABSHER_CODE_ALPHABET {name}, use full or unambiguous");
+            Alphabet::Full
+        }),
+    };
+
+    CodeFormat {
+        len: code_len("ABSHER_CODE_LENGTH", 9),
+        alphabet,
+        checksum: *CHECKSUM,
+        chars: alphabet.chars(),
+    }
+});
+
+/// digits carry far less per character, so these default longer
+static NUMERIC: LazyLock<CodeFormat> = LazyLock::new(|| CodeFormat {
+    len: code_len("ABSHER_NUMERIC_CODE_LENGTH", 10),
+    alphabet: Alphabet::Digits,
+    checksum: *CHECKSUM,
+    chars: Alphabet::Digits.chars(),
+});
+
+impl CodeKind {
+    pub fn format(self) -> &'static CodeFormat {
+        match self {
+            CodeKind::Letters => &LETTERS,
+            CodeKind::Numeric => &NUMERIC,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CodeKind::Letters => "letters",
+            CodeKind::Numeric => "numeric",
+        }
+    }
+}
+
+/// below this many hours, a single source may plausibly guess one specific live code
+const GUESS_BUDGET_HOURS: f64 = 24.0 * 30.0;
+
+/// logs how long guessing one live code of each kind takes a source limited to `guesses_per_hour`
+pub fn report_guess_budget(guesses_per_hour: f64) {
+    for kind in [CodeKind::Letters, CodeKind::Numeric] {
+        let format = kind.format();
+        // on average half the space has to be searched
+        let hours = format.bits().exp2() / 2.0 / guesses_per_hour;
+        if hours < GUESS_BUDGET_HOURS {
+            log::warn!(
+                "{} codes carry {:.1} bits, one source could guess a live one in about {hours:.0}h",
+                kind.name(), format.bits(),
+            );
+        } else {
+            log::info!("{} codes carry {:.1} bits, about {hours:.0}h to guess a live one", kind.name(), format.bits());
+        }
+    }
+}
+
+/// the unused tail of the buffer is always zero, so equality and hashing only see the code
 #[derive(Debug, Copy, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
-pub struct RequestCode([u8; 9]);
+pub struct RequestCode {
+    kind: CodeKind,
+    chars: [u8; BUF_LEN],
+    len: u8,
+}
+
+/// set for end to end tests and the demo, codes are drawn from it instead of the os rng
+static SEEDED: OnceLock<Mutex<StdRng>> = OnceLock::new();
+
+/// makes every code from now on follow from `seed`, so tests can know them in advance. seeded
+/// codes can be guessed by anyone who knows the seed, nothing real may run like this; false when
+/// codes were seeded already
+pub fn seed(seed: u64) -> bool {
+    let seeded = SEEDED.set(Mutex::new(StdRng::seed_from_u64(seed))).is_ok();
+    if seeded {
+        log::warn!("request codes are seeded with {seed}, anyone knowing it can predict them");
+    }
+    seeded
+}
+
+fn draw(format: &CodeFormat, chars: &mut [u8], rng: &mut impl Rng) {
+    for char in chars {
+        *char = format.chars[rng.random_range(0..format.chars.len())];
+    }
+}
+
+/// draws past this many taken codes mean the code space is getting crowded
+const CROWDED_ATTEMPTS: u32 = 8;
 
 impl RequestCode {
-    pub fn new_rand() -> Self {
-        let mut rng = rand::rng();
-        let key = core::array::from_fn(|_| {
-            rng.random_range(b'A'..=b'Z')
-        });
-        Self(key)
+    /// codes are bearer tokens, so they come straight from the os rng unless they were seeded
+    pub fn new_rand(kind: CodeKind) -> Self {
+        let format = kind.format();
+        let mut chars = [0; BUF_LEN];
+        match SEEDED.get() {
+            Some(seeded) => draw(format, &mut chars[..format.len], &mut *seeded.lock().unwrap_or_else(PoisonError::into_inner)),
+            None => draw(format, &mut chars[..format.len], &mut OsRng.unwrap_err()),
+        }
+
+        if format.checksum {
+            chars[format.len] = format.check_char(&chars[..format.len]);
+        }
+
+        Self { kind, chars, len: format.total_len() as u8 }
+    }
+
+    /// draws codes of `kind` until `claim` takes one, `claim` should check and reserve the code
+    /// in its store in one step and return `None` when the code is already in use
+    pub fn generate<T>(kind: CodeKind, mut claim: impl FnMut(RequestCode) -> Option<T>) -> (Self, T) {
+        let mut attempts = 0;
+        loop {
+            let code = code_pool::take(kind).unwrap_or_else(|| Self::new_rand(kind));
+            if let Some(claimed) = claim(code) {
+                break (code, claimed)
+            }
+
+            // the numeric space is small enough for this to happen with a lot of live codes
+            attempts += 1;
+            if attempts == CROWDED_ATTEMPTS {
+                log::warn!("{attempts} {} request codes in a row were already taken", kind.name());
+            }
+        }
     }
 
-    pub const fn as_str(&self) -> &str {
-        unsafe { core::str::from_utf8_unchecked(&self.0) }
+    pub fn kind(&self) -> CodeKind {
+        self.kind
+    }
+
+    pub fn as_str(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.chars[..self.len as usize]) }
+    }
+
+    /// `ABC-DEF-GHI`, easier to read out and type
+    pub fn display_grouped(&self) -> String {
+        let code = self.as_str();
+        let mut grouped = String::with_capacity(code.len() + code.len() / 3);
+        for (pos, char) in code.char_indices() {
+            // a lone leftover character stays with the group before it
+            if pos > 0 && pos % 3 == 0 && code.len() - pos > 1 {
+                grouped.push('-');
+            }
+            grouped.push(char);
+        }
+        grouped
+    }
+
+    /// stands in for the code in logs, traces and headers, so flows can be followed without
+    /// writing down usable codes; keyed, or anyone reading them could hash every possible code
+    /// and find the ones still open
+    pub fn correlation_id(&self) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&*CORRELATION_KEY).expect("hmac to take keys of any length");
+        mac.update(self.as_str().as_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        digest[..8].iter().fold(String::with_capacity(16), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
     }
 }
 
 impl FromStr for RequestCode {
-    type Err = &'static str;
+    type Err = ParseCodeError;
 
+    /// forgiving of how people type codes: any case, padded, and split by hyphens or spaces
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let key = <[u8; 9]>::try_from(s.as_bytes())
-            .map_err(|_| "mismatched keycode length")?;
+        let mut typed = [0; BUF_LEN];
+        let mut len = 0;
+        for char in s.trim().bytes().filter(|&char| char != b'-' && char != b' ') {
+            if len == BUF_LEN {
+                return Err(ParseCodeError::Length)
+            }
+            typed[len] = char.to_ascii_uppercase();
+            len += 1;
+        }
+
+        // the alphabets don't overlap, so the first character tells the kinds apart
+        let key = &typed[..len];
+        let kind = match key.first() {
+            Some(char) if char.is_ascii_digit() => CodeKind::Numeric,
+            _ => CodeKind::Letters,
+        };
+
+        let format = kind.format();
+        if key.len() != format.total_len() {
+            return Err(ParseCodeError::Length)
+        }
+
+        if key.iter().any(|&char| !format.alphabet.contains(char)) {
+            return Err(ParseCodeError::Character)
+        }
 
-        if key.iter().any(|char| !(b'A'..=b'Z').contains(char)) {
-            return Err("invalid character in key")
+        if format.checksum && format.check_char(&key[..format.len]) != key[format.len] {
+            return Err(ParseCodeError::Checksum)
         }
 
-        Ok(Self(key))
+        let mut chars = [0; BUF_LEN];
+        chars[..key.len()].copy_from_slice(key);
+        Ok(Self { kind, chars, len: key.len() as u8 })
+    }
+}
+
+impl Serialize for RequestCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
     }
 }
 
@@ -97,4 +394,65 @@ impl<'de> Deserialize<'de> for RequestCode {
 
         deserializer.deserialize_str(CodeVisitor)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taken_codes_are_drawn_again() {
+        let mut offered = Vec::new();
+        let (code, claimed) = RequestCode::generate(CodeKind::Letters, |code| {
+            offered.push(code);
+            (offered.len() == 3).then_some(offered.len())
+        });
+
+        assert_eq!(claimed, 3);
+        assert_eq!(offered.last(), Some(&code));
+        assert_ne!(offered[0], offered[1]);
+    }
+
+    #[test]
+    fn check_characters_catch_single_typos() {
+        let chars = Alphabet::Unambiguous.chars();
+        let format = CodeFormat { len: 9, alphabet: Alphabet::Unambiguous, checksum: true, chars: chars.clone() };
+
+        let code = *b"KXRTPABZM";
+        let check = format.check_char(&code);
+        assert!(format.alphabet.contains(check));
+
+        for pos in 0..code.len() {
+            for &typo in chars.iter().filter(|&&char| char != code[pos]) {
+                let mut typed = code;
+                typed[pos] = typo;
+                assert_ne!(format.check_char(&typed), check, "{} went unnoticed", String::from_utf8_lossy(&typed));
+            }
+        }
+    }
+
+    #[test]
+    fn grouped_codes_read_back() {
+        let code = "ABCDEFGHI".parse::<RequestCode>().unwrap();
+        assert_eq!(code.display_grouped(), "ABC-DEF-GHI");
+        assert_eq!(code.display_grouped().parse::<RequestCode>(), Ok(code));
+        assert_eq!(" abc-def ghi ".parse::<RequestCode>(), Ok(code));
+
+        // a lone leftover character stays with the group before it
+        let numeric = "0123456789".parse::<RequestCode>().unwrap();
+        assert_eq!(numeric.kind(), CodeKind::Numeric);
+        assert_eq!(numeric.display_grouped(), "012-345-6789");
+
+        for kind in [CodeKind::Letters, CodeKind::Numeric] {
+            let code = RequestCode::new_rand(kind);
+            assert_eq!(code.display_grouped().parse::<RequestCode>(), Ok(code));
+        }
+    }
+
+    #[test]
+    fn malformed_codes_are_told_apart() {
+        assert_eq!("ABC-DEF".parse::<RequestCode>(), Err(ParseCodeError::Length));
+        assert_eq!("ABC-DEF-GHIJKLMNOP".parse::<RequestCode>(), Err(ParseCodeError::Length));
+        assert_eq!("ABC-D3F-GHI".parse::<RequestCode>(), Err(ParseCodeError::Character));
+        assert_eq!("012-34A-6789".parse::<RequestCode>(), Err(ParseCodeError::Character));
+    }
+}
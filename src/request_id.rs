@@ -0,0 +1,113 @@
+use std::time::Instant;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpRequest};
+use rand::distr::{Alphanumeric, SampleString};
+use crate::{health, logging};
+use crate::req_code::RequestCode;
+
+/// names one http request, set by a proxy in front of us or made up here
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// names everything that happened to one code, from listen to resolve
+pub const CORRELATION_HEADER: HeaderName = HeaderName::from_static("x-correlation-id");
+
+/// longest incoming id we take over, anything longer or odder gets replaced
+const MAX_LEN: usize = 64;
+
+/// logged for paths no route matches, the path itself may hold a code
+const UNMATCHED: &str = "-";
+
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    /// the id of the request being served, for error bodies that have no request to hand
+    static CURRENT: String;
+}
+
+/// the id of the request being served, for log lines
+pub fn of(req: &HttpRequest) -> String {
+    req.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default()
+}
+
+/// the id of the request this task is serving, `None` outside of one
+pub fn current() -> Option<String> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+fn acceptable(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id.bytes().all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+}
+
+/// the id a caller sent along, or a new one if it didn't send a usable one
+pub(crate) fn adopt(presented: Option<&str>) -> String {
+    presented
+        .filter(|id| acceptable(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::rng(), 16))
+}
+
+fn log_access(id: &str, code_hash: Option<&str>, method: &Method, route: &str, status: StatusCode, started: Instant) {
+    log::info!(
+        target: "access",
+        request_id = id,
+        code_hash = code_hash.unwrap_or_default(),
+        method = method.as_str(),
+        route = route,
+        status = status.as_u16(),
+        duration_ms = started.elapsed().as_millis() as u64;
+        "{method} {route} {}", status.as_u16()
+    );
+}
+
+/// gives every request an id and sends it back, along with the code's correlation id if it names one
+pub async fn tag(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = adopt(req.headers().get(HEADER).and_then(|id| id.to_str().ok()));
+    let header = HeaderValue::from_str(&id).expect("ids to be plain ascii");
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let started = Instant::now();
+    let method = req.method().clone();
+    let health = health::PATHS.contains(&req.path());
+    // only ever the pattern, a path can hold a live code
+    let route = req.match_pattern().unwrap_or_else(|| UNMATCHED.to_owned());
+
+    let mut response = match CURRENT.scope(id.clone(), next.call(req)).await {
+        Ok(response) => response,
+        // turned away by a middleware, the request and its code are gone by now
+        Err(err) => {
+            let mut response = CURRENT.sync_scope(id.clone(), || err.error_response());
+            response.headers_mut().insert(HEADER, header);
+            if logging::json() {
+                log_access(&id, None, &method, &route, response.status(), started);
+            }
+            return Err(InternalError::from_response(err, response).into())
+        }
+    };
+
+    let correlation = response.request().match_info().get("code")
+        .and_then(|code| code.parse::<RequestCode>().ok())
+        .map(|code| code.correlation_id());
+
+    // the text format keeps actix's access log, which can't carry fields
+    if logging::json() && !health {
+        log_access(&id, correlation.as_deref(), &method, &route, response.status(), started);
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(HEADER, header);
+    if let Some(correlation) = correlation.and_then(|id| HeaderValue::from_str(&id).ok()) {
+        headers.insert(CORRELATION_HEADER, correlation);
+    }
+
+    Ok(response)
+}
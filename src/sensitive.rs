@@ -0,0 +1,26 @@
+use actix_web::web::Bytes;
+use bytestring::ByteString;
+use serde_json::Value;
+use zeroize::{Zeroize, Zeroizing};
+
+/// `bytes` handed off to be sent, wiped once the last copy of them is dropped rather than left
+/// for the allocator to hand out again
+pub fn bytes(bytes: Vec<u8>) -> Bytes {
+    Bytes::from_owner(Zeroizing::new(bytes))
+}
+
+/// `text` handed off to be sent, wiped the same way
+pub fn text(text: String) -> ByteString {
+    // it was a String a moment ago, it can't fail to be utf-8 now
+    ByteString::try_from(bytes(text.into_bytes())).unwrap_or_else(|_| ByteString::new())
+}
+
+/// overwrites every string in `value`, for json that had to be parsed out of shared data
+pub fn wipe(value: &mut Value) {
+    match value {
+        Value::String(text) => text.zeroize(),
+        Value::Array(items) => items.iter_mut().for_each(wipe),
+        Value::Object(fields) => fields.values_mut().for_each(wipe),
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::LazyLock;
+use dashmap::DashMap;
+use tokio::sync::oneshot;
+use crate::req_code::RequestCode;
+
+/// longest client session id we bother tracking
+const MAX_SESSION_LEN: usize = 128;
+
+/// (origin, client session id) to that session's newest listener
+static LISTENERS: LazyLock<DashMap<(String, String), Listener>> = LazyLock::new(DashMap::new);
+
+struct Listener {
+    code: RequestCode,
+    supersede: oneshot::Sender<()>,
+}
+
+/// a listener's claim on its session, released when dropped
+pub struct Registration {
+    key: (String, String),
+    code: RequestCode,
+    superseded: Option<oneshot::Receiver<()>>,
+}
+
+/// makes `code` the live request for this origin and session, telling any older listener it lost
+pub fn register(origin: String, session: String, code: RequestCode) -> Option<Registration> {
+    if session.is_empty() || session.len() > MAX_SESSION_LEN {
+        return None
+    }
+
+    let key = (origin, session);
+    let (supersede, superseded) = oneshot::channel();
+
+    if let Some(older) = LISTENERS.insert(key.clone(), Listener { code, supersede }) {
+        let _ = older.supersede.send(());
+    }
+
+    Some(Registration { key, code, superseded: Some(superseded) })
+}
+
+/// sessions with a live listener
+pub fn count() -> usize {
+    LISTENERS.len()
+}
+
+impl Registration {
+    /// resolves once a newer listener took over this session
+    pub async fn superseded(&mut self) {
+        let superseded = match self.superseded.take() {
+            Some(superseded) => superseded.await.is_ok(),
+            None => false,
+        };
+
+        if !superseded {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        LISTENERS.remove_if(&self.key, |_key, listener| listener.code == self.code);
+    }
+}
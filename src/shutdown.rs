@@ -0,0 +1,57 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+use actix_web::dev::ServerHandle;
+use tokio::sync::watch;
+use crate::{env_or, systemd};
+
+/// how long open requests and listeners get to wind down before the process exits anyway
+pub static GRACE: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_SHUTDOWN_GRACE_SECS", 30))
+});
+
+/// flips once, when a SIGTERM or SIGINT arrives
+static DRAINING: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::Sender::new(false));
+
+pub fn draining() -> bool {
+    *DRAINING.borrow()
+}
+
+/// resolves once the server started shutting down
+pub async fn drained() {
+    let _ = DRAINING.subscribe().wait_for(|draining| *draining).await;
+}
+
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = terminate.recv() => {},
+                _ = tokio::signal::ctrl_c() => {},
+            },
+            Err(err) => {
+                log::error!("unable to listen for SIGTERM, only SIGINT shuts down gracefully: {err}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// waits for a signal, then turns new listeners away, tells the open ones to come back
+/// later and lets the servers finish what they are serving
+pub async fn on_signal(servers: Vec<ServerHandle>) {
+    terminated().await;
+    log::warn!("shutting down, draining for up to {}s", GRACE.as_secs());
+    systemd::notify("STOPPING=1");
+    DRAINING.send_replace(true);
+
+    // all of them stop taking connections right away, then wind down side by side
+    let stopping = servers.iter().map(|server| server.stop(true)).collect::<Vec<_>>();
+    for stopped in stopping {
+        stopped.await;
+    }
+}
@@ -0,0 +1,323 @@
+use std::sync::LazyLock;
+use base64::prelude::{Engine, BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use ed25519_dalek::{Signature, Signer, SigningKey};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use crate::config::setting;
+use crate::error::AppError;
+use crate::{env_or, to_json_str, unix_now};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// how long verifiers may hold on to the published keys, well short of a rotation period so the
+/// next key is always fetched before it signs anything
+pub const MAX_AGE_SECS: u32 = 60 * 60;
+
+/// every key is the root seed or derived from it, so instances sharing the seed sign with the same
+/// key at the same time; from a base64 ed25519 seed in the environment or made up at startup
+static ROOT: LazyLock<[u8; 32]> = LazyLock::new(|| {
+    let seed = setting("ABSHER_SIGNING_KEY").as_deref().and_then(parse_signing_key);
+
+    seed.unwrap_or_else(|| {
+        log::warn!("no ABSHER_SIGNING_KEY set, receipts will stop verifying after a restart");
+        let mut seed = [0; 32];
+        rand::rng().fill_bytes(&mut seed);
+        seed
+    })
+});
+
+/// how often a fresh key takes over, the root key signs everything for good without one
+static ROTATION: LazyLock<Option<u64>> = LazyLock::new(|| {
+    let days = env_or("ABSHER_SIGNING_ROTATION_DAYS", 0_u64);
+    (days > 0).then(|| days.saturating_mul(DAY_SECS))
+});
+
+/// retired keys that stay published, so what they signed can still be checked for a while
+static RETAINED: LazyLock<u64> = LazyLock::new(|| env_or("ABSHER_SIGNING_RETAINED", 2));
+
+/// seeds that signed before the root seed was changed or rotation was turned on, published and
+/// accepted until they're dropped from here
+static PREVIOUS: LazyLock<Vec<ServerKey>> = LazyLock::new(|| {
+    let Some(seeds) = setting("ABSHER_SIGNING_PREVIOUS_KEYS") else {
+        return Vec::new()
+    };
+
+    seeds.split(',').map(str::trim).filter(|seed| !seed.is_empty()).filter_map(|seed| {
+        let parsed = parse_signing_key(seed).map(|seed| ServerKey::new(&seed));
+        if parsed.is_none() {
+            log::error!("one of ABSHER_SIGNING_PREVIOUS_KEYS isn't 32 bytes of base64, left out");
+        }
+        parsed
+    }).collect()
+});
+
+static ROOT_KEY: LazyLock<ServerKey> = LazyLock::new(|| ServerKey::new(&ROOT));
+
+/// keys derived so far, by the rotation period they sign in
+static DERIVED: LazyLock<DashMap<u64, ServerKey>> = LazyLock::new(DashMap::new);
+
+#[derive(Clone)]
+struct ServerKey {
+    signing: SigningKey,
+    kid: String,
+}
+
+impl ServerKey {
+    fn new(seed: &[u8; 32]) -> Self {
+        let signing = SigningKey::from_bytes(seed);
+        let fingerprint = Sha256::digest(signing.verifying_key().as_bytes());
+        let kid = BASE64_URL_SAFE_NO_PAD.encode(&fingerprint[..8]);
+        Self { signing, kid }
+    }
+
+    fn jwk(&self) -> Jwk {
+        Jwk {
+            kty: "OKP",
+            crv: "Ed25519",
+            alg: "EdDSA",
+            kid: self.kid.clone(),
+            x: BASE64_URL_SAFE_NO_PAD.encode(self.signing.verifying_key().as_bytes()),
+        }
+    }
+}
+
+/// an ed25519 seed as `ABSHER_SIGNING_KEY` holds it, 32 bytes in standard base64
+pub fn parse_signing_key(seed: &str) -> Option<[u8; 32]> {
+    let seed = BASE64_STANDARD.decode(seed.trim()).ok()?;
+    <[u8; 32]>::try_from(seed).ok()
+}
+
+/// a secret for `purpose` that follows from the root seed, so instances sharing the seed agree on
+/// it and nobody without the seed can work it out
+pub(crate) fn derived_secret(purpose: &str) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"absher-zt secret ")
+        .chain_update(purpose.as_bytes())
+        .chain_update(*ROOT)
+        .finalize()
+        .into()
+}
+
+/// the rotation period we're in, `None` when keys don't rotate
+fn period() -> Option<u64> {
+    ROTATION.map(|rotation| unix_now() / rotation)
+}
+
+/// the key that signs in rotation period `period`
+fn derived(period: u64) -> ServerKey {
+    if let Some(key) = DERIVED.get(&period) {
+        return key.clone()
+    }
+
+    let seed = Sha256::new()
+        .chain_update(b"absher-zt signing key")
+        .chain_update(*ROOT)
+        .chain_update(period.to_be_bytes())
+        .finalize();
+    let key = ServerKey::new(&seed.into());
+
+    // periods that aren't published anymore won't be asked for again
+    let oldest = self::period().unwrap_or(period).saturating_sub(*RETAINED);
+    DERIVED.retain(|kept, _key| *kept >= oldest);
+    DERIVED.insert(period, key.clone());
+    key
+}
+
+/// the key that signs right now
+fn current() -> ServerKey {
+    match period() {
+        Some(period) => derived(period),
+        None => ROOT_KEY.clone(),
+    }
+}
+
+/// every key verifiers should know, the one signing now first; with rotation on that is also the
+/// next one, which doesn't sign anything until its period starts, and the retired ones
+fn published() -> Vec<ServerKey> {
+    published_in(period())
+}
+
+/// the keys published in rotation period `period`
+fn published_in(period: Option<u64>) -> Vec<ServerKey> {
+    let mut keys = match period {
+        Some(period) => {
+            let retired = (1..=*RETAINED).filter_map(|back| period.checked_sub(back)).map(derived);
+            [derived(period), derived(period + 1)].into_iter().chain(retired).collect()
+        }
+        None => vec![ROOT_KEY.clone()],
+    };
+
+    keys.extend(PREVIOUS.iter().cloned());
+    keys
+}
+
+#[derive(Serialize)]
+struct Header<'a> {
+    alg: &'static str,
+    typ: &'static str,
+    kid: &'a str,
+}
+
+fn encoded_header(typ: &'static str, key: &ServerKey) -> Result<String, AppError> {
+    let header = Header { alg: "EdDSA", typ, kid: &key.kid };
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(to_json_str(&header)?))
+}
+
+/// `claims` as a compact JWS typed `typ`, signed with the current key
+pub(crate) fn compact(typ: &'static str, claims: &impl Serialize) -> Result<String, AppError> {
+    compact_with(&current(), typ, claims)
+}
+
+fn compact_with(key: &ServerKey, typ: &'static str, claims: &impl Serialize) -> Result<String, AppError> {
+    let signing_input = format!(
+        "{}.{}",
+        encoded_header(typ, key)?,
+        BASE64_URL_SAFE_NO_PAD.encode(to_json_str(&claims)?),
+    );
+
+    let signature = key.signing.sign(signing_input.as_bytes());
+    Ok(format!("{signing_input}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature.to_bytes())))
+}
+
+/// a JWS over `payload` with the payload left out, `<header>..<signature>` as in RFC 7515
+/// appendix F; the receiver puts the base64url of what it got back in the middle to check it
+pub(crate) fn detached(typ: &'static str, payload: &[u8]) -> Result<String, AppError> {
+    let key = current();
+    let header = encoded_header(typ, &key)?;
+    let signing_input = format!("{header}.{}", BASE64_URL_SAFE_NO_PAD.encode(payload));
+
+    let signature = key.signing.sign(signing_input.as_bytes());
+    Ok(format!("{header}..{}", BASE64_URL_SAFE_NO_PAD.encode(signature.to_bytes())))
+}
+
+#[derive(Deserialize)]
+struct ParsedHeader {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+/// the claims of a compact JWS this server signed as `typ` with any published key, `None` for
+/// anything else
+pub(crate) fn verify_compact<T: DeserializeOwned>(typ: &str, token: &str) -> Option<T> {
+    verify_against(published(), typ, token)
+}
+
+/// the claims of a compact JWS signed as `typ` with one of `keys`
+fn verify_against<T: DeserializeOwned>(keys: Vec<ServerKey>, typ: &str, token: &str) -> Option<T> {
+    let (signing_input, signature) = token.rsplit_once('.')?;
+    let (header, claims) = signing_input.split_once('.')?;
+
+    let header = serde_json::from_slice::<ParsedHeader>(&BASE64_URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    // a receipt or credential must never pass for some other kind of token
+    if header.alg != "EdDSA" || header.typ != typ {
+        return None
+    }
+
+    let key = keys.into_iter().find(|key| key.kid == header.kid)?;
+    let signature = Signature::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?).ok()?;
+    key.signing.verifying_key().verify_strict(signing_input.as_bytes(), &signature).ok()?;
+    serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(claims).ok()?).ok()
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Jwk {
+    kty: &'static str,
+    crv: &'static str,
+    alg: &'static str,
+    kid: String,
+    x: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// the public half of the key signing right now
+pub fn current_jwk() -> Jwk {
+    current().jwk()
+}
+
+/// the public halves of every published key, for verifiers to pick from by `kid`
+pub fn jwks() -> Jwks {
+    Jwks { keys: published().iter().map(ServerKey::jwk).collect() }
+}
+
+/// forces the keys to load at startup instead of on the first resolve
+pub fn init() {
+    LazyLock::force(&ROOT_KEY);
+    LazyLock::force(&PREVIOUS);
+    if let Some(rotation) = *ROTATION {
+        log::info!("rotating signing keys every {} days, keeping {} retired ones", rotation / DAY_SECS, *RETAINED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use super::*;
+
+    const TYP: &str = "absher-test+jwt";
+
+    #[test]
+    fn tokens_only_verify_as_what_they_were_signed_as() {
+        let claims = json!({"sub": "someone", "n": 1});
+        let token = compact(TYP, &claims).unwrap();
+        assert_eq!(verify_compact::<Value>(TYP, &token), Some(claims));
+        assert_eq!(verify_compact::<Value>("at+jwt", &token), None);
+
+        let (header, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = BASE64_URL_SAFE_NO_PAD.encode(br#"{"sub":"someone else","n":1}"#);
+        assert_eq!(verify_compact::<Value>(TYP, &format!("{header}.{forged}.{signature}")), None);
+
+        let mut tampered = token.into_bytes();
+        let last = tampered.len() - 2;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        assert_eq!(verify_compact::<Value>(TYP, &String::from_utf8(tampered).unwrap()), None);
+        assert_eq!(verify_compact::<Value>(TYP, "not.a.token"), None);
+    }
+
+    #[test]
+    fn detached_signatures_cover_the_payload() {
+        let signature = detached(TYP, br#"{"code":"ABC"}"#).unwrap();
+        let (header, signature) = signature.split_once("..").unwrap();
+
+        let attached = |payload: &[u8]| format!("{header}.{}.{signature}", BASE64_URL_SAFE_NO_PAD.encode(payload));
+        assert_eq!(verify_compact::<Value>(TYP, &attached(br#"{"code":"ABC"}"#)), Some(json!({"code": "ABC"})));
+        assert_eq!(verify_compact::<Value>(TYP, &attached(br#"{"code":"XYZ"}"#)), None);
+    }
+
+    #[test]
+    fn rotated_keys_verify_until_they_retire() {
+        let period = 20_000;
+        assert_eq!(derived(period).kid, derived(period).kid);
+        assert_ne!(derived(period).kid, derived(period + 1).kid);
+
+        let token = compact_with(&derived(period), TYP, &json!({"n": 1})).unwrap();
+        let verifies_in = |now| verify_against::<Value>(published_in(Some(now)), TYP, &token).is_some();
+        // the next key is out before it signs anything, retired ones linger
+        assert!(verifies_in(period - 1));
+        for now in period..=period + *RETAINED {
+            assert!(verifies_in(now), "a key retired {} periods ago stopped verifying", now - period);
+        }
+        assert!(!verifies_in(period + *RETAINED + 1));
+        assert!(!verifies_in(period - 2));
+    }
+
+    #[test]
+    fn the_signing_key_is_published_first() {
+        let jwks = jwks();
+        assert_eq!(jwks.keys[0].kid, current_jwk().kid);
+
+        let period = 30_000;
+        let kids = published_in(Some(period)).into_iter().map(|key| key.kid).collect::<Vec<_>>();
+        assert_eq!(kids[..2], [derived(period).kid, derived(period + 1).kid]);
+        assert_eq!(kids.len(), 2 + *RETAINED as usize);
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Cursor;
+use std::time::Duration;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use image::{ImageFormat, Rgb, RgbImage};
+use rand::Rng;
+use serde::Deserialize;
+use crate::cli::{Outcome, SimulateArgs};
+use crate::client::{Error, ResolverClient};
+use crate::custom_fields::{CustomType, CustomValue};
+use crate::handlers::FetchResponse;
+use crate::identity::{Address, Document, DocumentFields, Gender, Name, NameParts};
+use crate::model::AutofillData;
+use crate::req_code::RequestCode;
+
+/// how often the server is asked for new requests when no code was given
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// a national id with a valid check digit, the first digit says citizen or resident
+fn fake_national_id(rng: &mut impl Rng) -> String {
+    let mut digits: Vec<u32> = vec![1];
+    digits.extend((0..8).map(|_| rng.random_range(0..10)));
+    // the digit that makes the luhn sum of all ten a multiple of ten
+    let sum: u32 = digits.iter().rev().enumerate().map(|(position, &digit)| match position % 2 {
+        0 if digit > 4 => digit * 2 - 9,
+        0 => digit * 2,
+        _ => digit,
+    }).sum();
+    digits.push((10 - sum % 10) % 10);
+    digits.iter().map(|digit| char::from_digit(*digit, 10).unwrap_or('0')).collect()
+}
+
+/// a small solid png, enough to get through the image checks
+fn fake_image(rng: &mut impl Rng) -> String {
+    let image = RgbImage::from_pixel(64, 64, Rgb([rng.random(), rng.random(), rng.random()]));
+    let mut png = Cursor::new(Vec::new());
+    // writing to memory only fails on allocation
+    let _ = image.write_to(&mut png, ImageFormat::Png);
+    BASE64_STANDARD.encode(png.into_inner())
+}
+
+fn fake_document(fields: &DocumentFields, authority: &str, rng: &mut impl Rng) -> Option<Document> {
+    let asked = fields.number || fields.issued_on || fields.expires_on || fields.authority || fields.image;
+    asked.then(|| Document {
+        number: fields.number.then(|| format!("{:09}", rng.random_range(0..1_000_000_000u32))),
+        issued_on: fields.issued_on.then(|| "2020-03-01".to_owned()),
+        expires_on: fields.expires_on.then(|| "2030-02-28".to_owned()),
+        authority: fields.authority.then(|| authority.to_owned()),
+        image: fields.image.then(|| fake_image(rng)),
+    })
+}
+
+/// made up data for everything `request` asks for and nothing else
+pub fn fake_data(request: &FetchResponse) -> AutofillData {
+    let mut rng = rand::rng();
+    let fields = &request.fields;
+    let name = (fields.name.arabic || fields.name.english).then(|| Name {
+        arabic: fields.name.arabic.then(|| NameParts { given: Some("محمد".to_owned()), family: Some("العتيبي".to_owned()) }),
+        english: fields.name.english.then(|| NameParts { given: Some("Mohammed".to_owned()), family: Some("Alotaibi".to_owned()) }),
+    });
+    // old enough for any age check a listener asks for
+    let date_of_birth = match fields.age_over {
+        Some(age) if age >= 50 => "1920-01-01",
+        _ => "1975-06-15",
+    };
+
+    let custom_fields = request.custom_fields.iter()
+        .map(|field| {
+            let value = match field.kind {
+                CustomType::String => CustomValue::String("simulated".to_owned()),
+                CustomType::Number => CustomValue::Number(rng.random_range(1..1000u32).into()),
+                CustomType::Bool => CustomValue::Bool(true),
+            };
+            (field.key.clone(), value)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    AutofillData {
+        name,
+        email: fields.email.then(|| format!("user{}@example.com", rng.random_range(1000..10000u32))),
+        phone_number: fields.phone_number.then(|| format!("+9665{:08}", rng.random_range(0..100_000_000u32))),
+        id: fields.id.then(|| fake_national_id(&mut rng)),
+        date_of_birth: (fields.date_of_birth || fields.age_over.is_some()).then(|| date_of_birth.to_owned()),
+        nationality: fields.nationality.then(|| "SA".to_owned()),
+        gender: fields.gender.then_some(Gender::Male),
+        address: fields.address.then(|| Address {
+            street: Some("King Fahd Road".to_owned()),
+            district: Some("Al Olaya".to_owned()),
+            city: Some("Riyadh".to_owned()),
+            postal_code: Some("12211".to_owned()),
+        }),
+        profile_picture: fields.profile_picture.then(|| fake_image(&mut rng)),
+        license: fields.license.then(|| fake_image(&mut rng)),
+        id_image: fields.id_image.then(|| fake_image(&mut rng)),
+        passport: fake_document(&fields.passport, "Saudi Passport Department", &mut rng),
+        driving_license: fake_document(&fields.driving_license, "General Department of Traffic", &mut rng),
+        custom_fields,
+        age_over: None,
+        hash_matches: BTreeMap::new(),
+    }
+}
+
+/// does what `outcome` says with one request, after the simulated user thought it over
+async fn answer(client: ResolverClient, code: RequestCode, outcome: Outcome, delay: Duration, pin: Option<String>) {
+    let correlation = code.correlation_id();
+    let request = match client.fetch_request(code).await {
+        Ok(request) => request,
+        Err(err) => return log::warn!("[{correlation}] unable to fetch {}: {err}", code.as_str()),
+    };
+    if request.public_key.is_some() && outcome == Outcome::Resolve {
+        return log::warn!("[{correlation}] {} wants sealed data, which the simulator can't make", request.display)
+    }
+    if request.pin_required && pin.is_none() && outcome == Outcome::Resolve {
+        return log::warn!("[{correlation}] {} has a pin, pass it with --pin", request.display)
+    }
+
+    actix_web::rt::time::sleep(delay).await;
+    let answered = match outcome {
+        Outcome::Resolve => client.resolve(code, &fake_data(&request), pin.as_deref()).await.map(|resolved| {
+            match resolved.delivered {
+                true => "resolved",
+                false => "resolved, but nobody was listening",
+            }
+        }),
+        Outcome::Reject => client.reject(code, Some("simulated")).await.map(|()| "rejected"),
+        Outcome::Ignore => Ok("left to time out"),
+    };
+
+    match answered {
+        Ok(answered) => log::info!("[{correlation}] {} {answered}", request.display),
+        Err(err) => log::warn!("[{correlation}] {}: {err}", request.display),
+    }
+}
+
+#[derive(Deserialize)]
+struct Pending {
+    code: RequestCode,
+}
+
+/// the open requests, through the admin api
+async fn pending(server: &str, admin_token: &str) -> Result<Vec<Pending>, Error> {
+    let mut response = awc::Client::default()
+        .get(format!("{server}/admin/requests"))
+        .bearer_auth(admin_token)
+        .send().await
+        .map_err(|err| Error::Connect(err.to_string()))?;
+    if !response.status().is_success() {
+        return Err(Error::Status(response.status().as_u16()))
+    }
+    response.json().limit(16 << 20).await.map_err(|err| Error::Protocol(err.to_string()))
+}
+
+pub async fn run(args: &SimulateArgs) -> std::io::Result<()> {
+    let server = args.server.trim_end_matches('/');
+    let client = ResolverClient::new(server);
+    let delay = Duration::from_secs(args.delay_secs);
+
+    if let Some(code) = &args.code {
+        let code = code.parse().map_err(|_| std::io::Error::other(format!("{code} isn't a request code")))?;
+        answer(client, code, args.outcome, delay, args.pin.clone()).await;
+        return Ok(())
+    }
+
+    let Some(admin_token) = &args.admin_token else {
+        return Err(std::io::Error::other("answering every request takes --admin-token, or pass a --code"))
+    };
+
+    log::info!("answering every new request on {server}");
+    let mut seen = HashSet::new();
+    loop {
+        match pending(server, admin_token).await {
+            Ok(pending) => {
+                let open = pending.into_iter().map(|pending| pending.code).collect::<HashSet<_>>();
+                for &code in open.difference(&seen) {
+                    actix_web::rt::spawn(answer(client.clone(), code, args.outcome, delay, args.pin.clone()));
+                }
+                // codes come back around once their tombstone is gone
+                seen = open;
+            }
+            Err(err) => log::warn!("unable to list requests on {server}: {err}"),
+        }
+        actix_web::rt::time::sleep(POLL_INTERVAL).await;
+    }
+}
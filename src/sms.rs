@@ -0,0 +1,272 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Map;
+use crate::config::setting;
+use crate::error::AppError;
+use crate::metrics::{Delivery, DeliveryError, METRICS};
+use crate::req_code::RequestCode;
+use crate::{admin, broker, demo, env_or, http_client, unix_now, validate};
+
+static GATEWAY_URL: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_SMS_GATEWAY_URL"));
+static GATEWAY_TOKEN: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_SMS_GATEWAY_TOKEN"));
+static TWILIO_SID: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_SMS_TWILIO_SID"));
+static TWILIO_TOKEN: LazyLock<Option<String>> = LazyLock::new(|| setting("ABSHER_SMS_TWILIO_TOKEN"));
+/// the sender number or alphanumeric sender id texts come from
+static FROM: LazyLock<String> = LazyLock::new(|| env_or("ABSHER_SMS_FROM", "Absher".to_owned()));
+static TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(env_or("ABSHER_SMS_TIMEOUT_MS", 5000))
+});
+
+/// texts a tenant may send a day unless its policy says otherwise
+static DAILY_QUOTA: LazyLock<u32> = LazyLock::new(|| env_or("ABSHER_SMS_DAILY_QUOTA", 1000));
+/// share of its daily quota a tenant reaches before operators are told, in percent
+static QUOTA_WARNING_PERCENT: LazyLock<u32> = LazyLock::new(|| env_or("ABSHER_SMS_QUOTA_WARNING_PERCENT", 80).clamp(1, 100));
+static TEMPLATE: LazyLock<String> = LazyLock::new(|| env_or(
+    "ABSHER_SMS_TEMPLATE",
+    "{display} is your code for {tenant}, it expires in {minutes} minutes. Never share it with anyone.".to_owned(),
+));
+
+/// keeps a page from flooding someone else's phone, whichever tenant it goes through
+const MAX_PER_NUMBER_PER_HOUR: usize = 3;
+const HOUR: Duration = Duration::from_secs(60 * 60);
+/// two segments at most, longer texts cost more and get split on the way
+const MAX_TEMPLATE_CHARS: usize = 300;
+
+/// how a tenant's codes are texted
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct SmsPolicy {
+    /// texts a day, `ABSHER_SMS_DAILY_QUOTA` if unset and none with 0
+    #[serde(default)]
+    pub daily_quota: Option<u32>,
+    /// `{display}`, `{code}`, `{tenant}` and `{minutes}` are filled in, `ABSHER_SMS_TEMPLATE` if unset
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+impl SmsPolicy {
+    pub fn validate(&self) -> Result<(), AppError> {
+        let Some(template) = &self.template else {
+            return Ok(())
+        };
+
+        let shows_code = template.contains("{display}") || template.contains("{code}");
+        match shows_code && template.chars().count() <= MAX_TEMPLATE_CHARS {
+            true => Ok(()),
+            false => Err(AppError::InvalidSmsTemplate { max_chars: MAX_TEMPLATE_CHARS }),
+        }
+    }
+}
+
+pub type Sending<'a> = Pin<Box<dyn Future<Output = Result<(), DeliveryError>> + 'a>>;
+
+/// delivers a text to a phone number in E.164, runs on the worker that issued the code
+pub trait Provider: Send + Sync {
+    fn send<'a>(&'a self, to: &'a str, body: &'a str) -> Sending<'a>;
+}
+
+/// twilio's messages api
+pub struct Twilio {
+    pub account_sid: String,
+    pub auth_token: String,
+    pub from: String,
+    pub timeout: Duration,
+}
+
+impl Provider for Twilio {
+    fn send<'a>(&'a self, to: &'a str, body: &'a str) -> Sending<'a> {
+        Box::pin(async move {
+            let url = format!("https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json", self.account_sid);
+            let response = http_client().post(url)
+                .timeout(self.timeout)
+                .basic_auth(&self.account_sid, &self.auth_token)
+                .send_form(&[("To", to), ("From", self.from.as_str()), ("Body", body)])
+                .await?;
+
+            match response.status().is_success() {
+                true => Ok(()),
+                false => Err(DeliveryError::status(response.status())),
+            }
+        })
+    }
+}
+
+/// posts `{to, from, body}` to a local sms gateway of the operator's
+pub struct Gateway {
+    pub url: String,
+    pub token: Option<String>,
+    pub from: String,
+    pub timeout: Duration,
+}
+
+#[derive(Serialize)]
+struct GatewayBody<'a> {
+    to: &'a str,
+    from: &'a str,
+    body: &'a str,
+}
+
+impl Provider for Gateway {
+    fn send<'a>(&'a self, to: &'a str, body: &'a str) -> Sending<'a> {
+        Box::pin(async move {
+            let mut request = http_client().post(&self.url).timeout(self.timeout);
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+
+            let response = request
+                .send_json(&GatewayBody { to, from: &self.from, body })
+                .await?;
+
+            match response.status().is_success() {
+                true => Ok(()),
+                false => Err(DeliveryError::status(response.status())),
+            }
+        })
+    }
+}
+
+/// only logs what would have been sent, for dev mode
+pub struct LogOnly;
+
+impl Provider for LogOnly {
+    fn send<'a>(&'a self, to: &'a str, body: &'a str) -> Sending<'a> {
+        log::info!("would text {} {body:?}", masked(to));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+static PROVIDER: OnceLock<Option<Box<dyn Provider>>> = OnceLock::new();
+
+/// replaces the provider the settings pick, only before the first text; hands `provider` back
+/// if that is too late
+pub fn install<P: Provider + 'static>(provider: P) -> Result<(), P> {
+    let mut provider = Some(provider);
+    PROVIDER.get_or_init(|| provider.take().map(|provider| Box::new(provider) as Box<dyn Provider>));
+    match provider {
+        None => Ok(()),
+        Some(provider) => Err(provider),
+    }
+}
+
+/// twilio or the gateway, whichever is set, logging in dev mode, otherwise nothing is texted
+fn provider() -> Option<&'static dyn Provider> {
+    PROVIDER.get_or_init(|| {
+        let from = FROM.clone();
+        let provider: Box<dyn Provider> = match (TWILIO_SID.clone(), TWILIO_TOKEN.clone(), GATEWAY_URL.clone()) {
+            (Some(account_sid), Some(auth_token), _) => Box::new(Twilio { account_sid, auth_token, from, timeout: *TIMEOUT }),
+            (_, _, Some(url)) => Box::new(Gateway { url, token: GATEWAY_TOKEN.clone(), from, timeout: *TIMEOUT }),
+            _ if demo::enabled() => Box::new(LogOnly),
+            _ => return None,
+        };
+        Some(provider)
+    }).as_deref()
+}
+
+/// texts so far on the current utc day, by tenant
+static SENT_TODAY: LazyLock<DashMap<String, (u64, u32)>> = LazyLock::new(DashMap::new);
+/// when each number was last texted, within the hour
+static RECENT: LazyLock<DashMap<String, Vec<Instant>>> = LazyLock::new(DashMap::new);
+
+/// the last two digits, enough to tell numbers apart in the logs
+fn masked(number: &str) -> String {
+    let tail = number.len().saturating_sub(2);
+    format!("***{}", &number[tail..])
+}
+
+/// a text that is within its limits, waiting for its code
+pub struct Text {
+    number: String,
+    template: String,
+    tenant_name: String,
+}
+
+/// holds a text for `tenant` to `number` against both limits
+pub fn reserve(tenant: Option<&str>, number: &str) -> Result<Text, AppError> {
+    let (Some(tenant), Some(_provider)) = (tenant, provider()) else {
+        return Err(AppError::SmsUnavailable)
+    };
+    let number = validate::phone_number(number).map_err(|_| AppError::InvalidPhoneNumber)?;
+    let (policy, tenant_name) = admin::tenant_sms(tenant);
+
+    let now = Instant::now();
+    let mut recent = RECENT.entry(number.clone()).or_default();
+    recent.retain(|sent| now.duration_since(*sent) < HOUR);
+    if recent.len() >= MAX_PER_NUMBER_PER_HOUR {
+        return Err(AppError::SmsLimitReached)
+    }
+
+    let today = unix_now() / (24 * 60 * 60);
+    let quota = policy.daily_quota.unwrap_or(*DAILY_QUOTA);
+    let mut sent = SENT_TODAY.entry(tenant.to_owned()).or_insert((today, 0));
+    if sent.0 != today {
+        *sent = (today, 0);
+    }
+    if sent.1 >= quota {
+        return Err(AppError::SmsLimitReached)
+    }
+
+    sent.1 += 1;
+    if sent.1 == quota_warning(quota) {
+        warn_quota(tenant, sent.1, quota);
+    }
+    recent.push(now);
+    let template = policy.template.unwrap_or_else(|| TEMPLATE.clone());
+    Ok(Text { number, template, tenant_name })
+}
+
+/// the text of the day that crosses the warning share of `quota`
+fn quota_warning(quota: u32) -> u32 {
+    (u64::from(quota) * u64::from(*QUOTA_WARNING_PERCENT)).div_ceil(100).max(1) as u32
+}
+
+/// once a day per tenant, while there is still time to raise its quota
+fn warn_quota(tenant: &str, sent: u32, quota: u32) {
+    log::warn!("tenant {tenant} sent {sent} of its {quota} texts for today");
+    METRICS.sms_quota_warnings.inc();
+    let details = Map::from_iter([("sent".to_owned(), sent.into()), ("quota".to_owned(), quota.into())]);
+    broker::publish("sms_quota_warning", Some(tenant), details);
+}
+
+fn render(template: &str, code: &RequestCode, tenant_name: &str, expires_in: Duration) -> String {
+    template
+        .replace("{display}", &code.display_grouped())
+        .replace("{code}", code.as_str())
+        .replace("{tenant}", tenant_name)
+        .replace("{minutes}", &expires_in.as_secs().div_ceil(60).to_string())
+}
+
+/// texts `code` in the background
+pub fn send(text: Text, code: &RequestCode, expires_in: Duration) {
+    let Some(provider) = provider() else {
+        return
+    };
+
+    let Text { number, template, tenant_name } = text;
+    let body = render(&template, code, &tenant_name, expires_in);
+    let correlation = code.correlation_id();
+    let delivery = Delivery::queue("sms");
+    // awc clients are bound to the current thread, so is this
+    actix_web::rt::spawn(async move {
+        match provider.send(&number, &body).await {
+            Ok(()) => {
+                delivery.delivered();
+                log::info!(code_hash = correlation.as_str(); "[{correlation}] texted to {}", masked(&number));
+            }
+            Err(err) => {
+                delivery.failed(err.cause);
+                log::warn!(code_hash = correlation.as_str(); "[{correlation}] unable to text {}: {err}", masked(&number));
+            }
+        }
+    });
+}
+
+pub fn prune() {
+    let now = Instant::now();
+    RECENT.retain(|_number, recent| recent.iter().any(|sent| now.duration_since(*sent) < HOUR));
+    let today = unix_now() / (24 * 60 * 60);
+    SENT_TODAY.retain(|_tenant, (day, _sent)| *day == today);
+}
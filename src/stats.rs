@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use serde::Serialize;
+use crate::env_or;
+use crate::metrics::METRICS;
+
+/// how far back rates and latencies look
+static WINDOW: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_STATS_WINDOW_SECS", 300))
+});
+
+/// keeps a burst from growing the window without bound, the oldest go first
+const MAX_SAMPLES: usize = 100_000;
+
+struct Sample {
+    at: Instant,
+    tenant: Option<String>,
+    /// how the request ended, `resolved` ones also have their latency recorded on their own
+    outcome: &'static str,
+}
+
+struct Latency {
+    at: Instant,
+    tenant: Option<String>,
+    took: Duration,
+}
+
+static ENDED: LazyLock<Mutex<VecDeque<Sample>>> = LazyLock::new(Default::default);
+static LATENCIES: LazyLock<Mutex<VecDeque<Latency>>> = LazyLock::new(Default::default);
+/// open listener websockets of each tenant, keyless ones are only in the total
+static LISTENERS: LazyLock<DashMap<String, usize>> = LazyLock::new(DashMap::new);
+
+fn push<T>(samples: &Mutex<VecDeque<T>>, sample: T) {
+    let mut samples = samples.lock().unwrap_or_else(PoisonError::into_inner);
+    if samples.len() >= MAX_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+pub fn ended(tenant: Option<&str>, outcome: &'static str) {
+    push(&ENDED, Sample { at: Instant::now(), tenant: tenant.map(str::to_owned), outcome });
+}
+
+/// from a resolve arriving to its data being handed to the listener, like `resolve_duration_seconds`
+pub fn resolved_in(tenant: Option<&str>, took: Duration) {
+    push(&LATENCIES, Latency { at: Instant::now(), tenant: tenant.map(str::to_owned), took });
+}
+
+pub fn listener_opened(tenant: &str) {
+    *LISTENERS.entry(tenant.to_owned()).or_default() += 1;
+}
+
+pub fn listener_closed(tenant: &str) {
+    LISTENERS.remove_if_mut(tenant, |_tenant, open| {
+        *open -= 1;
+        *open == 0
+    });
+}
+
+#[derive(Serialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p95: f64,
+}
+
+/// what is going on right now, for everyone or one tenant
+#[derive(Default, Serialize)]
+pub struct Counts {
+    pub pending: usize,
+    pub open_websockets: usize,
+    pub resolves_per_min: f64,
+    /// the share of requests that ended in the window because they expired
+    pub timeout_rate: Option<f64>,
+    /// how the requests that ended in the window did
+    pub ended: BTreeMap<&'static str, usize>,
+    pub resolve_latency_ms: Option<Percentiles>,
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    pub window_secs: u64,
+    #[serde(flatten)]
+    pub total: Counts,
+    pub tenants: BTreeMap<String, Counts>,
+}
+
+/// nearest rank, over latencies in milliseconds
+fn percentiles(mut millis: Vec<f64>) -> Option<Percentiles> {
+    if millis.is_empty() {
+        return None
+    }
+    millis.sort_by(f64::total_cmp);
+    let rank = |p: f64| millis[((p * millis.len() as f64).ceil() as usize).clamp(1, millis.len()) - 1];
+    Some(Percentiles { p50: rank(0.5), p95: rank(0.95) })
+}
+
+impl Counts {
+    fn finish(&mut self, latencies: Vec<f64>) {
+        let window_mins = WINDOW.as_secs_f64() / 60.0;
+        let ended = self.ended.values().sum::<usize>();
+        let resolved = self.ended.get("resolved").copied().unwrap_or(0);
+        let expired = self.ended.get("expired").copied().unwrap_or(0);
+
+        self.resolves_per_min = resolved as f64 / window_mins;
+        self.timeout_rate = (ended > 0).then(|| expired as f64 / ended as f64);
+        self.resolve_latency_ms = percentiles(latencies);
+    }
+}
+
+/// the numbers support looks at, with pending requests counted by the caller
+pub fn snapshot(pending: BTreeMap<Option<String>, usize>) -> Snapshot {
+    let since = Instant::now().checked_sub(*WINDOW);
+    let recent = |at: Instant| since.is_none_or(|since| at >= since);
+    let mut total = Counts::default();
+    let mut tenants = BTreeMap::<String, Counts>::new();
+    let mut latencies = BTreeMap::<Option<String>, Vec<f64>>::new();
+
+    for (tenant, count) in pending {
+        total.pending += count;
+        if let Some(tenant) = tenant {
+            tenants.entry(tenant).or_default().pending = count;
+        }
+    }
+    total.open_websockets = METRICS.listeners.get().max(0) as usize;
+    for open in LISTENERS.iter() {
+        tenants.entry(open.key().clone()).or_default().open_websockets = *open.value();
+    }
+
+    {
+        let mut ended = ENDED.lock().unwrap_or_else(PoisonError::into_inner);
+        ended.retain(|sample| recent(sample.at));
+        for sample in ended.iter() {
+            *total.ended.entry(sample.outcome).or_default() += 1;
+            if let Some(tenant) = &sample.tenant {
+                *tenants.entry(tenant.clone()).or_default().ended.entry(sample.outcome).or_default() += 1;
+            }
+        }
+    }
+    {
+        let mut samples = LATENCIES.lock().unwrap_or_else(PoisonError::into_inner);
+        samples.retain(|latency| recent(latency.at));
+        for latency in samples.iter() {
+            latencies.entry(latency.tenant.clone()).or_default().push(latency.took.as_secs_f64() * 1000.0);
+        }
+    }
+
+    total.finish(latencies.values().flatten().copied().collect());
+    for (tenant, counts) in &mut tenants {
+        counts.finish(latencies.remove(&Some(tenant.clone())).unwrap_or_default());
+    }
+
+    Snapshot { window_secs: WINDOW.as_secs(), total, tenants }
+}
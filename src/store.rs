@@ -0,0 +1,447 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use bytestring::ByteString;
+use dashmap::{DashMap, Entry};
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+use tokio::sync::oneshot;
+use crate::audit::Actor;
+use crate::custom_fields::CustomField;
+use crate::devices::Owner;
+use crate::error::AppError;
+use crate::geo::{self, GeoCheck};
+use crate::hash_checks::HashChecks;
+use crate::metrics::METRICS;
+use crate::model::{Payload, RequestedAutofillFields};
+use crate::ocr::Verification;
+use crate::pin::{Attempt, Pin};
+use crate::protocol::OutputFormat;
+use crate::req_code::{CodeKind, RequestCode};
+use crate::timing::Timing;
+use crate::{admin, audit, blocklist, broker, capacity, clock, denylist, devices, env_or, events, expiry, health, history, lockout, oidc, parked, protocol, rate_limit, sms, stats, ttl, unix_now, uploads, webhooks};
+
+/// what a resolve hands over to the waiting listener
+pub struct Resolution {
+    pub(crate) data: Payload,
+    pub(crate) timing: Timing,
+    pub(crate) handed_off: Instant,
+    pub(crate) verification: Option<Verification>,
+    /// signed proof of what was shared, given to both sides
+    pub(crate) receipt: String,
+    /// for the listener to push its next requests to the device that answered
+    pub(crate) user_hint: Option<String>,
+}
+
+pub(crate) struct PendingRequest {
+    pub(crate) notify: oneshot::Sender<Resolution>,
+    pub(crate) data_requested: RequestedAutofillFields,
+    /// x25519 key resolvers have to seal the data to, if the listener wants end to end encryption
+    pub(crate) public_key: Option<String>,
+    /// unix seconds, for receipts
+    pub(crate) created_at: u64,
+    /// whose api key opened the request, if any
+    pub(crate) tenant: Option<String>,
+    /// set when the listener wants resolvers to also type the pin shown next to the code
+    pub(crate) pin: Option<Pin>,
+    /// where the data goes besides the listener's websocket
+    pub(crate) webhooks: Vec<String>,
+    /// how many other websockets may follow the request
+    pub(crate) max_followers: usize,
+    /// the ones that do, each is sent what the webhooks get
+    pub(crate) followers: Vec<oneshot::Sender<ByteString>>,
+    pub(crate) custom_fields: Vec<CustomField>,
+    pub(crate) hash_checks: Option<HashChecks>,
+    pub(crate) format: OutputFormat,
+    /// set when only the devices of a linked user may answer
+    pub(crate) answerable_by: Option<Owner>,
+    /// where the listener was, when its tenant checks that resolvers are there too
+    pub(crate) country: Option<String>,
+    pub(crate) expires_at: Instant,
+    /// counts the request against the store's limits until it leaves the map
+    pub(crate) _slot: capacity::Slot,
+}
+
+pub(crate) static MAP: LazyLock<DashMap<RequestCode, PendingRequest>> = LazyLock::new(DashMap::new);
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Burial {
+    Resolved,
+    Expired,
+    Superseded,
+    /// too many wrong pins
+    Locked,
+    /// the user declined in the app
+    Rejected,
+}
+
+impl Burial {
+    fn name(self) -> &'static str {
+        match self {
+            Burial::Resolved => "resolved",
+            Burial::Expired => "expired",
+            Burial::Superseded => "superseded",
+            Burial::Locked => "locked",
+            Burial::Rejected => "rejected",
+        }
+    }
+}
+
+pub(crate) struct Tombstone {
+    cause: Burial,
+    /// of the listener the request was for, reports about it still go to that tenant
+    tenant: Option<String>,
+    until: Instant,
+}
+
+/// codes that were recently consumed or expired
+pub(crate) static TOMBSTONES: LazyLock<DashMap<RequestCode, Tombstone>> = LazyLock::new(DashMap::new);
+pub(crate) static TOMBSTONE_TTL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_TOMBSTONE_SECS", 10 * 60))
+});
+/// how often expired requests, tombstones and the other stores get swept
+static CLEANUP_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(env_or("ABSHER_CLEANUP_INTERVAL_SECS", 360).max(1))
+});
+
+/// fails unless `code` is a live request that can still be answered
+pub fn ensure_pending(code: RequestCode) -> Result<(), AppError> {
+    if denylist::is_denied(&code) {
+        return Err(AppError::RequestDenied(code))
+    }
+
+    let now = clock::now();
+    match (MAP.get(&code).map(|pending| pending.expires_at), burial(&code, now)) {
+        (Some(expires_at), _) if now <= expires_at => Ok(()),
+        (Some(_), _) => Err(AppError::RequestExpired(code)),
+        (None, Some(cause)) => Err(buried_error(code, cause)),
+        (None, None) => Err(AppError::RequestNotFound(Some(code))),
+    }
+}
+
+/// turns away devices other than the linked user's from requests only they may answer
+pub(crate) fn check_device(code: RequestCode, device: Option<&str>) -> Result<(), AppError> {
+    let allowed = MAP.get(&code)
+        .and_then(|pending| pending.answerable_by.clone())
+        .is_none_or(|owner| device.is_some_and(|device| devices::belongs_to(device, &owner)));
+
+    match allowed {
+        true => Ok(()),
+        false => Err(AppError::DeviceNotAllowed(code)),
+    }
+}
+
+/// applies the tenant's geo check to answers from another country than the listener's, when
+/// either side can't be placed the answer goes through
+pub(crate) fn check_country(code: RequestCode, ip: Option<IpAddr>) -> Result<(), AppError> {
+    let Some((listener, tenant)) = MAP.get(&code)
+        .and_then(|pending| pending.country.clone().zip(pending.tenant.clone()))
+    else {
+        return Ok(())
+    };
+    let Some(resolver) = ip.and_then(geo::country) else {
+        return Ok(())
+    };
+    if resolver == listener {
+        return Ok(())
+    }
+
+    let check = admin::tenant_geo(&tenant);
+    let correlation = code.correlation_id();
+    match check {
+        GeoCheck::Off => Ok(()),
+        GeoCheck::Warn => {
+            METRICS.country_mismatches.with_label_values(&["warned"]).inc();
+            log::warn!(code_hash = correlation.as_str(), tenant = tenant.as_str();
+                "[{correlation}] shown in {listener}, answered from {resolver}");
+            Ok(())
+        }
+        GeoCheck::Block => {
+            METRICS.country_mismatches.with_label_values(&["blocked"]).inc();
+            log::warn!(code_hash = correlation.as_str(), tenant = tenant.as_str();
+                "[{correlation}] shown in {listener}, turned away an answer from {resolver}");
+            Err(AppError::CountryMismatch(code))
+        }
+    }
+}
+
+/// spends an attempt on a wrong pin and cancels the request once they run out, requests
+/// without a pin and ones that are gone or expired are left to the normal lookup
+pub(crate) fn check_pin(code: RequestCode, presented: Option<&str>, now: Instant) -> Result<(), AppError> {
+    let Some(mut pending) = MAP.get_mut(&code).filter(|pending| now <= pending.expires_at) else {
+        return Ok(())
+    };
+
+    let Some(pin) = pending.pin.as_mut() else {
+        return Ok(())
+    };
+
+    // an app that doesn't know about pins yet shouldn't burn attempts
+    let Some(presented) = presented else {
+        return Err(AppError::PinRequired(code))
+    };
+
+    match pin.attempt(presented) {
+        Attempt::Accepted => Ok(()),
+        Attempt::Rejected { attempts_left } => Err(AppError::PinIncorrect { code, attempts_left }),
+        Attempt::Exhausted => {
+            drop(pending);
+            // dropping the request tells its listener, which looks up why in the tombstone
+            if let Some((_, request)) = MAP.remove(&code) {
+                bury(code, Burial::Locked, request.tenant.as_deref(), now);
+            }
+            Err(AppError::PinAttemptsExhausted(code))
+        }
+    }
+}
+
+pub(crate) fn bury(code: RequestCode, cause: Burial, tenant: Option<&str>, now: Instant) {
+    METRICS.ended.with_label_values(&[cause.name()]).inc();
+    stats::ended(tenant, cause.name());
+    events::emit(cause.name(), &code, tenant);
+    broker::completed(cause.name(), &code, tenant);
+    history::ended(&code, cause.name());
+    TOMBSTONES.insert(code, Tombstone { cause, tenant: tenant.map(str::to_owned), until: now + *TOMBSTONE_TTL });
+}
+
+pub(crate) fn burial(code: &RequestCode, now: Instant) -> Option<Burial> {
+    TOMBSTONES.get(code)
+        .filter(|tombstone| now <= tombstone.until)
+        .map(|tombstone| tombstone.cause)
+}
+
+/// the tenant of a pending or recently ended request, `None` when there is no such request and
+/// `Some(None)` for a keyless listener's
+pub(crate) fn tenant_of(code: &RequestCode, now: Instant) -> Option<Option<String>> {
+    if let Some(pending) = MAP.get(code) {
+        return Some(pending.tenant.clone())
+    }
+
+    TOMBSTONES.get(code)
+        .filter(|tombstone| now <= tombstone.until)
+        .map(|tombstone| tombstone.tenant.clone())
+}
+
+pub(crate) fn buried_error(code: RequestCode, cause: Burial) -> AppError {
+    match cause {
+        Burial::Resolved => AppError::RequestAlreadyResolved(code),
+        Burial::Expired => AppError::RequestExpired(code),
+        Burial::Superseded => AppError::RequestSuperseded(code),
+        Burial::Locked => AppError::PinAttemptsExhausted(code),
+        Burial::Rejected => AppError::RequestRejected(code),
+    }
+}
+
+/// ends a live request the user declined through `actor`, its listener looks up why in the
+/// tombstone
+pub(crate) fn reject(code: RequestCode, now: Instant, actor: &Actor) -> Result<(), AppError> {
+    let pending = MAP.remove_if(&code, |_code, pending| now <= pending.expires_at);
+    match (pending, burial(&code, now)) {
+        // the tombstone has to be in place before the listener wakes up to the dropped sender
+        (Some((_, pending)), _) => {
+            bury(code, Burial::Rejected, pending.tenant.as_deref(), now);
+            audit::record("rejected", &code, pending.tenant.as_deref(), pending.data_requested.names(), actor);
+            Ok(())
+        }
+        (None, Some(cause)) => Err(buried_error(code, cause)),
+        (None, None) if MAP.contains_key(&code) => Err(AppError::RequestExpired(code)),
+        (None, None) => Err(AppError::RequestNotFound(Some(code))),
+    }
+}
+
+/// the header resolvers put a random key in so retried resolves get the first answer again
+pub const IDEMPOTENCY_HEADER: &str = "idempotency-key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+
+/// how a resolve was answered, for retries of it
+struct Replay {
+    key: String,
+    delivered: bool,
+    receipt: String,
+    until: Instant,
+}
+
+static REPLAYS: LazyLock<DashMap<RequestCode, Replay>> = LazyLock::new(DashMap::new);
+
+/// the idempotency key a resolve came with, if it is usable
+pub(crate) fn idempotency_key(req: &actix_web::HttpRequest) -> Option<&str> {
+    req.headers().get(IDEMPOTENCY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .filter(|key| usable_idempotency_key(key))
+}
+
+pub(crate) fn usable_idempotency_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN
+}
+
+pub(crate) fn remember_resolve(code: RequestCode, key: &str, delivered: bool, receipt: String, now: Instant) {
+    REPLAYS.insert(code, Replay { key: key.to_owned(), delivered, receipt, until: now + *TOMBSTONE_TTL });
+}
+
+/// whether the resolve was relayed and its receipt, when `key` already resolved `code`
+pub(crate) fn replayed_resolve(code: &RequestCode, key: &str, now: Instant) -> Option<(bool, String)> {
+    REPLAYS.get(code)
+        .filter(|replay| now <= replay.until && bool::from(replay.key.as_bytes().ct_eq(key.as_bytes())))
+        .map(|replay| (replay.delivered, replay.receipt.clone()))
+}
+
+/// what a listener set up besides the fields it asks for
+pub struct RequestOptions {
+    pub public_key: Option<String>,
+    pub pin: Option<Pin>,
+    pub webhooks: Vec<String>,
+    pub followers: usize,
+    pub custom_fields: Vec<CustomField>,
+    pub hash_checks: Option<HashChecks>,
+    pub format: OutputFormat,
+    pub answerable_by: Option<Owner>,
+    pub country: Option<String>,
+}
+
+pub fn new_request(
+    kind: CodeKind,
+    selected: RequestedAutofillFields,
+    ttl: Duration,
+    mut options: RequestOptions,
+    slot: capacity::Slot,
+) -> (RequestCode, oneshot::Receiver<Resolution>) {
+    // the slot was taken for the tenant of the listener's key
+    let tenant = slot.tenant().map(str::to_owned);
+    let mut slot = Some(slot);
+    RequestCode::generate(kind, |code| {
+        // handing out a freshly buried code would turn its 410 back into a live request
+        if TOMBSTONES.contains_key(&code) || denylist::is_denied(&code) {
+            return None
+        }
+
+        let Entry::Vacant(vacant) = MAP.entry(code) else {
+            return None
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let timeout = clock::now().checked_add(ttl + ttl::GRACE).unwrap();
+
+        vacant.insert(PendingRequest {
+            notify: tx,
+            data_requested: selected,
+            public_key: options.public_key.take(),
+            created_at: unix_now(),
+            tenant: tenant.clone(),
+            pin: options.pin.take(),
+            webhooks: std::mem::take(&mut options.webhooks),
+            max_followers: options.followers,
+            followers: Vec::new(),
+            custom_fields: std::mem::take(&mut options.custom_fields),
+            hash_checks: options.hash_checks.take(),
+            format: options.format,
+            answerable_by: options.answerable_by.take(),
+            country: options.country.take(),
+            expires_at: timeout,
+            _slot: slot.take().expect("a request to be inserted once"),
+        });
+        expiry::schedule(code, timeout);
+        METRICS.created.inc();
+        events::emit("created", &code, tenant.as_deref());
+
+        Some(rx)
+    })
+}
+
+/// drops a pending request without answering it, its listener is told why by whoever calls this
+pub fn withdraw_request(code: &RequestCode) -> bool {
+    let Some((_, request)) = MAP.remove(code) else {
+        return false
+    };
+    METRICS.ended.with_label_values(&["withdrawn"]).inc();
+    stats::ended(request.tenant.as_deref(), "withdrawn");
+    events::emit("withdrawn", code, request.tenant.as_deref());
+    broker::completed("withdrawn", code, request.tenant.as_deref());
+    history::ended(code, "withdrawn");
+    true
+}
+
+#[derive(Serialize)]
+pub struct PendingOverview {
+    code: RequestCode,
+    tenant: Option<String>,
+    created_at: u64,
+    expires_in_secs: u64,
+    fields: RequestedAutofillFields,
+    encrypted: bool,
+    pin: bool,
+    webhooks: usize,
+}
+
+/// every open request, for operators looking at a live instance
+pub fn pending_requests() -> Vec<PendingOverview> {
+    let now = clock::now();
+    MAP.iter()
+        .filter(|pending| now <= pending.expires_at)
+        .map(|pending| PendingOverview {
+            code: *pending.key(),
+            tenant: pending.tenant.clone(),
+            created_at: pending.created_at,
+            expires_in_secs: pending.expires_at.saturating_duration_since(now).as_secs(),
+            fields: pending.data_requested,
+            encrypted: pending.public_key.is_some(),
+            pin: pending.pin.is_some(),
+            webhooks: pending.webhooks.len(),
+        })
+        .collect()
+}
+
+/// how many requests each tenant has open, keyless ones under `None`
+pub fn pending_by_tenant() -> BTreeMap<Option<String>, usize> {
+    let mut counts = BTreeMap::new();
+    for pending in MAP.iter() {
+        *counts.entry(pending.tenant.clone()).or_default() += 1;
+    }
+    counts
+}
+
+/// ends a pending request early, its listener is told it expired
+pub fn expire_request(code: RequestCode) -> bool {
+    let Some((_, request)) = MAP.remove(&code) else {
+        return false
+    };
+    bury(code, Burial::Expired, request.tenant.as_deref(), clock::now());
+    audit::record("expired", &code, request.tenant.as_deref(), request.data_requested.names(), &Actor::admin());
+    true
+}
+
+/// the expiry task's answer to a request running out, the code may have been answered, or
+/// answered and handed out again since
+pub fn expire_due(code: RequestCode, now: Instant) {
+    if let Some((_, request)) = MAP.remove_if(&code, |_code, pending| pending.expires_at <= now) {
+        bury(code, Burial::Expired, request.tenant.as_deref(), now);
+        audit::record("expired", &code, request.tenant.as_deref(), request.data_requested.names(), &Actor::default());
+    }
+}
+
+/// whether `code` could be handed out right now
+pub fn is_free(code: &RequestCode) -> bool {
+    !MAP.contains_key(code) && !TOMBSTONES.contains_key(code) && !denylist::is_denied(code)
+}
+
+/// requests expire on time through `expiry`, the rest can wait for this sweep, which never returns
+pub async fn sweep() {
+    let _watch = health::watch("cleanup");
+    let map = LazyLock::force(&MAP);
+    loop {
+        tokio::time::sleep(*CLEANUP_INTERVAL).await;
+        let now = clock::now();
+        TOMBSTONES.retain(|_code, tombstone| now <= tombstone.until);
+        REPLAYS.retain(|_code, replay| now <= replay.until);
+        uploads::prune(|code| map.contains_key(code));
+        rate_limit::prune();
+        lockout::prune();
+        denylist::prune();
+        blocklist::prune();
+        webhooks::prune();
+        sms::prune();
+        parked::prune();
+        oidc::prune();
+        protocol::report_legacy_usage();
+    }
+}
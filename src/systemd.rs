@@ -0,0 +1,73 @@
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+/// a socket systemd opened and passed in, for socket activation
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// where passed sockets start, after stdin, stdout and stderr
+#[cfg(unix)]
+const FIRST_FD: i32 = 3;
+
+/// the sockets in `LISTEN_FDS`, none unless systemd started this very process with them
+#[cfg(unix)]
+pub fn listeners() -> Vec<Listener> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    let var = |name: &str| std::env::var(name).ok().and_then(|value| value.parse::<u32>().ok());
+    if var("LISTEN_PID") != Some(std::process::id()) {
+        return Vec::new()
+    }
+
+    let count = var("LISTEN_FDS").unwrap_or(0) as i32;
+    (FIRST_FD..FIRST_FD.saturating_add(count))
+        .map(|fd| {
+            // systemd hands each of them to this process alone, and they are only taken here
+            let unix = unsafe { UnixListener::from_raw_fd(fd) };
+            match unix.local_addr() {
+                Ok(_) => Listener::Unix(unix),
+                // not a unix socket, so one of the tcp ports of the socket unit
+                Err(_) => Listener::Tcp(unsafe { TcpListener::from_raw_fd(unix.into_raw_fd()) }),
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Vec<Listener> {
+    Vec::new()
+}
+
+/// tells systemd how the service is doing, for `Type=notify` units; does nothing without
+/// `NOTIFY_SOCKET`
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::net::UnixDatagram;
+
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return
+        };
+
+        let sent = UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            _ => socket.send_to(state.as_bytes(), &path),
+        });
+        if let Err(err) = sent {
+            log::warn!("unable to tell systemd {state}: {err}");
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = state;
+}
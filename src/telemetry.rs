@@ -0,0 +1,117 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::Next;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::field::Empty;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use crate::req_code::RequestCode;
+
+/// what our spans are tagged with, `#[instrument]` needs it spelled out
+pub const TARGET: &str = "absher::trace";
+
+/// keeps the exporter running, flushing what is still buffered when dropped
+pub struct Telemetry(Option<SdkTracerProvider>);
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = &self.0
+            && let Err(err) = provider.shutdown()
+        {
+            log::warn!("unable to flush the last traces: {err}");
+        }
+    }
+}
+
+/// exports spans over otlp if `OTEL_EXPORTER_OTLP_ENDPOINT` is set, otherwise spans cost next to nothing
+pub fn init() -> Telemetry {
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return Telemetry(None)
+    }
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(err) => {
+            log::error!("unable to set up the otlp exporter, tracing is off: {err}");
+            return Telemetry(None)
+        }
+    };
+
+    let mut resource = Resource::builder();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+    }
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")));
+    if let Err(err) = tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer)) {
+        log::error!("unable to install the trace subscriber: {err}");
+        return Telemetry(None)
+    }
+
+    log::info!("exporting traces over otlp");
+    Telemetry(Some(provider))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// runs the request in a span that continues the caller's `traceparent`, if it sent one
+pub async fn trace(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let route = req.match_pattern().unwrap_or_else(|| req.path().to_owned());
+    let span = tracing::info_span!(
+        target: TARGET,
+        "request",
+        otel.name = format!("{} {route}", req.method()),
+        otel.status_code = Empty,
+        http.request.method = %req.method(),
+        http.route = route,
+        http.response.status_code = Empty,
+        absher.correlation_id = Empty,
+    );
+
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(req.headers())));
+    let _ = span.set_parent(parent);
+
+    // listeners only get their code later, `listen` records it then
+    if let Some(Ok(code)) = req.match_info().get("code").map(str::parse::<RequestCode>) {
+        span.record("absher.correlation_id", code.correlation_id());
+    }
+
+    let result = next.call(req).instrument(span.clone()).await;
+
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    span.record("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    result
+}
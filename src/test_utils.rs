@@ -0,0 +1,259 @@
+use std::net::SocketAddr;
+#[cfg(any(test, feature = "test-utils"))]
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use actix_web::dev::ServerHandle;
+use actix_web::middleware::from_fn;
+use actix_web::{App, HttpServer};
+use crate::client::{AbsherZtClient, ResolverClient};
+#[cfg(any(test, feature = "test-utils"))]
+use crate::clock::{self, ManualClock};
+#[cfg(any(test, feature = "test-utils"))]
+use crate::req_code::RequestCode;
+use crate::{expiry, handlers, rate_limit, request_id, signing, store};
+
+/// how long a stopped server waits for listeners still connected, nothing should outlive a test
+const STOP_GRACE: Duration = Duration::from_secs(1);
+
+/// what every test server measures request lifetimes with, time only passes when a test says so
+#[cfg(any(test, feature = "test-utils"))]
+static CLOCK: LazyLock<Arc<ManualClock>> = LazyLock::new(Default::default);
+/// false when something in the process already read the time, advancing would do nothing then
+#[cfg(any(test, feature = "test-utils"))]
+static CLOCK_INSTALLED: LazyLock<bool> = LazyLock::new(|| clock::install(CLOCK.clone()).is_ok());
+/// advancing the clock ages every running test's requests, so tests that open any take turns
+#[cfg(test)]
+static SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// a turn with the process' clock, for tests that open requests without a test server; puts the
+/// test clock in place first so servers started after them can still move it
+#[cfg(test)]
+pub(crate) async fn clock_turn() -> tokio::sync::MutexGuard<'static, ()> {
+    assert!(*CLOCK_INSTALLED, "something read the time before the test clock was installed");
+    SERIAL.lock().await
+}
+
+/// the whole app on a random local port, for end to end tests
+///
+/// it shares this process' store and clock with every other test server, codes are random so
+/// tests running side by side don't step on each other, but advancing the clock ages all of their
+/// requests; in tests and with the test-utils feature that clock only moves on `advance`. rate
+/// limits are off, a suite or a bench opens more requests a second than any page would. awc and
+/// the server need actix's runtime, so tests using this run under `#[actix_web::test]`
+pub struct TestServer {
+    addr: SocketAddr,
+    handle: ServerHandle,
+}
+
+impl TestServer {
+    /// boots the app the way the binary does, without tls, logging or the cleanup sweep
+    pub async fn start() -> std::io::Result<Self> {
+        #[cfg(any(test, feature = "test-utils"))]
+        if !*CLOCK_INSTALLED {
+            return Err(std::io::Error::other("the test clock has to be installed before anything reads the time"))
+        }
+        signing::init();
+        rate_limit::disable();
+
+        let server = HttpServer::new(|| {
+            App::new()
+                .configure(handlers::configure)
+                .wrap(from_fn(request_id::tag))
+        })
+            .workers(1)
+            .disable_signals()
+            .shutdown_timeout(STOP_GRACE.as_secs())
+            .bind(("127.0.0.1", 0))?;
+
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        actix_web::rt::spawn(expiry::run(store::expire_due));
+
+        Ok(Self { addr, handle })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// `http://` and the server's address, for calls the clients don't cover
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    /// acts as the website, without retries so a refused connection fails the test right away
+    pub fn listener(&self) -> AbsherZtClient {
+        AbsherZtClient::new(format!("ws://{}", self.addr)).retries(0)
+    }
+
+    /// acts as the app's backend
+    pub fn resolver(&self) -> ResolverClient {
+        ResolverClient::new(format!("http://{}", self.addr)).retries(0)
+    }
+
+    /// lets `by` pass for every request, the ones that ran out by then expire right away
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn advance(&self, by: Duration) {
+        CLOCK.advance(by);
+    }
+
+    /// runs a request out as if its time was up, its listener is told it expired
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn expire(&self, code: &str) -> bool {
+        code.parse::<RequestCode>().is_ok_and(store::expire_request)
+    }
+
+    /// whether `code` is still waiting for an answer
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn is_pending(&self, code: &str) -> bool {
+        code.parse::<RequestCode>().is_ok_and(|code| store::ensure_pending(code).is_ok())
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(true).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::client::{Error, Issued};
+    use crate::model::AutofillData;
+    use crate::protocol::ListenSpec;
+    use crate::req_code::RequestCode;
+    use crate::ttl;
+    use super::{TestServer, SERIAL};
+
+    fn email_spec() -> ListenSpec {
+        let mut spec = ListenSpec::default();
+        spec.fields.email = true;
+        spec
+    }
+
+    fn answer() -> AutofillData {
+        serde_json::from_str(r#"{"email":"user@example.com"}"#).expect("an answer with only an email to parse")
+    }
+
+    fn code(issued: &Issued) -> RequestCode {
+        issued.code.parse().expect("the server issued a malformed code")
+    }
+
+    /// the server's error code, for calls that were refused
+    fn refusal<T>(result: Result<T, Error>) -> String {
+        match result {
+            Err(Error::Rejected(error)) => error.code,
+            Err(err) => panic!("expected the server to refuse, got {err}"),
+            Ok(_) => panic!("expected the server to refuse"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn resolved_data_reaches_the_listener() {
+        let _serial = SERIAL.lock().await;
+        let server = TestServer::start().await.unwrap();
+
+        let (issued, data) = server.listener().request_fields(email_spec()).await.unwrap();
+        assert!(server.is_pending(&issued.code));
+
+        let resolved = server.resolver().resolve(code(&issued), &answer(), None).await.unwrap();
+        assert!(resolved.delivered);
+        assert!(!resolved.receipt.is_empty());
+
+        let data = data.await.unwrap();
+        assert_eq!(data.email.as_deref(), Some("user@example.com"));
+        assert!(!server.is_pending(&issued.code));
+
+        let again = server.resolver().resolve(code(&issued), &answer(), None).await;
+        assert_eq!(refusal(again), "REQUEST_ALREADY_RESOLVED");
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn unanswered_requests_expire() {
+        let _serial = SERIAL.lock().await;
+        let server = TestServer::start().await.unwrap();
+
+        let (issued, data) = server.listener().request_fields(email_spec()).await.unwrap();
+        // answers that were on their way still count for a moment after the code ran out
+        server.advance(issued.expires_in + ttl::GRACE + Duration::from_secs(1));
+
+        assert!(matches!(data.await, Err(Error::Expired)));
+        assert!(!server.is_pending(&issued.code));
+
+        let late = server.resolver().resolve(code(&issued), &answer(), None).await;
+        assert_eq!(refusal(late), "REQUEST_EXPIRED");
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn rejections_reach_the_listener() {
+        let _serial = SERIAL.lock().await;
+        let server = TestServer::start().await.unwrap();
+
+        let (issued, data) = server.listener().request_fields(email_spec()).await.unwrap();
+        server.resolver().reject(code(&issued), Some("not me")).await.unwrap();
+
+        assert_eq!(refusal(data.await), "REQUEST_REJECTED");
+        let late = server.resolver().resolve(code(&issued), &answer(), None).await;
+        assert_eq!(refusal(late), "REQUEST_REJECTED");
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn wrong_pins_cancel_the_request() {
+        let _serial = SERIAL.lock().await;
+        let server = TestServer::start().await.unwrap();
+
+        let mut spec = email_spec();
+        spec.pin = true;
+        let (issued, data) = server.listener().request_fields(spec).await.unwrap();
+        let pin = issued.pin.clone().expect("a request with a pin came without one");
+        let wrong = if pin == "0000" { "1111" } else { "0000" };
+
+        let resolver = server.resolver();
+        let mut attempts_left = Vec::new();
+        let last = loop {
+            let refused = resolver.resolve(code(&issued), &answer(), Some(wrong)).await;
+            match refused {
+                Err(Error::Rejected(error)) if error.code == "PIN_INCORRECT" && attempts_left.len() < 10 => {
+                    attempts_left.push(error.attempts_left.expect("a wrong pin came without the attempts left"));
+                }
+                refused => break refusal(refused),
+            }
+        };
+
+        assert_eq!(last, "PIN_ATTEMPTS_EXHAUSTED");
+        assert!(attempts_left.windows(2).all(|pair| pair[1] + 1 == pair[0]));
+        assert_eq!(attempts_left.last().copied().unwrap_or(1), 1);
+
+        // the request is gone, the right pin doesn't bring it back
+        let right = resolver.resolve(code(&issued), &answer(), Some(&pin)).await;
+        assert_eq!(refusal(right), "PIN_ATTEMPTS_EXHAUSTED");
+        assert_eq!(refusal(data.await), "PIN_ATTEMPTS_EXHAUSTED");
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn newer_listeners_supersede_older_ones() {
+        let _serial = SERIAL.lock().await;
+        let server = TestServer::start().await.unwrap();
+
+        let mut spec = email_spec();
+        spec.session = Some("browser-session".to_owned());
+        let (older, older_data) = server.listener().request_fields(spec).await.unwrap();
+
+        let mut spec = email_spec();
+        spec.session = Some("browser-session".to_owned());
+        let (newer, newer_data) = server.listener().request_fields(spec).await.unwrap();
+
+        assert!(matches!(older_data.await, Err(Error::Superseded)));
+        let late = server.resolver().resolve(code(&older), &answer(), None).await;
+        assert_eq!(refusal(late), "REQUEST_SUPERSEDED");
+
+        server.resolver().resolve(code(&newer), &answer(), None).await.unwrap();
+        assert_eq!(newer_data.await.unwrap().email.as_deref(), Some("user@example.com"));
+        server.stop().await;
+    }
+}
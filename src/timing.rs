@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::HttpMessage;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// when a request first reached us, before any middleware or body parsing
+#[derive(Copy, Clone)]
+pub struct Arrival(pub Instant);
+
+pub async fn stamp(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    req.extensions_mut().insert(Arrival(Instant::now()));
+    next.call(req).await
+}
+
+/// whole microseconds, anything finer is noise
+fn millis(dur: Duration) -> f64 {
+    dur.as_micros() as f64 / 1000.0
+}
+
+/// server side breakdown of a resolve, in milliseconds
+#[derive(Copy, Clone, Serialize, ToSchema)]
+pub struct Timing {
+    /// arrival until the handler ran, covers reading and parsing the body
+    pub queue_ms: f64,
+    /// looking the code up and checking it is still live
+    pub validation_ms: f64,
+    /// handing the data over to the listener
+    pub relay_ms: f64,
+}
+
+impl Timing {
+    pub fn new(arrived: Instant, started: Instant, validated: Instant, relayed: Instant) -> Self {
+        Self {
+            queue_ms: millis(started.saturating_duration_since(arrived)),
+            validation_ms: millis(validated.saturating_duration_since(started)),
+            relay_ms: millis(relayed.saturating_duration_since(validated)),
+        }
+    }
+
+    /// the same breakdown with relay measured up to some later point, like the listener's send
+    pub fn relayed_after(self, relay: Duration) -> Self {
+        Self { relay_ms: millis(relay), ..self }
+    }
+
+    /// value for a `Server-Timing` header
+    pub fn header_value(&self) -> String {
+        format!(
+            "queue;dur={:.3}, validation;dur={:.3}, relay;dur={:.3}",
+            self.queue_ms, self.validation_ms, self.relay_ms,
+        )
+    }
+}
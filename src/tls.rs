@@ -0,0 +1,84 @@
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use actix_web::http::header;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::ServerConfig;
+use crate::config::setting;
+
+/// native https, for deployments that sit straight on the internet
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// plain http port that only redirects to https
+    pub redirect_port: Option<u16>,
+    /// udp port http/3 is served on with the same certificate
+    pub http3_port: Option<u16>,
+}
+
+impl TlsSettings {
+    /// tls is on when both a certificate chain and a key are configured
+    pub fn from_env() -> Option<Self> {
+        let cert = setting("ABSHER_TLS_CERT")?;
+        let key = setting("ABSHER_TLS_KEY")?;
+        let redirect_port = setting("ABSHER_TLS_REDIRECT_PORT")
+            .and_then(|port| port.parse().ok());
+        let http3_port = setting("ABSHER_TLS_HTTP3_PORT")
+            .and_then(|port| port.parse().ok());
+
+        Some(Self { cert: cert.into(), key: key.into(), redirect_port, http3_port })
+    }
+
+    pub fn server_config(&self) -> io::Result<ServerConfig> {
+        let invalid = |what: &str, err: &dyn std::fmt::Display| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("unable to load tls {what}: {err}"))
+        };
+
+        let chain = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| invalid("certificate chain", &err))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|err| invalid("private key", &err))?;
+
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| invalid("protocol versions", &err))?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(|err| invalid("certificate", &err))
+    }
+}
+
+/// a bare http server that sends everyone to the same path over https
+pub fn redirect_server(ips: &[IpAddr], port: u16, https_port: u16) -> io::Result<actix_web::dev::Server> {
+    let redirect = move |req: HttpRequest| async move {
+        let host = req.connection_info().host().to_owned();
+        // drop whatever port the client used for plain http
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name.to_owned(),
+            _ => host,
+        };
+
+        let authority = match https_port {
+            443 => host,
+            port => format!("{host}:{port}"),
+        };
+
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        HttpResponse::PermanentRedirect()
+            .insert_header((header::LOCATION, format!("https://{authority}{path}")))
+            .finish()
+    };
+
+    // stopped along with the main server, see `shutdown::on_signal`
+    let mut server = HttpServer::new(move || App::new().default_service(web::to(redirect)))
+        .disable_signals();
+    for &ip in ips {
+        server = server.bind((ip, port))?;
+    }
+
+    Ok(server.run())
+}
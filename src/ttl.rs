@@ -0,0 +1,73 @@
+use std::sync::{LazyLock, PoisonError, RwLock};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::env_or;
+use crate::error::AppError;
+
+/// operator set limits every tenant policy and listener request has to fit in
+#[derive(Copy, Clone)]
+struct Bounds {
+    min_secs: u64,
+    default_secs: u64,
+    max_secs: u64,
+}
+
+impl Bounds {
+    fn from_env() -> Self {
+        let min_secs = env_or("ABSHER_MIN_TTL_SECS", 30);
+        let max_secs = env_or("ABSHER_MAX_TTL_SECS", 15 * 60).max(min_secs);
+        let default_secs = env_or("ABSHER_DEFAULT_TTL_SECS", 3 * 60).clamp(min_secs, max_secs);
+        Self { min_secs, default_secs, max_secs }
+    }
+}
+
+/// open requests keep the ttl they got, a reload only applies to new ones
+static BOUNDS: LazyLock<RwLock<Bounds>> = LazyLock::new(|| RwLock::new(Bounds::from_env()));
+
+fn bounds() -> Bounds {
+    *BOUNDS.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn reload() {
+    *BOUNDS.write().unwrap_or_else(PoisonError::into_inner) = Bounds::from_env();
+}
+
+/// undocumented and not guaranteed, but absorbs clock skew and slow phones
+pub const GRACE: Duration = Duration::from_secs(3);
+
+#[derive(Copy, Clone, Default, Deserialize, Serialize)]
+pub struct TtlPolicy {
+    #[serde(default)]
+    pub default_secs: Option<u64>,
+    #[serde(default)]
+    pub max_secs: Option<u64>,
+}
+
+impl TtlPolicy {
+    pub fn validate(&self) -> Result<(), AppError> {
+        let bounds = bounds();
+        let in_bounds = |secs: u64| (bounds.min_secs..=bounds.max_secs).contains(&secs);
+
+        let valid = self.default_secs.is_none_or(in_bounds)
+            && self.max_secs.is_none_or(in_bounds)
+            && match (self.default_secs, self.max_secs) {
+                (Some(default), Some(max)) => default <= max,
+                _ => true,
+            };
+
+        match valid {
+            true => Ok(()),
+            false => Err(AppError::InvalidTtlPolicy { min_secs: bounds.min_secs, max_secs: bounds.max_secs })
+        }
+    }
+}
+
+/// how long a new request lives, given what the listener asked for and its tenant's policy
+pub fn resolve(requested_secs: Option<u64>, policy: TtlPolicy) -> Duration {
+    let bounds = bounds();
+    let max = policy.max_secs.unwrap_or(bounds.max_secs).clamp(bounds.min_secs, bounds.max_secs);
+    let default = policy.default_secs.unwrap_or(bounds.default_secs);
+
+    let secs = requested_secs.unwrap_or(default).clamp(bounds.min_secs, max);
+    Duration::from_secs(secs)
+}
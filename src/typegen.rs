@@ -0,0 +1,332 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use serde_json::{json, Map, Value};
+use crate::openapi;
+
+const KOTLIN_PACKAGE: &str = "sa.absher.zt";
+const HEADER: &str = "generated from the absher-zt models with `export-types`, don't edit by hand";
+
+/// writes the api's types for the sdks: `absher.ts`, `Absher.kt` and `absher.schema.json`, all
+/// from the same openapi components so they can't drift apart
+pub fn export(dir: &Path) -> std::io::Result<()> {
+    let Value::Object(schemas) = json!(openapi::document().components.map(|components| components.schemas)) else {
+        return Err(std::io::Error::other("the openapi document has no schemas"))
+    };
+
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join("absher.schema.json"), json_schema(&schemas))?;
+    fs::write(dir.join("absher.ts"), typescript(&schemas))?;
+    fs::write(dir.join("Absher.kt"), kotlin(&schemas))?;
+
+    println!("wrote {} types to {}", schemas.len(), dir.display());
+    Ok(())
+}
+
+fn ref_name(schema: &Value) -> Option<&str> {
+    schema.get("$ref")?.as_str()?.strip_prefix("#/components/schemas/")
+}
+
+/// the `type` of a schema, which may be a list when the value is nullable
+fn types(schema: &Value) -> Vec<&str> {
+    match schema.get("type") {
+        Some(Value::String(kind)) => vec![kind],
+        Some(Value::Array(kinds)) => kinds.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn list<'a>(schema: &'a Value, key: &str) -> &'a [Value] {
+    schema.get(key).and_then(Value::as_array).map_or(&[], Vec::as_slice)
+}
+
+fn properties(schema: &Value) -> Option<&Map<String, Value>> {
+    schema.get("properties")?.as_object()
+}
+
+fn required(schema: &Value, property: &str) -> bool {
+    list(schema, "required").iter().any(|name| name == property)
+}
+
+fn nullable(schema: &Value) -> bool {
+    types(schema).contains(&"null") || list(schema, "oneOf").iter().any(|variant| types(variant) == ["null"])
+}
+
+/// doc comments spanning lines come through with their line breaks, which a `/** */` can't keep
+fn description(schema: &Value) -> Option<String> {
+    Some(schema.get("description")?.as_str()?.replace('\n', " "))
+}
+
+/// draft 2020-12 with the components under `$defs`, openapi 3.1 schemas already are json schema
+fn json_schema(schemas: &Map<String, Value>) -> String {
+    fn rewrite(value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(target)) = map.get_mut("$ref") {
+                    *target = target.replace("#/components/schemas/", "#/$defs/");
+                }
+                map.values_mut().for_each(rewrite);
+            }
+            Value::Array(values) => values.iter_mut().for_each(rewrite),
+            _ => {}
+        }
+    }
+
+    let mut defs = Value::Object(schemas.clone());
+    rewrite(&mut defs);
+
+    let document = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": "urn:absher-zt:types",
+        "$comment": HEADER,
+        "$defs": defs,
+    });
+    serde_json::to_string_pretty(&document).unwrap_or_default() + "\n"
+}
+
+fn ts_type(schema: &Value) -> String {
+    if let Some(name) = ref_name(schema) {
+        return name.to_owned()
+    }
+
+    for (key, separator) in [("oneOf", " | "), ("anyOf", " | "), ("allOf", " & ")] {
+        let parts = list(schema, key);
+        if !parts.is_empty() {
+            let parts: Vec<String> = parts.iter().map(|part| {
+                let part = ts_type(part);
+                match part.contains(' ') && !part.starts_with('{') {
+                    true => format!("({part})"),
+                    false => part,
+                }
+            }).collect();
+            return parts.join(separator)
+        }
+    }
+
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        return values.iter().map(Value::to_string).collect::<Vec<_>>().join(" | ")
+    }
+
+    let kinds = types(schema);
+    if kinds.is_empty() {
+        return "unknown".to_owned()
+    }
+
+    kinds.iter().map(|kind| match *kind {
+        "string" => "string".to_owned(),
+        "integer" | "number" => "number".to_owned(),
+        "boolean" => "boolean".to_owned(),
+        "null" => "null".to_owned(),
+        "array" => match schema.get("items") {
+            Some(items) => format!("Array<{}>", ts_type(items)),
+            None => "unknown[]".to_owned(),
+        },
+        "object" => match (properties(schema), schema.get("additionalProperties")) {
+            (Some(props), _) => ts_object(schema, props, ""),
+            (None, Some(Value::Object(_))) => format!("Record<string, {}>", ts_type(&schema["additionalProperties"])),
+            (None, _) => "Record<string, unknown>".to_owned(),
+        },
+        _ => "unknown".to_owned(),
+    }).collect::<Vec<_>>().join(" | ")
+}
+
+fn ts_object(schema: &Value, props: &Map<String, Value>, indent: &str) -> String {
+    let mut out = "{\n".to_owned();
+    for (name, property) in props {
+        if let Some(doc) = description(property) {
+            let _ = writeln!(out, "{indent}  /** {doc} */");
+        }
+        let optional = if required(schema, name) { "" } else { "?" };
+        let _ = writeln!(out, "{indent}  {name}{optional}: {};", ts_type(property));
+    }
+    out + indent + "}"
+}
+
+fn typescript(schemas: &Map<String, Value>) -> String {
+    let mut out = format!("// {HEADER}\n");
+    for (name, schema) in schemas {
+        out.push('\n');
+        if let Some(doc) = description(schema) {
+            let _ = writeln!(out, "/** {doc} */");
+        }
+        match properties(schema) {
+            Some(props) if types(schema) == ["object"] => {
+                let _ = writeln!(out, "export interface {name} {}", ts_object(schema, props, ""));
+            }
+            _ => {
+                let _ = writeln!(out, "export type {name} = {};", ts_type(schema));
+            }
+        }
+    }
+    out
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split(['_', '-', '.', '+']).filter(|part| !part.is_empty()).map(|part| {
+        let mut chars = part.chars();
+        chars.next().map(|first| first.to_uppercase().chain(chars).collect::<String>()).unwrap_or_default()
+    }).collect()
+}
+
+fn camel_case(name: &str) -> String {
+    let pascal = pascal_case(name);
+    let mut chars = pascal.chars();
+    chars.next().map(|first| first.to_lowercase().chain(chars).collect()).unwrap_or_default()
+}
+
+/// the single value a property is pinned to, which is how tagged unions carry their tag
+fn constant(schema: &Value) -> Option<&str> {
+    match list(schema, "enum") {
+        [Value::String(tag)] => Some(tag),
+        _ => None,
+    }
+}
+
+/// one object out of an `allOf`, or the schema itself; `None` if a part isn't an object
+fn merged(schemas: &Map<String, Value>, schema: &Value) -> Option<Map<String, Value>> {
+    let parts = match list(schema, "allOf") {
+        [] => std::slice::from_ref(schema),
+        parts => parts,
+    };
+
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for part in parts {
+        let part = ref_name(part).and_then(|name| schemas.get(name)).unwrap_or(part);
+        properties.extend(self::properties(part)?.clone());
+        required.extend(list(part, "required").iter().cloned());
+    }
+
+    let mut object = Map::new();
+    object.insert("type".to_owned(), json!("object"));
+    object.insert("properties".to_owned(), Value::Object(properties));
+    object.insert("required".to_owned(), Value::Array(required));
+    Some(object)
+}
+
+/// a union tagged by one of its properties, like the websocket messages by `type`; returns the tag
+/// and each variant's value with the rest of its properties
+fn tagged_union(schemas: &Map<String, Value>, schema: &Value) -> Option<(String, Vec<(String, Value)>)> {
+    let variants = list(schema, "oneOf");
+    if variants.is_empty() {
+        return None
+    }
+
+    let mut tag = None;
+    let mut out = Vec::new();
+
+    for variant in variants {
+        // a newtype variant is the wrapped schema in an allOf with an object holding the tag
+        let mut object = merged(schemas, variant)?;
+        let props = object.get_mut("properties").and_then(Value::as_object_mut)?;
+        let (name, value) = props.iter().find_map(|(name, property)| Some((name.clone(), constant(property)?.to_owned())))?;
+        if tag.get_or_insert_with(|| name.clone()) != &name {
+            return None
+        }
+        props.remove(&name);
+        out.push((value, Value::Object(object)));
+    }
+
+    Some((tag?, out))
+}
+
+fn kt_type(schema: &Value) -> String {
+    let kinds: Vec<&str> = types(schema).into_iter().filter(|kind| *kind != "null").collect();
+    let base = if let Some(name) = ref_name(schema) {
+        name.to_owned()
+    } else if let [single] = list(schema, "oneOf").iter().filter(|variant| types(variant) != ["null"]).collect::<Vec<_>>()[..]
+        && list(schema, "oneOf").len() == 2
+    {
+        kt_type(single)
+    } else {
+        match kinds[..] {
+            ["string"] => "String".to_owned(),
+            ["integer"] if schema.get("format").is_some_and(|format| format == "int64") => "Long".to_owned(),
+            ["integer"] => "Int".to_owned(),
+            ["number"] => "Double".to_owned(),
+            ["boolean"] => "Boolean".to_owned(),
+            ["array"] => format!("List<{}>", schema.get("items").map_or("JsonElement".to_owned(), kt_type)),
+            ["object"] if properties(schema).is_none() => match schema.get("additionalProperties") {
+                Some(values @ Value::Object(_)) => format!("Map<String, {}>", kt_type(values)),
+                _ => "Map<String, JsonElement>".to_owned(),
+            },
+            _ => "JsonElement".to_owned(),
+        }
+    };
+
+    match nullable(schema) {
+        true => base + "?",
+        false => base,
+    }
+}
+
+fn kt_class(out: &mut String, keyword: &str, name: &str, schema: &Value, parent: Option<&str>, indent: &str) {
+    let props = properties(schema).cloned().unwrap_or_default();
+    let implements = parent.map(|parent| format!(" : {parent}")).unwrap_or_default();
+    if props.is_empty() {
+        let _ = writeln!(out, "{indent}@Serializable\n{indent}data object {name}{implements}");
+        return
+    }
+
+    let _ = writeln!(out, "{indent}@Serializable\n{indent}{keyword} {name}(");
+    for (property, value) in &props {
+        let mut kind = kt_type(value);
+        // kotlinx leaves out defaults, so whatever the server may leave out gets one
+        let default = match (required(schema, property), kind.ends_with('?')) {
+            (true, _) => "",
+            (false, true) => " = null",
+            (false, false) => {
+                kind.push('?');
+                " = null"
+            }
+        };
+        if let Some(doc) = description(value) {
+            let _ = writeln!(out, "{indent}    /** {doc} */");
+        }
+        let _ = writeln!(out, "{indent}    @SerialName(\"{property}\") val {}: {kind}{default},", camel_case(property));
+    }
+    let _ = writeln!(out, "{indent}){implements}");
+}
+
+fn kotlin(schemas: &Map<String, Value>) -> String {
+    let mut out = format!(
+        "// {HEADER}\n@file:OptIn(ExperimentalSerializationApi::class)\n\npackage {KOTLIN_PACKAGE}\n\n\
+        import kotlinx.serialization.ExperimentalSerializationApi\n\
+        import kotlinx.serialization.SerialName\n\
+        import kotlinx.serialization.Serializable\n\
+        import kotlinx.serialization.json.JsonClassDiscriminator\n\
+        import kotlinx.serialization.json.JsonElement\n"
+    );
+
+    for (name, schema) in schemas {
+        out.push('\n');
+        if let Some(doc) = description(schema) {
+            let _ = writeln!(out, "/** {doc} */");
+        }
+
+        if let Some(Value::Array(values)) = schema.get("enum")
+            && values.iter().all(Value::is_string)
+        {
+            let _ = writeln!(out, "@Serializable\nenum class {name} {{");
+            for value in values.iter().filter_map(Value::as_str) {
+                let _ = writeln!(out, "    @SerialName(\"{value}\") {},", value.to_uppercase().replace(['-', '.', '+'], "_"));
+            }
+            out.push_str("}\n");
+        } else if let Some((tag, variants)) = tagged_union(schemas, schema) {
+            let _ = writeln!(out, "@Serializable\n@JsonClassDiscriminator(\"{tag}\")\nsealed interface {name} {{");
+            for (value, variant) in &variants {
+                let _ = writeln!(out, "    @SerialName(\"{value}\")");
+                let mut class = String::new();
+                kt_class(&mut class, "data class", &pascal_case(value), variant, Some(name), "    ");
+                out.push_str(&class);
+            }
+            out.push_str("}\n");
+        } else if let Some(object) = merged(schemas, schema) {
+            kt_class(&mut out, "data class", name, &Value::Object(object), None, "");
+        } else {
+            // untagged unions and the like, left for the app to pick apart
+            let _ = writeln!(out, "typealias {name} = {}", kt_type(schema).trim_end_matches('?'));
+        }
+    }
+    out
+}
@@ -0,0 +1,388 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use std::time::Duration;
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpRequest, HttpResponse};
+use dashmap::{DashMap, Entry};
+use futures_util::TryStreamExt;
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::error::{AppError, ErrorResponse};
+use crate::req_code::RequestCode;
+use crate::store::ensure_pending;
+use crate::{env_or, lockout, rate_limit, to_json_str};
+
+/// how a payload field points at a finished upload instead of carrying the data inline
+pub const REFERENCE_PREFIX: &str = "upload:";
+
+/// largest document a single upload may assemble
+static MAX_BYTES: LazyLock<u64> = LazyLock::new(|| env_or("ABSHER_UPLOAD_MAX_BYTES", 32 << 20));
+/// bytes every upload in flight may claim together, they all live in memory
+static BUDGET_BYTES: LazyLock<u64> = LazyLock::new(|| env_or("ABSHER_UPLOAD_BUDGET_BYTES", 1 << 30));
+/// largest body a single chunk may carry
+static CHUNK_BYTES: LazyLock<usize> = LazyLock::new(|| env_or("ABSHER_UPLOAD_CHUNK_BYTES", 4 << 20));
+
+/// the payload fields a multipart form may carry
+const ATTACHABLE_FIELDS: [&str; 5] = ["profile_picture", "license", "id_image", "passport.image", "driving_license.image"];
+
+/// one per image field, with room for a sealed payload and a few restarts
+const MAX_UPLOADS_PER_REQUEST: usize = 8;
+
+static UPLOADS: LazyLock<DashMap<String, Upload>> = LazyLock::new(DashMap::new);
+/// uploads per request, kept next to `UPLOADS` so a new one doesn't have to walk them all
+static PER_REQUEST: LazyLock<DashMap<RequestCode, usize>> = LazyLock::new(DashMap::new);
+/// declared sizes of every upload in `UPLOADS`
+static CLAIMED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// how long a client is asked to wait when the budget is spent, uploads finish or expire by then
+const BUDGET_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+struct Upload {
+    code: RequestCode,
+    size: u64,
+    data: Vec<u8>,
+    finalized: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct NewUpload {
+    /// total length of the document in bytes
+    size: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UploadStatus<'a> {
+    upload: &'a str,
+    size: u64,
+    /// bytes stored so far, a dropped client resumes from here
+    received: u64,
+    finalized: bool,
+}
+
+/// what an attachments form turned into, each field goes into the resolve payload as it is
+#[derive(Serialize, ToSchema)]
+struct Attached {
+    attachments: BTreeMap<String, String>,
+}
+
+impl Upload {
+    fn status<'a>(&self, id: &'a str) -> UploadStatus<'a> {
+        UploadStatus {
+            upload: id,
+            size: self.size,
+            received: self.data.len() as u64,
+            finalized: self.finalized,
+        }
+    }
+}
+
+fn json_status(status: UploadStatus) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(to_json_str(&status)?))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(
+            web::resource("/requests/{code}/uploads")
+                .route(web::post().to(create))
+                .wrap(from_fn(rate_limit::limit))
+                .wrap(from_fn(lockout::guard))
+        )
+        // a big document takes many chunks, but each one is a full body to buffer
+        .service(
+            web::resource("/requests/{code}/uploads/{id}")
+                .app_data(web::PayloadConfig::new(*CHUNK_BYTES))
+                .route(web::get().to(status))
+                .route(web::patch().to(append).wrap(from_fn(rate_limit::limit)))
+                .wrap(from_fn(lockout::guard))
+        )
+        // a form carries whole documents, so it is throttled like the uploads it turns into
+        .service(
+            web::resource("/requests/{code}/attachments")
+                .route(web::post().to(attach))
+                .wrap(from_fn(rate_limit::limit))
+                .wrap(from_fn(lockout::guard))
+        )
+        .service(
+            web::resource("/requests/{code}/uploads/{id}/finalize")
+                .route(web::post().to(finalize))
+                .wrap(from_fn(rate_limit::limit))
+                .wrap(from_fn(lockout::guard))
+        );
+}
+
+#[utoipa::path(
+    post,
+    path = "/requests/{code}/uploads",
+    tag = "uploads",
+    description = "Starts a resumable upload for a document too large to send inline.",
+    params(("code" = String, Path, description = "the request code")),
+    request_body = NewUpload,
+    responses(
+        (status = 201, body = UploadStatus),
+        (status = 409, description = "the request has as many uploads as it can take", body = ErrorResponse),
+        (status = 413, body = ErrorResponse),
+        (status = 503, description = "uploads in flight hold all the memory set aside for them", body = ErrorResponse),
+    ),
+)]
+async fn create(code: web::Path<RequestCode>, body: web::Json<NewUpload>) -> Result<HttpResponse, AppError> {
+    let code = code.into_inner();
+    ensure_pending(code)?;
+
+    let NewUpload { size } = body.into_inner();
+    if size > *MAX_BYTES {
+        return Err(AppError::UploadTooLarge { max_bytes: *MAX_BYTES })
+    }
+
+    let id = insert(Upload { code, size, data: Vec::new(), finalized: false })?;
+    let status = UploadStatus { upload: &id, size, received: 0, finalized: false };
+    Ok(HttpResponse::Created()
+        .content_type("application/json")
+        .body(to_json_str(&status)?))
+}
+
+/// stores an upload under a fresh id, as long as its request has room for another one
+/// and the budget has room for its bytes
+fn insert(upload: Upload) -> Result<String, AppError> {
+    claim(upload.code, upload.size)?;
+
+    loop {
+        let id = Alphanumeric.sample_string(&mut rand::rng(), 16);
+        if let Entry::Vacant(vacant) = UPLOADS.entry(id.clone()) {
+            vacant.insert(upload);
+            break Ok(id)
+        }
+    }
+}
+
+fn uploads_for(code: RequestCode) -> usize {
+    PER_REQUEST.get(&code).map_or(0, |count| *count)
+}
+
+/// counts one more upload of `size` bytes for `code`, or says why it can't be taken
+fn claim(code: RequestCode, size: u64) -> Result<(), AppError> {
+    let mut count = PER_REQUEST.entry(code).or_insert(0);
+    if *count >= MAX_UPLOADS_PER_REQUEST {
+        return Err(AppError::UploadLimitReached(code))
+    }
+
+    CLAIMED_BYTES.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |claimed| {
+        claimed.checked_add(size).filter(|claimed| *claimed <= *BUDGET_BYTES)
+    }).map_err(|_| AppError::AtCapacity { retry_after: BUDGET_RETRY_AFTER })?;
+
+    *count += 1;
+    Ok(())
+}
+
+/// gives back what `claim` counted for an upload that was dropped
+fn release(upload: &Upload) {
+    CLAIMED_BYTES.fetch_sub(upload.size, Ordering::Relaxed);
+    if let Entry::Occupied(mut count) = PER_REQUEST.entry(upload.code) {
+        *count.get_mut() -= 1;
+        if *count.get() == 0 {
+            count.remove();
+        }
+    }
+}
+
+/// takes whole documents as multipart form fields named like the payload fields they are for,
+/// and answers with the references to resolve with instead of base64
+#[utoipa::path(
+    post,
+    path = "/requests/{code}/attachments",
+    tag = "uploads",
+    params(("code" = String, Path, description = "the request code")),
+    request_body(content_type = "multipart/form-data", description = "one part per image, named `profile_picture`, `license`, `id_image`, `passport.image` or `driving_license.image`, each name at most once"),
+    responses(
+        (status = 201, body = Attached),
+        (status = 400, body = ErrorResponse),
+        (status = 409, description = "the request has as many uploads as it can take", body = ErrorResponse),
+        (status = 413, body = ErrorResponse),
+    ),
+)]
+async fn attach(code: web::Path<RequestCode>, mut form: Multipart) -> Result<HttpResponse, AppError> {
+    let code = code.into_inner();
+    ensure_pending(code)?;
+
+    let invalid = |err: actix_multipart::MultipartError| AppError::InvalidBody(err.to_string());
+    let mut attachments = BTreeMap::new();
+    while let Some(mut field) = form.try_next().await.map_err(invalid)? {
+        let name = field.name()
+            .filter(|name| ATTACHABLE_FIELDS.contains(name))
+            .map(str::to_owned)
+            .ok_or_else(|| AppError::InvalidBody(format!("attachments can only be {}", ATTACHABLE_FIELDS.join(", "))))?;
+        if attachments.contains_key(&name) {
+            return Err(AppError::InvalidBody(format!("`{name}` is attached more than once")))
+        }
+        // refused before its bytes are read, not after
+        if uploads_for(code) >= MAX_UPLOADS_PER_REQUEST {
+            return Err(AppError::UploadLimitReached(code))
+        }
+
+        let mut data = Vec::new();
+        while let Some(chunk) = field.try_next().await.map_err(invalid)? {
+            if (data.len() + chunk.len()) as u64 > *MAX_BYTES {
+                return Err(AppError::UploadTooLarge { max_bytes: *MAX_BYTES })
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        let id = insert(Upload { code, size: data.len() as u64, data, finalized: true })?;
+        attachments.insert(name, format!("{REFERENCE_PREFIX}{id}"));
+    }
+
+    Ok(HttpResponse::Created()
+        .content_type("application/json")
+        .body(to_json_str(&Attached { attachments })?))
+}
+
+/// an upload that exists and belongs to this still pending request
+fn find(code: RequestCode, id: &str) -> Result<dashmap::mapref::one::RefMut<'static, String, Upload>, AppError> {
+    ensure_pending(code)?;
+    UPLOADS.get_mut(id)
+        .filter(|upload| upload.code == code)
+        .ok_or(AppError::UploadNotFound)
+}
+
+#[utoipa::path(
+    get,
+    path = "/requests/{code}/uploads/{id}",
+    tag = "uploads",
+    description = "How much of an upload arrived, a client that lost its connection resumes from `received`.",
+    params(("code" = String, Path, description = "the request code"), ("id" = String, Path, description = "the upload id")),
+    responses((status = 200, body = UploadStatus), (status = 404, body = ErrorResponse)),
+)]
+async fn status(path: web::Path<(RequestCode, String)>) -> Result<HttpResponse, AppError> {
+    let (code, id) = path.into_inner();
+    let upload = find(code, &id)?;
+    json_status(upload.status(&id))
+}
+
+/// `bytes {first}-{last}/{total}`, inclusive like http ranges
+fn parse_content_range(value: &str) -> Option<(u64, u64, u64)> {
+    let range = value.trim().strip_prefix("bytes ")?;
+    let (span, total) = range.split_once('/')?;
+    let (first, last) = span.split_once('-')?;
+
+    let first = first.parse().ok()?;
+    let last = last.parse().ok()?;
+    (first <= last).then_some((first, last, total.parse().ok()?))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/requests/{code}/uploads/{id}",
+    tag = "uploads",
+    params(("code" = String, Path, description = "the request code"), ("id" = String, Path, description = "the upload id"), ("content-range" = String, Header, description = "`bytes first-last/size`")),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, body = UploadStatus),
+        (status = 400, description = "the content range doesn't match the chunk or the upload", body = ErrorResponse),
+        (status = 409, description = "the chunk doesn't continue the upload, or it was finalized", body = ErrorResponse),
+    ),
+)]
+async fn append(
+    req: HttpRequest,
+    path: web::Path<(RequestCode, String)>,
+    chunk: web::Bytes,
+) -> Result<HttpResponse, AppError> {
+    let (code, id) = path.into_inner();
+
+    let range = req.headers().get(header::CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(parse_content_range);
+
+    let mut upload = find(code, &id)?;
+    let Some((first, last, total)) = range else {
+        return Err(AppError::InvalidContentRange)
+    };
+
+    if total != upload.size || last >= total || last - first + 1 != chunk.len() as u64 {
+        return Err(AppError::InvalidContentRange)
+    }
+
+    if upload.finalized {
+        return Err(AppError::UploadFinalized)
+    }
+
+    // a client that lost the last response resends from where it thinks it was
+    let received = upload.data.len() as u64;
+    if first > received {
+        return Err(AppError::UploadOffsetMismatch { received })
+    }
+
+    upload.data.truncate(first as usize);
+    upload.data.extend_from_slice(&chunk);
+    json_status(upload.status(&id))
+}
+
+#[utoipa::path(
+    post,
+    path = "/requests/{code}/uploads/{id}/finalize",
+    tag = "uploads",
+    description = "Ends an upload, it is then referenced as `upload:{id}` in the resolve payload.",
+    params(("code" = String, Path, description = "the request code"), ("id" = String, Path, description = "the upload id")),
+    responses((status = 200, body = UploadStatus), (status = 409, description = "bytes are still missing", body = ErrorResponse)),
+)]
+async fn finalize(path: web::Path<(RequestCode, String)>) -> Result<HttpResponse, AppError> {
+    let (code, id) = path.into_inner();
+    let mut upload = find(code, &id)?;
+
+    let received = upload.data.len() as u64;
+    if received != upload.size {
+        return Err(AppError::UploadIncomplete { received, size: upload.size })
+    }
+
+    upload.finalized = true;
+    json_status(upload.status(&id))
+}
+
+/// the finished document behind `value` if it is an upload reference, for relaying
+pub fn referenced(code: RequestCode, value: &str) -> Result<Option<Vec<u8>>, AppError> {
+    let Some(id) = value.strip_prefix(REFERENCE_PREFIX) else {
+        return Ok(None)
+    };
+
+    let upload = UPLOADS.get(id)
+        .filter(|upload| upload.code == code)
+        .ok_or(AppError::UploadNotFound)?;
+
+    if !upload.finalized {
+        return Err(AppError::UploadIncomplete { received: upload.data.len() as u64, size: upload.size })
+    }
+
+    Ok(Some(upload.data.clone()))
+}
+
+/// uploads in flight or waiting to be relayed
+pub fn count() -> usize {
+    UPLOADS.len()
+}
+
+/// forgets every upload made for `code`, once it was relayed or can't be anymore
+pub fn discard(code: &RequestCode) {
+    UPLOADS.retain(|_id, upload| {
+        let keep = upload.code != *code;
+        if !keep {
+            release(upload);
+        }
+        keep
+    });
+}
+
+/// drops uploads whose request is gone
+pub fn prune(is_pending: impl Fn(&RequestCode) -> bool) {
+    UPLOADS.retain(|_id, upload| {
+        let keep = is_pending(&upload.code);
+        if !keep {
+            release(upload);
+        }
+        keep
+    });
+}
@@ -0,0 +1,89 @@
+use crate::identity;
+
+/// why an email was turned away, or `None` if it looks deliverable; this is syntax only, whether
+/// the mailbox exists is for the listener to find out
+pub fn email(email: &str) -> Option<&'static str> {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return Some("is missing an @")
+    };
+
+    let labels_ok = domain.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+    });
+
+    if email.len() > 254 || local.is_empty() || local.len() > 64 {
+        Some("has the wrong length")
+    } else if local.chars().any(|c| c.is_whitespace() || c.is_control() || c == '@') {
+        Some("has characters an address can't have")
+    } else if !domain.contains('.') || !labels_ok {
+        Some("has an invalid domain")
+    } else {
+        None
+    }
+}
+
+/// a phone number in E.164, saudi numbers may come in any of the ways people write them, like
+/// `05x xxx xxxx` or `009665xxxxxxxx`
+pub fn phone_number(phone: &str) -> Result<String, &'static str> {
+    let compact: String = phone.chars().filter(|c| !matches!(c, ' ' | '-' | '(' | ')' | '.')).collect();
+    let (international, digits) = match compact.strip_prefix('+') {
+        Some(digits) => (true, digits),
+        None => match compact.strip_prefix("00") {
+            Some(digits) => (true, digits),
+            None => (false, compact.as_str()),
+        },
+    };
+
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err("can only have digits after an optional +")
+    }
+
+    let saudi = match (international, digits.strip_prefix("966")) {
+        (true, Some(national)) => national,
+        (true, None) if (8..=15).contains(&digits.len()) => return Ok(format!("+{digits}")),
+        (true, None) => return Err("is not a valid international number"),
+        (false, _) => digits.strip_prefix('0').unwrap_or(digits),
+    };
+
+    // nine digits after the country code, mobiles start with 5 and landlines with their area code
+    match saudi.len() == 9 && !saudi.starts_with('0') {
+        true => Ok(format!("+966{saudi}")),
+        false => Err("is not a valid saudi number, add the country code for other countries"),
+    }
+}
+
+/// a national id or iqama number: ten digits, starting with 1 for citizens and 2 for residents,
+/// the last being a luhn check digit
+pub fn national_id(id: &str) -> Option<&'static str> {
+    if id.len() != 10 || !id.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Some("has to be 10 digits")
+    }
+    if !id.starts_with(['1', '2']) {
+        return Some("has to start with 1 or 2")
+    }
+
+    let sum: u32 = id.bytes().rev().enumerate().map(|(position, byte)| {
+        let digit = u32::from(byte - b'0');
+        match position % 2 {
+            0 => digit,
+            _ if digit > 4 => digit * 2 - 9,
+            _ => digit * 2,
+        }
+    }).sum();
+
+    (!sum.is_multiple_of(10)).then_some("has a wrong check digit, check it for typos")
+}
+
+pub fn date(date: &str) -> Option<&'static str> {
+    identity::parse_date(date).is_none().then_some("has to be a YYYY-MM-DD date")
+}
+
+/// ISO 3166-1 alpha-2, which the apps send uppercase
+pub fn country(country: &str) -> Option<&'static str> {
+    let valid = country.len() == 2 && country.bytes().all(|byte| byte.is_ascii_uppercase());
+    (!valid).then_some("has to be a two letter country code, like SA")
+}
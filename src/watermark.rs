@@ -0,0 +1,209 @@
+use std::io::Cursor;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use image::{ImageFormat, ImageReader, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::images;
+use crate::req_code::RequestCode;
+
+/// how a tenant's relayed document images get marked
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Watermark {
+    #[default]
+    Off,
+    /// a readable "shared with" line across the image
+    Visible,
+    /// the visible line plus a tag hidden in the pixels, tying leaked copies to one resolve
+    Full,
+}
+
+/// marks the hidden tag so it can be told apart from noise when reading it back
+const TAG_MAGIC: &[u8; 4] = b"AZT1";
+
+/// what gets stamped onto every image of one resolve
+pub struct Mark {
+    mode: Watermark,
+    text: String,
+    /// `TAG_MAGIC` then the first bytes of sha256(code, tenant, time)
+    tag: [u8; 12],
+}
+
+impl Mark {
+    pub fn new(mode: Watermark, tenant_name: &str, tenant: &str, code: RequestCode, at: u64) -> Self {
+        let text = format!("shared with {tenant_name} on {} via Absher ZT", date(at)).to_uppercase();
+
+        let digest = Sha256::new()
+            .chain_update(code.as_str())
+            .chain_update(tenant)
+            .chain_update(at.to_be_bytes())
+            .finalize();
+
+        let mut tag = [0; 12];
+        tag[..4].copy_from_slice(TAG_MAGIC);
+        tag[4..].copy_from_slice(&digest[..8]);
+
+        Self { mode, text, tag }
+    }
+
+    /// the marked image as base64, or `None` if it isn't an image we can decode
+    pub fn apply(&self, image: &str) -> Option<String> {
+        if self.mode == Watermark::Off {
+            return None
+        }
+
+        let bytes = BASE64_STANDARD.decode(image).ok()?;
+        let format = image::guess_format(&bytes).ok()?;
+        let mut reader = ImageReader::with_format(Cursor::new(&bytes), format);
+        reader.limits(images::limits());
+        let mut pixels = reader.decode().ok()?.to_rgba8();
+
+        draw_visible(&mut pixels, &self.text);
+
+        // the hidden tag lives in the lowest bits, which only a lossless format keeps
+        let format = match self.mode {
+            Watermark::Full => {
+                embed_tag(&mut pixels, &self.tag);
+                ImageFormat::Png
+            }
+            _ => format,
+        };
+
+        let mut out = Cursor::new(Vec::new());
+        let encoded = match format {
+            // jpeg has no alpha channel
+            ImageFormat::Jpeg => image::DynamicImage::ImageRgba8(pixels).to_rgb8().write_to(&mut out, format),
+            _ => pixels.write_to(&mut out, format),
+        };
+
+        if let Err(err) = encoded {
+            log::warn!("unable to re-encode a watermarked image: {err}");
+            return None
+        }
+
+        Some(BASE64_STANDARD.encode(out.into_inner()))
+    }
+}
+
+/// `YYYY-MM-DD` in utc for a unix timestamp
+fn date(unix: u64) -> String {
+    // days to civil date, from Howard Hinnant's date algorithms
+    let days = (unix / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+
+/// 5x7 bitmaps for what watermark lines are made of, anything else is left blank
+fn glyph(char: char) -> [u8; 7] {
+    match char {
+        'A' => [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'B' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+        'C' => [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+        'D' => [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100],
+        'E' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111],
+        'F' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+        'G' => [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+        'H' => [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'I' => [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'J' => [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100],
+        'K' => [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+        'M' => [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001],
+        'N' => [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'P' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000],
+        'Q' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101],
+        'R' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+        'S' => [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'V' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'W' => [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010],
+        'X' => [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+        'Y' => [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+        'Z' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111],
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        '-' => [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000],
+        '.' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100],
+        ':' => [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000],
+        '/' => [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000],
+        _ => [0; 7],
+    }
+}
+
+/// mixes `color` into a pixel at half strength, so the document stays readable underneath
+fn blend(pixel: &mut Rgba<u8>, color: [u8; 3]) {
+    for (channel, color) in pixel.0.iter_mut().zip(color) {
+        *channel = ((u16::from(*channel) + u16::from(color)) / 2) as u8;
+    }
+}
+
+/// the text repeated in bands down the image, so it can't simply be cropped off
+fn draw_visible(pixels: &mut RgbaImage, text: &str) {
+    let (width, height) = pixels.dimensions();
+    let advance = GLYPH_WIDTH + 1;
+    let text_width = text.chars().count() as u32 * advance;
+    let scale = (width * 9 / 10 / text_width.max(1)).max(1);
+    let line_height = (GLYPH_HEIGHT + 2) * scale;
+
+    let left = width.saturating_sub(text_width * scale) / 2;
+    let mut top = line_height;
+    while top + line_height <= height {
+        for (index, char) in text.chars().enumerate() {
+            let glyph_left = left + index as u32 * advance * scale;
+            for (row, bits) in glyph(char).into_iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                        continue
+                    }
+
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            let x = glyph_left + col * scale + dx;
+                            let y = top + row as u32 * scale + dy;
+                            // a dark shadow keeps the light text visible on light documents
+                            if let Some(shadow) = pixels.get_pixel_mut_checked(x + scale, y + scale) {
+                                blend(shadow, [0, 0, 0]);
+                            }
+                            if let Some(pixel) = pixels.get_pixel_mut_checked(x, y) {
+                                blend(pixel, [255, 255, 255]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        top += (height / 4).max(line_height);
+    }
+}
+
+/// writes the tag into the lowest blue bit of every pixel, repeated so any crop still holds it
+fn embed_tag(pixels: &mut RgbaImage, tag: &[u8]) {
+    let bits = tag.len() * 8;
+    for (index, pixel) in pixels.pixels_mut().enumerate() {
+        let bit = index % bits;
+        let value = (tag[bit / 8] >> (7 - bit % 8)) & 1;
+        pixel.0[2] = (pixel.0[2] & !1) | value;
+    }
+}
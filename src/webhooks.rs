@@ -0,0 +1,374 @@
+use std::error::Error;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use actix_tls::connect::{Connector, Resolve, Resolver};
+use actix_web::http::Uri;
+use actix_web::web;
+use bytestring::ByteString;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use utoipa::ToSchema;
+use crate::{admin, env_or, sensitive, signing};
+use crate::error::AppError;
+use crate::metrics::{Delivery, DeliveryError};
+use crate::req_code::RequestCode;
+
+/// sinks a single request may fan out to besides its websocket
+pub const MAX_WEBHOOKS: usize = 4;
+/// websockets a single request may fan out to besides its listener's
+pub const MAX_FOLLOWERS: usize = 4;
+/// pauses between tries for tenants that didn't pick their own
+const DEFAULT_BACKOFF_SECS: [u64; 2] = [2, 4];
+/// retries a tenant may ask for
+const MAX_RETRIES: usize = 8;
+/// longest pause a tenant may ask for between two tries, all of them together stay well inside the
+/// time statuses are kept
+const MAX_BACKOFF_SECS: u64 = 3 * 60;
+/// how long delivery statuses stay around for a tenant to look at
+const RETENTION: Duration = Duration::from_secs(30 * 60);
+
+static TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(env_or("ABSHER_WEBHOOK_TIMEOUT_MS", 5000))
+});
+/// a detached JWS over the body, checked against /.well-known/jwks.json like receipts
+pub const SIGNATURE_HEADER: &str = "x-absher-signature";
+
+/// plain http is only for trying things out against a local receiver
+static ALLOW_HTTP: LazyLock<bool> = LazyLock::new(|| env_or("ABSHER_WEBHOOK_ALLOW_HTTP", false));
+/// so is posting to loopback and private addresses, anywhere else it would let listeners reach into
+/// the network we run in
+static ALLOW_PRIVATE: LazyLock<bool> = LazyLock::new(|| env_or("ABSHER_WEBHOOK_ALLOW_PRIVATE", false));
+
+/// how a tenant's webhooks are retried
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct RetryPolicy {
+    /// seconds to wait before each retry, so one more try than there are pauses; `[2, 4]` if
+    /// unset and a single try with `[]`
+    #[serde(default)]
+    pub backoff_secs: Option<Vec<u64>>,
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), AppError> {
+        let backoff = self.backoff_secs.as_deref().unwrap_or_default();
+        match backoff.len() <= MAX_RETRIES && backoff.iter().all(|&secs| secs <= MAX_BACKOFF_SECS) {
+            true => Ok(()),
+            false => Err(AppError::InvalidRetryPolicy { max_retries: MAX_RETRIES, max_backoff_secs: MAX_BACKOFF_SECS }),
+        }
+    }
+
+    fn backoff(&self) -> Vec<Duration> {
+        self.backoff_secs.as_deref()
+            .unwrap_or(&DEFAULT_BACKOFF_SECS)
+            .iter()
+            .map(|&secs| Duration::from_secs(secs))
+            .collect()
+    }
+}
+
+/// whether `url` is somewhere we are willing to post resolved data to; a name is only resolved
+/// when it's posted to, and `PublicOnly` turns it away then if it leads somewhere private
+pub fn acceptable(url: &str) -> bool {
+    let Ok(uri) = url.parse::<Uri>() else {
+        return false
+    };
+
+    let scheme_ok = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => *ALLOW_HTTP,
+        _ => false,
+    };
+
+    let host_ok = uri.host().is_some_and(|host| {
+        // v6 addresses keep their brackets in a uri
+        let literal = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>();
+        let private = match literal {
+            Ok(ip) => !is_public(ip),
+            Err(_) => host.eq_ignore_ascii_case("localhost") || host.to_ascii_lowercase().ends_with(".localhost"),
+        };
+        !host.is_empty() && (*ALLOW_PRIVATE || !private)
+    });
+
+    scheme_ok && host_ok && url.len() <= 2048
+}
+
+/// whether `ip` is out on the internet, rather than this host, a private network or a range that
+/// isn't routed at all
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_v4(mapped),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || first == 0
+        // carrier grade nat
+        || (first == 100 && (64..128).contains(&second))
+        // benchmarking
+        || (first == 198 && (18..20).contains(&second))
+        // reserved for future use
+        || first >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // nat64 carries a v4 address in its last 32 bits
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., high, low] = segments;
+        return is_public_v4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+    }
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // documentation
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+}
+
+/// resolves webhook hosts and refuses the ones leading anywhere private; the connection goes to the
+/// very addresses checked here, so a name can't be pointed elsewhere between the check and the post
+struct PublicOnly;
+
+impl Resolve for PublicOnly {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<SocketAddr>, Box<dyn Error>>> + 'a>> {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host, port)).await?.collect::<Vec<_>>();
+            match addrs.iter().all(|addr| is_public(addr.ip())) {
+                true => Ok(addrs),
+                false => Err(format!("{host} resolves to a private address").into()),
+            }
+        })
+    }
+}
+
+fn build_client() -> awc::Client {
+    // a redirect could lead anywhere, the webhook url is all that was checked
+    let builder = awc::Client::builder().timeout(*TIMEOUT).disable_redirects();
+    if *ALLOW_PRIVATE {
+        return builder.finish()
+    }
+
+    let connector = awc::Connector::new().connector(Connector::new(Resolver::custom(PublicOnly)).service());
+    builder.connector(connector).finish()
+}
+
+thread_local! {
+    /// one per worker, so retries and busy receivers reuse their connections
+    static CLIENT: awc::Client = build_client();
+}
+
+fn client() -> awc::Client {
+    CLIENT.with(awc::Client::clone)
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Status {
+    /// still being tried
+    Pending,
+    Delivered,
+    Failed { error: String },
+    /// the websocket was already gone when the data arrived
+    NoListener,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct SinkStatus {
+    /// `websocket`, `follower {n}` in the order they attached, or the webhook url
+    sink: String,
+    #[serde(flatten)]
+    status: Status,
+    attempts: u32,
+}
+
+struct Deliveries {
+    tenant: String,
+    sinks: Vec<SinkStatus>,
+    until: Instant,
+}
+
+static DELIVERIES: LazyLock<DashMap<RequestCode, Deliveries>> = LazyLock::new(DashMap::new);
+
+/// hands `body` to every follower and posts it to every webhook of `code`, and keeps track of how
+/// that and the websocket went
+pub fn fan_out(
+    code: RequestCode,
+    tenant: String,
+    listener_reached: bool,
+    followers: Vec<oneshot::Sender<ByteString>>,
+    urls: Vec<String>,
+    body: String,
+) {
+    // held and retried for a while, so it gets wiped like everything else the payload is sent in
+    let body = sensitive::bytes(body.into_bytes());
+    if urls.is_empty() && followers.is_empty() {
+        return
+    }
+
+    let websocket = SinkStatus {
+        sink: "websocket".to_owned(),
+        status: if listener_reached { Status::Delivered } else { Status::NoListener },
+        attempts: 1,
+    };
+
+    // it was a String a moment ago, and the text shares the buffer that gets wiped
+    let text = ByteString::try_from(body.clone()).unwrap_or_default();
+    let followed = followers.into_iter().enumerate().map(|(index, follower)| SinkStatus {
+        sink: format!("follower {}", index + 1),
+        status: if follower.send(text.clone()).is_ok() { Status::Delivered } else { Status::NoListener },
+        attempts: 1,
+    }).collect::<Vec<_>>();
+    let first_webhook = 1 + followed.len();
+
+    let webhooks = urls.iter().map(|url| SinkStatus {
+        sink: url.clone(),
+        status: Status::Pending,
+        attempts: 0,
+    });
+
+    let sinks = std::iter::once(websocket).chain(followed).chain(webhooks).collect();
+    let backoff = admin::tenant_webhook_retries(&tenant).backoff();
+    DELIVERIES.insert(code, Deliveries { tenant, sinks, until: Instant::now() + RETENTION });
+
+    // signed once, so every retry carries the same signature
+    let signature = signing::detached("absher-webhook+jws", &body).unwrap_or_default();
+    for (index, url) in urls.into_iter().enumerate() {
+        // awc clients are bound to the current thread, so is this
+        let delivery = Delivery::queue("webhook");
+        actix_web::rt::spawn(deliver(code, first_webhook + index, url, body.clone(), signature.clone(), backoff.clone(), delivery));
+    }
+}
+
+async fn post(url: &str, body: web::Bytes, signature: &str, correlation: &str) -> Result<(), DeliveryError> {
+    let response = client().post(url)
+        .content_type("application/json")
+        .insert_header(("x-correlation-id", correlation))
+        .insert_header((SIGNATURE_HEADER, signature))
+        .send_body(body)
+        .await?;
+
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(DeliveryError::status(response.status())),
+    }
+}
+
+async fn deliver(
+    code: RequestCode,
+    index: usize,
+    url: String,
+    body: web::Bytes,
+    signature: String,
+    backoff: Vec<Duration>,
+    delivery: Delivery,
+) {
+    let correlation = code.correlation_id();
+    let mut pauses = backoff.into_iter();
+    for attempt in 1.. {
+        let result = post(&url, body.clone(), &signature, &correlation).await;
+        match &result {
+            Ok(()) => delivery.delivered(),
+            Err(err) => {
+                delivery.failed(err.cause);
+                log::warn!(code_hash = correlation.as_str(); "[{correlation}] webhook {url} failed on attempt {attempt}: {err}");
+            }
+        }
+
+        let pause = pauses.next();
+        let done = result.is_ok() || pause.is_none();
+        if let Some(mut deliveries) = DELIVERIES.get_mut(&code)
+            && let Some(sink) = deliveries.sinks.get_mut(index)
+        {
+            sink.attempts = attempt;
+            sink.status = match result {
+                Ok(()) => Status::Delivered,
+                Err(error) if done => Status::Failed { error: error.message },
+                Err(_) => Status::Pending,
+            };
+        }
+
+        match pause {
+            Some(pause) if !done => delivery.retry_after(pause).await,
+            _ => return,
+        }
+    }
+}
+
+/// how delivering `code` went, only for the tenant that asked for it
+pub fn statuses(code: &RequestCode, tenant: &str) -> Option<Vec<SinkStatus>> {
+    DELIVERIES.get(code)
+        .filter(|deliveries| deliveries.tenant == tenant)
+        .map(|deliveries| deliveries.sinks.clone())
+}
+
+pub fn prune() {
+    let now = Instant::now();
+    DELIVERIES.retain(|_code, deliveries| now <= deliveries.until);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_https_urls_are_taken() {
+        assert!(acceptable("https://hooks.example.com/absher"));
+        assert!(acceptable("https://[2606:4700::1111]/absher"));
+        assert!(!acceptable("http://hooks.example.com/absher"));
+        assert!(!acceptable("ftp://hooks.example.com/absher"));
+        assert!(!acceptable("hooks.example.com/absher"));
+        assert!(!acceptable(&format!("https://hooks.example.com/{}", "a".repeat(2048))));
+
+        for private in [
+            "https://localhost/", "https://api.LOCALHOST:8443/", "https://127.0.0.1/", "https://10.1.2.3/",
+            "https://169.254.169.254/latest/meta-data", "https://[::1]/", "https://[::ffff:192.168.0.1]/",
+            "https://[64:ff9b::a9fe:a9fe]/", "https://[fd00::1]/",
+        ] {
+            assert!(!acceptable(private), "{private} was let through");
+        }
+    }
+
+    #[test]
+    fn unrouted_ranges_are_not_public() {
+        let public = ["1.1.1.1", "100.128.0.1", "198.20.0.1", "2606:4700::1111", "::ffff:8.8.8.8", "64:ff9b::808:808"];
+        for ip in public {
+            assert!(is_public(ip.parse().unwrap()), "{ip} was taken for private");
+        }
+
+        let private = [
+            "0.1.2.3", "100.64.0.1", "100.127.255.255", "198.18.0.1", "198.19.255.255", "192.0.2.1", "224.0.0.1",
+            "240.0.0.1", "255.255.255.255", "::", "fe80::1", "ff02::1", "2001:db8::1", "64:ff9b::7f00:1",
+        ];
+        for ip in private {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} was taken for public");
+        }
+    }
+
+    #[actix_web::test]
+    async fn names_leading_somewhere_private_are_not_connected_to() {
+        assert!(PublicOnly.lookup("localhost", 443).await.is_err());
+        assert!(PublicOnly.lookup("127.0.0.1", 443).await.is_err());
+    }
+}
@@ -0,0 +1,505 @@
+use std::time::Duration;
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
+use actix_web::{get, web, HttpRequest, Responder};
+use actix_ws::{Message, ProtocolError};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use tokio::sync::oneshot;
+use tracing::Instrument;
+use zeroize::Zeroize;
+use crate::admin::ApiKey;
+use crate::audit::Actor;
+use crate::challenge::Challenge;
+use crate::devices::Owner;
+use crate::error::{AppError, ErrorResponse};
+use crate::geo::GeoCheck;
+use crate::handlers::presented_key;
+use crate::metrics::{ListenerGuard, METRICS};
+use crate::pin::Pin;
+use crate::protocol::{ClientMessage, Frame, ListenSpec, OutputFormat, Protocol};
+use crate::req_code::RequestCode;
+use crate::store::{bury, burial, new_request, withdraw_request, Burial, RequestOptions, Resolution, MAP};
+use crate::ttl::TtlPolicy;
+use crate::{
+    admin, audit, capacity, cbor, challenge, client_errors, clock, connections, cors, custom_fields, denylist, devices, maintenance,
+    geo, history, metering, peer, protocol, push, rate_limit, request_id, sensitive, sessions, shutdown, sms, telemetry, ttl, webhooks,
+};
+
+/// listeners hand out raw x25519 public keys as standard base64
+fn valid_public_key(public_key: &str) -> bool {
+    BASE64_STANDARD.decode(public_key).is_ok_and(|key| key.len() == 32)
+}
+
+/// how long a challenged listener gets to send its solution
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// sends a frame the way the listener spoke first, text for json and binary for cbor; its buffer
+/// is wiped once sent, a data frame holds everything that was shared
+async fn send(session: &mut actix_ws::Session, frame: Frame) -> Result<(), actix_ws::Closed> {
+    match frame {
+        Frame::Text(frame) => session.text(sensitive::text(frame)).await,
+        Frame::Binary(frame) => session.binary(sensitive::bytes(frame)).await,
+    }
+}
+
+/// a later message from the listener, in the encoding it picked with its first one
+fn client_message(protocol: Protocol, msg: &Message) -> Option<ClientMessage> {
+    match msg {
+        Message::Text(json) if !protocol.binary() => protocol::parse_message(json),
+        Message::Binary(cbor) if protocol.binary() => protocol::parse_message(&cbor::to_json(cbor)?),
+        _ => None,
+    }
+}
+
+async fn close_with(mut session: actix_ws::Session, protocol: Option<Protocol>, err: AppError) {
+    if let Some(protocol) = protocol
+        && let Some(Ok(frame)) = protocol.error_frame(&err).map(|frame| protocol.encode(frame))
+    {
+        let _ = send(&mut session, frame).await;
+    }
+
+    let _ = session.close(Some(err.close_reason())).await;
+}
+
+#[utoipa::path(
+    get,
+    path = "/listen",
+    tag = "listener",
+    description = "Upgrades to the listener websocket, the messages sent over it are described at the top.",
+    params(("x-api-key" = Option<String>, Header, description = "the tenant's api key, keyless listeners may be challenged")),
+    responses(
+        (status = 101, description = "switched to the websocket"),
+        (status = 429, description = "too many connections or requests from this address", body = ErrorResponse),
+        (status = 503, description = "at capacity, under maintenance or shutting down", body = ErrorResponse),
+    ),
+)]
+#[get("/listen", wrap = "from_fn(rate_limit::limit)", wrap = "from_fn(telemetry::trace)")]
+pub async fn listen(req: HttpRequest, body: web::Payload) -> actix_web::Result<impl Responder> {
+    // only browsers send an origin, anything else could make one up anyway
+    if req.headers().get(header::ORIGIN).is_some_and(|origin| !cors::allows(origin.as_bytes())) {
+        return Err(AppError::OriginNotAllowed.into())
+    }
+
+    let origin = req.headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+
+    if shutdown::draining() {
+        return Err(AppError::ShuttingDown.into())
+    }
+
+    // requests that are already open can still be resolved, only new ones wait
+    if maintenance::enabled() {
+        return Err(AppError::UnderMaintenance.into())
+    }
+
+    // listeners without a key are still allowed, a key only restricts what can be requested
+    let key = presented_key(&req)?;
+
+    let ip = peer::ip(&req);
+    let connection = connections::open(ip)?;
+
+    // taken before the upgrade, so sockets that never send a specification count as well
+    let slot = capacity::reserve(key.as_ref().map(|key| key.tenant.as_str()))?;
+
+    let (response, mut session, msg_stream) = actix_ws::handle(&req, body)
+        .map_err(|_| AppError::ExpectedWebsocket)?;
+    let mut msg_stream = msg_stream.max_frame_size(*connections::MAX_MESSAGE_BYTES);
+
+    // the span lasts as long as the socket, so it covers the resolve it waits for
+    let span = tracing::Span::current();
+    let request_id = request_id::of(&req);
+    actix_web::rt::spawn(async move {
+        let _listener = ListenerGuard::new(key.as_ref().map(|key| key.tenant.as_str()));
+        let _connection = connection;
+        let first = match tokio::time::timeout(*connections::SPEC_TIMEOUT, msg_stream.recv()).await {
+            Ok(Some(Ok(Message::Text(json)))) => Some((json.to_string(), false)),
+            Ok(Some(Ok(Message::Binary(cbor)))) => cbor::to_json(&cbor).map(|json| (json, true)),
+            Err(_) => return close_with(session, None, AppError::SpecificationTimeout).await,
+            Ok(Some(Err(ProtocolError::Overflow))) => {
+                let max = *connections::MAX_MESSAGE_BYTES;
+                return close_with(session, None, AppError::MessageTooLarge { max }).await
+            }
+            Ok(_) => return close_with(session, None, AppError::ExpectedSpecification).await,
+        };
+
+        let Some((protocol, mut spec)) = first.and_then(|(json, binary)| protocol::parse_first_frame(&json, binary)) else {
+            close_with(session, None, AppError::InvalidSpecification).await;
+            return;
+        };
+        METRICS.protocols.with_label_values(&[protocol.name()]).inc();
+
+        if let Err(err) = check_spec(&spec, key.as_ref()) {
+            close_with(session, Some(protocol), err).await;
+            return;
+        }
+
+        // keyless listeners are the ones bots use, so only they pay for codes under attack
+        if key.is_none() && challenge::required() {
+            if protocol == Protocol::Legacy {
+                close_with(session, Some(protocol), AppError::ChallengeRequired).await;
+                return;
+            }
+
+            let challenge = Challenge::new();
+            let frame = match Protocol::challenge_frame(&challenge).and_then(|frame| protocol.encode(frame)) {
+                Ok(frame) => frame,
+                Err(err) => return close_with(session, Some(protocol), err).await
+            };
+
+            if send(&mut session, frame).await.is_err() {
+                return;
+            }
+
+            let answer = tokio::time::timeout(CHALLENGE_TIMEOUT, msg_stream.recv()).await;
+            let solved = match answer {
+                Ok(Some(Ok(msg))) => matches!(
+                    client_message(protocol, &msg),
+                    Some(ClientMessage::Solution { solution }) if challenge.verify(&solution)
+                ),
+                _ => false
+            };
+
+            if !solved {
+                close_with(session, Some(protocol), AppError::ChallengeFailed).await;
+                return;
+            }
+        }
+
+        let actor = Actor { by: key.as_ref().map(|key| key.id.clone()), ip, request_id: Some(request_id.clone()) };
+        let tenant = key.map(|key| key.tenant);
+        let client_session = spec.session.take();
+        let mut opened = match open(spec, tenant.as_deref(), slot, &actor) {
+            Ok(opened) => opened,
+            Err(err) => return close_with(session, Some(protocol), err).await,
+        };
+        let (code, ttl) = (opened.code, opened.ttl);
+        let correlation = code.correlation_id();
+        tracing::Span::current().record("absher.correlation_id", &correlation);
+        log::info!(
+            code_hash = correlation.as_str(),
+            request_id = request_id.as_str(),
+            tenant = tenant.as_deref().unwrap_or_default();
+            "[{correlation}] issued to listener {request_id} for {}s", ttl.as_secs()
+        );
+
+        let frame = match protocol.code_frame(&code, ttl, opened.pin.as_deref()).and_then(|frame| protocol.encode(frame)) {
+            Ok(frame) => frame,
+            Err(err) => return close_with(session, Some(protocol), err).await
+        };
+
+        let Ok(()) = send(&mut session, frame).await else {
+            // web socket closed
+            return;
+        };
+
+        opened.announce();
+
+        // a duplicate tab of the same page takes over, so only one code is ever worth scanning
+        let mut registration = client_session
+            .and_then(|client_session| sessions::register(origin, client_session, code));
+
+        let superseded = async {
+            match registration.as_mut() {
+                Some(registration) => registration.superseded().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let data_rcv = opened.resolution;
+        tokio::pin!(data_rcv, superseded);
+        let mut listening = true;
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut data_rcv => break outcome,
+                () = &mut superseded => {
+                    if let Some((_, request)) = MAP.remove(&code) {
+                        bury(code, Burial::Superseded, request.tenant.as_deref(), clock::now());
+                    }
+
+                    if let Some(Ok(frame)) = protocol.superseded_frame().map(|frame| protocol.encode(frame?)) {
+                        let _ = send(&mut session, frame).await;
+                    }
+
+                    log::info!(code_hash = correlation.as_str(); "[{correlation}] superseded by a newer listener");
+                    let _ = session.close(Some(AppError::RequestSuperseded(code).close_reason())).await;
+                    return;
+                }
+                msg = msg_stream.recv(), if listening => match msg {
+                    Some(Ok(msg)) => {
+                        if let Some(ClientMessage::ClientError(client_error)) = client_message(protocol, &msg)
+                            && client_error.validate().is_ok()
+                        {
+                            client_errors::record(code, tenant.as_deref(), &client_error);
+                            audit::record("client_error", &code, tenant.as_deref(), Vec::new(), &actor);
+                        }
+                    }
+                    // the request stays answerable, the listener may just have lost its connection
+                    Some(Err(_)) | None => listening = false,
+                },
+                () = shutdown::drained() => {
+                    // nothing survives the restart, the page should open a new request on the next instance
+                    withdraw_request(&code);
+                    log::info!(code_hash = correlation.as_str(); "[{correlation}] sent away by a shutdown");
+                    close_with(session, Some(protocol), AppError::ShuttingDown).await;
+                    return;
+                }
+            }
+        };
+
+        match outcome {
+            Ok(resolution) => match protocol.data_frame(&resolution.data, resolution.verification.as_ref())
+                .and_then(|frame| protocol.encode(frame))
+            {
+                Ok(mut frame) => {
+                    let integrity = protocol.integrity_frame(&[frame.bytes()]);
+                    // the announcement is the only part that could fail, sending whole still works then
+                    if let Some(Ok(stream)) = protocol.stream_frames(frame.bytes())
+                        && let Ok(announcement) = protocol.encode(stream.announcement)
+                    {
+                        let _ = send(&mut session, announcement).await;
+                        for chunk in stream.chunks {
+                            let _ = session.binary(sensitive::bytes(chunk)).await;
+                        }
+                        frame.zeroize();
+                    } else {
+                        let _ = send(&mut session, frame).await;
+                    }
+
+                    let timing = resolution.timing.relayed_after(resolution.handed_off.elapsed());
+                    let trailers = [
+                        protocol.timing_frame(&timing),
+                        protocol.receipt_frame(&resolution.receipt),
+                        resolution.user_hint.as_deref().and_then(|user_hint| protocol.linked_frame(user_hint)),
+                        integrity,
+                    ];
+                    for frame in trailers.into_iter().flatten() {
+                        if let Ok(frame) = frame.and_then(|frame| protocol.encode(frame)) {
+                            let _ = send(&mut session, frame).await;
+                        }
+                    }
+
+                    log::info!(code_hash = correlation.as_str(); "[{correlation}] delivered to the listener");
+
+                    let _ = session.close(None).await;
+                }
+                Err(err) => close_with(session, Some(protocol), err).await
+            },
+            // the request was dropped from the map without an answer
+            Err(_) => match unanswered(code) {
+                // the expiry task ran it out, or an operator did
+                AppError::RequestExpired(_) => {
+                    if let Some(Ok(frame)) = protocol.expired_frame().map(|frame| protocol.encode(frame?)) {
+                        let _ = send(&mut session, frame).await;
+                    }
+
+                    log::info!(code_hash = correlation.as_str(); "[{correlation}] expired before an answer came");
+                    let _ = session.close(Some(AppError::RequestExpired(code).close_reason())).await;
+                }
+                err => {
+                    if matches!(err, AppError::RequestRejected(_)) {
+                        log::info!(code_hash = correlation.as_str(); "[{correlation}] declined by the user");
+                    }
+                    close_with(session, Some(protocol), err).await;
+                }
+            },
+        }
+
+        log::info!(code_hash = correlation.as_str(); "[{correlation}] listener closed");
+    }.instrument(span));
+
+    Ok(response)
+}
+
+#[utoipa::path(
+    get,
+    path = "/requests/{code}/follow",
+    tag = "listener",
+    description = "Upgrades to a websocket that gets the answer to a request opened with `followers`, as the json its \
+        webhooks get, and then closes; closes like a listener's would if the request ends without one. Takes the api \
+        key the request was opened with.",
+    params(("code" = String, Path, description = "the request code"), ("x-api-key" = String, Header)),
+    responses(
+        (status = 101, description = "switched to the websocket"),
+        (status = 401, description = "missing or wrong api key", body = ErrorResponse),
+        (status = 404, description = "no such request for this tenant", body = ErrorResponse),
+        (status = 409, description = "the request has as many followers as it was opened for", body = ErrorResponse),
+    ),
+)]
+#[get("/requests/{code}/follow", wrap = "from_fn(rate_limit::limit)")]
+pub async fn follow(req: HttpRequest, code: web::Path<RequestCode>, body: web::Payload) -> actix_web::Result<impl Responder> {
+    let code = code.into_inner();
+    let Some(key) = presented_key(&req)? else {
+        return Err(AppError::InvalidApiKey.into())
+    };
+    let connection = connections::open(peer::ip(&req))?;
+
+    // nothing is sent before the response is returned, a follower that isn't let in gets a plain error
+    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, body)
+        .map_err(|_| AppError::ExpectedWebsocket)?;
+
+    let answer = {
+        let mut pending = MAP.get_mut(&code)
+            .filter(|pending| pending.tenant.as_deref() == Some(key.tenant.as_str()))
+            .ok_or(AppError::RequestNotFound(Some(code)))?;
+        if pending.followers.len() >= pending.max_followers {
+            return Err(AppError::FollowerLimitReached(code).into())
+        }
+
+        let (tx, rx) = oneshot::channel();
+        pending.followers.push(tx);
+        rx
+    };
+
+    actix_web::rt::spawn(async move {
+        let _connection = connection;
+        tokio::pin!(answer);
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut answer => break outcome,
+                // a follower that goes away is reported as `no_listener` once the answer comes
+                msg = msg_stream.recv() => match msg {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return
+                        }
+                    }
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                    Some(Ok(_)) => {}
+                },
+                () = shutdown::drained() => return close_with(session, None, AppError::ShuttingDown).await,
+            }
+        };
+
+        match outcome {
+            Ok(body) => {
+                if session.text(body).await.is_ok() {
+                    let _ = session.close(None).await;
+                }
+            }
+            Err(_) => close_with(session, None, unanswered(code)).await,
+        }
+    });
+
+    Ok(response)
+}
+
+/// turns away what a listener with `key` may not ask for, before it gets a code
+pub(crate) fn check_spec(spec: &ListenSpec, key: Option<&ApiKey>) -> Result<(), AppError> {
+    if spec.public_key.as_deref().is_some_and(|public_key| !valid_public_key(public_key)) {
+        return Err(AppError::InvalidSpecification)
+    }
+
+    if key.is_some_and(|key| !spec.fields.is_within(&key.scopes)) {
+        return Err(AppError::ScopeExceeded)
+    }
+
+    // posting data to arbitrary urls is only for listeners we know
+    let webhooks_ok = spec.webhooks.is_empty() || (key.is_some()
+        && spec.webhooks.len() <= webhooks::MAX_WEBHOOKS
+        && spec.webhooks.iter().all(|url| webhooks::acceptable(url)));
+    if !webhooks_ok {
+        return Err(AppError::InvalidWebhook { max: webhooks::MAX_WEBHOOKS })
+    }
+
+    if spec.followers > 0 && (key.is_none() || spec.followers > webhooks::MAX_FOLLOWERS) {
+        return Err(AppError::InvalidFollowers { max: webhooks::MAX_FOLLOWERS })
+    }
+
+    custom_fields::check_declared(&spec.custom_fields)?;
+
+    if spec.format != OutputFormat::Json && spec.public_key.is_some() {
+        return Err(AppError::CredentialUnavailable)
+    }
+
+    if let Some(checks) = &spec.hash_checks {
+        checks.check_declared()?;
+
+        // a match says almost as much as the value, so it takes the same scope
+        if key.is_some_and(|key| !checks.fields().all(|field| key.scopes.requests_text(field))) {
+            return Err(AppError::ScopeExceeded)
+        }
+    }
+
+    Ok(())
+}
+
+/// a request opened for a listener that doesn't have its code yet
+pub(crate) struct Opened {
+    pub(crate) code: RequestCode,
+    pub(crate) ttl: Duration,
+    /// shown next to the code, when the listener wanted one
+    pub(crate) pin: Option<String>,
+    pub(crate) resolution: oneshot::Receiver<Resolution>,
+    owner: Option<Owner>,
+    text: Option<sms::Text>,
+}
+
+impl Opened {
+    /// pushes and texts the code, once the listener has it
+    pub(crate) fn announce(&mut self) {
+        if let Some(owner) = &self.owner {
+            push::notify(owner, &self.code, self.ttl);
+        }
+        if let Some(text) = self.text.take() {
+            sms::send(text, &self.code, self.ttl);
+        }
+    }
+}
+
+/// issues a code for `spec`, which has already been checked, to the listener `actor`
+pub(crate) fn open(spec: ListenSpec, tenant: Option<&str>, slot: capacity::Slot, actor: &Actor) -> Result<Opened, AppError> {
+    // keyless listeners don't get to hold codes open for longer than usual, they get the default
+    let ttl = match tenant {
+        Some(tenant) => ttl::resolve(spec.ttl_secs, admin::tenant_ttl(tenant)),
+        None => ttl::resolve(None, TtlPolicy::default()),
+    };
+
+    let pin = spec.pin.then(Pin::new);
+    let shown_pin = pin.as_ref().map(|pin| pin.value().to_owned());
+    // hints are per tenant, a keyless listener's can't stand for anyone
+    let owner = tenant.zip(spec.user_hint.as_deref())
+        .and_then(|(tenant, user_hint)| devices::owner(tenant, user_hint));
+    if spec.linked_devices_only && owner.is_none() {
+        return Err(AppError::UserNotLinked)
+    }
+    // counted against the limits before there is a code, a listener over them gets none
+    let text = spec.sms_to.as_deref().map(|number| sms::reserve(tenant, number)).transpose()?;
+    let (encrypted, pinned) = (spec.public_key.is_some(), pin.is_some());
+    let options = RequestOptions {
+        public_key: spec.public_key,
+        pin,
+        webhooks: spec.webhooks,
+        followers: spec.followers,
+        custom_fields: spec.custom_fields,
+        hash_checks: spec.hash_checks,
+        format: spec.format,
+        answerable_by: owner.clone().filter(|_| spec.linked_devices_only),
+        // only looked up for tenants that check it, keyless listeners have no say
+        country: tenant
+            .filter(|tenant| admin::tenant_geo(tenant) != GeoCheck::Off)
+            .and(actor.ip)
+            .and_then(geo::country),
+    };
+    let (code, resolution) = new_request(spec.code_format, spec.fields, ttl, options, slot);
+    audit::record("created", &code, tenant, spec.fields.names(), actor);
+    metering::created(tenant, &spec.fields);
+    history::created(&code, tenant, &spec.fields, encrypted, pinned);
+    Ok(Opened { code, ttl, pin: shown_pin, resolution, owner, text })
+}
+
+/// why `code` left the store without an answer
+pub(crate) fn unanswered(code: RequestCode) -> AppError {
+    if denylist::is_denied(&code) {
+        return AppError::RequestDenied(code)
+    }
+
+    match burial(&code, clock::now()) {
+        Some(Burial::Locked) => AppError::PinAttemptsExhausted(code),
+        Some(Burial::Rejected) => AppError::RequestRejected(code),
+        // the expiry task ran it out, or an operator did
+        _ => AppError::RequestExpired(code),
+    }
+}